@@ -14,6 +14,8 @@
 #![feature(slice_patterns)]
 #![feature(linkage)]
 #![feature(stmt_expr_attributes)]
+#![feature(repr_align, attr_literals)]
+#![feature(integer_atomics)]
 #![cfg_attr(target_arch = "x86_64", feature(abi_x86_interrupt))]
 #![no_std]
 
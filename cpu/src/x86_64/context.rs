@@ -11,9 +11,10 @@
 //! This is inteded to be general-purpose and composable, so that the same
 //! code can be reused for interrupts and for multithreading.
 
-use core::mem;
-use core::fmt;
+use core::{fmt, mem, ptr};
+use util::backtrace::{self, Backtrace};
 use super::flags::{Flags as RFlags};
+use super::fpu::{self, ExtendedState};
 use super::segment;
 
 /// Registers pushed to the stack when handling an interrupt or context switch.
@@ -130,6 +131,20 @@ pub struct InterruptFrame {
   , __pad_4: u16
 }
 
+impl InterruptFrame {
+    /// Returns a backtrace of the code that was interrupted, starting with
+    /// the interrupted instruction.
+    ///
+    /// # Safety
+    /// + This must be called directly from the interrupt handler which was
+    ///   passed this frame, and not from a function it calls, since it
+    ///   reads the interrupted `%rbp` from the handler's own stack frame.
+    #[inline(always)]
+    pub unsafe fn backtrace(&self) -> Backtrace {
+        Backtrace::from_interrupt(self.rip as usize, backtrace::current_rbp())
+    }
+}
+
 #[cfg(test)]
 mod test {
     #[test]
@@ -161,7 +176,8 @@ impl fmt::Debug for InterruptFrame {
 }
 
 /// Thread execution context
-#[repr(C, packed)]
+//  (this can't be packed, since the extended state must be 64-byte aligned)
+#[repr(C)]
 pub struct Context { /// Value of the stack pointer (`rsp`) register
                      pub rsp: *mut u8
                    , /// Value of the caller-saved registers
@@ -169,16 +185,111 @@ pub struct Context { /// Value of the stack pointer (`rsp`) register
                    , /// Value of the instruction pointer (`rip`) register
                      pub rip: *mut u8
                  //, pub stack: [u8] // TODO: should be box
+                   , /// The x87, SSE and AVX registers
+                     pub extended: ExtendedState
                    }
 
 impl Context {
-    pub fn empty() -> Self {
-        unsafe {
-            Context { rsp: mem::transmute(0u64)
-                    , registers: Registers::empty()
-                    , rip: mem::transmute(0u64)
-                  //, stack: [0u8; 8]
-                    }
+    pub const fn empty() -> Self {
+        Context { rsp: ptr::null_mut()
+                , registers: Registers::empty()
+                , rip: ptr::null_mut()
+              //, stack: [0u8; 8]
+                , extended: ExtendedState::new()
+                }
+    }
+
+    /// Returns a new context which will run `entry(arg)` on the stack whose
+    /// top is `stack_top`, the first time it is switched to.
+    ///
+    /// The callee-saved registers that [`switch_to`] restores are written
+    /// just below `stack_top`. `entry` is called with a null frame pointer,
+    /// so backtraces stop there, and must never return.
+    ///
+    /// # Safety
+    /// + `stack_top` must be the top of a mapped, writable stack, which is
+    ///   not in use.
+    ///
+    /// [`switch_to`]: #method.switch_to
+    pub unsafe fn new( stack_top: *mut u8
+                     , entry: extern "C" fn(usize) -> !
+                     , arg: usize)
+                     -> Self {
+        // after `switch_stacks` pops this frame and returns into the
+        // trampoline, `%rsp` must be 16-byte aligned, so that it's aligned
+        // as the ABI expects when the trampoline calls `entry`.
+        let top = stack_top as usize & !0xf;
+        let frame = (top - 9 * 8) as *mut u64;
+        let words = [ 0                               // r15
+                    , 0                               // r14
+                    , entry as usize as u64           // r13
+                    , arg as u64                      // r12
+                    , 0                               // rbx
+                    , 0                               // rbp
+                    , thread_trampoline as usize as u64 // return address
+                    , 0                               // (never returned to)
+                    ];
+        for (i, &word) in words.iter().enumerate() {
+            *frame.offset(i as isize) = word;
         }
+        Context { rsp: frame as *mut u8, ..Context::empty() }
     }
+
+    /// Switch from this context (which must be the one running) to `next`.
+    ///
+    /// This saves the callee-saved registers on the current stack, and its
+    /// stack pointer in `self`, then switches to `next`'s stack and returns
+    /// to wherever `next` last called `switch_to` (or to its entry point, if
+    /// it has never run). Extended state is switched with `fpu::switch_to`.
+    ///
+    /// # Safety
+    /// + `next` must have been created with [`new`], or saved by a previous
+    ///   call to `switch_to`.
+    /// + Both contexts must stay where they are until `next` switches back.
+    ///
+    /// [`new`]: #method.new
+    #[inline(never)]
+    pub unsafe fn switch_to(&mut self, next: &mut Context) {
+        fpu::switch_to(&mut next.extended);
+        switch_stacks(&mut self.rsp, next.rsp);
+    }
+}
+
+/// Save the callee-saved registers, store the stack pointer in `*from`,
+/// load the stack pointer `to`, and restore its callee-saved registers.
+///
+/// THIS FUNCTION IS NAKED. ONLY CALL IT FROM `Context::switch_to`.
+#[naked]
+#[inline(never)]
+unsafe extern "C" fn switch_stacks(_from: *mut *mut u8, _to: *mut u8) {
+    asm!( "push rbp
+           push rbx
+           push r12
+           push r13
+           push r14
+           push r15
+           mov [rdi], rsp
+           mov rsp, rsi
+           pop r15
+           pop r14
+           pop r13
+           pop r12
+           pop rbx
+           pop rbp
+           ret"
+        :::: "intel"
+           , "volatile");
+}
+
+/// The first code a new context runs, which calls its entry point (in
+/// `%r13`) with its argument (in `%r12`).
+///
+/// THIS FUNCTION IS NAKED. DO NOT CALL IT NORMALLY.
+#[naked]
+unsafe extern "C" fn thread_trampoline() {
+    asm!( "mov rdi, r12
+           call r13
+           ud2"
+        :::: "intel"
+           , "volatile");
 }
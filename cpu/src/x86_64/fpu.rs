@@ -0,0 +1,440 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! x87 FPU, SSE and AVX state.
+//!
+//! The kernel itself is built without SSE (see the target spec), so only
+//! code which opts in to floating point, such as user code, touches these
+//! registers. Each execution context has an [`ExtendedState`] area which
+//! holds them while it isn't using the FPU. It is saved with `XSAVE` if the
+//! CPU supports it, and with `FXSAVE` otherwise.
+//!
+//! Contexts are switched with [`switch_to`]. With [`Switching::Lazy`] (the
+//! default), that only sets `TS` in `%cr0`. The first FPU instruction the
+//! new context executes then raises a device not available exception
+//! (`#NM`), and [`handle_device_not_available`] saves the old context's
+//! state and restores the new one's. With [`Switching::Eager`], the state
+//! is switched straight away.
+//!
+//! Which context is running, and which one the FPU's registers belong to,
+//! is tracked separately for each CPU, in a [`PerCpu`] in the CPU's per-CPU
+//! area. The kernel must put it at [`GS_OFFSET`] from the `%gs` base before
+//! calling [`initialize`]. Since a CPU's registers may hold a context's
+//! state until that CPU switches away from it, a context must not move to
+//! another CPU with lazy switching.
+//!
+//! [`ExtendedState`]: struct.ExtendedState.html
+//! [`switch_to`]: fn.switch_to.html
+//! [`Switching::Lazy`]: enum.Switching.html
+//! [`Switching::Eager`]: enum.Switching.html
+//! [`handle_device_not_available`]: fn.handle_device_not_available.html
+//! [`PerCpu`]: struct.PerCpu.html
+//! [`GS_OFFSET`]: constant.GS_OFFSET.html
+//! [`initialize`]: fn.initialize.html
+use core::{fmt, ptr};
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+
+use spin::Once;
+
+use ::control_regs::{cr0, cr4};
+use ::cpuid;
+use ::interrupts::without_interrupts;
+
+/// The size of an `ExtendedState` area, in bytes.
+///
+/// This is enough for the x87, SSE and AVX state; larger state components
+/// (such as AVX-512) are never enabled.
+pub const AREA_SIZE: usize = 1024;
+
+/// The value of `MXCSR` after initialization, with every SIMD exception
+/// masked.
+pub const DEFAULT_MXCSR: u32 = 0x1f80;
+
+/// Offset of `MXCSR` in the `FXSAVE` and `XSAVE` areas.
+const MXCSR_OFFSET: usize = 24;
+
+/// Offset of the running CPU's [`PerCpu`] from the `%gs` base.
+///
+/// [`PerCpu`]: struct.PerCpu.html
+pub const GS_OFFSET: usize = 32;
+
+/// Offset of `PerCpu::current`.
+const CURRENT: usize = 0;
+/// Offset of `PerCpu::owner`.
+const OWNER: usize = 8;
+
+bitflags! {
+    /// State components which can be enabled in `XCR0`.
+    pub flags Components: u64 { /// x87 FPU state
+                                const X87 = 1 << 0
+                              , /// SSE state (`%xmm` registers and `MXCSR`)
+                                const SSE = 1 << 1
+                              , /// AVX state (upper halves of `%ymm`
+                                /// registers)
+                                const AVX = 1 << 2
+                              }
+}
+
+/// How extended state is saved and restored.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Mode { /// `FXSAVE` and `FXRSTOR`, for the x87 and SSE state
+                FxSave
+              , /// `XSAVE` and `XRSTOR`, for the enabled components
+                XSave(Components)
+              }
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Mode::FxSave => f.write_str("FXSAVE")
+          , Mode::XSave(components) => write!(f, "XSAVE ({:?})", components)
+        }
+    }
+}
+
+/// When extended state is switched.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Switching { /// Switch on the first FPU instruction after a
+                     /// context switch, by trapping `#NM`
+                     Lazy
+                   , /// Switch on every context switch
+                     Eager
+                   }
+
+/// The saved x87, SSE and AVX registers of an execution context.
+#[repr(C, align(64))]
+pub struct ExtendedState { area: [u8; AREA_SIZE]
+                         , /// Whether `area` holds any saved state yet
+                           saved: bool
+                         }
+
+impl ExtendedState {
+    /// Returns a new `ExtendedState`.
+    ///
+    /// Until it is saved to, restoring it loads the FPU's initial state.
+    pub const fn new() -> Self {
+        ExtendedState { area: [0; AREA_SIZE], saved: false }
+    }
+
+    /// Save the FPU's current state here.
+    ///
+    /// # Safety
+    /// + `TS` must be clear in `%cr0`, or this raises `#NM`.
+    pub unsafe fn save(&mut self) {
+        match mode() {
+            Some(Mode::XSave(components)) =>
+                xsave(self.area.as_mut_ptr(), components)
+          , Some(Mode::FxSave) => fxsave(self.area.as_mut_ptr())
+          , None => return
+        }
+        self.saved = true;
+    }
+
+    /// Load this state into the FPU.
+    ///
+    /// # Safety
+    /// + `TS` must be clear in `%cr0`, or this raises `#NM`.
+    pub unsafe fn restore(&self) {
+        let area = if self.saved { &self.area }
+                   else {
+                       match DEFAULT.try() {
+                           Some(default) => &default.area
+                         , None => return
+                       }
+                   };
+        match mode() {
+            Some(Mode::XSave(components)) => xrstor(area.as_ptr(), components)
+          , Some(Mode::FxSave) => fxrstor(area.as_ptr())
+          , None => { }
+        }
+    }
+
+    /// Returns the saved value of `MXCSR`, if any state has been saved.
+    pub fn mxcsr(&self) -> Option<u32> {
+        if !self.saved { return None }
+        let bytes = &self.area[MXCSR_OFFSET .. MXCSR_OFFSET + 4];
+        Some( bytes[0] as u32 | (bytes[1] as u32) << 8
+            | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24 )
+    }
+}
+
+/// The extended state a CPU is switching between.
+///
+/// Each CPU must have its own, at [`GS_OFFSET`] in its per-CPU area. The
+/// functions in this module refer to these fields by offset, so their order
+/// must not change.
+///
+/// [`GS_OFFSET`]: constant.GS_OFFSET.html
+#[derive(Debug)]
+#[repr(C)]
+pub struct PerCpu { /// The state of the context which is running
+                    current: *mut ExtendedState
+                  , /// The state of the context which the FPU's registers
+                    /// belong to
+                    owner: *mut ExtendedState
+                  }
+
+impl PerCpu {
+    /// Returns a new `PerCpu`, with no contexts.
+    pub const fn new() -> Self {
+        PerCpu { current: 0 as *mut _, owner: 0 as *mut _ }
+    }
+}
+
+impl fmt::Debug for ExtendedState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.mxcsr() {
+            Some(mxcsr) =>
+                write!(f, "ExtendedState {{ mxcsr: {:#06x} }}", mxcsr)
+          , None => f.write_str("ExtendedState { unsaved }")
+        }
+    }
+}
+
+/// How extended state is saved, once the FPU is initialized.
+static MODE: Once<Mode> = Once::new();
+
+/// The FPU's initial state, which contexts start out with.
+static DEFAULT: Once<ExtendedState> = Once::new();
+
+/// Whether state is switched eagerly.
+static EAGER: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Read the field at `field` of the running CPU's `PerCpu`.
+#[inline]
+unsafe fn read_field(field: usize) -> *mut ExtendedState {
+    let state: *mut ExtendedState;
+    asm!( "movq %gs:($1), $0"
+        : "=r"(state)
+        : "r"(GS_OFFSET + field)
+        :: "volatile");
+    state
+}
+
+/// Set the field at `field` of the running CPU's `PerCpu` to `state`.
+#[inline]
+unsafe fn write_field(field: usize, state: *mut ExtendedState) {
+    asm!( "movq $0, %gs:($1)"
+        :: "r"(state), "r"(GS_OFFSET + field)
+        : "memory" : "volatile");
+}
+
+/// Returns how extended state is saved, or `None` if the FPU hasn't been
+/// initialized.
+#[inline]
+pub fn mode() -> Option<Mode> { MODE.try().cloned() }
+
+/// Set when extended state is switched.
+pub fn set_switching(switching: Switching) {
+    EAGER.store(switching == Switching::Eager, Ordering::Release);
+}
+
+/// Initialize the FPU and SSE unit on this CPU.
+///
+/// This enables `FXSAVE` and SIMD exceptions in `%cr4`, and `XSAVE` with
+/// the x87, SSE and AVX components if the CPU supports it. The FPU is then
+/// reset, and `MXCSR` set to [`DEFAULT_MXCSR`].
+///
+/// # Returns
+/// + `Ok(Mode)` with how extended state will be saved
+/// + `Err(&str)` if the CPU lacks `FXSAVE` or SSE.
+///
+/// # Safety
+/// + This should be called once on each CPU, before anything uses the FPU.
+/// + The CPU's [`PerCpu`] must be at [`GS_OFFSET`] from the `%gs` base.
+///
+/// [`DEFAULT_MXCSR`]: constant.DEFAULT_MXCSR.html
+/// [`PerCpu`]: struct.PerCpu.html
+/// [`GS_OFFSET`]: constant.GS_OFFSET.html
+pub unsafe fn initialize() -> Result<Mode, &'static str> {
+    let info = cpuid::info();
+    if !info.has(cpuid::FPU | cpuid::FXSR | cpuid::SSE) {
+        return Err("the CPU doesn't support FXSAVE and SSE")
+    }
+
+    // use the FPU natively (rather than emulating it), report x87 errors as
+    // exceptions, and trap `WAIT` as well as FPU instructions when `TS` is
+    // set.
+    let mut flags = cr0::read();
+    flags.remove(cr0::EM | cr0::TS);
+    flags.insert(cr0::MP | cr0::NE);
+    cr0::write(flags);
+
+    let mut flags = cr4::read();
+    flags.insert(cr4::OSFXSR | cr4::OSXMMEXCPT);
+    let mode = if info.has(cpuid::XSAVE) {
+        flags.insert(cr4::OSXSAVE);
+        cr4::write(flags);
+        let mut components = X87 | SSE;
+        if info.has(cpuid::AVX) {
+            components.insert(AVX);
+        }
+        write_xcr0(components);
+        // leaf 0xd reports the area size for the components enabled in XCR0
+        if cpuid::cpuid(0xd, 0).ebx as usize > AREA_SIZE {
+            return Err("the XSAVE area is larger than expected")
+        }
+        Mode::XSave(components)
+    } else {
+        cr4::write(flags);
+        Mode::FxSave
+    };
+    let mode = *MODE.call_once(|| mode);
+
+    asm!("fninit" :::: "volatile");
+    write_mxcsr(DEFAULT_MXCSR);
+    DEFAULT.call_once(|| {
+        let mut state = ExtendedState::new();
+        state.save();
+        state
+    });
+    Ok(mode)
+}
+
+/// Make `next` the running context's extended state.
+///
+/// With lazy switching, this just sets `TS` if the FPU holds some other
+/// context's state. With eager switching, it saves that state and restores
+/// `next`.
+///
+/// # Safety
+/// + `next` must stay valid (and must not move) until it is passed to
+///   [`release`].
+/// + Interrupts must be disabled.
+///
+/// [`release`]: fn.release.html
+pub unsafe fn switch_to(next: *mut ExtendedState) {
+    if mode().is_none() { return }
+    write_field(CURRENT, next);
+    if read_field(OWNER) == next {
+        cr0::set_task_switched(false);
+    } else if EAGER.load(Ordering::Acquire) {
+        load_current();
+    } else {
+        cr0::set_task_switched(true);
+    }
+}
+
+/// Forget about `state`, such as when its context exits, so that it is
+/// never saved to again.
+///
+/// This only forgets it on the running CPU, which must be the last one its
+/// context ran on.
+pub fn release(state: *mut ExtendedState) {
+    if mode().is_none() { return }
+    without_interrupts(|| unsafe {
+        for &field in &[OWNER, CURRENT] {
+            if read_field(field) == state {
+                write_field(field, ptr::null_mut());
+            }
+        }
+    })
+}
+
+/// Handle a device not available exception (`#NM`), by switching to the
+/// running context's extended state.
+///
+/// # Returns
+/// + `true` if the exception was caused by lazy switching, and has been
+///   handled
+/// + `false` if it wasn't, and is a real error.
+///
+/// # Safety
+/// + This should only be called by the `#NM` handler.
+pub unsafe fn handle_device_not_available() -> bool {
+    if mode().is_none() || !cr0::is_task_switched() {
+        return false
+    }
+    load_current();
+    true
+}
+
+/// Save the owner's state, restore the running context's, and make it the
+/// owner.
+unsafe fn load_current() {
+    cr0::set_task_switched(false);
+    let current = read_field(CURRENT);
+    let owner = read_field(OWNER);
+    if owner == current { return }
+    if let Some(owner) = owner.as_mut() {
+        owner.save();
+    }
+    if let Some(current) = current.as_ref() {
+        current.restore();
+    }
+    write_field(OWNER, current);
+}
+
+/// Returns the current value of `MXCSR`.
+///
+/// # Safety
+/// + SSE must be enabled, and `TS` must be clear in `%cr0`.
+pub unsafe fn read_mxcsr() -> u32 {
+    let mut mxcsr: u32 = 0;
+    asm!("stmxcsr ($0)" :: "r"(&mut mxcsr) : "memory" : "volatile");
+    mxcsr
+}
+
+/// Set `MXCSR` to `mxcsr`.
+///
+/// # Safety
+/// + SSE must be enabled, and `TS` must be clear in `%cr0`.
+pub unsafe fn write_mxcsr(mxcsr: u32) {
+    asm!("ldmxcsr ($0)" :: "r"(&mxcsr) :: "volatile");
+}
+
+#[inline]
+unsafe fn write_xcr0(components: Components) {
+    let bits = components.bits();
+    asm!( "xsetbv"
+        :: "{ecx}"(0u32), "{eax}"(bits as u32), "{edx}"((bits >> 32) as u32)
+        :: "volatile");
+}
+
+#[inline]
+unsafe fn xsave(area: *mut u8, components: Components) {
+    let bits = components.bits();
+    asm!( "xsave64 ($0)"
+        :: "r"(area), "{eax}"(bits as u32), "{edx}"((bits >> 32) as u32)
+        : "memory" : "volatile");
+}
+
+#[inline]
+unsafe fn xrstor(area: *const u8, components: Components) {
+    let bits = components.bits();
+    asm!( "xrstor64 ($0)"
+        :: "r"(area), "{eax}"(bits as u32), "{edx}"((bits >> 32) as u32)
+        :: "volatile");
+}
+
+#[inline]
+unsafe fn fxsave(area: *mut u8) {
+    asm!("fxsave64 ($0)" :: "r"(area) : "memory" : "volatile");
+}
+
+#[inline]
+unsafe fn fxrstor(area: *const u8) {
+    asm!("fxrstor64 ($0)" :: "r"(area) :: "volatile");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem;
+
+    #[test]
+    fn test_area_is_aligned() {
+        assert_eq!(mem::align_of::<ExtendedState>(), 64);
+        assert!(mem::size_of::<ExtendedState>() >= AREA_SIZE);
+    }
+
+    #[test]
+    fn test_unsaved_mxcsr() {
+        assert_eq!(ExtendedState::new().mxcsr(), None);
+    }
+}
@@ -15,8 +15,9 @@
 #[path = "../x86_all/mod.rs"] mod cpu_all;
 
 pub mod context;
+pub mod fpu;
 pub mod task;
-pub mod msr;
+pub mod syscall;
 
 pub use self::context::Registers;
 pub use self::cpu_all::*;
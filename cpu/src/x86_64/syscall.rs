@@ -0,0 +1,443 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Fast system calls, with `syscall` and `sysret`.
+//!
+//! User code makes a system call by putting the call's number in `%rax`
+//! and up to six arguments in `%rdi`, `%rsi`, `%rdx`, `%r10`, `%r8` and
+//! `%r9`, and executing `syscall`. The result comes back in `%rax`: a
+//! non-negative value on success, or a negated [`Error`] code on failure.
+//!
+//! `syscall` doesn't switch stacks, so the entry stub uses `swapgs` to find
+//! this CPU's [`SyscallStack`], saves the user stack pointer there and
+//! switches to the kernel stack. It then saves the user's registers and
+//! calls into Rust, which looks the call up in the table set with
+//! [`set_table`]. Each call is its own entry in the table, rather than a
+//! multiplexer in the style of `ioctl`; handlers decode their arguments
+//! with [`Args::get`].
+//!
+//! While the kernel runs, the `%gs` base points at the `SyscallStack`, and
+//! `IA32_KERNEL_GS_BASE` holds the user's `%gs` base. In user mode, it's the
+//! other way around. N.B. that only this entry stub swaps them: interrupt
+//! and exception handlers don't, so they must not be entered from user mode
+//! until they `swapgs` too.
+//!
+//! System calls run with interrupts disabled, since `IF` is masked on
+//! entry.
+//!
+//! `sysret` takes the user's `%rip` from `%rcx`. If that isn't a user
+//! address, `sysret` faults in kernel mode, but on the user's stack, which
+//! the user controls. So a system call which would return to anything else
+//! (such as `syscall` at the very end of user memory, which returns to
+//! `USER_TOP`) doesn't return at all: the calling thread is killed with the
+//! function set with [`set_kill`].
+//!
+//! [`Error`]: enum.Error.html
+//! [`SyscallStack`]: struct.SyscallStack.html
+//! [`set_table`]: fn.set_table.html
+//! [`set_kill`]: fn.set_kill.html
+//! [`Args::get`]: struct.Args.html#method.get
+use core::{fmt, mem, slice};
+use core::marker::PhantomData;
+
+use memory::VAddr;
+use spin::Once;
+
+use ::{flags, msr};
+use ::segment::{KERNEL_CODE, USER_DATA};
+
+/// The first address above the lower (user) half of the address space.
+pub const USER_TOP: u64 = 0x0000_8000_0000_0000;
+
+/// An error returned by a system call.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u64)]
+pub enum Error { /// There is no system call with that number.
+                 NoSuchCall = 1
+               , /// A pointer argument is null, misaligned, or not a user
+                 /// address.
+                 BadAddress = 2
+               , /// An argument is out of range for its type, or is
+                 /// otherwise invalid.
+                 InvalidArgument = 3
+               }
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Error::NoSuchCall => "no such system call"
+          , Error::BadAddress => "bad address"
+          , Error::InvalidArgument => "invalid argument"
+        })
+    }
+}
+
+/// The result of a system call.
+pub type SyscallResult = Result<u64, Error>;
+
+/// A function which handles a system call.
+///
+/// Handlers run on their CPU's system call stack, which every system call on
+/// that CPU shares. So a handler must not block or switch threads: if
+/// another thread made a system call in the meantime, it would overwrite the
+/// first thread's saved registers. [`in_progress`] lets the scheduler check
+/// for this.
+///
+/// [`in_progress`]: fn.in_progress.html
+pub type Handler = fn(args: &Args) -> SyscallResult;
+
+/// A function which ends the calling thread, used when a system call can't
+/// safely return to it.
+pub type Kill = fn() -> !;
+
+/// A type which can be decoded from a system call argument.
+pub trait FromArg: Sized {
+    /// Decode an argument from the value in its register.
+    fn from_arg(arg: u64) -> Result<Self, Error>;
+}
+
+macro_rules! from_arg_unsigned {
+    ($($ty:ty),+) => {
+        $(impl FromArg for $ty {
+            #[inline] fn from_arg(arg: u64) -> Result<Self, Error> {
+                if arg > <$ty>::max_value() as u64 {
+                    Err(Error::InvalidArgument)
+                } else {
+                    Ok(arg as $ty)
+                }
+            }
+        })+
+    }
+}
+
+from_arg_unsigned! { u8, u16, u32, u64, usize }
+
+impl FromArg for i64 {
+    #[inline] fn from_arg(arg: u64) -> Result<Self, Error> { Ok(arg as i64) }
+}
+
+impl FromArg for bool {
+    #[inline] fn from_arg(arg: u64) -> Result<Self, Error> {
+        match arg {
+            0 => Ok(false)
+          , 1 => Ok(true)
+          , _ => Err(Error::InvalidArgument)
+        }
+    }
+}
+
+/// A pointer into user memory, passed as a system call argument.
+///
+/// Decoding a `UserPtr` checks that it is non-null, aligned for `T`, and in
+/// the user half of the address space. It does not check that the memory
+/// is mapped.
+#[derive(Debug)]
+pub struct UserPtr<T> { addr: u64
+                      , _ty: PhantomData<*mut T>
+                      }
+
+impl<T> Clone for UserPtr<T> {
+    #[inline] fn clone(&self) -> Self { *self }
+}
+impl<T> Copy for UserPtr<T> { }
+
+impl<T> FromArg for UserPtr<T> {
+    fn from_arg(arg: u64) -> Result<Self, Error> {
+        if arg == 0 || arg >= USER_TOP
+            || arg % mem::align_of::<T>() as u64 != 0 {
+            Err(Error::BadAddress)
+        } else {
+            Ok(UserPtr { addr: arg, _ty: PhantomData })
+        }
+    }
+}
+
+impl<T> UserPtr<T> {
+    /// Returns the address this pointer points to.
+    #[inline] pub fn addr(&self) -> u64 { self.addr }
+
+    /// Returns the `len` values of type `T` starting at this pointer.
+    ///
+    /// # Returns
+    /// + `Ok(&[T])` if the whole slice is in the user half of the address
+    ///   space
+    /// + `Err(Error::BadAddress)` if it is not.
+    ///
+    /// # Safety
+    /// + The memory must be mapped, and must only be accessed inside a
+    ///   [`UserAccess`] window.
+    ///
+    /// [`UserAccess`]: ../smap/struct.UserAccess.html
+    pub unsafe fn as_slice<'a>(&self, len: usize) -> Result<&'a [T], Error> {
+        (len as u64).checked_mul(mem::size_of::<T>() as u64)
+            .and_then(|size| self.addr.checked_add(size))
+            .and_then(|end| if end <= USER_TOP { Some(()) } else { None })
+            .ok_or(Error::BadAddress)
+            .map(|_| slice::from_raw_parts(self.addr as *const T, len))
+    }
+}
+
+/// The arguments to a system call.
+#[derive(Copy, Clone, Debug)]
+pub struct Args([u64; 6]);
+
+impl Args {
+    /// Returns the raw value of argument `index`, or 0 if there is no such
+    /// argument.
+    #[inline] pub fn raw(&self, index: usize) -> u64 {
+        self.0.get(index).cloned().unwrap_or(0)
+    }
+
+    /// Decode argument `index` as a `T`.
+    ///
+    /// # Returns
+    /// + `Ok(T)` if the argument is a valid `T`
+    /// + `Err(Error)` if it is not, or `index` is not an argument register.
+    #[inline] pub fn get<T: FromArg>(&self, index: usize) -> Result<T, Error> {
+        self.0.get(index)
+            .ok_or(Error::InvalidArgument)
+            .and_then(|&arg| T::from_arg(arg))
+    }
+}
+
+/// The per-CPU data the system call entry stub finds with `swapgs`.
+///
+/// Each CPU must have its own `SyscallStack`, with its own kernel stack:
+/// the entry stub saves the user's stack pointer here, and the kernel stack
+/// is in use until `sysret`, so a CPU which shared either with another
+/// could have them overwritten in the middle of a system call.
+///
+/// The entry stub and [`in_progress`] refer to these fields by offset, so
+/// their order must not change.
+///
+/// [`in_progress`]: fn.in_progress.html
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallStack { /// The top of the kernel stack to switch to
+                          kernel_rsp: u64
+                        , /// The user stack pointer, while in a system call
+                          user_rsp: u64
+                        , /// Nonzero while a system call is running
+                          in_call: u64
+                        }
+
+/// Offset of `SyscallStack::in_call`.
+const IN_CALL: usize = 16;
+
+impl SyscallStack {
+    /// Returns a new `SyscallStack` with no kernel stack.
+    pub const fn new() -> Self {
+        SyscallStack { kernel_rsp: 0, user_rsp: 0, in_call: 0 }
+    }
+
+    /// Returns the top of the kernel stack that system calls run on, or 0
+    /// if it hasn't been set.
+    #[inline] pub fn kernel_stack(&self) -> VAddr {
+        VAddr::from(self.kernel_rsp as usize)
+    }
+
+    /// Set the top of the kernel stack that system calls run on.
+    #[inline] pub fn set_kernel_stack(&mut self, top: VAddr) {
+        self.kernel_rsp = top.as_usize() as u64;
+    }
+}
+
+/// The user's registers, as saved by the entry stub.
+///
+/// The entry stub pushes these in reverse order, so their order must not
+/// change.
+#[repr(C)]
+struct SyscallFrame { rax: u64
+                    , rdi: u64
+                    , rsi: u64
+                    , rdx: u64
+                    , r10: u64
+                    , r8: u64
+                    , r9: u64
+                    , /// The user's `%rip`, saved in `%rcx` by `syscall`
+                      rcx: u64
+                    , /// The user's `%rflags`, saved in `%r11` by `syscall`
+                      r11: u64
+                    , /// The user's `%rsp`
+                      rsp: u64
+                    }
+
+/// The system call table, indexed by call number.
+static TABLE: Once<&'static [Handler]> = Once::new();
+
+/// The function which kills threads that can't be returned to.
+static KILL: Once<Kill> = Once::new();
+
+/// Handle system calls with `table`, which is indexed by call number.
+///
+/// This can only be done once; later calls do nothing.
+pub fn set_table(table: &'static [Handler]) {
+    TABLE.call_once(|| table);
+}
+
+/// Use `kill` to end threads whose system calls can't return to them.
+///
+/// If this hasn't been set, such a system call panics instead.
+///
+/// This can only be done once; later calls do nothing.
+pub fn set_kill(kill: Kill) {
+    KILL.call_once(|| kill);
+}
+
+/// Returns true if the running CPU is in the middle of a system call.
+///
+/// This reads the running CPU's `SyscallStack` through `%gs`, so it must not
+/// be called before the `%gs` base points at it (see [`enable`]).
+///
+/// [`enable`]: fn.enable.html
+#[inline]
+pub fn in_progress() -> bool {
+    let in_call: u64;
+    unsafe {
+        asm!( "movq %gs:($1), $0"
+            : "=r"(in_call)
+            : "r"(IN_CALL)
+            :: "volatile");
+    }
+    in_call != 0
+}
+
+/// Mark whether the running CPU is in the middle of a system call.
+#[inline]
+unsafe fn set_in_progress(in_call: bool) {
+    asm!( "movq $0, %gs:($1)"
+        :: "r"(in_call as u64), "r"(IN_CALL)
+        : "memory" : "volatile");
+}
+
+/// Called by the entry stub to run a system call.
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    let args = Args([ frame.rdi, frame.rsi, frame.rdx
+                    , frame.r10, frame.r8, frame.r9 ]);
+    let number = frame.rax;
+    unsafe { set_in_progress(true) };
+    let result = TABLE.try()
+                      .and_then(|table| table.get(number as usize))
+                      .ok_or(Error::NoSuchCall)
+                      .and_then(|handler| handler(&args));
+    // the handler is done, so switching threads is safe again. this must
+    // happen before `kill`, which switches away for good.
+    unsafe { set_in_progress(false) };
+    if let Err(why) = result {
+        trace!("system call {} failed: {}", number, why);
+    }
+    frame.rax = match result {
+        Ok(value) => value
+      , Err(why) => (-(why as i64)) as u64
+    };
+    if frame.rcx >= USER_TOP {
+        // `sysret` would fault in kernel mode, on the user's stack.
+        match KILL.try() {
+            Some(kill) => {
+                error!( "system call {} can't return to {:#x}, killing the \
+                         thread", number, frame.rcx);
+                kill()
+            }
+          , None => panic!( "system call {} can't return to {:#x}"
+                          , number, frame.rcx)
+        }
+    }
+}
+
+/// The system call entry stub, which `%lstar` points to.
+///
+/// This switches to the kernel stack, saves the user's registers as a
+/// `SyscallFrame`, and calls `syscall_dispatch`. The callee-saved registers
+/// are preserved by `syscall_dispatch` itself, which only returns here if
+/// the saved `%rcx` is a user address that `sysret` can return to.
+#[naked]
+unsafe extern "C" fn syscall_entry() {
+    asm!("swapgs
+          movq %rsp, %gs:8
+          movq %gs:0, %rsp
+          pushq %gs:8
+          pushq %r11
+          pushq %rcx
+          pushq %r9
+          pushq %r8
+          pushq %r10
+          pushq %rdx
+          pushq %rsi
+          pushq %rdi
+          pushq %rax
+          movq %rsp, %rdi
+          call syscall_dispatch
+          popq %rax
+          popq %rdi
+          popq %rsi
+          popq %rdx
+          popq %r10
+          popq %r8
+          popq %r9
+          popq %rcx
+          popq %r11
+          popq %rsp
+          swapgs
+          sysretq"
+        :::: "volatile");
+}
+
+/// Enable system calls on this CPU.
+///
+/// System calls run on the kernel stack in the `SyscallStack` which the
+/// `%gs` base points at.
+///
+/// # Safety
+/// + The `%gs` base must point at a `SyscallStack` with a kernel stack,
+///   which belongs to this CPU alone (see [`SyscallStack`]), and
+///   `IA32_KERNEL_GS_BASE` must hold the user's `%gs` base.
+/// + The GDT must contain the kernel and user segments in the order that
+///   `syscall` and `sysret` expect (see `segment::USER_DATA`).
+/// + This should be called once on each CPU.
+///
+/// [`SyscallStack`]: struct.SyscallStack.html
+pub unsafe fn enable() {
+    // `sysret` loads the user stack segment from the base plus 8, and the
+    // user code segment from the base plus 16.
+    let sysret_base = USER_DATA.bits() as u64 - 8;
+    msr::write( msr::IA32_STAR
+              , sysret_base << 48 | (KERNEL_CODE.bits() as u64) << 32 );
+    msr::write(msr::IA32_LSTAR, syscall_entry as usize as u64);
+    msr::write( msr::IA32_FMASK
+              , (flags::IF | flags::TF | flags::DF | flags::AC).bits() as u64);
+    msr::enable_syscall();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_args() {
+        let args = Args([0x1000, 300, 1, USER_TOP, 0x1001, 0]);
+        assert_eq!(args.get::<u64>(0), Ok(0x1000));
+        assert_eq!(args.get::<u8>(1), Err(Error::InvalidArgument));
+        assert_eq!(args.get::<u16>(1), Ok(300));
+        assert_eq!(args.get::<bool>(2), Ok(true));
+        assert_eq!(args.get::<u64>(6), Err(Error::InvalidArgument));
+        assert!(args.get::<UserPtr<u8>>(0).is_ok());
+        assert!(args.get::<UserPtr<u8>>(3).is_err());
+        assert!(args.get::<UserPtr<u64>>(4).is_err());
+        assert!(args.get::<UserPtr<u8>>(5).is_err());
+    }
+
+    #[test]
+    fn test_user_slice_bounds() {
+        let ptr = UserPtr::<u8>::from_arg(USER_TOP - 16).unwrap();
+        unsafe {
+            assert!(ptr.as_slice(16).is_ok());
+            assert!(ptr.as_slice(17).is_err());
+        }
+    }
+}
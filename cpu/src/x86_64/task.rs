@@ -7,15 +7,60 @@
 //  directory of this repository for more information.
 //
 //! Tasking
+//!
+//! We don't use hardware task switching, but long mode still requires a task
+//! state segment (TSS), which holds the stack pointers the CPU switches to
+//! when an interrupt arrives. Each entry in the TSS's interrupt stack table
+//! (IST) can be given to IDT gates with [`Gate::set_stack_index`], so that
+//! those handlers always run on a known-good stack, even if the kernel stack
+//! has overflowed.
+//!
+//! [`Gate::set_stack_index`]: ../interrupts/idt/struct.Gate.html#method.set_stack_index
+use core::mem::size_of;
+
 use ::segment;
 use memory::VAddr;
 
+/// The IST entry used by the double fault handler.
+pub const DOUBLE_FAULT_IST: u8 = 1;
+/// The IST entry used by the non-maskable interrupt handler.
+pub const NMI_IST: u8 = 2;
+/// The IST entry used by the machine check handler.
+pub const MACHINE_CHECK_IST: u8 = 3;
+
 /// A 64-bit Task State Descriptor
-#[repr(packed)]
-pub struct StateDescriptor { pub upper: segment::Descriptor
-                           , pub lower: u64
+///
+/// This is a system segment descriptor, extended to 16 bytes to hold a
+/// 64-bit base address.
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct StateDescriptor { /// The first 8 bytes, laid out like any other
+                             /// segment descriptor
+                             pub lower: segment::Descriptor
+                           , /// The upper 32 bits of the base address (the
+                             /// rest is reserved)
+                             pub upper: u64
                            }
 
+impl StateDescriptor {
+    /// Returns a new, non-present TSS descriptor.
+    pub const fn null() -> Self {
+        StateDescriptor { lower: segment::Descriptor::null(), upper: 0 }
+    }
+
+    /// Returns a descriptor for the available TSS `tss`.
+    pub fn new(tss: &'static StateSegment) -> Self {
+        let base = tss as *const _ as u64;
+        let mut lower = segment::Descriptor::new( base as u32
+                                                , size_of::<StateSegment>()
+                                                  as u32 - 1 );
+        lower.flags.insert(segment::PRESENT);
+        lower.flags.insert(segment::Flags::from_raw(
+            segment::SysType::TssAvailable as u16));
+        StateDescriptor { lower: lower, upper: base >> 32 }
+    }
+}
+
 
 /// A 64-bit Task State Segment
 #[repr(C, packed)]
@@ -25,7 +70,7 @@ pub struct StateSegment {
   , /// 64-bit values of the stack pointers (`%rsp`) for privilege rings 0-2
     //  TODO: should this be an array or just three u64s?
     pub rsp: [VAddr; 3]
-  , _reserved_2: u32
+  , _reserved_2: u64
   , /// 64-bit values of the interrupt stack table registers
     pub ist: [VAddr; 7]
   , _reserved_3: u64
@@ -44,7 +89,69 @@ impl StateSegment {
                      , ist: [ VAddr::new(0); 7 ]
                      , _reserved_3: 0
                      , _reserved_4: 0
-                     , iomap_base_offset: 0
+                     , // there's no I/O permission bitmap, so its offset is
+                       // the size of the TSS.
+                       iomap_base_offset: 104
                      }
     }
+
+    /// Returns the stack pointer for the IST entry `index`, as used by
+    /// `Gate::set_stack_index`.
+    ///
+    /// # Panics
+    /// + If `index` is not between 1 and 7.
+    #[inline]
+    pub fn ist_stack(&self, index: u8) -> VAddr {
+        assert!(index >= 1 && index <= 7, "no such IST entry {}", index);
+        self.ist[index as usize - 1]
+    }
+
+    /// Set the stack pointer for the IST entry `index`, as used by
+    /// `Gate::set_stack_index`.
+    ///
+    /// # Panics
+    /// + If `index` is not between 1 and 7.
+    #[inline]
+    pub fn set_ist_stack(&mut self, index: u8, top: VAddr) -> &mut Self {
+        assert!(index >= 1 && index <= 7, "no such IST entry {}", index);
+        self.ist[index as usize - 1] = top;
+        self
+    }
+}
+
+/// Load `selector` into the task register with the `ltr` instruction.
+///
+/// # Safety
+/// + `selector` must refer to an available TSS descriptor in the current
+///   GDT, and the TSS it describes must live for as long as it is loaded.
+pub unsafe fn load_task_register(selector: segment::Selector) {
+    asm!( "ltr $0"
+        :: "r"(selector.bits())
+        :  "memory" );
+}
+
+#[cfg(test)]
+mod tests {
+    use core::mem::size_of;
+    use super::*;
+
+    #[test]
+    fn test_tss_size() {
+        assert_eq!(size_of::<StateSegment>(), 104);
+        assert_eq!(StateSegment::new().iomap_base_offset as usize
+                  , size_of::<StateSegment>());
+    }
+
+    #[test]
+    fn test_descriptor_size() {
+        assert_eq!(size_of::<StateDescriptor>(), 16);
+    }
+
+    #[test]
+    fn test_ist_index() {
+        let mut tss = StateSegment::new();
+        tss.set_ist_stack(DOUBLE_FAULT_IST, VAddr::new(0x1000));
+        assert_eq!(tss.ist[0], VAddr::new(0x1000));
+        assert_eq!(tss.ist_stack(DOUBLE_FAULT_IST), VAddr::new(0x1000));
+    }
 }
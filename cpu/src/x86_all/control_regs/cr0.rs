@@ -71,42 +71,11 @@ cpu_flag! {
         protect.",
     WP, is_write_protected, enable_write_protect
 }
-
-///// Set the write protect bit in `%cr0`.
-//pub fn enable_write_protect() {
-//    let mut flags: Flags = read();
-//    if !flags.contains(WP) {
-//        flags.insert(WP);
-//        unsafe { write(flags) }
-//    }
-//}
-//
-///// Unset the write protect bit in `%cr0`.
-//pub fn disable_write_protect() {
-//    let mut flags: Flags = read();
-//    if flags.contains(WP) {
-//        flags.remove(WP);
-//        unsafe { write(flags) }
-//    }
-//}
-//
-///// Set the paging bit in `%cr0`.
-//pub fn enable_paging() {
-//    let mut flags: Flags = read();
-//    if !flags.contains(WP) {
-//        flags.insert(WP);
-//        unsafe { write(flags) }
-//    }
-//}
-//
-///// Unset the paging bit in `%cr0`.
-//pub fn disable_paging() {
-//    let mut flags: Flags = read();
-//    if flags.contains(PG) {
-//        flags.remove(PG);
-//        unsafe { write(flags) }
-//    }
-//}
+cpu_flag! {
+    doc="If set, the next x87, MMX or SSE instruction raises a device not \
+        available exception.",
+    TS, is_task_switched, set_task_switched
+}
 
 /// Read the current value from `%cr0`.
 ///
@@ -117,3 +117,12 @@ cpu_flag! {
     doc="If disabled, the `RTDSC` instruction can only be executed in Ring 0.",
     TSD, is_timestamp_disabled, disable_timestamp
 }
+cpu_flag! {
+    doc="If set, the kernel can't execute code in user-accessible pages.",
+    SMEP, is_smep_enabled, enable_smep
+}
+cpu_flag! {
+    doc="If set, the kernel can't access data in user-accessible pages, \
+        unless the `AC` flag is set.",
+    SMAP, is_smap_enabled, enable_smap
+}
@@ -0,0 +1,550 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The `CPUID` instruction.
+//!
+//! `CPUID` returns information about the processor in the `%eax`, `%ebx`,
+//! `%ecx`, and `%edx` registers. Which information is returned depends on the
+//! requested leaf (`%eax`) and subleaf (`%ecx`).
+//!
+//! The leaves the kernel cares about are queried once, the first time
+//! [`info`] is called, and decoded into a [`CpuInfo`]. The `has_*` functions
+//! are shorthands for checking its [`Features`].
+//!
+//! See the [OS Dev Wiki](http://wiki.osdev.org/CPUID) for more information.
+//!
+//! [`info`]: fn.info.html
+//! [`CpuInfo`]: struct.CpuInfo.html
+//! [`Features`]: struct.Features.html
+#![warn(missing_docs)]
+use core::{fmt, iter, slice, str};
+
+use spin::Once;
+
+/// The registers returned by a `CPUID` instruction.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CpuId { /// Value of `%eax`
+                   pub eax: u32
+                 , /// Value of `%ebx`
+                   pub ebx: u32
+                 , /// Value of `%ecx`
+                   pub ecx: u32
+                 , /// Value of `%edx`
+                   pub edx: u32
+                 }
+
+/// Execute `CPUID` with the given `leaf` and `subleaf`.
+#[inline]
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuId {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!(  "cpuid"
+            :  "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+            :  "{eax}"(leaf), "{ecx}"(subleaf)
+            :: "volatile" );
+    }
+    CpuId { eax: eax, ebx: ebx, ecx: ecx, edx: edx }
+}
+
+/// Returns the highest basic leaf supported by `CPUID`.
+#[inline]
+pub fn max_leaf() -> u32 { cpuid(0, 0).eax }
+
+/// Returns the highest extended leaf supported by `CPUID`.
+#[inline]
+pub fn max_extended_leaf() -> u32 { cpuid(0x8000_0000, 0).eax }
+
+bitflags! {
+    /// CPU features the kernel knows how to detect.
+    ///
+    /// These are gathered from several `CPUID` leaves, so the bits don't
+    /// correspond to any one register.
+    pub flags Features: u64 { /// x87 floating point unit
+                              const FPU = 1 << 0
+                            , /// Timestamp counter
+                              const TSC = 1 << 1
+                            , /// Model-specific registers
+                              const MSR = 1 << 2
+                            , /// Physical address extension
+                              const PAE = 1 << 3
+                            , /// On-chip local APIC
+                              const APIC = 1 << 4
+                            , /// Global pages
+                              const PGE = 1 << 5
+                            , /// Page attribute table
+                              const PAT = 1 << 6
+                            , /// `fxsave` and `fxrstor`
+                              const FXSR = 1 << 7
+                            , /// SSE
+                              const SSE = 1 << 8
+                            , /// SSE2
+                              const SSE2 = 1 << 9
+                            , /// SSE3
+                              const SSE3 = 1 << 10
+                            , /// Process-context identifiers
+                              const PCID = 1 << 11
+                            , /// x2APIC mode of the local APIC
+                              const X2APIC = 1 << 12
+                            , /// Local APIC timer TSC deadline mode
+                              const TSC_DEADLINE = 1 << 13
+                            , /// `xsave`, `xrstor` and `XCR0`
+                              const XSAVE = 1 << 14
+                            , /// `XSAVE` has been enabled by the OS on
+                              /// this CPU (see `CpuInfo::has`)
+                              const OSXSAVE = 1 << 15
+                            , /// AVX
+                              const AVX = 1 << 16
+                            , /// The `rdrand` instruction
+                              const RDRAND = 1 << 17
+                            , /// Running under a hypervisor
+                              const HYPERVISOR = 1 << 18
+                            , /// `rdfsbase`, `wrfsbase`, `rdgsbase` and
+                              /// `wrgsbase`
+                              const FSGSBASE = 1 << 19
+                            , /// Supervisor mode execution prevention
+                              const SMEP = 1 << 20
+                            , /// The `invpcid` instruction
+                              const INVPCID = 1 << 21
+                            , /// The `rdseed` instruction
+                              const RDSEED = 1 << 22
+                            , /// Supervisor mode access prevention
+                              const SMAP = 1 << 23
+                            , /// `syscall` and `sysret`
+                              const SYSCALL = 1 << 24
+                            , /// The no-execute page bit
+                              const NX = 1 << 25
+                            , /// 1 GiB pages
+                              const PAGE_1GB = 1 << 26
+                            , /// The `rdtscp` instruction
+                              const RDTSCP = 1 << 27
+                            , /// Long mode
+                              const LONG_MODE = 1 << 28
+                            , /// The timestamp counter runs at a constant
+                              /// rate in every power state
+                              const INVARIANT_TSC = 1 << 29
+                            }
+}
+
+/// The manufacturer of a CPU.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Vendor { /// `GenuineIntel`
+                  Intel
+                , /// `AuthenticAMD`
+                  Amd
+                , /// Any other vendor string
+                  Other
+                }
+
+impl<'a> From<&'a [u8]> for Vendor {
+    fn from(id: &'a [u8]) -> Self {
+        match id {
+            b"GenuineIntel" => Vendor::Intel
+          , b"AuthenticAMD" => Vendor::Amd
+          , _ => Vendor::Other
+        }
+    }
+}
+
+/// The family, model and stepping of a CPU.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Signature { /// The processor family, including the extended
+                       /// family
+                       pub family: u32
+                     , /// The model, including the extended model
+                       pub model: u32
+                     , /// The stepping
+                       pub stepping: u32
+                     }
+
+impl Signature {
+    /// Decode a signature from `%eax` of leaf 1.
+    pub fn from_eax(eax: u32) -> Self {
+        let family = (eax >> 8) & 0xf;
+        let model = (eax >> 4) & 0xf;
+        Signature {
+            family: if family == 0xf { family + ((eax >> 20) & 0xff) }
+                    else { family }
+          , model: if family == 0x6 || family == 0xf {
+                        model | ((eax >> 16) & 0xf) << 4
+                   } else { model }
+          , stepping: eax & 0xf
+        }
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!( f, "family {:#x}, model {:#x}, stepping {}"
+              , self.family, self.model, self.stepping)
+    }
+}
+
+/// The type of a CPU cache.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CacheType { /// Data cache
+                     Data
+                   , /// Instruction cache
+                     Instruction
+                   , /// Unified data and instruction cache
+                     Unified
+                   }
+
+impl fmt::Display for CacheType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            CacheType::Data => "data"
+          , CacheType::Instruction => "instruction"
+          , CacheType::Unified => "unified"
+        })
+    }
+}
+
+/// A CPU cache.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Cache { /// The cache level, starting at 1
+                   pub level: u8
+                 , /// What the cache holds
+                   pub ty: CacheType
+                 , /// The size of a cache line, in bytes
+                   pub line_size: u32
+                 , /// The number of ways of associativity
+                   pub ways: u32
+                 , /// The number of sets
+                   pub sets: u32
+                 , /// The number of line partitions
+                   pub partitions: u32
+                 }
+
+impl Cache {
+    /// Decode a cache from a subleaf of the deterministic cache parameters
+    /// leaf (leaf 4 on Intel CPUs, and `0x8000_001d` on AMD CPUs).
+    ///
+    /// # Returns
+    /// + `Some(Cache)` if the subleaf describes a cache
+    /// + `None` if there are no more caches.
+    pub fn from_regs(regs: CpuId) -> Option<Self> {
+        let ty = match regs.eax & 0x1f {
+            1 => CacheType::Data
+          , 2 => CacheType::Instruction
+          , 3 => CacheType::Unified
+          , _ => return None
+        };
+        Some(Cache { level: ((regs.eax >> 5) & 0x7) as u8
+                   , ty: ty
+                   , line_size: (regs.ebx & 0xfff) + 1
+                   , partitions: ((regs.ebx >> 12) & 0x3ff) + 1
+                   , ways: (regs.ebx >> 22) + 1
+                   , sets: regs.ecx + 1
+                   })
+    }
+
+    /// Returns the size of the cache, in bytes.
+    #[inline]
+    pub fn size(&self) -> u32 {
+        self.line_size * self.partitions * self.ways * self.sets
+    }
+}
+
+impl fmt::Display for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!( f, "L{} {}: {} KiB, {}-way, {}-byte lines"
+              , self.level, self.ty, self.size() / 1024, self.ways
+              , self.line_size)
+    }
+}
+
+/// The maximum number of caches a `CpuInfo` records.
+pub const MAX_CACHES: usize = 8;
+
+/// An iterator over the caches in a `CpuInfo`.
+pub type Caches<'a>
+    = iter::FilterMap< slice::Iter<'a, Option<Cache>>
+                     , fn(&'a Option<Cache>) -> Option<&'a Cache>
+                     >;
+
+/// Information about the CPU, decoded from `CPUID`.
+///
+/// The topology fields describe the CPU which called [`info`] first, which
+/// is the boot CPU.
+///
+/// [`info`]: fn.info.html
+#[derive(Clone, Debug)]
+pub struct CpuInfo { vendor_id: [u8; 12]
+                   , brand: [u8; 48]
+                   , /// The manufacturer
+                     pub vendor: Vendor
+                   , /// The family, model and stepping
+                     pub signature: Signature
+                   , /// Supported features, except for `OSXSAVE`,
+                     /// which is never cached
+                     pub features: Features
+                   , /// The highest basic leaf supported by `CPUID`
+                     pub max_leaf: u32
+                   , /// The highest extended leaf supported by `CPUID`
+                     pub max_extended_leaf: u32
+                   , /// The number of physical address bits
+                     pub physical_address_bits: u8
+                   , /// The number of linear (virtual) address bits
+                     pub linear_address_bits: u8
+                   , /// The initial APIC ID of the boot CPU
+                     pub apic_id: u8
+                   , /// The number of logical processors per package
+                     pub logical_per_package: u32
+                   , /// The number of cores per package
+                     pub cores_per_package: u32
+                   , caches: [Option<Cache>; MAX_CACHES]
+                   }
+
+impl CpuInfo {
+    /// Query `CPUID` and decode the results.
+    fn query() -> Self {
+        let max_leaf = max_leaf();
+        let max_extended_leaf = max_extended_leaf();
+        let leaf = |n| if n <= max_leaf { cpuid(n, 0) }
+                       else { CpuId::default() };
+        let extended_leaf = |n| if n <= max_extended_leaf { cpuid(n, 0) }
+                                else { CpuId::default() };
+
+        let mut info = CpuInfo { vendor_id: [0; 12]
+                               , brand: [0; 48]
+                               , vendor: Vendor::Other
+                               , signature: Signature::default()
+                               , features: Features::empty()
+                               , max_leaf: max_leaf
+                               , max_extended_leaf: max_extended_leaf
+                               // if leaf 0x8000_0008 isn't supported, these
+                               // are the widths every long mode CPU has.
+                               , physical_address_bits: 36
+                               , linear_address_bits: 48
+                               , apic_id: 0
+                               , logical_per_package: 1
+                               , cores_per_package: 1
+                               , caches: [None; MAX_CACHES]
+                               };
+
+        let id = cpuid(0, 0);
+        for (i, reg) in [id.ebx, id.edx, id.ecx].iter().enumerate() {
+            info.vendor_id[i * 4 .. i * 4 + 4]
+                .copy_from_slice(&to_bytes(*reg));
+        }
+        info.vendor = Vendor::from(&info.vendor_id[..]);
+
+        if max_extended_leaf >= 0x8000_0004 {
+            for (i, n) in (0x8000_0002..0x8000_0005).enumerate() {
+                let regs = cpuid(n, 0);
+                for (j, reg) in [regs.eax, regs.ebx, regs.ecx, regs.edx]
+                                    .iter().enumerate() {
+                    let at = i * 16 + j * 4;
+                    info.brand[at .. at + 4].copy_from_slice(&to_bytes(*reg));
+                }
+            }
+        }
+
+        let basic = leaf(1);
+        let extended = leaf(7);
+        let amd = extended_leaf(0x8000_0001);
+        let power = extended_leaf(0x8000_0007);
+        info.features = Features::decode(basic, extended, amd, power);
+        // `OSXSAVE` changes when the kernel sets `%cr4.OSXSAVE`, so caching
+        // it would make it stale.
+        info.features.remove(OSXSAVE);
+        info.signature = Signature::from_eax(basic.eax);
+        info.apic_id = (basic.ebx >> 24) as u8;
+        if basic.edx & (1 << 28) != 0 {
+            info.logical_per_package = (basic.ebx >> 16) & 0xff;
+        }
+
+        if max_extended_leaf >= 0x8000_0008 {
+            let widths = cpuid(0x8000_0008, 0);
+            info.physical_address_bits = widths.eax as u8;
+            info.linear_address_bits = (widths.eax >> 8) as u8;
+            if info.vendor == Vendor::Amd {
+                info.cores_per_package = (widths.ecx & 0xff) + 1;
+            }
+        }
+
+        // Intel describes its caches in leaf 4, and AMD in `0x8000_001d`,
+        // if it has topology extensions.
+        let cache_leaf = match info.vendor {
+            Vendor::Intel if max_leaf >= 4 => Some(4)
+          , Vendor::Amd if amd.ecx & (1 << 22) != 0
+                        && max_extended_leaf >= 0x8000_001d =>
+                Some(0x8000_001d)
+          , _ => None
+        };
+        if let Some(cache_leaf) = cache_leaf {
+            for (i, slot) in info.caches.iter_mut().enumerate() {
+                let regs = cpuid(cache_leaf, i as u32);
+                if i == 0 && info.vendor == Vendor::Intel {
+                    info.cores_per_package = (regs.eax >> 26) + 1;
+                }
+                *slot = Cache::from_regs(regs);
+                if slot.is_none() { break }
+            }
+        }
+
+        info
+    }
+
+    /// Returns true if the CPU supports all of `features`.
+    ///
+    /// `OSXSAVE` mirrors `%cr4.OSXSAVE`, which the kernel sets on each CPU
+    /// after `CPUID` has been queried, so it is read from `CPUID` on the
+    /// current CPU every time it is checked.
+    #[inline]
+    pub fn has(&self, features: Features) -> bool {
+        let mut supported = self.features;
+        if features.contains(OSXSAVE) && cpuid(1, 0).ecx & (1 << 27) != 0 {
+            supported.insert(OSXSAVE);
+        }
+        supported.contains(features)
+    }
+
+    /// Returns the vendor ID string, such as `GenuineIntel`.
+    pub fn vendor_id(&self) -> &str {
+        str::from_utf8(&self.vendor_id).unwrap_or("unknown")
+    }
+
+    /// Returns the processor brand string, if the CPU has one.
+    pub fn brand(&self) -> Option<&str> {
+        let end = self.brand.iter()
+                            .position(|&b| b == 0)
+                            .unwrap_or(self.brand.len());
+        str::from_utf8(&self.brand[..end]).ok()
+            .map(|brand| brand.trim())
+            .and_then(|brand| if brand.is_empty() { None }
+                              else { Some(brand) })
+    }
+
+    /// Returns an iterator over the CPU's caches.
+    pub fn caches(&self) -> Caches {
+        self.caches.iter().filter_map(Option::as_ref as fn(_) -> _)
+    }
+
+    /// Log a summary of the CPU.
+    pub fn print_summary(&self) {
+        kinfoln!( dots: " . . ", "{} ({})"
+                , self.brand().unwrap_or("unknown CPU"), self.vendor_id());
+        kinfoln!(dots: " . . ", "{}", self.signature);
+        kinfoln!( dots: " . . ", "{} cores, {} logical processors per package"
+                , self.cores_per_package, self.logical_per_package);
+        kinfoln!( dots: " . . ", "{}-bit physical, {}-bit linear addresses"
+                , self.physical_address_bits, self.linear_address_bits);
+        for cache in self.caches() {
+            kinfoln!(dots: " . . ", "{}", cache);
+        }
+        kinfoln!(dots: " . . ", "Features: {:?}", self.features);
+    }
+}
+
+impl Features {
+    /// Decode the features from leaves 1, 7, `0x8000_0001` and
+    /// `0x8000_0007`.
+    ///
+    /// Leaves which aren't supported should be passed as all zeroes.
+    pub fn decode( basic: CpuId, extended: CpuId
+                 , amd: CpuId, power: CpuId) -> Self {
+        let mut features = Features::empty();
+        macro_rules! check {
+            ($reg:expr, $($bit:expr => $flag:ident),+) => {
+                $(if $reg & (1 << $bit) != 0 { features.insert($flag) })+
+            }
+        }
+        check!(basic.edx, 0 => FPU, 4 => TSC, 5 => MSR, 6 => PAE, 9 => APIC
+                        , 13 => PGE, 16 => PAT, 24 => FXSR, 25 => SSE
+                        , 26 => SSE2);
+        check!(basic.ecx, 0 => SSE3, 17 => PCID, 21 => X2APIC
+                        , 24 => TSC_DEADLINE, 26 => XSAVE, 27 => OSXSAVE
+                        , 28 => AVX, 30 => RDRAND, 31 => HYPERVISOR);
+        check!(extended.ebx, 0 => FSGSBASE, 7 => SMEP, 10 => INVPCID
+                           , 18 => RDSEED, 20 => SMAP);
+        check!(amd.edx, 11 => SYSCALL, 20 => NX, 26 => PAGE_1GB
+                      , 27 => RDTSCP, 29 => LONG_MODE);
+        check!(power.edx, 8 => INVARIANT_TSC);
+        features
+    }
+}
+
+#[inline]
+fn to_bytes(reg: u32) -> [u8; 4] {
+    [reg as u8, (reg >> 8) as u8, (reg >> 16) as u8, (reg >> 24) as u8]
+}
+
+/// The decoded `CPUID` information, once it has been queried.
+static INFO: Once<CpuInfo> = Once::new();
+
+/// Returns information about the CPU, querying `CPUID` the first time this
+/// is called.
+#[inline]
+pub fn info() -> &'static CpuInfo {
+    INFO.call_once(CpuInfo::query)
+}
+
+/// Returns true if the CPU supports all of `features`.
+#[inline]
+pub fn has(features: Features) -> bool { info().has(features) }
+
+/// Returns true if the CPU has a timestamp counter.
+#[inline]
+pub fn has_tsc() -> bool { has(TSC) }
+
+/// Returns true if the CPU has an on-chip local APIC.
+#[inline]
+pub fn has_apic() -> bool { has(APIC) }
+
+/// Returns true if the CPU supports Supervisor Mode Execution Prevention.
+#[inline]
+pub fn has_smep() -> bool { has(SMEP) }
+
+/// Returns true if the CPU supports Supervisor Mode Access Prevention.
+#[inline]
+pub fn has_smap() -> bool { has(SMAP) }
+
+/// Returns true if the CPU supports the no-execute page bit.
+#[inline]
+pub fn has_nx() -> bool { has(NX) }
+
+/// Returns true if the timestamp counter runs at a constant rate in every
+/// power state, so that it can be used to tell the time.
+#[inline]
+pub fn has_invariant_tsc() -> bool { has(INVARIANT_TSC) }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        // an Intel Skylake CPU
+        let sig = Signature::from_eax(0x0005_06e3);
+        assert_eq!(sig, Signature { family: 6, model: 0x5e, stepping: 3 });
+        // an AMD Zen CPU
+        let sig = Signature::from_eax(0x0080_0f11);
+        assert_eq!(sig, Signature { family: 0x17, model: 1, stepping: 1 });
+    }
+
+    #[test]
+    fn test_cache() {
+        // a 32 KiB, 8-way L1 data cache with 64-byte lines
+        let regs = CpuId { eax: 0x121, ebx: 0x01c0_003f, ecx: 63, edx: 0 };
+        let cache = Cache::from_regs(regs).unwrap();
+        assert_eq!(cache.level, 1);
+        assert_eq!(cache.ty, CacheType::Data);
+        assert_eq!(cache.ways, 8);
+        assert_eq!(cache.size(), 32 * 1024);
+        assert_eq!(Cache::from_regs(CpuId::default()), None);
+    }
+
+    #[test]
+    fn test_features() {
+        let none = CpuId::default();
+        let amd = CpuId { edx: 1 << 20 | 1 << 26, ..none };
+        let features = Features::decode(none, none, amd, none);
+        assert_eq!(features, NX | PAGE_1GB);
+    }
+}
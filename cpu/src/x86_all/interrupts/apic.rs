@@ -0,0 +1,312 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Support for the local Advanced Programmable Interrupt Controller (APIC).
+//!
+//! Each CPU has its own local APIC, which receives interrupts from the
+//! I/O APIC (see [`ioapic`]) and from other CPUs, and has its own timer.
+//! Unlike the 8259 PICs, the local APIC is programmed through memory-mapped
+//! registers. Since this crate can't map memory, the caller is responsible
+//! for mapping the registers (at [`base_paddr`]) uncached, and passing their
+//! virtual address to [`init`].
+//!
+//! [`ioapic`]: ../ioapic/index.html
+//! [`base_paddr`]: fn.base_paddr.html
+//! [`init`]: fn.init.html
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+use memory::{Addr, PAddr, VAddr};
+use ::{cpuid, msr};
+
+/// Vector for spurious interrupts from the local APIC.
+///
+/// On older CPUs, the low four bits of the spurious vector are hardwired to
+/// 1, so this must end in `f`.
+pub const SPURIOUS_VECTOR: u8 = 0xef;
+/// Vector for local APIC error interrupts.
+pub const ERROR_VECTOR: u8 = 0xee;
+/// Vector for the local APIC timer.
+pub const TIMER_VECTOR: u8 = 0x30;
+
+// Local APIC register offsets.
+const ID: usize = 0x020;
+const VERSION: usize = 0x030;
+const TASK_PRIORITY: usize = 0x080;
+const EOI: usize = 0x0b0;
+const SPURIOUS: usize = 0x0f0;
+const IN_SERVICE: usize = 0x100;
+const ERROR_STATUS: usize = 0x280;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+const LVT_TIMER: usize = 0x320;
+const LVT_LINT0: usize = 0x350;
+const LVT_ERROR: usize = 0x370;
+const TIMER_INITIAL: usize = 0x380;
+const TIMER_CURRENT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3e0;
+
+/// Bit in the spurious interrupt vector register that enables the APIC.
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+/// Bit in a local vector table entry that masks the interrupt.
+const LVT_MASKED: u32 = 1 << 16;
+/// Bit in `IA32_APIC_BASE` that enables the APIC.
+const GLOBAL_ENABLE: u64 = 1 << 11;
+/// Mask for the base address in `IA32_APIC_BASE`.
+const BASE_MASK: u64 = 0x000f_ffff_ffff_f000;
+/// Delivery status bit in the interrupt command register.
+const ICR_PENDING: u32 = 1 << 12;
+/// Level bit in the interrupt command register.
+const ICR_ASSERT: u32 = 1 << 14;
+
+/// Virtual address of the local APIC registers, or 0 if the local APIC has
+/// not been enabled.
+///
+/// N.B. that this doesn't need to be per-CPU state: every local APIC's
+/// registers are at the same physical address, and each CPU sees its own
+/// local APIC there, so one mapping serves all CPUs.
+static BASE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Returns true if this CPU has a local APIC.
+#[inline]
+pub fn is_supported() -> bool { cpuid::has_apic() }
+
+/// Returns true if the local APIC has been enabled with [`init`].
+///
+/// [`init`]: fn.init.html
+#[inline]
+pub fn is_enabled() -> bool { BASE.load(Ordering::Acquire) != 0 }
+
+/// Returns the physical address of this CPU's local APIC registers.
+///
+/// # Safety
+/// + Causes a general protection fault if not executed in kernel mode.
+pub unsafe fn base_paddr() -> PAddr {
+    let base = msr::read(msr::IA32_APIC_BASE) & BASE_MASK;
+    PAddr::from(base as <PAddr as Addr>::Repr)
+}
+
+/// Enable this CPU's local APIC, whose registers are mapped at `base`.
+///
+/// After this is called, [`local`] returns the local APIC, and interrupts
+/// are acknowledged through it rather than through the PICs.
+///
+/// # Safety
+/// + `base` must be an uncached mapping of the local APIC's registers.
+/// + Causes a general protection fault if not executed in kernel mode.
+///
+/// [`local`]: fn.local.html
+pub unsafe fn init(base: VAddr) -> LocalApic {
+    let lapic = LocalApic::new(base);
+    lapic.enable();
+    BASE.store(base.as_usize(), Ordering::Release);
+    lapic
+}
+
+/// Returns this CPU's local APIC, if it has been enabled.
+#[inline]
+pub fn local() -> Option<LocalApic> {
+    match BASE.load(Ordering::Acquire) {
+        0 => None
+      , base => Some(LocalApic { base: base })
+    }
+}
+
+/// Modes for the local APIC timer.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TimerMode { /// Count down once, and fire a single interrupt.
+                     OneShot = 0
+                   , /// Fire an interrupt every time the count reaches
+                     /// zero, and then start again.
+                     Periodic = 1 << 17
+                   }
+
+/// Values the local APIC timer's clock can be divided by.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TimerDivide { By1 = 0b1011
+                     , By2 = 0b0000
+                     , By4 = 0b0001
+                     , By8 = 0b0010
+                     , By16 = 0b0011
+                     , By32 = 0b1000
+                     , By64 = 0b1001
+                     , By128 = 0b1010
+                     }
+
+/// An inter-processor interrupt.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Ipi { /// Deliver an interrupt on the given vector.
+               Fixed(u8)
+             , /// Deliver a non-maskable interrupt.
+               Nmi
+             , /// Reset the target CPU into its wait-for-SIPI state.
+               Init
+             , /// Start the target CPU executing in real mode at the given
+               /// page (i.e. at physical address `page << 12`).
+               Startup(u8)
+             }
+
+impl Ipi {
+    /// Returns the low half of the interrupt command register for this IPI.
+    fn icr(&self) -> u32 {
+        match *self {
+            Ipi::Fixed(vector) => vector as u32
+          , Ipi::Nmi => 0b100 << 8
+          , Ipi::Init => 0b101 << 8 | ICR_ASSERT
+          , Ipi::Startup(page) => 0b110 << 8 | page as u32
+        }
+    }
+}
+
+/// Which CPUs an inter-processor interrupt is sent to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Destination { /// The CPU whose local APIC has the given ID.
+                       Apic(u8)
+                     , /// The CPU sending the IPI.
+                       Current
+                     , /// Every CPU, including the one sending the IPI.
+                       All
+                     , /// Every CPU except the one sending the IPI.
+                       AllButCurrent
+                     }
+
+/// A CPU's local APIC.
+#[derive(Copy, Clone, Debug)]
+pub struct LocalApic { base: usize }
+
+impl LocalApic {
+    /// Returns the local APIC whose registers are mapped at `base`.
+    ///
+    /// # Safety
+    /// + `base` must be an uncached mapping of the local APIC's registers.
+    pub unsafe fn new(base: VAddr) -> Self {
+        LocalApic { base: base.as_usize() }
+    }
+
+    #[inline]
+    unsafe fn read(&self, reg: usize) -> u32 {
+        ptr::read_volatile((self.base + reg) as *const u32)
+    }
+
+    #[inline]
+    unsafe fn write(&self, reg: usize, value: u32) {
+        ptr::write_volatile((self.base + reg) as *mut u32, value)
+    }
+
+    /// Returns this local APIC's ID.
+    #[inline]
+    pub fn id(&self) -> u8 {
+        (unsafe { self.read(ID) } >> 24) as u8
+    }
+
+    /// Returns this local APIC's version.
+    #[inline]
+    pub fn version(&self) -> u8 {
+        unsafe { self.read(VERSION) as u8 }
+    }
+
+    /// Enable this local APIC.
+    ///
+    /// This sets the spurious interrupt vector, routes APIC errors to
+    /// `ERROR_VECTOR`, and masks `LINT0`, which the PICs are wired to.
+    ///
+    /// # Safety
+    /// + Causes a general protection fault if not executed in kernel mode.
+    pub unsafe fn enable(&self) {
+        let base = msr::read(msr::IA32_APIC_BASE);
+        msr::write(msr::IA32_APIC_BASE, base | GLOBAL_ENABLE);
+
+        self.write(SPURIOUS, SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+        self.write(LVT_LINT0, LVT_MASKED);
+        self.write(LVT_ERROR, ERROR_VECTOR as u32);
+        // the error status register must be written before it is read.
+        self.write(ERROR_STATUS, 0);
+        self.write(ERROR_STATUS, 0);
+        // accept interrupts of every priority.
+        self.write(TASK_PRIORITY, 0);
+    }
+
+    /// Returns the errors this local APIC has detected since the last call.
+    pub fn error_status(&self) -> u32 {
+        unsafe {
+            self.write(ERROR_STATUS, 0);
+            self.read(ERROR_STATUS)
+        }
+    }
+
+    /// Returns true if `vector` was delivered by this local APIC and its
+    /// handler has not yet signalled the end of the interrupt.
+    ///
+    /// Software interrupts (`int n`) and exceptions aren't delivered by the
+    /// local APIC, so they are never in service.
+    #[inline]
+    pub fn is_in_service(&self, vector: u8) -> bool {
+        // the in-service register is eight 32-bit registers, 16 bytes apart.
+        let reg = IN_SERVICE + (vector as usize / 32) * 0x10;
+        unsafe { self.read(reg) & (1 << (vector % 32)) != 0 }
+    }
+
+    /// Signal the end of the current interrupt.
+    ///
+    /// # Safety
+    /// + This should only be called by interrupt handler functions.
+    #[inline]
+    pub unsafe fn eoi(&self) {
+        self.write(EOI, 0)
+    }
+
+    /// Start the local APIC timer.
+    ///
+    /// # Arguments
+    /// + `vector`: the vector to fire when the count reaches zero
+    /// + `mode`: whether the timer fires once, or periodically
+    /// + `divide`: what to divide the timer's clock by
+    /// + `count`: the number of (divided) ticks to count down from
+    ///
+    /// # Safety
+    /// + There must be a handler for `vector` in the IDT.
+    pub unsafe fn start_timer( &self, vector: u8, mode: TimerMode
+                             , divide: TimerDivide, count: u32) {
+        self.write(TIMER_DIVIDE, divide as u32);
+        self.write(LVT_TIMER, mode as u32 | vector as u32);
+        self.write(TIMER_INITIAL, count);
+    }
+
+    /// Stop the local APIC timer.
+    pub fn stop_timer(&self) {
+        unsafe {
+            self.write(TIMER_INITIAL, 0);
+            self.write(LVT_TIMER, LVT_MASKED);
+        }
+    }
+
+    /// Returns the current count of the local APIC timer.
+    #[inline]
+    pub fn timer_count(&self) -> u32 {
+        unsafe { self.read(TIMER_CURRENT) }
+    }
+
+    /// Send an inter-processor interrupt, and wait for it to be delivered.
+    ///
+    /// # Safety
+    /// + Sending `Init` or `Startup` to a running CPU will reset it.
+    pub unsafe fn send_ipi(&self, dest: Destination, ipi: Ipi) {
+        let (apic, shorthand) = match dest {
+            Destination::Apic(id) => (id, 0b00)
+          , Destination::Current => (0, 0b01)
+          , Destination::All => (0, 0b10)
+          , Destination::AllButCurrent => (0, 0b11)
+        };
+        self.write(ICR_HIGH, (apic as u32) << 24);
+        // writing the low half sends the IPI.
+        self.write(ICR_LOW, ipi.icr() | shorthand << 18);
+        while self.read(ICR_LOW) & ICR_PENDING != 0 { }
+    }
+}
@@ -49,8 +49,9 @@ pub struct Gate<H = InterruptHandler>
       pub offset_lower: u16
     , /// code segment selector (GDT or LDT)
       pub selector: segment::Selector
-    , /// always zero
-      _zero: u8
+    , /// the interrupt stack table entry to switch to, or 0 to stay on the
+      /// current stack (only the low 3 bits are used)
+      pub ist: u8
     , /// indicates the gate's type and attributes.
       /// the second half indicates the type:
       ///   + `0b1100`: Call gate
@@ -78,7 +79,7 @@ impl<H> Gate<H> {
     pub const fn absent() -> Self {
        Gate { offset_lower: 0
             , selector: segment::Selector::from_raw(0)
-            , _zero: 0
+            , ist: 0
             , flags: GateFlags { bits:  0b1000_1110 }
             , offset_mid: 0
             , offset_upper: 0
@@ -102,6 +103,18 @@ impl<H> Gate<H> {
         self
     }
 
+    /// Run this gate's handler on the stack in the TSS's interrupt stack
+    /// table entry `index`, rather than on the current stack.
+    ///
+    /// # Panics
+    /// + If `index` is not between 1 and 7.
+    #[inline]
+    pub fn set_stack_index(&mut self, index: u8) -> &mut Self {
+        assert!(index >= 1 && index <= 7, "no such IST entry {}", index);
+        self.ist = index;
+        self
+    }
+
 }
 
 
@@ -109,7 +122,7 @@ impl<H> Default for Gate<H> {
     fn default() -> Self {
         Gate { offset_lower: 0
              , selector: segment::Selector::from_raw(0)
-             , _zero: 0
+             , ist: 0
              , flags: GateFlags { bits: 0b1000_1110 }
              , offset_mid: 0
              , offset_upper: 0
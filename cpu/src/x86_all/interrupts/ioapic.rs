@@ -0,0 +1,267 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Support for the I/O APIC.
+//!
+//! An I/O APIC routes external interrupts to the local APICs. Each I/O APIC
+//! handles a range of global system interrupts (GSIs), starting at its GSI
+//! base. Every GSI has an entry in the I/O APIC's redirection table, which
+//! selects the vector to fire, which local APIC to send it to, how the
+//! interrupt is triggered, and whether it is masked.
+//!
+//! ISA IRQs are usually connected to the GSI with the same number, but the
+//! firmware may say otherwise (the PIT's IRQ 0 is almost always GSI 2).
+//! Such overrides are recorded with [`override_isa_irq`].
+//!
+//! Like the local APIC, the I/O APIC is programmed through memory-mapped
+//! registers, which the caller must map before calling [`register`].
+//!
+//! [`override_isa_irq`]: fn.override_isa_irq.html
+//! [`register`]: fn.register.html
+use core::ptr;
+
+use memory::{PAddr, VAddr};
+use spin::Mutex;
+
+/// The physical address of the first I/O APIC on most systems.
+pub const DEFAULT_PADDR: PAddr = PAddr::new(0xfec0_0000);
+
+/// The maximum number of I/O APICs that may be registered.
+pub const MAX_IO_APICS: usize = 8;
+
+/// The number of ISA IRQs.
+pub const ISA_IRQS: usize = 16;
+
+// I/O APIC register offsets.
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+// Indirect I/O APIC registers.
+const IOAPICID: u32 = 0x00;
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+bitflags! {
+    /// Flags in an I/O APIC redirection table entry.
+    pub flags RedirectionFlags: u64 {
+        /// If set, the destination is a set of logical APICs, rather than
+        /// a physical APIC ID.
+        const LOGICAL_DEST = 1 << 11
+      , /// If set, the interrupt is active low. Otherwise, it is active high.
+        const ACTIVE_LOW = 1 << 13
+      , /// If set, the interrupt is level triggered. Otherwise, it is edge
+        /// triggered.
+        const LEVEL_TRIGGERED = 1 << 15
+      , /// If set, the interrupt is masked.
+        const MASKED = 1 << 16
+    }
+}
+
+/// An entry in an I/O APIC's redirection table.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RedirectionEntry(u64);
+
+impl RedirectionEntry {
+    /// Returns an entry that fires `vector` on the local APIC `dest`.
+    #[inline]
+    pub fn new(vector: u8, dest: u8, flags: RedirectionFlags) -> Self {
+        RedirectionEntry((dest as u64) << 56 | flags.bits() | vector as u64)
+    }
+
+    /// Returns the vector this entry fires.
+    #[inline] pub fn vector(&self) -> u8 { self.0 as u8 }
+
+    /// Returns the local APIC this entry is sent to.
+    #[inline] pub fn dest(&self) -> u8 { (self.0 >> 56) as u8 }
+
+    /// Returns this entry's flags.
+    #[inline]
+    pub fn flags(&self) -> RedirectionFlags {
+        RedirectionFlags::from_bits_truncate(self.0)
+    }
+
+    /// Returns true if this entry is masked.
+    #[inline]
+    pub fn is_masked(&self) -> bool { self.flags().contains(MASKED) }
+}
+
+/// An I/O APIC.
+#[derive(Copy, Clone, Debug)]
+pub struct IoApic { base: usize
+                  , /// The first GSI handled by this I/O APIC.
+                    gsi_base: u32
+                  }
+
+impl IoApic {
+    /// Returns the I/O APIC whose registers are mapped at `base`, and which
+    /// handles GSIs starting at `gsi_base`.
+    ///
+    /// # Safety
+    /// + `base` must be an uncached mapping of the I/O APIC's registers.
+    pub unsafe fn new(base: VAddr, gsi_base: u32) -> Self {
+        IoApic { base: base.as_usize(), gsi_base: gsi_base }
+    }
+
+    #[inline]
+    unsafe fn read(&self, reg: u32) -> u32 {
+        ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+        ptr::read_volatile((self.base + IOWIN) as *const u32)
+    }
+
+    #[inline]
+    unsafe fn write(&self, reg: u32, value: u32) {
+        ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+        ptr::write_volatile((self.base + IOWIN) as *mut u32, value)
+    }
+
+    /// Returns this I/O APIC's ID.
+    #[inline]
+    pub fn id(&self) -> u8 {
+        ((unsafe { self.read(IOAPICID) } >> 24) & 0xf) as u8
+    }
+
+    /// Returns the first GSI handled by this I/O APIC.
+    #[inline] pub fn gsi_base(&self) -> u32 { self.gsi_base }
+
+    /// Returns the number of entries in this I/O APIC's redirection table.
+    #[inline]
+    pub fn num_entries(&self) -> u32 {
+        ((unsafe { self.read(IOAPICVER) } >> 16) & 0xff) + 1
+    }
+
+    /// Returns true if this I/O APIC handles `gsi`.
+    #[inline]
+    pub fn handles(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.num_entries()
+    }
+
+    /// Read entry `i` in the redirection table.
+    pub fn read_entry(&self, i: u32) -> RedirectionEntry {
+        unsafe {
+            let low = self.read(IOREDTBL + i * 2) as u64;
+            let high = self.read(IOREDTBL + i * 2 + 1) as u64;
+            RedirectionEntry(high << 32 | low)
+        }
+    }
+
+    /// Write entry `i` in the redirection table.
+    ///
+    /// # Safety
+    /// + If `entry` is not masked, there must be a handler for its vector
+    ///   in the IDT.
+    pub unsafe fn write_entry(&self, i: u32, entry: RedirectionEntry) {
+        // mask the entry while it is half written.
+        self.write(IOREDTBL + i * 2, MASKED.bits() as u32);
+        self.write(IOREDTBL + i * 2 + 1, (entry.0 >> 32) as u32);
+        self.write(IOREDTBL + i * 2, entry.0 as u32);
+    }
+
+    /// Mask every entry in the redirection table.
+    pub fn mask_all(&self) {
+        for i in 0 .. self.num_entries() {
+            unsafe { self.write_entry(i, RedirectionEntry(MASKED.bits())) }
+        }
+    }
+}
+
+/// The registered I/O APICs.
+static IO_APICS: Mutex<[Option<IoApic>; MAX_IO_APICS]>
+    = Mutex::new([None; MAX_IO_APICS]);
+
+/// The GSI and flags for each ISA IRQ which doesn't use the default (the GSI
+/// with the same number, edge triggered and active high).
+static ISA_OVERRIDES: Mutex<[Option<(u32, RedirectionFlags)>; ISA_IRQS]>
+    = Mutex::new([None; ISA_IRQS]);
+
+/// Register an I/O APIC, masking all of its interrupts.
+///
+/// # Returns
+/// + `Ok(())` if the I/O APIC was registered
+/// + `Err(&str)` if `MAX_IO_APICS` are already registered.
+pub fn register(ioapic: IoApic) -> Result<(), &'static str> {
+    let mut ioapics = IO_APICS.lock();
+    let slot = ioapics.iter_mut()
+                      .find(|slot| slot.is_none())
+                      .ok_or("too many I/O APICs")?;
+    ioapic.mask_all();
+    *slot = Some(ioapic);
+    Ok(())
+}
+
+/// Returns the registered I/O APIC which handles `gsi`.
+fn for_gsi(gsi: u32) -> Result<IoApic, &'static str> {
+    IO_APICS.lock()
+            .iter()
+            .filter_map(|ioapic| *ioapic)
+            .find(|ioapic| ioapic.handles(gsi))
+            .ok_or("no I/O APIC handles that GSI")
+}
+
+/// Record that ISA IRQ `irq` is connected to `gsi`, with the given `flags`.
+///
+/// # Panics
+/// + If `irq` is not an ISA IRQ.
+pub fn override_isa_irq(irq: u8, gsi: u32, flags: RedirectionFlags) {
+    ISA_OVERRIDES.lock()[irq as usize] = Some((gsi, flags));
+}
+
+/// Returns the GSI that ISA IRQ `irq` is connected to, and the flags it
+/// should be routed with.
+///
+/// # Panics
+/// + If `irq` is not an ISA IRQ.
+pub fn isa_irq(irq: u8) -> (u32, RedirectionFlags) {
+    ISA_OVERRIDES.lock()[irq as usize]
+        .unwrap_or((irq as u32, RedirectionFlags::empty()))
+}
+
+/// Route `gsi` to `vector` on the local APIC `dest`, and unmask it.
+///
+/// # Safety
+/// + There must be a handler for `vector` in the IDT.
+pub unsafe fn route(gsi: u32, vector: u8, dest: u8, flags: RedirectionFlags)
+                   -> Result<(), &'static str> {
+    let ioapic = for_gsi(gsi)?;
+    let entry = RedirectionEntry::new(vector, dest, flags - MASKED);
+    ioapic.write_entry(gsi - ioapic.gsi_base, entry);
+    trace!("routed GSI {} to vector {:#x} on APIC {}", gsi, vector, dest);
+    Ok(())
+}
+
+/// Route ISA IRQ `irq` to `vector` on the local APIC `dest`, and unmask it.
+///
+/// # Safety
+/// + There must be a handler for `vector` in the IDT.
+pub unsafe fn route_isa_irq(irq: u8, vector: u8, dest: u8)
+                           -> Result<(), &'static str> {
+    let (gsi, flags) = isa_irq(irq);
+    route(gsi, vector, dest, flags)
+}
+
+/// Mask `gsi`.
+pub fn mask(gsi: u32) -> Result<(), &'static str> {
+    let ioapic = for_gsi(gsi)?;
+    let i = gsi - ioapic.gsi_base;
+    let entry = ioapic.read_entry(i);
+    unsafe {
+        ioapic.write_entry(i, RedirectionEntry(entry.0 | MASKED.bits()))
+    }
+    Ok(())
+}
+
+/// Unmask `gsi`.
+///
+/// # Safety
+/// + There must be a handler for the vector `gsi` is routed to.
+pub unsafe fn unmask(gsi: u32) -> Result<(), &'static str> {
+    let ioapic = for_gsi(gsi)?;
+    let i = gsi - ioapic.gsi_base;
+    let entry = ioapic.read_entry(i);
+    ioapic.write_entry(i, RedirectionEntry(entry.0 & !MASKED.bits()));
+    Ok(())
+}
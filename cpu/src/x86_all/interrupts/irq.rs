@@ -0,0 +1,351 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Runtime registration of IRQ handlers.
+//!
+//! Each ISA IRQ is delivered on vector [`VECTOR_BASE`] plus its number,
+//! whether it comes from the PICs or the I/O APIC. Every one of those
+//! vectors gets its own stub in [`STUBS`], which the kernel installs in the
+//! IDT, so the stub knows which IRQ fired and can pass it to the handlers
+//! registered for that line.
+//!
+//! Drivers add handlers with [`register`] and remove them with
+//! [`unregister`]. Up to [`MAX_SHARED`] handlers may share a line; when an
+//! IRQ arrives, each of them is called in turn, and each returns whether its
+//! device raised the interrupt. A line is unmasked when its first handler is
+//! registered, and masked again when its last handler is removed.
+//!
+//! [`stats`] reports how many times each IRQ fired, how many of those no
+//! handler claimed, and how many were spurious IRQs from the PICs.
+//!
+//! Once an IRQ has ended, the hook set with [`set_exit_hook`] is called.
+//! This is where the scheduler preempts the interrupted thread.
+//!
+//! [`VECTOR_BASE`]: constant.VECTOR_BASE.html
+//! [`STUBS`]: static.STUBS.html
+//! [`register`]: fn.register.html
+//! [`unregister`]: fn.unregister.html
+//! [`MAX_SHARED`]: constant.MAX_SHARED.html
+//! [`stats`]: fn.stats.html
+//! [`set_exit_hook`]: fn.set_exit_hook.html
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+use spin::Once;
+
+use context::InterruptFrame;
+use super::{apic, end_of_interrupt, ioapic, pics, InterruptHandler, IrqMutex};
+
+/// The vector that IRQ 0 is delivered on.
+pub const VECTOR_BASE: u8 = 0x20;
+
+/// The number of IRQ lines.
+pub const NUM_IRQS: usize = 16;
+
+/// The maximum number of handlers which may share an IRQ line.
+pub const MAX_SHARED: usize = 4;
+
+/// A function which handles an IRQ.
+///
+/// It is called with the IRQ's number, with interrupts disabled, and returns
+/// `true` if its device raised the interrupt. Handlers must not signal the
+/// end of the interrupt; that is done once every handler has run.
+pub type Handler = fn(irq: u8) -> bool;
+
+/// Identifies a registered handler, so that it can be unregistered.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct HandlerId { irq: u8, id: usize }
+
+impl HandlerId {
+    /// Returns the IRQ this handler is registered for.
+    #[inline] pub fn irq(&self) -> u8 { self.irq }
+}
+
+/// Statistics for an IRQ line.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct IrqStats { /// The number of times the IRQ fired, including
+                      /// spurious IRQs.
+                      pub count: usize
+                    , /// The number of times no handler claimed the IRQ.
+                      pub unhandled: usize
+                    , /// The number of spurious IRQs from the PICs.
+                      pub spurious: usize
+                    , /// The number of handlers registered for the IRQ.
+                      pub handlers: usize
+                    }
+
+/// A registered handler.
+#[derive(Copy, Clone)]
+struct Registration { id: usize
+                    , name: &'static str
+                    , handler: Handler
+                    }
+
+/// An IRQ line, and the handlers registered for it.
+#[derive(Copy, Clone)]
+struct Line { handlers: [Option<Registration>; MAX_SHARED]
+            , count: usize
+            , unhandled: usize
+            , spurious: usize
+            }
+
+impl Line {
+    const fn new() -> Self {
+        Line { handlers: [None; MAX_SHARED]
+             , count: 0
+             , unhandled: 0
+             , spurious: 0
+             }
+    }
+
+    /// Returns the number of handlers registered for this line.
+    fn num_handlers(&self) -> usize {
+        self.handlers.iter().filter(|h| h.is_some()).count()
+    }
+
+    /// Add a handler to this line.
+    ///
+    /// # Returns
+    /// + `Ok(())` if the handler was added
+    /// + `Err(&str)` if the line already has `MAX_SHARED` handlers.
+    fn add(&mut self, registration: Registration) -> Result<(), &'static str> {
+        let slot = self.handlers.iter_mut()
+                                .find(|slot| slot.is_none())
+                                .ok_or("too many handlers share that IRQ")?;
+        *slot = Some(registration);
+        Ok(())
+    }
+
+    /// Remove the handler with the ID `id` from this line.
+    ///
+    /// # Returns
+    /// + `Some(Registration)` if the handler was registered for this line
+    /// + `None` if it was not.
+    fn remove(&mut self, id: usize) -> Option<Registration> {
+        self.handlers.iter_mut()
+                     .find(|slot| slot.map(|r| r.id) == Some(id))
+                     .and_then(|slot| slot.take())
+    }
+
+    fn stats(&self) -> IrqStats {
+        IrqStats { count: self.count
+                 , unhandled: self.unhandled
+                 , spurious: self.spurious
+                 , handlers: self.num_handlers()
+                 }
+    }
+}
+
+/// Every IRQ line.
+///
+/// This is locked by the IRQ stubs, so it disables interrupts while it is
+/// held.
+static LINES: IrqMutex<[Line; NUM_IRQS]>
+    = IrqMutex::new([Line::new(); NUM_IRQS]);
+
+/// The ID to give the next handler.
+static NEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Unmask `irq` on whichever interrupt controller delivers it.
+unsafe fn enable_line(irq: u8) -> Result<(), &'static str> {
+    match apic::local() {
+        Some(lapic) => ioapic::route_isa_irq(irq, VECTOR_BASE + irq, lapic.id())
+      , None => { pics::unmask(irq); Ok(()) }
+    }
+}
+
+/// Mask `irq` on whichever interrupt controller delivers it.
+fn disable_line(irq: u8) -> Result<(), &'static str> {
+    if apic::is_enabled() {
+        ioapic::mask(ioapic::isa_irq(irq).0)
+    } else {
+        pics::mask(irq);
+        Ok(())
+    }
+}
+
+/// Register `handler` for IRQ `irq`.
+///
+/// If this is the first handler for `irq`, the line is unmasked. `name`
+/// identifies the handler in log messages.
+///
+/// # Returns
+/// + `Ok(HandlerId)` if the handler was registered
+/// + `Err(&str)` if `irq` is not an IRQ line, the line already has
+///   `MAX_SHARED` handlers, or it could not be unmasked.
+pub fn register(irq: u8, name: &'static str, handler: Handler)
+               -> Result<HandlerId, &'static str> {
+    if irq as usize >= NUM_IRQS {
+        return Err("no such IRQ line")
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let registration = Registration { id: id, name: name, handler: handler };
+    let mut lines = LINES.lock();
+    let line = &mut lines[irq as usize];
+    line.add(registration)?;
+    if line.num_handlers() == 1 {
+        // the stub for this line is always in the IDT.
+        if let Err(why) = unsafe { enable_line(irq) } {
+            line.remove(id);
+            return Err(why)
+        }
+    }
+    trace!("registered {} for IRQ {}", name, irq);
+    Ok(HandlerId { irq: irq, id: id })
+}
+
+/// Unregister the handler `id`.
+///
+/// If it was the last handler for its IRQ, the line is masked.
+///
+/// # Returns
+/// + `true` if the handler was registered, and has been removed
+/// + `false` if it had already been unregistered.
+pub fn unregister(id: HandlerId) -> bool {
+    let mut lines = LINES.lock();
+    let line = &mut lines[id.irq as usize];
+    let registration = match line.remove(id.id) {
+        Some(registration) => registration
+      , None => return false
+    };
+    trace!("unregistered {} from IRQ {}", registration.name, id.irq);
+    if line.num_handlers() == 0 {
+        if let Err(why) = disable_line(id.irq) {
+            warn!("could not mask IRQ {}: {}", id.irq, why);
+        }
+    }
+    true
+}
+
+/// A function called at the end of every IRQ.
+pub type ExitHook = fn();
+
+/// The hook called at the end of every IRQ, if one has been set.
+static EXIT_HOOK: Once<ExitHook> = Once::new();
+
+/// Call `hook` at the end of every (non-spurious) IRQ.
+///
+/// The hook runs after the interrupt has ended, with interrupts disabled, on
+/// the stack of the code that was interrupted. It may switch to another
+/// stack, in which case the interrupted code resumes (and returns from the
+/// interrupt) once something switches back.
+///
+/// This can only be done once; later calls do nothing.
+pub fn set_exit_hook(hook: ExitHook) {
+    EXIT_HOOK.call_once(|| hook);
+}
+
+/// Returns the statistics for IRQ `irq`, or `None` if there is no such IRQ.
+pub fn stats(irq: u8) -> Option<IrqStats> {
+    if irq as usize >= NUM_IRQS {
+        return None
+    }
+    Some(LINES.lock()[irq as usize].stats())
+}
+
+/// Returns true if `irq` is a spurious IRQ from the PICs.
+///
+/// Once IRQs are delivered through the I/O APIC, the PICs are masked, but
+/// they can still raise spurious IRQs 7 and 15. Those vectors only belong
+/// to the I/O APIC if a handler has been registered for them.
+fn is_spurious(irq: u8, line: &Line) -> bool {
+    (irq == 7 || irq == 15)
+        && (!apic::is_enabled() || line.num_handlers() == 0)
+        && pics::is_spurious(irq)
+}
+
+/// Run the handlers registered for `irq`, and end the interrupt.
+fn dispatch(irq: u8) {
+    let handlers = {
+        let mut lines = LINES.lock();
+        let line = &mut lines[irq as usize];
+        line.count += 1;
+        if is_spurious(irq, line) {
+            line.spurious += 1;
+            // spurious IRQs must not be acknowledged.
+            if !apic::is_enabled() {
+                unsafe { pics::end_spurious_interrupt(irq) }
+            }
+            return
+        }
+        line.handlers
+    };
+
+    // the lock is released while the handlers run, so that they may
+    // register or unregister handlers.
+    let mut handled = false;
+    for registration in handlers.iter().filter_map(|h| *h) {
+        handled |= (registration.handler)(irq);
+    }
+
+    if !handled {
+        LINES.lock()[irq as usize].unhandled += 1;
+        debug!("unhandled IRQ {} (vector {:#x})", irq, VECTOR_BASE + irq);
+    }
+    unsafe { end_of_interrupt(VECTOR_BASE + irq) }
+    if let Some(hook) = EXIT_HOOK.try() {
+        hook();
+    }
+}
+
+macro_rules! irq_stubs {
+    ( $($irq:expr => $name:ident),* ) => {
+        $(
+            #[inline(never)]
+            extern "x86-interrupt" fn $name(_frame: &InterruptFrame) {
+                dispatch($irq)
+            }
+        )*
+
+        /// The stub for each IRQ line, indexed by IRQ number.
+        ///
+        /// The stub for IRQ `n` should be installed in the IDT at vector
+        /// `VECTOR_BASE + n`.
+        pub static STUBS: [InterruptHandler; NUM_IRQS]
+            = [ $($name as InterruptHandler),* ];
+    }
+}
+
+irq_stubs! {
+    0 => irq0, 1 => irq1, 2 => irq2, 3 => irq3
+  , 4 => irq4, 5 => irq5, 6 => irq6, 7 => irq7
+  , 8 => irq8, 9 => irq9, 10 => irq10, 11 => irq11
+  , 12 => irq12, 13 => irq13, 14 => irq14, 15 => irq15
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Line, Registration, MAX_SHARED};
+
+    fn handler(_irq: u8) -> bool { true }
+
+    fn registration(id: usize) -> Registration {
+        Registration { id: id, name: "test", handler: handler }
+    }
+
+    #[test]
+    fn test_shared_line() {
+        let mut line = Line::new();
+        for id in 0 .. MAX_SHARED {
+            line.add(registration(id)).unwrap();
+        }
+        assert_eq!(line.num_handlers(), MAX_SHARED);
+        assert!(line.add(registration(MAX_SHARED)).is_err());
+    }
+
+    #[test]
+    fn test_remove_frees_slot() {
+        let mut line = Line::new();
+        line.add(registration(0)).unwrap();
+        line.add(registration(1)).unwrap();
+        assert!(line.remove(0).is_some());
+        assert!(line.remove(0).is_none());
+        assert_eq!(line.num_handlers(), 1);
+        line.add(registration(2)).unwrap();
+        assert_eq!(line.stats().handlers, 2);
+    }
+}
@@ -12,7 +12,10 @@
 //! `x86_64` as a black box. Code that depends on this can use the same API
 //! regardless of system word size.
 #![warn(missing_docs)]
+pub mod apic;
 pub mod idt;
+pub mod ioapic;
+pub mod irq;
 pub mod pics;
 
 use vga::{CONSOLE, Color};
@@ -21,6 +24,10 @@ use core::fmt;
 use core::fmt::Write;
 
 use context::InterruptFrame;
+use util::backtrace::Symbolized;
+
+pub use util::sync::{ IrqMutex, IrqMutexGuard
+                    , IrqRwLock, IrqReadGuard, IrqWriteGuard };
 
 /// Number of interrupt vectors corresponding to CPU exceptions.
 ///
@@ -51,11 +58,12 @@ pub struct ExceptionInfo { /// The name of the exception
                          }
 
 bitflags! {
-   flags PageFaultErrorCode: u32 {
+   /// The error code pushed by a page fault.
+   pub flags PageFaultErrorCode: u32 {
        /// If 1, the error was caused by a page that was present.
        /// Otherwise, the page was non-present.
        const PRESENT = 1 << 0
-     , /// If 1, the error was caused by a read. If 0, the cause was a write.
+     , /// If 1, the error was caused by a write. If 0, the cause was a read.
        const READ_WRITE = 1 << 1
      , /// If 1, the error was caused during user-mode execution.
        /// If 0, the processor was in kernel mode.
@@ -78,18 +86,68 @@ impl fmt::Display for PageFaultErrorCode {
                else { "" }
              , if self.contains(RESERVED) { " reserved bits set to one "}
                else { "" }
-             , if self.contains(READ_WRITE) { "read" } else { "write" }
+             , if self.contains(READ_WRITE) { "write" } else { "read" }
              , if self.contains(INST_FETCH) { " in an instruction fetch"}
                else { "" }
              , if self.contains(USER_MODE) { "user" } else { "kernel" }            )
    }
 }
 
-/// Handler for the system timer interrupt
-pub extern "x86-interrupt" fn timer(_frame: &InterruptFrame) {
-    // do nothing, just signal the pics to end the IRQ
-    // println!("timer!");
-    unsafe { pics::end_pic_interrupt(0x20); }
+/// Signal the end of the interrupt on `vector` to whichever interrupt
+/// controller delivered it.
+///
+/// If the local APIC is enabled, it is sent an EOI, but only if it delivered
+/// `vector`. Otherwise, if `vector` belongs to one of the PICs, the
+/// interrupt is ended on the PICs. CPU exceptions and software interrupts
+/// are not delivered by an interrupt controller, so nothing is done for
+/// them.
+///
+/// # Safety
+///  - This should only be called by interrupt handler functions.
+pub unsafe fn end_of_interrupt(vector: u8) {
+    if (vector as usize) < NUM_EXCEPTIONS {
+        return
+    }
+    match apic::local() {
+        // an EOI always ends the highest priority interrupt in service, so
+        // sending one for an interrupt the local APIC didn't deliver would
+        // end some other interrupt instead.
+        Some(lapic) => if lapic.is_in_service(vector) { lapic.eoi() }
+      , None => pics::end_pic_interrupt(vector)
+    }
+}
+
+/// Run `f` with interrupts disabled, then restore whether or not they were
+/// enabled.
+///
+/// Anything that interrupt handlers also lock should be an [`IrqMutex`] or
+/// [`IrqRwLock`] instead, which disable interrupts for as long as they are
+/// held.
+///
+/// [`IrqMutex`]: struct.IrqMutex.html
+/// [`IrqRwLock`]: struct.IrqRwLock.html
+pub fn without_interrupts<F, T>(f: F) -> T
+where F: FnOnce() -> T {
+    let enabled = ::flags::read().contains(::flags::IF);
+    if enabled { unsafe { idt::Idt::disable_interrupts() } }
+    let result = f();
+    if enabled { unsafe { idt::Idt::enable_interrupts() } }
+    result
+}
+
+/// Handler for spurious interrupts from the local APIC.
+///
+/// Spurious interrupts must not be acknowledged with an EOI.
+pub extern "x86-interrupt" fn spurious(_frame: &InterruptFrame) {
+    trace!("spurious interrupt");
+}
+
+/// Handler for local APIC error interrupts.
+pub extern "x86-interrupt" fn apic_error(_frame: &InterruptFrame) {
+    if let Some(lapic) = apic::local() {
+        error!("local APIC error: {:#x}", lapic.error_status());
+        unsafe { lapic.eoi() }
+    }
 }
 
 
@@ -97,19 +155,26 @@ pub extern "x86-interrupt" fn timer(_frame: &InterruptFrame) {
 /// Handles page fault exceptions
 #[no_mangle] #[inline(never)]
 pub extern "x86-interrupt" fn page_fault( frame: &InterruptFrame, error_code: usize) {
+   describe_page_fault(frame, error_code);
+   ::vga::panic::print_backtrace(unsafe { frame.backtrace() });
+   loop { }
+}
+
+/// Write a description of a page fault to the console.
+///
+/// This is used by the default page fault handler, and by page fault
+/// handlers which want to add more information of their own.
+pub fn describe_page_fault(frame: &InterruptFrame, error_code: usize) {
    let _ = write!( CONSOLE.lock()
                       .set_colors(Color::White, Color::Blue)
                    //   .clear()
-             , "IT'S NOT MY FAULT: Page Fault at {:p} \
+             , "IT'S NOT MY FAULT: Page Fault at {} \
                 \nError code: {:#x}\n\n{}\n{:?}"
-             , (*frame).rip
+             , Symbolized((*frame).rip as usize)
              , error_code
              , PageFaultErrorCode::from_bits_truncate(error_code as u32)
              , *frame
              );
-   // TODO: stack dumps please
-
-   loop { }
 }
 
 /// Test interrupt handler for ensuring that the IDT is configured correctly.
@@ -117,8 +182,6 @@ pub extern "x86-interrupt" fn page_fault( frame: &InterruptFrame, error_code: us
 pub extern "x86-interrupt" fn test(_frame: &InterruptFrame) {
    // assert_eq!(state.int_id, 0x80);
    kinfoln!(dots: " . . ", target: "Testing interrupt handling:", "[ OKAY ]");
-   // send the PICs the end interrupt signal
-   unsafe {
-       pics::end_pic_interrupt(0xff);
-   }
+   // this is only raised by `int 0xff`, which no interrupt controller
+   // delivered, so there is no end of interrupt to signal.
 }
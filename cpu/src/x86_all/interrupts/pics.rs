@@ -23,8 +23,6 @@
 use Port;
 use spin::Mutex;
 
-use core::mem::transmute;
-
 /// Starting offset for PIC1
 const OFFSET: u8 = 0x20;
 /// Command port for the follower PIC (PIC2)
@@ -152,20 +150,23 @@ impl PIC {
 }
 
 /// Trait for something which is capable of handling a PIC IRQ
+///
+/// IRQs are identified by their interrupt vector, rather than by an `IRQ`,
+/// since not every vector the PICs deliver has an `IRQ` variant.
 trait IRQHandler {
     /// Returns whether or not this handler handles the given IRQ
-    fn handles(&self, irq: IRQ) -> bool;
+    fn handles(&self, vector: u8) -> bool;
     /// End an interrupt request
-    fn end_interrupt(&self, irq: IRQ);
+    fn end_interrupt(&self, vector: u8);
 }
 
 impl IRQHandler for PIC {
 
-    fn handles(&self, irq: IRQ) -> bool {
-        self.offset <= (irq as u8) && (irq as u8) < self.offset + 8
+    fn handles(&self, vector: u8) -> bool {
+        self.offset <= vector && vector < self.offset + 8
     }
 
-    fn end_interrupt(&self, _: IRQ) {
+    fn end_interrupt(&self, _: u8) {
         let _ = self.send_command(Command::EndIRQ);
     }
 }
@@ -224,16 +225,16 @@ impl BothPICs {
 
 impl IRQHandler for BothPICs {
 
-    fn handles(&self, irq: IRQ) -> bool {
-        self.0.handles(irq) ||
-        self.1.handles(irq)
+    fn handles(&self, vector: u8) -> bool {
+        self.0.handles(vector) ||
+        self.1.handles(vector)
     }
 
-    fn end_interrupt(&self, irq: IRQ) {
-        if self.1.handles(irq) {
-            self.1.end_interrupt(irq);
+    fn end_interrupt(&self, vector: u8) {
+        if self.1.handles(vector) {
+            self.1.end_interrupt(vector);
         }
-        self.0.end_interrupt(irq);
+        self.0.end_interrupt(vector);
     }
 
 }
@@ -264,9 +265,89 @@ pub unsafe fn initialize() {
 ///  - This should only be called by interrupt handler functions.
 pub unsafe fn end_pic_interrupt(interrupt_id: u8) {
     let pics = PICS.lock();
-    let irq: IRQ = transmute(interrupt_id);
 
-    if pics.handles(irq) {
-        pics.end_interrupt(irq)
+    if pics.handles(interrupt_id) {
+        pics.end_interrupt(interrupt_id)
+    }
+}
+
+/// Returns the PIC that `irq` belongs to, and the IRQ's bit in its
+/// registers.
+#[inline]
+fn pic_for(pics: &BothPICs, irq: u8) -> (&PIC, u8) {
+    if irq < 8 { (&pics.0, 1 << irq) } else { (&pics.1, 1 << (irq - 8)) }
+}
+
+/// Mask the ISA IRQ `irq`.
+///
+/// # Panics
+///  - If `irq` is not an ISA IRQ (0 through 15).
+pub fn mask(irq: u8) {
+    assert!(irq < 16, "IRQ {} is not an ISA IRQ", irq);
+    let pics = PICS.lock();
+    let (pic, bit) = pic_for(&pics, irq);
+    pic.send_data(pic.data_port.read() | bit);
+}
+
+/// Unmask the ISA IRQ `irq`.
+///
+/// # Safety
+///  - There must be a handler for the IRQ's vector in the IDT.
+///
+/// # Panics
+///  - If `irq` is not an ISA IRQ (0 through 15).
+pub unsafe fn unmask(irq: u8) {
+    assert!(irq < 16, "IRQ {} is not an ISA IRQ", irq);
+    let pics = PICS.lock();
+    let (pic, bit) = pic_for(&pics, irq);
+    pic.send_data(pic.data_port.read() & !bit);
+    if !pic.is_leader() {
+        // the follower's IRQs arrive through the cascade IRQ.
+        pics.0.send_data(pics.0.data_port.read() & !(1 << 2));
+    }
+}
+
+/// Returns true if `irq` was raised spuriously by the PICs.
+///
+/// If an IRQ goes away before the leader PIC tells the CPU which one it
+/// was, the PIC raises IRQ 7 (or IRQ 15, for the follower) instead, without
+/// setting its bit in the in-service register. Only IRQs 7 and 15 can be
+/// spurious.
+pub fn is_spurious(irq: u8) -> bool {
+    let pics = PICS.lock();
+    match irq {
+        7 => pics.0.read_isr() & (1 << 7) == 0
+      , 15 => pics.1.read_isr() & (1 << 7) == 0
+      , _ => false
+    }
+}
+
+/// End a spurious IRQ.
+///
+/// A spurious IRQ must not be acknowledged on the PIC that raised it, but
+/// a spurious IRQ 15 still went through the leader PIC's cascade IRQ, so
+/// the leader must be sent an EOI.
+///
+/// # Safety
+///  - This should only be called by interrupt handler functions.
+pub unsafe fn end_spurious_interrupt(irq: u8) {
+    if irq == 15 {
+        let pics = PICS.lock();
+        pics.0.end_interrupt(OFFSET + 2);
     }
 }
+
+/// Mask every IRQ on both PICs.
+///
+/// This is used once IRQs are delivered through the I/O APIC instead. The
+/// PICs should still be initialized first, so that any spurious IRQs they
+/// raise are not mistaken for CPU exceptions.
+///
+/// # Safety
+///  - This should only ever be called by the kernel boot process.
+pub unsafe fn disable() {
+    let pics = PICS.lock();
+    pics.0.send_data(0xff);
+    pics.1.send_data(0xff);
+    kinfoln!(dots: " . . ", target: "Masking PICs", "[ OKAY ]");
+}
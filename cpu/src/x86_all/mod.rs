@@ -39,11 +39,14 @@ macro_rules! cpu_flag {
 }
 
 pub mod control_regs;
+pub mod cpuid;
 pub mod segment;
 pub mod dtable;
 pub mod flags;
+pub mod msr;
 pub mod timer;
 pub mod interrupts;
+pub mod smap;
 
 /// Represents an x86 privilege level.
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd, Ord, Eq)]
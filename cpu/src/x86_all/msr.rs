@@ -0,0 +1,100 @@
+//
+//  SOS: the Stupid Operating System
+//  by Hawk Weisman (hi@hawkweisman.me)
+//
+//  Copyright (c) 2015 Hawk Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Code for interacting with the Model-Specific Registers (MSRs).
+
+/// Extended Feature Enable Register (EFER) on IA-32
+pub const IA32_EFER: u32 = 0xc0000080;
+
+/// Local APIC base address register
+///
+/// Bits 12 and up hold the physical base address of the local APIC's
+/// registers, and bit 11 enables the local APIC.
+pub const IA32_APIC_BASE: u32 = 0x1b;
+
+/// Page Attribute Table (PAT)
+///
+/// Contains eight 8-bit memory types, which are selected by the `PAT`,
+/// `PCD` and `PWT` bits of a page table entry.
+pub const IA32_PAT: u32 = 0x277;
+
+/// System call target address register (`STAR`)
+///
+/// Bits 32-47 hold the kernel code segment selector loaded by `syscall`
+/// (the stack segment is the next descriptor). Bits 48-63 hold the base
+/// selector `sysret` uses to return to 64-bit user mode: the stack segment
+/// is the base plus 8, and the code segment is the base plus 16.
+pub const IA32_STAR: u32 = 0xc0000081;
+
+/// Long mode system call target address register (`LSTAR`)
+///
+/// Holds the address `syscall` jumps to in 64-bit mode.
+pub const IA32_LSTAR: u32 = 0xc0000082;
+
+/// System call flag mask register (`SFMASK`)
+///
+/// Each bit set here is cleared in `%rflags` by `syscall`.
+pub const IA32_FMASK: u32 = 0xc0000084;
+
+/// The base address of the `%fs` segment.
+pub const IA32_FS_BASE: u32 = 0xc0000100;
+
+/// The base address of the `%gs` segment.
+pub const IA32_GS_BASE: u32 = 0xc0000101;
+
+/// The base address `%gs` will have after the next `swapgs`
+/// (`KERNEL_GS_BASE`)
+///
+/// `swapgs` exchanges this with `IA32_GS_BASE`, so that the kernel can find
+/// its per-CPU data when it is entered from user mode.
+pub const IA32_KERNEL_GS_BASE: u32 = 0xc0000102;
+
+/// The system call enable (`SCE`) bit in the EFER.
+pub const EFER_SCE: u64 = 1 << 0;
+
+/// Write `value` to the specified `msr`
+///
+/// # Arguments
+/// + `msr`: which MSR to write to
+/// + `value`: the  bits to write
+pub unsafe fn write(msr: u32, value: u64) {
+    let low = value as u32;
+    let high = (value >> 32) as u32;
+    asm!(   "wrmsr"
+         :: "{ecx}" (msr), "{eax}" (low), "{edx}" (high)
+         :  "memory"
+         : "volatile" );
+}
+
+/// Read 64 bits from the specified `msr`
+pub unsafe fn read(msr: u32) -> u64 {
+    let low: u32;
+    let high: u32;
+    asm!( "rdmsr"
+        : "={eax}" (low), "={edx}" (high)
+        : "{ecx}" (msr)
+        : "memory"
+        : "volatile" );
+    ((high as u64) << 32) | (low as u64)
+}
+
+
+/// Enable the NXE (No Execute) in the IA-32 EFER register.
+///
+/// This allows us to set the NXE bit on pages.
+pub unsafe fn enable_nxe() {
+    let nxe_bit = 1 << 11;
+    let efer = read(IA32_EFER) | nxe_bit;
+    write(IA32_EFER, efer);
+}
+
+/// Enable the `syscall` and `sysret` instructions in the IA-32 EFER register.
+pub unsafe fn enable_syscall() {
+    let efer = read(IA32_EFER) | EFER_SCE;
+    write(IA32_EFER, efer);
+}
@@ -21,20 +21,131 @@
 
 use core::{fmt, mem};
 use super::{PrivilegeLevel, dtable};
-/// The number of entries in the GDT
 #[cfg(target_arch = "x86_64")]
-pub const GDT_SIZE: usize = 2;
+use ::task::{StateDescriptor, StateSegment};
+
+/// The maximum number of entries in the GDT
+///
+/// This leaves room for the kernel and user segments, and for a TSS for each
+/// CPU. Each TSS descriptor is twice the size of the other descriptors, so it
+/// takes up two entries.
+#[cfg(target_arch = "x86_64")]
+pub const GDT_SIZE: usize = 256;
+
+/// Selector for the kernel code segment.
+#[cfg(target_arch = "x86_64")]
+pub const KERNEL_CODE: Selector = Selector::new(1);
+/// Selector for the kernel data segment.
+#[cfg(target_arch = "x86_64")]
+pub const KERNEL_DATA: Selector = Selector::new(2);
+/// Selector for the user data segment, with an RPL of 3.
+///
+/// N.B. that this comes before the user code segment, since `sysret` loads
+/// the 64-bit user code segment from the entry after the user stack segment.
+#[cfg(target_arch = "x86_64")]
+pub const USER_DATA: Selector = Selector::from_raw(3 << 3 | RPL_RING_3.bits);
+/// Selector for the 64-bit user code segment, with an RPL of 3.
+#[cfg(target_arch = "x86_64")]
+pub const USER_CODE: Selector = Selector::from_raw(4 << 3 | RPL_RING_3.bits);
+
+/// The number of segment descriptors (including the null descriptor) which
+/// come before the TSS descriptors in the GDT.
+#[cfg(target_arch = "x86_64")]
+const NUM_SEGMENTS: usize = 5;
 
 /// Structure representing a Global Descriptor Table
+///
+/// Since we use paging rather than segmentation for memory protection, the
+/// GDT has the same layout on every x86_64 system: a null descriptor, the
+/// 64-bit kernel code and data segments, the user data and code segments,
+/// (see [`KERNEL_CODE`], [`KERNEL_DATA`], [`USER_DATA`] and [`USER_CODE`]),
+/// and then a TSS descriptor for each CPU, which are added at runtime with
+/// [`add_tss`].
+///
+/// [`KERNEL_CODE`]: constant.KERNEL_CODE.html
+/// [`KERNEL_DATA`]: constant.KERNEL_DATA.html
+/// [`USER_DATA`]: constant.USER_DATA.html
+/// [`USER_CODE`]: constant.USER_CODE.html
+/// [`add_tss`]: struct.Gdt.html#method.add_tss
 #[cfg(target_arch = "x86_64")]
-#[repr(C, packed)]
-pub struct Gdt { _null: Descriptor
-               , /// The code segment descriptor
-                 pub code: Descriptor
-            //    , /// The data segment descriptor
-            //      pub data: Descriptor
+#[repr(C)]
+pub struct Gdt { segments: [Descriptor; NUM_SEGMENTS]
+               , /// TSS descriptors, and room for more.
+                 system: [Descriptor; GDT_SIZE - NUM_SEGMENTS]
+               , /// the number of entries in use.
+                 len: usize
                }
 
+/// Flags for a 64-bit code segment.
+#[cfg(target_arch = "x86_64")]
+const CODE_64: u16 = PRESENT.bits | DESCR_TYPE.bits | LENGTH.bits
+                   | EXECUTE.bits | READ.bits;
+/// Flags for a data segment.
+#[cfg(target_arch = "x86_64")]
+const DATA: u16 = PRESENT.bits | DESCR_TYPE.bits | WRITE.bits;
+
+#[cfg(target_arch = "x86_64")]
+impl Gdt {
+    /// Returns a new GDT containing the kernel and user segments.
+    pub const fn new() -> Self {
+        Gdt { segments: [ Descriptor::null()
+                        , Descriptor::from_flags(Flags::from_raw(CODE_64))
+                        , Descriptor::from_flags(Flags::from_raw(DATA))
+                        , Descriptor::from_flags(Flags::from_raw( DATA
+                                                                | DPL.bits ))
+                        , Descriptor::from_flags(Flags::from_raw( CODE_64
+                                                                | DPL.bits ))
+                        ]
+            , system: [Descriptor::null(); GDT_SIZE - NUM_SEGMENTS]
+            , len: NUM_SEGMENTS
+            }
+    }
+
+    /// Add a descriptor for the TSS `tss`.
+    ///
+    /// If this GDT is already loaded, it must be loaded again before the
+    /// new descriptor can be used.
+    ///
+    /// # Returns
+    /// + `Ok(Selector)` with the selector for the TSS, to pass to `ltr`
+    /// + `Err(&str)` if the GDT is full.
+    pub fn add_tss(&mut self, tss: &'static StateSegment)
+                  -> Result<Selector, &'static str> {
+        if self.len + 2 > GDT_SIZE {
+            return Err("the GDT has no room for another TSS descriptor")
+        }
+        let index = self.len;
+        let i = index - NUM_SEGMENTS;
+        let descriptor = StateDescriptor::new(tss);
+        self.system[i] = descriptor.lower;
+        // the upper half of the descriptor holds the upper 32 bits of the
+        // base address in the place of the limit and lower base address.
+        self.system[i + 1] = Descriptor {
+            limit: descriptor.upper as u16
+          , base_low: (descriptor.upper >> 16) as u16
+          , ..Descriptor::null()
+        };
+        self.len += 2;
+        Ok(Selector::new(index as u16))
+    }
+
+    /// Load this GDT, and reload the segment registers with the kernel
+    /// segments.
+    ///
+    /// `%fs` and `%gs` are left alone, since reloading them would clear
+    /// their base addresses.
+    ///
+    /// # Safety
+    /// + This GDT must not be changed while it is being loaded.
+    pub unsafe fn activate(&'static self) {
+        dtable::DTable::load(self);
+        KERNEL_CODE.load_cs();
+        KERNEL_DATA.load_ss();
+        KERNEL_DATA.load_ds();
+        KERNEL_DATA.load_es();
+    }
+}
+
 /// The number of entries in the GDT
 #[cfg(target_arch = "x86")]
 pub const GDT_SIZE: usize = 512;
@@ -48,10 +159,17 @@ impl dtable::DTable for Gdt {
     /// Returns the number of Entries in the `DTable`.
     ///
     /// This is used for calculating the limit.
+    #[cfg(target_arch = "x86_64")]
+    #[inline(always)] fn entry_count(&self) -> usize { self.len }
+
+    /// Returns the number of Entries in the `DTable`.
+    ///
+    /// This is used for calculating the limit.
+    #[cfg(target_arch = "x86")]
     #[inline(always)] fn entry_count(&self) -> usize { GDT_SIZE }
 
     /// Load the GDT table with the `lgdt` instruction.
-    #[inline] fn load(&self) {
+    #[inline] fn load(&'static self) {
         unsafe {
             asm!(  "lgdt ($0)"
             :: "r"(&self.get_ptr())
@@ -60,19 +178,6 @@ impl dtable::DTable for Gdt {
     }
 }
 
-
-extern {
-
-    /// A Global Descriptor Table (GDT)
-    ///
-    /// This is used for configuring segmentation. Since we use paging rather than
-    /// segmentation for memory protection, we never actually _use_ the GDT, but
-    /// x86 requires that it be properly configured nonetheless. So, here it is.
-    #[cfg(target_arch = "x86_64")]
-    #[link_section = ".gdt64"]
-    pub static GDT: Gdt;
-}
-
 bitflags! {
     /// A segment selector is a 16-bit identifier for a segment.
     ///
@@ -221,14 +326,13 @@ impl Selector {
     /// and use `lret` to reload `cs`.
     #[cfg(target_arch = "x86_64")]
     pub unsafe fn load_cs(&self) {
-        asm!(  "push $0
-                lea rax, [rip + 1]
-                push rax
-                iret
+        asm!(  "pushq $0
+                leaq 1f(%rip), %rax
+                pushq %rax
+                lretq
                 1:"
             :: "r"(self.bits as u64)
-            :  "rax", "memory"
-            :  "intel");
+            :  "rax", "memory");
     }
 
 }
@@ -261,16 +365,17 @@ impl fmt::Display for Selector {
 /// application programs.
 ///
 #[repr(C, packed)]
-pub struct Descriptor { /// The last 8 bits of the base address
-                        pub base_high: u8
-                      , /// The next 16 bits are bitflags
-                        pub flags: Flags
-                      , /// The middle 8 bits of the base address
-                        pub base_mid: u8
+#[derive(Copy, Clone)]
+pub struct Descriptor { /// the first 16 bits of the segment limit
+                        pub limit: u16
                       , /// The first 16 bits of the base address
                         pub base_low: u16
-                      , /// the segment limit
-                        pub limit: u16
+                      , /// The middle 8 bits of the base address
+                        pub base_mid: u8
+                      , /// The next 16 bits are bitflags
+                        pub flags: Flags
+                      , /// The last 8 bits of the base address
+                        pub base_high: u8
                       }
 
 impl Descriptor {
@@ -285,26 +390,36 @@ impl Descriptor {
                    }
     }
 
+    /// Constructs a new `Descriptor` with a base and limit of 0 and the
+    /// given `flags`.
+    ///
+    /// In 64-bit mode, the base and limit of code and data segments are
+    /// ignored, so this is all that's needed to describe them.
+    pub const fn from_flags(flags: Flags) -> Self {
+        Descriptor { limit: 0
+                   , base_low: 0
+                   , base_mid: 0
+                   , flags: flags
+                   , base_high: 0
+                   }
+    }
+
     /// Constructs a new `Descriptor` from a `limit` and a `base` address
     pub fn new(base: u32, limit: u32) -> Self {
-        let (hi, mid, lo): (u8, u8, u16) = unsafe { mem::transmute(base) };
-        let (limit_lo, limit_hi): (u16, u16) = unsafe { mem::transmute(limit) };
-        // I hope this is right...
-        let flags = (limit_hi & 0b1111) << 8;
+        let flags = ((limit >> 16) as u16 & 0b1111) << 8;
 
-        Descriptor { base_high: hi
+        Descriptor { limit: limit as u16
+                   , base_low: base as u16
+                   , base_mid: (base >> 16) as u8
                    , flags: Flags::from_bits_truncate(flags)
-                   , base_mid: mid
-                   , base_low: lo
-                   , limit: limit_lo
+                   , base_high: (base >> 24) as u8
                    }
     }
 
     /// Extract the limit part from the flags and limit fields.
     #[inline]
     pub fn get_limit(&self) -> u32 {
-        // TODO: i hope this is right...
-        self.flags.get_limit_part() & self.limit as u32
+        self.flags.get_limit_part() | self.limit as u32
     }
 
 }
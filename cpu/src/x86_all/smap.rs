@@ -0,0 +1,171 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Supervisor Mode Execution and Access Prevention (SMEP and SMAP).
+//!
+//! When SMEP is enabled, the kernel faults if it tries to execute code in a
+//! user-accessible page. When SMAP is enabled, the kernel faults if it tries
+//! to read or write a user-accessible page, unless the `AC` flag is set.
+//!
+//! The few places where the kernel legitimately needs to touch user memory
+//! should do so inside a [`UserAccess`] window, which sets `AC` (with
+//! `stac`) when it is created and clears it (with `clac`) when it is dropped.
+//!
+//! [`UserAccess`]: struct.UserAccess.html
+#![warn(missing_docs)]
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+
+use super::{control_regs, cpuid, flags};
+use super::interrupts::{ PageFaultErrorCode, PRESENT, USER_MODE
+                       , INST_FETCH };
+
+/// Whether or not SMAP has been enabled.
+///
+/// `stac` and `clac` are invalid opcodes on CPUs without SMAP, so we must
+/// only execute them if SMAP is enabled.
+static SMAP_ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Which protections were enabled by [`enable`](fn.enable.html).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Enabled { /// true if SMEP was enabled
+                     pub smep: bool
+                   , /// true if SMAP was enabled
+                     pub smap: bool
+                   }
+
+/// Enable SMEP and SMAP, if the CPU supports them.
+///
+/// # Safety
+/// + Causes a general protection fault if not executed in kernel mode.
+/// + After this is called, any kernel access to user-accessible pages outside
+///   of a `UserAccess` window will fault.
+pub unsafe fn enable() -> Enabled {
+    let smep = cpuid::has_smep();
+    let smap = cpuid::has_smap();
+    if smep {
+        control_regs::cr4::enable_smep(true);
+    }
+    if smap {
+        control_regs::cr4::enable_smap(true);
+        SMAP_ENABLED.store(true, Ordering::Release);
+    }
+    Enabled { smep: smep, smap: smap }
+}
+
+/// Returns true if SMAP is enabled.
+#[inline]
+pub fn is_smap_enabled() -> bool {
+    SMAP_ENABLED.load(Ordering::Acquire)
+}
+
+/// Set the `AC` flag, allowing the kernel to access user memory.
+///
+/// # Safety
+/// + Causes an invalid opcode exception if the CPU doesn't support SMAP.
+#[inline(always)]
+pub unsafe fn stac() {
+    asm!("stac" :::: "volatile");
+}
+
+/// Clear the `AC` flag, preventing the kernel from accessing user memory.
+///
+/// # Safety
+/// + Causes an invalid opcode exception if the CPU doesn't support SMAP.
+#[inline(always)]
+pub unsafe fn clac() {
+    asm!("clac" :::: "volatile");
+}
+
+/// A window during which the kernel may access user memory.
+///
+/// While a `UserAccess` is alive, the `AC` flag is set; it is cleared again
+/// when the `UserAccess` is dropped, unless it was already set when the
+/// `UserAccess` was created. This means that windows may be nested: only
+/// the outermost one clears `AC`. If SMAP is not enabled, this does
+/// nothing.
+///
+/// Keep these windows as small as possible: in particular, don't call into
+/// code which doesn't expect to touch user memory while one is open.
+#[derive(Debug)]
+pub struct UserAccess {
+    /// Whether this window set `AC`, and so must clear it.
+    set_ac: bool
+  , // a `UserAccess` must be dropped on the CPU it was created on.
+    _not_send: PhantomData<*const ()>
+}
+
+impl UserAccess {
+    /// Open a new window during which the kernel may access user memory.
+    #[inline]
+    pub fn new() -> Self {
+        let set_ac = is_smap_enabled() && !flags::read().contains(flags::AC);
+        if set_ac {
+            // this is safe, since we know the CPU supports SMAP.
+            unsafe { stac() }
+        }
+        UserAccess { set_ac: set_ac, _not_send: PhantomData }
+    }
+}
+
+impl Drop for UserAccess {
+    #[inline]
+    fn drop(&mut self) {
+        if self.set_ac {
+            // this is safe, since we know the CPU supports SMAP.
+            unsafe { clac() }
+        }
+    }
+}
+
+/// Execute `f` inside a window during which the kernel may access user
+/// memory.
+#[inline]
+pub fn with_user_access<F, T>(f: F) -> T
+where F: FnOnce() -> T {
+    let _access = UserAccess::new();
+    f()
+}
+
+/// Explain a page fault which was caused by SMEP or SMAP.
+///
+/// # Arguments
+/// + `error_code`: the page fault error code
+/// + `rflags`: the value of `%rflags` when the fault occurred
+/// + `user_page`: whether the faulting address is in a user-accessible page
+///
+/// # Returns
+/// + `Some(&str)` with a diagnostic if the fault was caused by SMEP or SMAP
+/// + `None` otherwise
+pub fn diagnose( error_code: PageFaultErrorCode
+               , rflags: flags::Flags
+               , user_page: bool)
+               -> Option<&'static str> {
+    if !user_page || error_code.contains(USER_MODE)
+                  || !error_code.contains(PRESENT) {
+        return None
+    }
+    let cr4 = unsafe {
+        // this is safe, since page faults are handled in kernel mode.
+        control_regs::cr4::read()
+    };
+    if error_code.contains(INST_FETCH) {
+        if cr4.contains(control_regs::cr4::SMEP) {
+            Some("SMEP violation: the kernel tried to execute code in a \
+                  user-accessible page")
+        } else {
+            None
+        }
+    } else if cr4.contains(control_regs::cr4::SMAP)
+           && !rflags.contains(flags::AC) {
+        Some("SMAP violation: the kernel accessed a user-accessible page \
+              outside of a `UserAccess` window")
+    } else {
+        None
+    }
+}
@@ -0,0 +1,173 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Clock sources.
+//!
+//! [`now`] returns the time since boot, in nanoseconds. Until a better
+//! clock source is selected, it is measured in system ticks, so it only
+//! advances once per tick.
+//!
+//! If the CPU has an invariant timestamp counter (TSC), which runs at a
+//! constant rate regardless of power state, it can be used instead. Its rate
+//! isn't reported anywhere reliable, so it must first be measured against a
+//! reference [`Counter`] with a known frequency (the HPET, or failing that
+//! the system tick) with [`calibrate_tsc`], and then selected with
+//! [`use_tsc`].
+//!
+//! [`now`]: fn.now.html
+//! [`Counter`]: trait.Counter.html
+//! [`calibrate_tsc`]: fn.calibrate_tsc.html
+//! [`use_tsc`]: fn.use_tsc.html
+use spin::Once;
+
+use ::cpuid;
+use super::timestamp;
+
+/// Nanoseconds per second.
+const NS_PER_SECOND: u64 = 1_000_000_000;
+
+/// A free-running counter with a known frequency.
+pub trait Counter {
+    /// Returns the name of this counter.
+    fn name(&self) -> &'static str;
+    /// Returns the number of times this counter increments per second.
+    fn frequency(&self) -> u64;
+    /// Returns the current value of this counter.
+    fn read(&self) -> u64;
+    /// Returns the largest value this counter reaches before it wraps to 0.
+    ///
+    /// This must be one less than a power of two.
+    #[inline] fn mask(&self) -> u64 { u64::max_value() }
+}
+
+/// The system tick, as a counter of nanoseconds.
+///
+/// This only advances once per tick, and requires interrupts to be enabled.
+#[derive(Copy, Clone, Debug)]
+pub struct Ticks;
+
+impl Counter for Ticks {
+    #[inline] fn name(&self) -> &'static str { "PIT tick" }
+    #[inline] fn frequency(&self) -> u64 { NS_PER_SECOND }
+    #[inline] fn read(&self) -> u64 { super::uptime_ns() }
+}
+
+/// The timestamp counter, with a measured frequency.
+#[derive(Copy, Clone, Debug)]
+pub struct Tsc { /// The TSC's frequency, in Hz.
+                 pub hz: u64
+               , /// The TSC when it was selected with `use_tsc`.
+                 base_tsc: u64
+               , /// The time when it was selected with `use_tsc`.
+                 base_ns: u64
+               }
+
+impl Tsc {
+    /// Returns the time since boot, in nanoseconds.
+    #[inline]
+    fn now(&self) -> u64 {
+        let cycles = self.read().wrapping_sub(self.base_tsc);
+        // split the conversion to nanoseconds so that it can't overflow.
+        self.base_ns + (cycles / self.hz) * NS_PER_SECOND
+                     + (cycles % self.hz) * NS_PER_SECOND / self.hz
+    }
+}
+
+impl Counter for Tsc {
+    #[inline] fn name(&self) -> &'static str { "TSC" }
+    #[inline] fn frequency(&self) -> u64 { self.hz }
+    #[inline] fn read(&self) -> u64 { unsafe { timestamp::rtdsc() } }
+}
+
+/// The TSC, once it has been selected.
+static TSC: Once<Tsc> = Once::new();
+
+/// Check whether the TSC can be used to tell the time.
+///
+/// This requires that the TSC is invariant, and that reading it is allowed.
+///
+/// # Returns
+/// + `Ok(())` if the TSC can be used
+/// + `Err(&str)` describing why it can't.
+pub fn is_tsc_reliable() -> Result<(), &'static str> {
+    timestamp::is_available()?;
+    if cpuid::has_invariant_tsc() {
+        Ok(())
+    } else {
+        Err("the timestamp counter is not invariant")
+    }
+}
+
+/// Measure the TSC's frequency against `reference`, for roughly `ms`
+/// milliseconds.
+///
+/// # Returns
+/// + The TSC's frequency, in Hz.
+pub fn calibrate_tsc<C: Counter>(reference: &C, ms: u64) -> u64 {
+    let ref_hz = reference.frequency();
+    let wait = ref_hz * ms / 1000;
+    // the reference counter may wrap during calibration, so intervals are
+    // measured modulo its width.
+    let mask = reference.mask();
+
+    // start on an edge of the reference counter, in case it is coarse.
+    let edge = reference.read();
+    while reference.read() == edge { }
+    let ref_start = reference.read();
+    let tsc_start = unsafe { timestamp::rtdsc() };
+
+    let mut ref_end = ref_start;
+    while ref_end.wrapping_sub(ref_start) & mask < wait {
+        ref_end = reference.read();
+    }
+    let tsc_end = unsafe { timestamp::rtdsc() };
+
+    let cycles = tsc_end - tsc_start;
+    let elapsed = ref_end.wrapping_sub(ref_start) & mask;
+    // split the multiplication so that it can't overflow.
+    (cycles / elapsed) * ref_hz + (cycles % elapsed) * ref_hz / elapsed
+}
+
+/// Tell the time with the TSC, which runs at `hz`, from now on.
+///
+/// This can only be done once; later calls do nothing.
+///
+/// # Returns
+/// + `Ok(())` if the TSC is now the clock source
+/// + `Err(&str)` if `hz` is 0, in which case the clock source is unchanged.
+pub fn use_tsc(hz: u64) -> Result<(), &'static str> {
+    if hz == 0 {
+        return Err("the TSC's frequency was measured as 0 Hz")
+    }
+    TSC.call_once(|| Tsc { hz: hz
+                         , base_tsc: unsafe { timestamp::rtdsc() }
+                         , base_ns: super::uptime_ns()
+                         });
+    Ok(())
+}
+
+/// Returns the name of the clock source that [`now`] reads.
+///
+/// [`now`]: fn.now.html
+pub fn source() -> &'static str {
+    match TSC.try() {
+        Some(tsc) => tsc.name()
+      , None => Ticks.name()
+    }
+}
+
+/// Returns the time since boot, in nanoseconds.
+///
+/// This never goes backwards.
+#[inline]
+pub fn now() -> u64 {
+    match TSC.try() {
+        Some(tsc) => tsc.now()
+      , None => super::uptime_ns()
+    }
+}
@@ -0,0 +1,128 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Support for the High Precision Event Timer (HPET).
+//!
+//! For now, we only use the HPET's main counter, as a reference for
+//! calibrating the timestamp counter. Like the APICs, the HPET is programmed
+//! through memory-mapped registers, which the caller must map (at the
+//! address in the ACPI HPET table) before calling [`Hpet::new`].
+//!
+//! The main counter may be only 32 bits wide, in which case it wraps every
+//! few minutes, so [`Counter::mask`] reports how wide it is.
+//!
+//! [`Hpet::new`]: struct.Hpet.html#method.new
+//! [`Counter::mask`]: ../clock/trait.Counter.html#method.mask
+use core::ptr;
+
+use memory::VAddr;
+
+use super::clock::Counter;
+
+// HPET register offsets.
+const CAPABILITIES: usize = 0x000;
+const CONFIG: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0f0;
+
+/// Bit in the capabilities register that is set if the main counter is 64
+/// bits wide.
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+
+/// Bit in the configuration register that starts the main counter.
+const ENABLE: u64 = 1 << 0;
+
+/// The longest main counter period the HPET specification allows (100 ns),
+/// in femtoseconds.
+const MAX_PERIOD_FS: u32 = 100_000_000;
+
+/// Femtoseconds per second.
+const FS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+/// An HPET.
+#[derive(Copy, Clone, Debug)]
+pub struct Hpet { base: usize
+                , /// Set if the main counter is 64 bits wide
+                  wide: bool
+                }
+
+impl Hpet {
+    /// Returns the HPET whose registers are mapped at `base`.
+    ///
+    /// # Returns
+    /// + `Ok(Hpet)` if the HPET reports a valid counter period
+    /// + `Err(&str)` if its period is 0 or longer than 100 ns, which means
+    ///   it's broken (or isn't an HPET at all).
+    ///
+    /// # Safety
+    /// + `base` must be an uncached mapping of the HPET's registers.
+    pub unsafe fn new(base: VAddr) -> Result<Self, &'static str> {
+        let base = base.as_usize();
+        let caps = ptr::read_volatile((base + CAPABILITIES) as *const u64);
+        let hpet = Hpet { base: base, wide: caps & COUNT_SIZE_CAP != 0 };
+        match hpet.period_fs() {
+            0 => Err("the HPET's counter period is 0")
+          , period if period > MAX_PERIOD_FS =>
+                Err("the HPET's counter period is longer than 100 ns")
+          , _ => Ok(hpet)
+        }
+    }
+
+    #[inline]
+    unsafe fn read(&self, reg: usize) -> u64 {
+        ptr::read_volatile((self.base + reg) as *const u64)
+    }
+
+    #[inline]
+    unsafe fn write(&self, reg: usize, value: u64) {
+        ptr::write_volatile((self.base + reg) as *mut u64, value)
+    }
+
+    /// Returns the period of the main counter, in femtoseconds.
+    #[inline]
+    pub fn period_fs(&self) -> u32 {
+        (unsafe { self.read(CAPABILITIES) } >> 32) as u32
+    }
+
+    /// Start the main counter, if it isn't already running.
+    ///
+    /// # Safety
+    /// + Starting the counter may start any comparators which have been
+    ///   configured to fire interrupts.
+    pub unsafe fn enable(&self) {
+        let config = self.read(CONFIG);
+        self.write(CONFIG, config | ENABLE);
+    }
+
+    /// Returns true if the main counter is 64 bits wide.
+    #[inline]
+    pub fn is_64_bit(&self) -> bool { self.wide }
+
+    /// Returns the value of the main counter.
+    ///
+    /// N.B. that if the HPET's counter is only 32 bits wide, this wraps
+    /// every few minutes.
+    #[inline]
+    pub fn counter(&self) -> u64 {
+        unsafe { self.read(MAIN_COUNTER) & self.mask() }
+    }
+}
+
+impl Counter for Hpet {
+    #[inline] fn name(&self) -> &'static str { "HPET" }
+
+    // `Hpet::new` checked that the period isn't 0.
+    #[inline]
+    fn frequency(&self) -> u64 { FS_PER_SECOND / self.period_fs() as u64 }
+
+    #[inline] fn read(&self) -> u64 { self.counter() }
+
+    #[inline]
+    fn mask(&self) -> u64 {
+        if self.wide { u64::max_value() } else { u32::max_value() as u64 }
+    }
+}
@@ -0,0 +1,307 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2016 Eliza eisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Code for interacting with the system timer & timestamp register.
+//!
+//! The system tick is driven by the PIT (see [`pit`]), which fires IRQ 0 at
+//! the rate passed to [`init`]. Each tick, the IRQ 0 handler registered by
+//! [`init`] calls [`tick`], which advances the tick count and runs any timers
+//! which have expired.
+//!
+//! Timers are callbacks which run once at a [`Deadline`] (see [`one_shot`])
+//! or repeatedly (see [`periodic`]). They run in the timer interrupt
+//! handler, with interrupts disabled, so they should be short.
+//!
+//! The tick is too coarse to measure short intervals with; [`clock::now`]
+//! tells the time more precisely, if the CPU's timestamp counter allows.
+//!
+//! [`pit`]: pit/index.html
+//! [`clock::now`]: clock/fn.now.html
+//! [`init`]: fn.init.html
+//! [`tick`]: fn.tick.html
+//! [`Deadline`]: struct.Deadline.html
+//! [`one_shot`]: fn.one_shot.html
+//! [`periodic`]: fn.periodic.html
+#![warn(missing_docs)]
+use core::sync::atomic::{ AtomicU64, AtomicUsize, ATOMIC_U64_INIT
+                        , ATOMIC_USIZE_INIT, Ordering };
+
+use ::interrupts::{irq, IrqMutex};
+use self::queue::{Timer, TimerQueue};
+
+pub mod clock;
+pub mod hpet;
+pub mod pit;
+mod queue;
+
+pub use self::queue::MAX_TIMERS;
+
+/// The default rate of the system tick, in Hz.
+pub const DEFAULT_HZ: u32 = 1000;
+
+/// The number of ticks since [`init`] was called.
+///
+/// This is 64 bits wide even on 32-bit `x86`, where a `usize` would wrap
+/// after about 49 days at 1000 Hz, and break `Deadline` comparisons.
+///
+/// [`init`]: fn.init.html
+static TICKS: AtomicU64 = ATOMIC_U64_INIT;
+
+/// The length of a tick, in nanoseconds, or 0 if the tick hasn't started.
+static TICK_NS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The ID to give the next timer.
+static NEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Pending timers.
+///
+/// This is locked by the timer interrupt handler, so it disables interrupts
+/// while it is held.
+static TIMERS: IrqMutex<TimerQueue> = IrqMutex::new(TimerQueue::new());
+
+/// A function called when a timer expires.
+pub type Callback = fn();
+
+/// Identifies a timer, so that it can be cancelled.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TimerId(usize);
+
+/// Start the system tick at (approximately) `hz` ticks per second.
+///
+/// This registers the IRQ 0 handler, so the IRQ stubs must already be in the
+/// IDT.
+///
+/// # Returns
+/// + `Ok(u64)` with the actual length of a tick, in nanoseconds
+/// + `Err(&str)` if the IRQ 0 handler could not be registered.
+///
+/// # Panics
+/// + If `hz` is 0.
+pub fn init(hz: u32) -> Result<u64, &'static str> {
+    let divisor = pit::PIT.lock().start_periodic(hz);
+    let tick_ns = pit::period_ns(divisor);
+    TICK_NS.store(tick_ns as usize, Ordering::Release);
+    irq::register(0, "PIT", handle_irq)?;
+    Ok(tick_ns)
+}
+
+/// Handler for IRQ 0, which the PIT fires once per tick.
+fn handle_irq(_irq: u8) -> bool {
+    tick();
+    true
+}
+
+/// Returns the length of a tick, in nanoseconds.
+///
+/// # Panics
+/// + If the system tick hasn't been started with [`init`].
+///
+/// [`init`]: fn.init.html
+#[inline]
+pub fn tick_ns() -> u64 {
+    match TICK_NS.load(Ordering::Acquire) {
+        0 => panic!("the system tick has not been started")
+      , ns => ns as u64
+    }
+}
+
+/// Returns the number of ticks since the system tick was started.
+#[inline]
+pub fn ticks() -> u64 { TICKS.load(Ordering::Acquire) }
+
+/// Returns the time since the system tick was started, in nanoseconds.
+#[inline]
+pub fn uptime_ns() -> u64 { ticks() * tick_ns() }
+
+/// Returns the time since the system tick was started, in milliseconds.
+#[inline]
+pub fn uptime_ms() -> u64 { uptime_ns() / 1_000_000 }
+
+/// Returns the number of ticks in `ms` milliseconds, rounded up.
+#[inline]
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    let tick_ns = tick_ns();
+    (ms * 1_000_000 + tick_ns - 1) / tick_ns
+}
+
+/// A point in time, measured in system ticks.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Deadline { tick: u64 }
+
+impl Deadline {
+    /// Returns the deadline at the given tick.
+    #[inline]
+    pub const fn at_tick(tick: u64) -> Self { Deadline { tick: tick } }
+
+    /// Returns the deadline `ticks` ticks from now.
+    #[inline]
+    pub fn after_ticks(ticks: u64) -> Self {
+        Deadline { tick: self::ticks() + ticks }
+    }
+
+    /// Returns the deadline `ms` milliseconds from now.
+    #[inline]
+    pub fn after_ms(ms: u64) -> Self { Deadline::after_ticks(ms_to_ticks(ms)) }
+
+    /// Returns the tick at which this deadline passes.
+    #[inline] pub fn tick(&self) -> u64 { self.tick }
+
+    /// Returns true if this deadline has passed.
+    #[inline]
+    pub fn has_passed(&self) -> bool { ticks() >= self.tick }
+
+    /// Returns the number of ticks until this deadline passes, or 0 if it
+    /// already has.
+    #[inline]
+    pub fn remaining_ticks(&self) -> u64 {
+        self.tick.saturating_sub(ticks())
+    }
+}
+
+/// Wait until `deadline` has passed.
+///
+/// The CPU is halted between ticks.
+///
+/// # Panics
+/// + If interrupts are disabled, since the deadline would never pass.
+pub fn sleep_until(deadline: Deadline) {
+    assert!( ::flags::read().contains(::flags::IF)
+           , "cannot sleep with interrupts disabled");
+    while !deadline.has_passed() {
+        unsafe { asm!("hlt" :::: "volatile") }
+    }
+}
+
+/// Wait for `ms` milliseconds.
+///
+/// # Panics
+/// + If interrupts are disabled, since the deadline would never pass.
+#[inline]
+pub fn sleep_ms(ms: u64) { sleep_until(Deadline::after_ms(ms)) }
+
+/// Add a timer to the timer queue.
+fn add_timer(deadline: u64, period: u64, callback: Callback)
+            -> Result<TimerId, &'static str> {
+    let id = TimerId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let timer = Timer { deadline: deadline
+                      , period: period
+                      , id: id
+                      , callback: callback
+                      };
+    TIMERS.lock().push(timer)
+        .map(|_| id)
+        .map_err(|_| "too many pending timers")
+}
+
+/// Call `callback` once, when `deadline` passes.
+///
+/// # Returns
+/// + `Ok(TimerId)` if the timer was added
+/// + `Err(&str)` if there are already `MAX_TIMERS` pending timers.
+pub fn one_shot(deadline: Deadline, callback: Callback)
+               -> Result<TimerId, &'static str> {
+    add_timer(deadline.tick, 0, callback)
+}
+
+/// Call `callback` every `period_ms` milliseconds (rounded up to a whole
+/// number of ticks, and at least one tick), starting `period_ms` from now.
+///
+/// # Returns
+/// + `Ok(TimerId)` if the timer was added
+/// + `Err(&str)` if there are already `MAX_TIMERS` pending timers.
+pub fn periodic(period_ms: u64, callback: Callback)
+               -> Result<TimerId, &'static str> {
+    let period = ms_to_ticks(period_ms).max(1);
+    add_timer(ticks() + period, period, callback)
+}
+
+/// Cancel the timer `id`.
+///
+/// # Returns
+/// + `true` if the timer was pending, and has been cancelled
+/// + `false` if it had already expired, or been cancelled.
+pub fn cancel(id: TimerId) -> bool {
+    TIMERS.lock().remove(id).is_some()
+}
+
+/// Advance the system tick, and run any timers which have expired.
+///
+/// This is called by the IRQ 0 handler, with interrupts disabled.
+pub fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::AcqRel) + 1;
+    // the lock is released before each callback, so that callbacks may add
+    // or cancel timers.
+    loop {
+        let expired = TIMERS.lock().pop_expired(now);
+        match expired {
+            Some(timer) => (timer.callback)()
+          , None => break
+        }
+    }
+}
+
+pub mod timestamp {
+    //! x86 Timestamp register
+    use core::mem;
+
+
+    /// Read the current value of the timestamp counter.
+    ///
+    /// # Safety
+    /// + This will cause a General Protection Fault if the TSD flag in register
+    ///   `%cr4` is set and the CPL is greater than 0.
+    pub unsafe fn rtdsc() -> u64 {
+        let (high, low): (u32, u32);
+        asm!( "rdtsc"
+            : "={eax}" (low), "={edx}" (high));
+        mem::transmute((high, low))
+    }
+
+    /// Read the current timestamp, after other instructions have been executed.
+    ///
+    /// # Safety
+    /// + This will cause a General Protection Fault if the TSD flag in register
+    ///   `%cr4` is set and the CPL is greater than 0.
+    pub unsafe fn rtdscp() -> u64 {
+        let (high, low): (u32, u32);
+        asm!( "rdtscp"
+            : "={eax}" (low), "={edx}" (high)
+            ::: "volatile");
+        mem::transmute((high, low))
+    }
+
+    /// Returns true if timestamps are currently available.
+    #[inline]
+    pub fn is_available() -> Result<(), &'static str> {
+        use ::control_regs::cr4;
+        use ::PrivilegeLevel;
+
+        if !::cpuid::has_tsc() {
+            Err("the CPU has no timestamp counter")
+        } else if PrivilegeLevel::current_iopl() != PrivilegeLevel::KernelMode {
+            Err("Reading timestamp register requires kernel mode.")
+        } else if
+            // it's safe to do this since we already know we are in kernel mode.
+            unsafe { cr4::is_timestamp_disabled() } {
+            Err("Timestamp Disable bit in %cr4 is set")
+        } else { Ok(()) }
+    }
+
+    /// Returns the current timestamp, or an error
+    #[inline]
+    pub fn get_timestamp() -> Result<u64, &'static str> {
+        is_available().map(|_| unsafe { rtdsc() })
+    }
+
+    /// Returns the current timestamp or an error, after other instructions have
+    /// been executed.
+    #[inline]
+    pub fn wait_get_timestamp() -> Result<u64, &'static str> {
+        is_available().map(|_| unsafe { rtdscp() })
+    }
+}
@@ -0,0 +1,105 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Support for the 8254 Programmable Interval Timer (PIT).
+//!
+//! The PIT's channel 0 is connected to ISA IRQ 0. We run it as a rate
+//! generator (mode 2), so that it fires IRQ 0 at a fixed rate, which is its
+//! input clock (about 1.193182 MHz) divided by a 16-bit divisor.
+use Port;
+use spin::Mutex;
+
+/// The frequency of the PIT's input clock, in Hz.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+/// Data port for channel 0.
+const CHANNEL0_PORT: u16 = 0x40;
+/// Mode/command port.
+const COMMAND_PORT: u16 = 0x43;
+
+// Fields of the mode/command register.
+const SELECT_CHANNEL0: u8 = 0b00 << 6;
+const ACCESS_LATCH: u8 = 0b00 << 4;
+const ACCESS_LO_HI: u8 = 0b11 << 4;
+const MODE_RATE_GENERATOR: u8 = 0b010 << 1;
+
+/// The smallest divisor that may be used in rate generator mode.
+const MIN_DIVISOR: u32 = 2;
+/// The largest divisor that may be used.
+const MAX_DIVISOR: u32 = 0xffff;
+
+/// The PIT.
+pub static PIT: Mutex<Pit>
+    = Mutex::new(Pit { channel0: Port::<u8>::new(CHANNEL0_PORT)
+                     , command: Port::<u8>::new(COMMAND_PORT)
+                     , divisor: 0
+                     });
+
+/// The 8254 PIT.
+pub struct Pit { channel0: Port<u8>
+               , command: Port<u8>
+               , /// The divisor channel 0 was last programmed with, or 0
+                 /// if it hasn't been.
+                 divisor: u16
+               }
+
+/// Returns the divisor which comes closest to firing at `hz`.
+///
+/// # Panics
+/// + If `hz` is 0.
+#[inline]
+pub fn divisor_for(hz: u32) -> u16 {
+    assert!(hz != 0, "the PIT cannot run at 0 Hz");
+    let divisor = (BASE_FREQUENCY + hz / 2) / hz;
+    if divisor < MIN_DIVISOR { MIN_DIVISOR as u16 }
+    else if divisor > MAX_DIVISOR { MAX_DIVISOR as u16 }
+    else { divisor as u16 }
+}
+
+/// Returns the length of a period of the PIT with `divisor`, in nanoseconds.
+#[inline]
+pub fn period_ns(divisor: u16) -> u64 {
+    divisor as u64 * 1_000_000_000 / BASE_FREQUENCY as u64
+}
+
+impl Pit {
+    /// Program channel 0 to fire IRQ 0 periodically, as close to `hz` times
+    /// per second as it can.
+    ///
+    /// # Returns
+    /// + The divisor that channel 0 was programmed with.
+    ///
+    /// # Panics
+    /// + If `hz` is 0.
+    pub fn start_periodic(&mut self, hz: u32) -> u16 {
+        let divisor = divisor_for(hz);
+        self.command.write(SELECT_CHANNEL0 | ACCESS_LO_HI | MODE_RATE_GENERATOR);
+        self.channel0.write(divisor as u8);
+        self.channel0.write((divisor >> 8) as u8);
+        self.divisor = divisor;
+        divisor
+    }
+
+    /// Returns the divisor channel 0 was last programmed with, if it has
+    /// been.
+    #[inline]
+    pub fn divisor(&self) -> Option<u16> {
+        match self.divisor {
+            0 => None
+          , divisor => Some(divisor)
+        }
+    }
+
+    /// Returns channel 0's current count.
+    pub fn count(&mut self) -> u16 {
+        self.command.write(SELECT_CHANNEL0 | ACCESS_LATCH);
+        let low = self.channel0.read() as u16;
+        let high = self.channel0.read() as u16;
+        high << 8 | low
+    }
+}
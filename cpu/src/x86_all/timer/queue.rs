@@ -0,0 +1,215 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The timer queue.
+//!
+//! Pending timers are kept in a fixed-capacity binary min-heap, ordered by
+//! deadline, so that checking for expired timers on every tick only has to
+//! look at the root of the heap. Timers with the same deadline expire in
+//! the order they were added.
+use super::{Callback, TimerId};
+
+/// The maximum number of pending timers.
+pub const MAX_TIMERS: usize = 64;
+
+/// A pending timer.
+#[derive(Copy, Clone, Debug)]
+pub struct Timer { /// The tick at which this timer expires.
+                   pub deadline: u64
+                 , /// The number of ticks between expiries, or 0 if this is
+                   /// a one-shot timer.
+                   pub period: u64
+                 , pub id: TimerId
+                 , pub callback: Callback
+                 }
+
+impl Timer {
+    /// Returns the key this timer is ordered by in the heap.
+    #[inline]
+    fn key(&self) -> (u64, usize) { (self.deadline, self.id.0) }
+}
+
+/// A queue of pending timers.
+pub struct TimerQueue { heap: [Option<Timer>; MAX_TIMERS]
+                      , len: usize
+                      }
+
+impl TimerQueue {
+    /// Returns a new, empty `TimerQueue`.
+    pub const fn new() -> Self {
+        TimerQueue { heap: [None; MAX_TIMERS], len: 0 }
+    }
+
+    /// Returns the number of pending timers.
+    #[inline] pub fn len(&self) -> usize { self.len }
+
+    /// Returns the earliest deadline of any pending timer.
+    #[inline]
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.heap[0].map(|timer| timer.deadline)
+    }
+
+    #[inline]
+    fn key(&self, i: usize) -> (u64, usize) {
+        self.heap[i].expect("timer heap slot should not be empty").key()
+    }
+
+    /// Add a timer to the queue.
+    ///
+    /// # Returns
+    /// + `Ok(())` if the timer was added
+    /// + `Err(Timer)` with the timer, if the queue is full.
+    pub fn push(&mut self, timer: Timer) -> Result<(), Timer> {
+        if self.len == MAX_TIMERS {
+            return Err(timer)
+        }
+        self.heap[self.len] = Some(timer);
+        self.len += 1;
+        let last = self.len - 1;
+        self.sift_up(last);
+        Ok(())
+    }
+
+    /// Remove the earliest timer, if it expires at or before `now`.
+    ///
+    /// If the timer is periodic, its next expiry is added back to the queue
+    /// before it is returned, so that it can be cancelled by its own
+    /// callback.
+    pub fn pop_expired(&mut self, now: u64) -> Option<Timer> {
+        match self.next_deadline() {
+            Some(deadline) if deadline <= now => {
+                let timer = self.remove_at(0);
+                if timer.period != 0 {
+                    let next = Timer { deadline: timer.deadline + timer.period
+                                     , ..timer };
+                    // we just removed a timer, so there is room for this.
+                    let _ = self.push(next);
+                }
+                Some(timer)
+            }
+          , _ => None
+        }
+    }
+
+    /// Remove the timer with the ID `id`.
+    ///
+    /// # Returns
+    /// + `Some(Timer)` if the timer was pending
+    /// + `None` if it was not.
+    pub fn remove(&mut self, id: TimerId) -> Option<Timer> {
+        (0 .. self.len).find(|&i| self.heap[i].map(|t| t.id) == Some(id))
+                       .map(|i| self.remove_at(i))
+    }
+
+    /// Remove the timer at index `i` in the heap.
+    fn remove_at(&mut self, i: usize) -> Timer {
+        let last = self.len - 1;
+        self.heap.swap(i, last);
+        let timer = self.heap[last].take()
+                        .expect("timer heap slot should not be empty");
+        self.len -= 1;
+        if i < self.len {
+            self.sift_down(i);
+            self.sift_up(i);
+        }
+        timer
+    }
+
+    fn sift_up(&mut self, mut i: usize) {
+        while i > 0 {
+            let parent = (i - 1) / 2;
+            if self.key(i) >= self.key(parent) { break }
+            self.heap.swap(i, parent);
+            i = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut i: usize) {
+        loop {
+            let (left, right) = (2 * i + 1, 2 * i + 2);
+            let mut least = i;
+            if left < self.len && self.key(left) < self.key(least) {
+                least = left;
+            }
+            if right < self.len && self.key(right) < self.key(least) {
+                least = right;
+            }
+            if least == i { break }
+            self.heap.swap(i, least);
+            i = least;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::TimerId;
+
+    fn nop() { }
+
+    fn timer(deadline: u64, period: u64, id: usize) -> Timer {
+        Timer { deadline: deadline, period: period
+              , id: TimerId(id), callback: nop }
+    }
+
+    #[test]
+    fn test_pop_in_deadline_order() {
+        let mut queue = TimerQueue::new();
+        for (id, &deadline) in [5, 1, 4, 2, 3].iter().enumerate() {
+            queue.push(timer(deadline, 0, id)).unwrap();
+        }
+        for deadline in 1 .. 6 {
+            assert_eq!(queue.pop_expired(10).unwrap().deadline, deadline);
+        }
+        assert!(queue.pop_expired(10).is_none());
+    }
+
+    #[test]
+    fn test_pop_only_expired() {
+        let mut queue = TimerQueue::new();
+        queue.push(timer(10, 0, 0)).unwrap();
+        assert!(queue.pop_expired(9).is_none());
+        assert_eq!(queue.pop_expired(10).unwrap().id, TimerId(0));
+    }
+
+    #[test]
+    fn test_periodic_is_requeued() {
+        let mut queue = TimerQueue::new();
+        queue.push(timer(3, 3, 0)).unwrap();
+        assert_eq!(queue.pop_expired(3).unwrap().deadline, 3);
+        assert_eq!(queue.next_deadline(), Some(6));
+        assert!(queue.remove(TimerId(0)).is_some());
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn test_remove_keeps_order() {
+        let mut queue = TimerQueue::new();
+        for id in 0 .. 8 {
+            queue.push(timer(8 - id as u64, 0, id)).unwrap();
+        }
+        assert!(queue.remove(TimerId(3)).is_some());
+        assert!(queue.remove(TimerId(3)).is_none());
+        let mut last = 0;
+        while let Some(t) = queue.pop_expired(100) {
+            assert!(t.deadline > last);
+            assert!(t.id != TimerId(3));
+            last = t.deadline;
+        }
+    }
+
+    #[test]
+    fn test_full_queue() {
+        let mut queue = TimerQueue::new();
+        for id in 0 .. MAX_TIMERS {
+            queue.push(timer(1, 0, id)).unwrap();
+        }
+        assert!(queue.push(timer(1, 0, MAX_TIMERS)).is_err());
+    }
+}
@@ -0,0 +1,567 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Decoding DWARF line number information.
+//!
+//! The `.debug_line` section maps instruction addresses to source files and
+//! line numbers. It contains a line number program for each compilation
+//! unit; running a program through the DWARF line state machine produces a
+//! table of [`Row`]s, each of which gives the source location of the
+//! instructions from its address up to the next row's.
+//!
+//! Nothing here allocates: programs are decoded as they are iterated over,
+//! and file names are looked up in the program header when they are needed.
+//! This makes each lookup a linear scan of `.debug_line`, which is fine for
+//! crash reports.
+//!
+//! Versions 2, 3 and 4 of the line number program format are supported.
+//!
+//! For more information, refer to section 6.2 of the [DWARF 4 standard].
+//!
+//! [`Row`]: struct.Row.html
+//! [DWARF 4 standard]: http://www.dwarfstd.org/doc/DWARF4.pdf
+use super::ElfResult;
+
+use core::str;
+
+// Standard opcodes.
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_SET_COLUMN: u8 = 5;
+const DW_LNS_NEGATE_STMT: u8 = 6;
+const DW_LNS_SET_BASIC_BLOCK: u8 = 7;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+// Extended opcodes.
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+
+/// Reads DWARF encoded values from a byte slice.
+#[derive(Clone, Debug)]
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    #[inline] fn is_empty(&self) -> bool { self.0.is_empty() }
+
+    fn bytes(&mut self, n: usize) -> ElfResult<&'a [u8]> {
+        if n > self.0.len() {
+            return Err("unexpected end of DWARF data")
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    /// Read an `n`-byte little-endian unsigned integer.
+    fn uint(&mut self, n: usize) -> ElfResult<u64> {
+        Ok(self.bytes(n)?
+               .iter().rev()
+               .fold(0, |value, &byte| (value << 8) | byte as u64))
+    }
+
+    #[inline] fn u8(&mut self) -> ElfResult<u8> { Ok(self.bytes(1)?[0]) }
+    #[inline] fn u16(&mut self) -> ElfResult<u16> {
+        self.uint(2).map(|x| x as u16)
+    }
+    #[inline] fn u32(&mut self) -> ElfResult<u32> {
+        self.uint(4).map(|x| x as u32)
+    }
+    #[inline] fn u64(&mut self) -> ElfResult<u64> { self.uint(8) }
+
+    /// Read an unsigned LEB128 number.
+    fn uleb128(&mut self) -> ElfResult<u64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value)
+            }
+        }
+    }
+
+    /// Read a signed LEB128 number.
+    fn sleb128(&mut self) -> ElfResult<i64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    // sign extend
+                    value |= -1 << shift;
+                }
+                return Ok(value)
+            }
+        }
+    }
+
+    /// Read a null-terminated string.
+    fn cstr(&mut self) -> ElfResult<&'a str> {
+        let len = self.0.iter().position(|&b| b == 0)
+                      .ok_or("unterminated string in DWARF data")?;
+        let bytes = self.bytes(len + 1)?;
+        str::from_utf8(&bytes[..len])
+            .map_err(|_| "DWARF string is not valid UTF-8")
+    }
+
+    /// Read an initial length field, returning the length and whether this
+    /// is 64-bit DWARF.
+    fn initial_length(&mut self) -> ElfResult<(usize, bool)> {
+        match self.u32()? {
+            0xffff_ffff => Ok((self.u64()? as usize, true))
+          , len if len >= 0xffff_fff0 => Err("reserved DWARF unit length")
+          , len => Ok((len as usize, false))
+        }
+    }
+}
+
+/// The contents of a `.debug_line` section.
+#[derive(Clone, Debug)]
+pub struct DebugLine<'a>(&'a [u8]);
+
+impl<'a> From<&'a [u8]> for DebugLine<'a> {
+    #[inline] fn from(data: &'a [u8]) -> Self { DebugLine(data) }
+}
+
+impl<'a> DebugLine<'a> {
+    /// Returns an iterator over the line number programs in this section.
+    #[inline] pub fn programs(&self) -> Programs<'a> {
+        Programs(Reader(self.0))
+    }
+
+    /// Find the source location of the instruction at `addr`.
+    ///
+    /// Programs which can't be decoded are skipped.
+    pub fn find(&self, addr: u64) -> Option<Location<'a>> {
+        self.programs()
+            .filter_map(|program| program.ok())
+            .filter_map(|program| {
+                program.find(addr).and_then(|row| program.location(&row))
+            })
+            .next()
+    }
+}
+
+/// Iterator over the line number programs in a `.debug_line` section.
+#[derive(Clone, Debug)]
+pub struct Programs<'a>(Reader<'a>);
+
+impl<'a> Iterator for Programs<'a> {
+    type Item = ElfResult<LineProgram<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() {
+            return None
+        }
+        let unit = self.0.initial_length()
+                         .and_then(|(len, is_64)| {
+                             self.0.bytes(len).map(|unit| (unit, is_64))
+                         });
+        match unit {
+            Ok((unit, is_64)) => Some(LineProgram::parse(unit, is_64))
+          , Err(why) => {
+                // we can't find the next unit without this one's length.
+                self.0 = Reader(&[]);
+                Some(Err(why))
+            }
+        }
+    }
+}
+
+/// A line number program for one compilation unit.
+#[derive(Clone, Debug)]
+pub struct LineProgram<'a> { /// The version of the line program format
+                             pub version: u16
+                           , min_instruction_length: u8
+                           , default_is_stmt: bool
+                           , line_base: i8
+                           , line_range: u8
+                           , opcode_base: u8
+                           , /// The number of arguments each standard
+                             /// opcode takes
+                             standard_opcode_lengths: &'a [u8]
+                           , /// The raw include directories table
+                             include_directories: &'a [u8]
+                           , /// The raw file names table
+                             file_names: &'a [u8]
+                           , /// The line number program itself
+                             program: &'a [u8]
+                           }
+
+impl<'a> LineProgram<'a> {
+    /// Parse the line number program in `unit`, which follows the unit's
+    /// initial length field.
+    fn parse(unit: &'a [u8], is_64: bool) -> ElfResult<Self> {
+        let mut unit = Reader(unit);
+
+        let version = unit.u16()?;
+        if version < 2 || version > 4 {
+            return Err("unsupported DWARF line program version")
+        }
+        let header_length = if is_64 { unit.u64()? as usize }
+                            else { unit.u32()? as usize };
+        let mut header = Reader(unit.bytes(header_length)?);
+        let program = unit.0;
+
+        let min_instruction_length = header.u8()?;
+        if version >= 4 {
+            // maximum operations per instruction, which only matters for
+            // VLIW architectures.
+            header.u8()?;
+        }
+        let default_is_stmt = header.u8()? != 0;
+        let line_base = header.u8()? as i8;
+        let line_range = header.u8()?;
+        if line_range == 0 {
+            return Err("DWARF line program has a line range of zero")
+        }
+        let opcode_base = header.u8()?;
+        let standard_opcode_lengths
+            = header.bytes((opcode_base as usize).saturating_sub(1))?;
+
+        // find the ends of the two tables, so that they can be walked later.
+        let dirs_start = header.clone();
+        while header.cstr()? != "" { }
+        let dirs_len = dirs_start.0.len() - header.0.len();
+        let files_start = header.clone();
+        loop {
+            if header.cstr()? == "" { break }
+            header.uleb128()?; // directory index
+            header.uleb128()?; // modification time
+            header.uleb128()?; // length
+        }
+        let files_len = files_start.0.len() - header.0.len();
+
+        Ok(LineProgram { version: version
+                       , min_instruction_length: min_instruction_length
+                       , default_is_stmt: default_is_stmt
+                       , line_base: line_base
+                       , line_range: line_range
+                       , opcode_base: opcode_base
+                       , standard_opcode_lengths: standard_opcode_lengths
+                       , include_directories: &dirs_start.0[..dirs_len]
+                       , file_names: &files_start.0[..files_len]
+                       , program: program
+                       })
+    }
+
+    /// Returns an iterator over the rows of this program's line table.
+    #[inline] pub fn rows(&self) -> Rows<'a> {
+        Rows { program: self.clone()
+             , reader: Reader(self.program)
+             , state: Row::new(self.default_is_stmt)
+             }
+    }
+
+    /// Find the row describing the instruction at `addr`.
+    pub fn find(&self, addr: u64) -> Option<Row> {
+        let mut prev: Option<Row> = None;
+        for row in self.rows() {
+            if let Some(prev) = prev {
+                if !prev.end_sequence
+                    && prev.address <= addr && addr < row.address {
+                    return Some(prev)
+                }
+            }
+            prev = Some(row);
+        }
+        None
+    }
+
+    /// Returns the include directory with the 1-based index `index`.
+    fn directory(&self, index: u64) -> Option<&'a str> {
+        let mut reader = Reader(self.include_directories);
+        let mut current = 1;
+        loop {
+            match reader.cstr() {
+                Ok("") | Err(_) => return None
+              , Ok(dir) if current == index => return Some(dir)
+              , Ok(_) => current += 1
+            }
+        }
+    }
+
+    /// Returns the name and directory index of the file with the 1-based
+    /// index `index`.
+    fn file(&self, index: u64) -> Option<(&'a str, u64)> {
+        let mut reader = Reader(self.file_names);
+        let mut current = 1;
+        loop {
+            let name = match reader.cstr() {
+                Ok("") | Err(_) => return None
+              , Ok(name) => name
+            };
+            let dir = reader.uleb128().ok()?;
+            reader.uleb128().ok()?;
+            reader.uleb128().ok()?;
+            if current == index {
+                return Some((name, dir))
+            }
+            current += 1;
+        }
+    }
+
+    /// Returns the source location `row` refers to.
+    ///
+    /// # Returns
+    /// + `Some(Location)` if the row's file is in the program header
+    /// + `None` if it isn't (e.g. if it was added by `DW_LNE_define_file`).
+    pub fn location(&self, row: &Row) -> Option<Location<'a>> {
+        self.file(row.file).map(|(file, dir)| {
+            Location { directory: if dir == 0 { None }
+                                  else { self.directory(dir) }
+                     , file: file
+                     , line: row.line
+                     , column: row.column
+                     }
+        })
+    }
+}
+
+/// A row in a line table.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Row { /// The address of the first instruction this row describes
+                 pub address: u64
+               , /// The index of the source file in the program header
+                 pub file: u64
+               , /// The source line number, starting at 1
+                 pub line: u64
+               , /// The source column number, or 0 for the whole line
+                 pub column: u64
+               , /// Whether this is a recommended breakpoint location
+                 pub is_stmt: bool
+               , /// Whether this row marks the first address past the end
+                 /// of a sequence of instructions
+                 pub end_sequence: bool
+               }
+
+impl Row {
+    fn new(is_stmt: bool) -> Self {
+        Row { address: 0
+            , file: 1
+            , line: 1
+            , column: 0
+            , is_stmt: is_stmt
+            , end_sequence: false
+            }
+    }
+}
+
+/// Iterator over the rows of a line table.
+///
+/// Iteration stops early if the program is malformed.
+#[derive(Clone, Debug)]
+pub struct Rows<'a> { program: LineProgram<'a>
+                    , reader: Reader<'a>
+                    , /// The line state machine's registers
+                      state: Row
+                    }
+
+impl<'a> Rows<'a> {
+    /// Advance the address by `operations` times the minimum instruction
+    /// length.
+    #[inline] fn advance(&mut self, operations: u64) {
+        self.state.address = self.state.address.wrapping_add(
+            operations * self.program.min_instruction_length as u64);
+    }
+
+    /// Emit the current row, and reset the registers which only apply to
+    /// one row.
+    fn emit(&mut self) -> Row {
+        let row = self.state;
+        if row.end_sequence {
+            self.state = Row::new(self.program.default_is_stmt);
+        }
+        row
+    }
+
+    /// Run the program until it emits a row.
+    fn step(&mut self) -> ElfResult<Option<Row>> {
+        while !self.reader.is_empty() {
+            let opcode = self.reader.u8()?;
+            let opcode_base = self.program.opcode_base;
+            let line_range = self.program.line_range;
+            match opcode {
+                op if op >= opcode_base => {
+                    let adjusted = op - opcode_base;
+                    self.advance((adjusted / line_range) as u64);
+                    let line_advance = self.program.line_base as i64
+                                     + (adjusted % line_range) as i64;
+                    self.state.line = (self.state.line as i64
+                                      + line_advance) as u64;
+                    return Ok(Some(self.emit()))
+                }
+              , 0 => {
+                    let len = self.reader.uleb128()? as usize;
+                    let mut args = Reader(self.reader.bytes(len)?);
+                    match args.u8()? {
+                        DW_LNE_END_SEQUENCE => {
+                            self.state.end_sequence = true;
+                            return Ok(Some(self.emit()))
+                        }
+                      , DW_LNE_SET_ADDRESS => {
+                            let size = args.0.len();
+                            self.state.address = args.uint(size)?;
+                        }
+                        // anything else (such as `DW_LNE_define_file`
+                        // or `DW_LNE_set_discriminator`) doesn't affect
+                        // the rows.
+                      , _ => {}
+                    }
+                }
+              , DW_LNS_COPY => return Ok(Some(self.emit()))
+              , DW_LNS_ADVANCE_PC => {
+                    let operations = self.reader.uleb128()?;
+                    self.advance(operations);
+                }
+              , DW_LNS_ADVANCE_LINE => {
+                    let delta = self.reader.sleb128()?;
+                    self.state.line = (self.state.line as i64 + delta) as u64;
+                }
+              , DW_LNS_SET_FILE => self.state.file = self.reader.uleb128()?
+              , DW_LNS_SET_COLUMN => {
+                    self.state.column = self.reader.uleb128()?
+                }
+              , DW_LNS_NEGATE_STMT => {
+                    self.state.is_stmt = !self.state.is_stmt
+                }
+              , DW_LNS_SET_BASIC_BLOCK => {}
+              , DW_LNS_CONST_ADD_PC => {
+                    self.advance(((255 - opcode_base) / line_range) as u64);
+                }
+              , DW_LNS_FIXED_ADVANCE_PC => {
+                    let delta = self.reader.u16()?;
+                    self.state.address = self.state.address
+                                             .wrapping_add(delta as u64);
+                }
+              , op => {
+                    // skip the arguments of any other standard opcode.
+                    let n_args = self.program.standard_opcode_lengths
+                                     .get(op as usize - 1)
+                                     .cloned()
+                                     .unwrap_or(0);
+                    for _ in 0..n_args {
+                        self.reader.uleb128()?;
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a> Iterator for Rows<'a> {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Row> {
+        match self.step() {
+            Ok(row) => row
+          , Err(_) => {
+                self.reader = Reader(&[]);
+                None
+            }
+        }
+    }
+}
+
+/// A source location.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Location<'a> { /// The directory containing `file`, if it is
+                          /// not the compilation directory
+                          pub directory: Option<&'a str>
+                        , /// The source file's name
+                          pub file: &'a str
+                        , /// The line number, starting at 1
+                          pub line: u64
+                        , /// The column number, or 0 for the whole line
+                          pub column: u64
+                        }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A version 2 line program for `src/foo.rs`, with these rows:
+    ///
+    /// | address | line |
+    /// |---------|------|
+    /// | 0x1000  | 1    |
+    /// | 0x1004  | 3    |
+    /// | 0x1010  | 10   |
+    /// | 0x1020  | end  |
+    const PROGRAM: &'static [u8] = &[
+        // unit length
+        62, 0, 0, 0
+        // version
+      , 2, 0
+        // header length
+      , 33, 0, 0, 0
+        // min instruction length, default is_stmt, line base, line range,
+        // opcode base
+      , 1, 1, 0xfb, 14, 13
+        // standard opcode lengths
+      , 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1
+        // include directories: "src"
+      , b's', b'r', b'c', 0, 0
+        // file names: "foo.rs", in directory 1
+      , b'f', b'o', b'o', b'.', b'r', b's', 0, 1, 0, 0, 0
+        // DW_LNE_set_address 0x1000
+      , 0, 9, DW_LNE_SET_ADDRESS, 0x00, 0x10, 0, 0, 0, 0, 0, 0
+        // DW_LNS_copy
+      , DW_LNS_COPY
+        // special opcode: address += 4, line += 2
+      , 13 + (4 * 14) + (2 + 5)
+        // DW_LNS_advance_pc 12; DW_LNS_advance_line 7; DW_LNS_copy
+      , DW_LNS_ADVANCE_PC, 12, DW_LNS_ADVANCE_LINE, 7, DW_LNS_COPY
+        // DW_LNS_advance_pc 16; DW_LNE_end_sequence
+      , DW_LNS_ADVANCE_PC, 16, 0, 1, DW_LNE_END_SEQUENCE
+    ];
+
+    #[test]
+    fn test_leb128() {
+        let mut reader = Reader(&[0xe5, 0x8e, 0x26, 0x7f, 0x80, 0x7f]);
+        assert_eq!(reader.uleb128(), Ok(624485));
+        assert_eq!(reader.sleb128(), Ok(-1));
+        assert_eq!(reader.sleb128(), Ok(-128));
+    }
+
+    #[test]
+    fn test_rows() {
+        let program = DebugLine::from(PROGRAM).programs().next()
+                                              .unwrap().unwrap();
+        let rows = program.rows()
+                          .map(|row| (row.address, row.line, row.end_sequence));
+        let expected = [ (0x1000, 1, false), (0x1004, 3, false)
+                       , (0x1010, 10, false), (0x1020, 10, true) ];
+        assert!(rows.eq(expected.iter().cloned()));
+    }
+
+    #[test]
+    fn test_find() {
+        let debug_line = DebugLine::from(PROGRAM);
+        let location = debug_line.find(0x1008).unwrap();
+        assert_eq!(location.directory, Some("src"));
+        assert_eq!(location.file, "foo.rs");
+        assert_eq!(location.line, 3);
+        assert_eq!(debug_line.find(0x1010).map(|l| l.line), Some(10));
+        assert_eq!(debug_line.find(0xfff), None);
+        assert_eq!(debug_line.find(0x1020), None);
+    }
+}
@@ -77,9 +77,11 @@ macro_rules! impl_getters {
     () => {};
 }
 
+pub mod dwarf;
 pub mod section;
 pub mod file;
 pub mod program;
+pub mod symbol;
 
 /// An ELF section header.
 pub type Section<W> = section::Header<Word = W>;
@@ -154,6 +156,54 @@ where Word: ElfWord + 'a
         section::StrTable::from(&self.binary[self.header.sh_str_idx()..])
     }
 
+    /// Returns the binary's symbol table (its `.symtab` section).
+    ///
+    /// `S` must be the [symbol] type for the binary's word size.
+    ///
+    /// [symbol]: symbol/trait.Symbol.html
+    pub fn symbol_table<S>(&'a self) -> ElfResult<symbol::SymbolTable<'a, S>>
+    where S: symbol::Symbol<Word = Word> {
+        self.symbols(section::Type::SymbolTable)
+    }
+
+    /// Returns the binary's dynamic linking symbol table (its `.dynsym`
+    /// section).
+    ///
+    /// `S` must be the [symbol] type for the binary's word size.
+    ///
+    /// [symbol]: symbol/trait.Symbol.html
+    pub fn dynamic_symbol_table<S>(&'a self)
+                                  -> ElfResult<symbol::SymbolTable<'a, S>>
+    where S: symbol::Symbol<Word = Word> {
+        self.symbols(section::Type::DynSymTable)
+    }
+
+    /// Returns the first symbol table section of type `ty`, along with the
+    /// string table it links to.
+    fn symbols<S>(&'a self, ty: section::Type)
+                 -> ElfResult<symbol::SymbolTable<'a, S>>
+    where S: symbol::Symbol<Word = Word> {
+        use section::Header;
+        let symtab = self.sections.iter()
+                         .find(|s| s.get_type().map(|t| t == ty)
+                                               .unwrap_or(false))
+                         .ok_or("no symbol table section")?;
+        let strtab = self.sections.get(symtab.link() as usize)
+                         .ok_or("symbol table links to a missing section")?;
+        unsafe {
+            symbol::SymbolTable::new( self.section_data(symtab)?
+                                    , self.section_data(strtab)? )
+        }
+    }
+
+    /// Returns the contents of `section`.
+    fn section_data(&'a self, section: &SectHeader) -> ElfResult<&'a [u8]> {
+        use section::Header;
+        let start = section.offset();
+        self.binary.get(start .. start + section.length())
+            .ok_or("section extends past the end of the binary")
+    }
+
 }
 
 impl<'a, Word, PH, SH, FH> TryFrom<&'a [u8]> for Image<'a, Word, PH, SH, FH>
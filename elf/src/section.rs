@@ -84,8 +84,10 @@ pub trait Header: fmt::Debug {
     }
 
     /// Look up the name of this section in the passed string table.
+    ///
+    /// If the name can't be found, this returns an empty string.
     #[inline] fn get_name<'a>(&self, strtab: StrTable<'a>) -> &'a str {
-        unimplemented!()
+        strtab.at_index(self.name_offset() as usize).unwrap_or("")
     }
 
     // Field accessors -------------------------------------------------
@@ -386,6 +388,26 @@ where W: ElfWord {
 
 }
 
+impl<'a, W: 'a> Sections<'a, W>
+where W: ElfWord
+    , HeaderRepr<W>: Header<Word = W> {
+
+    /// Returns the section header `idx` headers after the next one this
+    /// iterator will examine, including null sections.
+    ///
+    /// For an iterator which has not yet been advanced, this is the section
+    /// with index `idx` in the section header table (as in the `link` field
+    /// of a section header).
+    pub fn get(&self, idx: u32) -> Option<&'a Header<Word = W>> {
+        if idx < self.remaining {
+            Some(unsafe { &*(self.curr as *const HeaderRepr<W>)
+                                .offset(idx as isize) })
+        } else {
+            None
+        }
+    }
+}
+
 
 impl<'a, W> Iterator for Sections<'a, W>
 where W: ElfWord
@@ -447,10 +469,10 @@ impl<'a> StrTable<'a> {
     // TODO: can this be replaced with an ops::Index implementation?
     //       but then we can't implement Deref to a slice any more?
     //          - eliza, 03/07/2017
-    pub fn at_index(&'a self, i: usize) -> Option<&'a str> {
+    pub fn at_index(&self, i: usize) -> Option<&'a str> {
         use core::str::from_utf8_unchecked;
         if i <= self.len() {
-            read_to_null(&self[i..])
+            read_to_null(&self.0[i..])
                 .map(|bytes| unsafe {
                     // TODO: should this be checked, or do we assume the ELF
                     //       binary has only well-formed strings? this could be
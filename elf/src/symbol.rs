@@ -0,0 +1,424 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! ELF symbol tables.
+//!
+//! A [`SymbolTable`] is the contents of a `.symtab` or `.dynsym` section,
+//! together with the string table holding its symbols' names. To find the
+//! symbol containing an address, build a [`SymbolIndex`] over it once; the
+//! index sorts the table's function symbols by address, so each lookup is a
+//! binary search.
+//!
+//! For more information on ELF symbol tables, refer to:
+//! + the ELF [specification]
+//! + the [OS Dev Wiki]
+//!
+//! [`SymbolTable`]: struct.SymbolTable.html
+//! [`SymbolIndex`]: struct.SymbolIndex.html
+//! [specification]: http://www.sco.com/developers/gabi/latest/ch4.symtab.html
+//! [OS Dev Wiki]: http://wiki.osdev.org/ELF_Tutorial#The_Symbol_Table
+use super::{ElfResult, ElfWord, extract_from_slice};
+use section::{StrTable, SHN_ABS, SHN_UNDEF};
+
+use core::{mem, slice};
+
+/// Trait representing an ELF symbol table entry.
+///
+/// This trait allows [`SymbolRepr32`] and [`SymbolRepr64`], whose fields are
+/// laid out differently, to provide a consistent API. Addresses and sizes
+/// are widened to `u64`.
+///
+/// [`SymbolRepr32`]: struct.SymbolRepr32.html
+/// [`SymbolRepr64`]: struct.SymbolRepr64.html
+pub trait Symbol: Sized {
+    type Word: ElfWord;
+
+    /// Returns the [binding](enum.Binding.html) of this symbol.
+    #[inline] fn binding(&self) -> Binding {
+        Binding::from(self.info() >> 4)
+    }
+
+    /// Returns the [type](enum.Type.html) of this symbol.
+    #[inline] fn ty(&self) -> Type {
+        Type::from(self.info() & 0xf)
+    }
+
+    /// Returns true if this symbol is defined in some section of the binary.
+    #[inline] fn is_defined(&self) -> bool {
+        self.section_index() != SHN_UNDEF
+    }
+
+    /// Returns true if `addr` lies inside this symbol.
+    ///
+    /// Symbols with no size only contain their own address.
+    #[inline] fn contains(&self, addr: u64) -> bool {
+        let start = self.value();
+        addr == start || (addr > start && addr - start < self.size())
+    }
+
+    // Field accessors -------------------------------------------------
+    /// Offset of this symbol's name in the symbol table's string table.
+    fn name_offset(&self) -> u32;
+    /// This symbol's value; for functions and objects, their address.
+    fn value(&self) -> u64;
+    /// The size of the function or object this symbol refers to.
+    fn size(&self) -> u64;
+    /// This symbol's type and binding.
+    fn info(&self) -> u8;
+    /// The index of the section this symbol is defined in.
+    fn section_index(&self) -> u16;
+}
+
+macro_rules! impl_symbol {
+    ($($name:ident: $size:ty),+) => {
+        $(impl Symbol for $name {
+            type Word = $size;
+
+            impl_getters! {
+                fn name_offset(&self) -> u32;
+                fn value(&self) -> u64;
+                fn size(&self) -> u64;
+                fn info(&self) -> u8;
+                fn section_index(&self) -> u16;
+            }
+        })+
+    }
+}
+
+/// A 64-bit ELF symbol table entry (`Elf64_Sym`).
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct SymbolRepr64 { name_offset: u32
+                        , info: u8
+                        , other: u8
+                        , section_index: u16
+                        , value: u64
+                        , size: u64
+                        }
+
+/// A 32-bit ELF symbol table entry (`Elf32_Sym`).
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct SymbolRepr32 { name_offset: u32
+                        , value: u32
+                        , size: u32
+                        , info: u8
+                        , other: u8
+                        , section_index: u16
+                        }
+
+impl_symbol! { SymbolRepr64: u64, SymbolRepr32: u32 }
+
+/// The binding of a symbol, which determines its linkage visibility.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Binding {
+    /// `STB_LOCAL`: not visible outside the object file defining it.
+    Local
+  , /// `STB_GLOBAL`: visible to all object files being combined.
+    Global
+  , /// `STB_WEAK`: like a global symbol, but with lower precedence.
+    Weak
+  , /// An OS- or processor-specific binding.
+    Other(u8)
+}
+
+impl From<u8> for Binding {
+    #[inline] fn from(bits: u8) -> Self {
+        match bits {
+            0 => Binding::Local
+          , 1 => Binding::Global
+          , 2 => Binding::Weak
+          , x => Binding::Other(x)
+        }
+    }
+}
+
+/// The type of a symbol.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Type {
+    /// `STT_NOTYPE`: the symbol's type is not specified.
+    ///
+    /// Labels defined in assembly usually have this type.
+    NoType
+  , /// `STT_OBJECT`: the symbol is a data object, such as a variable.
+    Object
+  , /// `STT_FUNC`: the symbol is a function, or other executable code.
+    Function
+  , /// `STT_SECTION`: the symbol is associated with a section.
+    Section
+  , /// `STT_FILE`: the symbol's name is the name of a source file.
+    File
+  , /// `STT_COMMON`: the symbol is an uninitialized common block.
+    Common
+  , /// `STT_TLS`: the symbol is a thread-local storage entity.
+    ThreadLocal
+  , /// An OS- or processor-specific type.
+    Other(u8)
+}
+
+impl From<u8> for Type {
+    #[inline] fn from(bits: u8) -> Self {
+        match bits {
+            0 => Type::NoType
+          , 1 => Type::Object
+          , 2 => Type::Function
+          , 3 => Type::Section
+          , 4 => Type::File
+          , 5 => Type::Common
+          , 6 => Type::ThreadLocal
+          , x => Type::Other(x)
+        }
+    }
+}
+
+/// A symbol table, and the string table holding its symbols' names.
+#[derive(Clone, Debug)]
+pub struct SymbolTable<'a, S: 'a> { symbols: &'a [S]
+                                  , strings: StrTable<'a>
+                                  }
+
+impl<'a, S: Symbol> SymbolTable<'a, S> {
+    /// Returns the symbol table whose entries are in `symbols`, and whose
+    /// names are in `strings`.
+    ///
+    /// # Safety
+    /// + `symbols` must contain valid symbol table entries of type `S`.
+    pub unsafe fn new(symbols: &'a [u8], strings: &'a [u8])
+                     -> ElfResult<Self> {
+        if symbols.as_ptr() as usize % mem::align_of::<S>() != 0 {
+            return Err("symbol table is not aligned")
+        }
+        let n = symbols.len() / mem::size_of::<S>();
+        Ok(SymbolTable { symbols: extract_from_slice::<S>(symbols, 0, n)?
+                       , strings: StrTable::from(strings)
+                       })
+    }
+
+    /// Returns the number of symbols in this table.
+    #[inline] pub fn len(&self) -> usize { self.symbols.len() }
+
+    /// Returns true if this table has no symbols.
+    #[inline] pub fn is_empty(&self) -> bool { self.symbols.is_empty() }
+
+    /// Returns the symbol at index `i`, if there is one.
+    #[inline] pub fn get(&self, i: usize) -> Option<&'a S> {
+        self.symbols.get(i)
+    }
+
+    /// Returns an iterator over the symbols in this table.
+    #[inline] pub fn iter(&self) -> slice::Iter<'a, S> {
+        self.symbols.iter()
+    }
+
+    /// Returns the name of `symbol`, if it has one.
+    #[inline] pub fn name(&self, symbol: &S) -> Option<&'a str> {
+        match symbol.name_offset() {
+            0 => None
+          , offset => self.strings.at_index(offset as usize)
+        }
+    }
+}
+
+/// Returns true if a [`SymbolIndex`] should include `symbol`.
+///
+/// Only defined functions (and untyped labels, such as those in assembly)
+/// which have an address are indexed.
+///
+/// [`SymbolIndex`]: struct.SymbolIndex.html
+fn is_indexed<S: Symbol>(symbol: &S) -> bool {
+    symbol.is_defined()
+        && symbol.section_index() != SHN_ABS
+        && symbol.value() != 0
+        && (symbol.ty() == Type::Function || symbol.ty() == Type::NoType)
+}
+
+/// An index of a symbol table's functions, sorted by address.
+///
+/// The index is stored in a buffer provided by the caller, since the
+/// kernel may need to look up symbols before it has a heap. The buffer must
+/// have room for [`SymbolIndex::required_len`] entries.
+///
+/// [`SymbolIndex::required_len`]: #method.required_len
+#[derive(Debug)]
+pub struct SymbolIndex<'a, S: 'a> { table: SymbolTable<'a, S>
+                                  , /// indices into `table`, sorted by
+                                    /// the symbols' addresses
+                                    sorted: &'a [u32]
+                                  }
+
+impl<'a, S: Symbol> SymbolIndex<'a, S> {
+    /// Returns the number of entries an index of `table` requires.
+    pub fn required_len(table: &SymbolTable<'a, S>) -> usize {
+        table.iter().filter(|s| is_indexed(*s)).count()
+    }
+
+    /// Build an index of `table` in `buf`.
+    ///
+    /// # Returns
+    /// + `Ok(SymbolIndex)` if the index was built
+    /// + `Err(&str)` if `buf` is too short to hold it.
+    pub fn new(table: SymbolTable<'a, S>, buf: &'a mut [u32])
+              -> ElfResult<Self> {
+        let mut len = 0;
+        for (i, symbol) in table.iter().enumerate() {
+            if is_indexed(symbol) {
+                *buf.get_mut(len).ok_or("symbol index buffer is too short")?
+                    = i as u32;
+                len += 1;
+            }
+        }
+        let sorted = &mut buf[..len];
+        {
+            let symbols = table.symbols;
+            sorted.sort_unstable_by_key(|&i| symbols[i as usize].value());
+        }
+        Ok(SymbolIndex { table: table, sorted: sorted })
+    }
+
+    /// Returns the number of symbols in this index.
+    #[inline] pub fn len(&self) -> usize { self.sorted.len() }
+
+    /// Returns true if this index has no symbols.
+    #[inline] pub fn is_empty(&self) -> bool { self.sorted.is_empty() }
+
+    /// Returns the symbol table this index refers to.
+    #[inline] pub fn table(&self) -> &SymbolTable<'a, S> { &self.table }
+
+    /// Find the symbol containing `addr`.
+    ///
+    /// # Returns
+    /// + `Some((symbol, offset))` where `offset` is the distance from the
+    ///   start of `symbol` to `addr`
+    /// + `None` if no indexed symbol contains `addr`.
+    pub fn lookup(&self, addr: u64) -> Option<(&'a S, u64)> {
+        let symbols = self.table.symbols;
+        let position = self.sorted.binary_search_by_key(
+            &addr, |&i| symbols[i as usize].value());
+        // the nearest symbol at or below `addr`.
+        let nearest = match position {
+            Ok(i) => i
+          , Err(0) => return None
+          , Err(i) => i - 1
+        };
+        let symbol = &symbols[self.sorted[nearest] as usize];
+        let contains = if symbol.size() == 0 {
+            // unsized symbols (such as assembly labels) extend to the start
+            // of the next symbol. if there isn't one, we can't tell where
+            // they end, so they only contain their own address.
+            self.sorted[nearest + 1 ..].iter()
+                .map(|&i| symbols[i as usize].value())
+                .find(|&next| next > symbol.value())
+                .map(|next| addr < next)
+                .unwrap_or(addr == symbol.value())
+        } else {
+            symbol.contains(addr)
+        };
+        if contains {
+            Some((symbol, addr - symbol.value()))
+        } else {
+            None
+        }
+    }
+
+    /// Find the name of the symbol containing `addr`.
+    ///
+    /// # Returns
+    /// + `Some((name, offset))` where `offset` is the distance from the
+    ///   start of the symbol to `addr`
+    /// + `None` if no named symbol contains `addr`.
+    pub fn symbolize(&self, addr: u64) -> Option<(&'a str, u64)> {
+        self.lookup(addr)
+            .and_then(|(symbol, offset)| {
+                self.table.name(symbol).map(|name| (name, offset))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::{mem, slice};
+
+    const STRINGS: &'static [u8] = b"\0foo\0bar\0baz\0qux\0quux\0";
+
+    fn symbol(name: u32, value: u64, size: u64) -> SymbolRepr64 {
+        SymbolRepr64 { name_offset: name
+                     , info: 0x12 // STB_GLOBAL, STT_FUNC
+                     , other: 0
+                     , section_index: 1
+                     , value: value
+                     , size: size
+                     }
+    }
+
+    fn table(symbols: &[SymbolRepr64]) -> SymbolTable<SymbolRepr64> {
+        unsafe {
+            let bytes = slice::from_raw_parts(
+                symbols.as_ptr() as *const u8
+              , symbols.len() * mem::size_of::<SymbolRepr64>());
+            SymbolTable::new(bytes, STRINGS).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_symbol_sizes() {
+        assert_eq!(mem::size_of::<SymbolRepr64>(), 24);
+        assert_eq!(mem::size_of::<SymbolRepr32>(), 16);
+    }
+
+    #[test]
+    fn test_info() {
+        let sym = symbol(1, 0x1000, 0x10);
+        assert_eq!(sym.binding(), Binding::Global);
+        assert_eq!(sym.ty(), Type::Function);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let symbols = [ symbol(0, 0, 0) // the null symbol
+                      , symbol(9, 0x3000, 0x10)
+                      , symbol(1, 0x1000, 0x100)
+                      , symbol(5, 0x2000, 0x20)
+                      ];
+        let table = table(&symbols);
+        let mut buf = [0; 4];
+        assert_eq!(SymbolIndex::required_len(&table), 3);
+        let index = SymbolIndex::new(table, &mut buf).unwrap();
+
+        assert_eq!(index.symbolize(0x1000), Some(("foo", 0)));
+        assert_eq!(index.symbolize(0x2008), Some(("bar", 8)));
+        assert_eq!(index.symbolize(0x300f), Some(("baz", 0xf)));
+        assert_eq!(index.symbolize(0xfff), None);
+        // past the end of `foo`, but before `bar`.
+        assert_eq!(index.symbolize(0x1100), None);
+    }
+
+    #[test]
+    fn test_lookup_unsized() {
+        let symbols = [ symbol(13, 0x1000, 0) // an assembly label
+                      , symbol(1, 0x2000, 0x10)
+                      , symbol(17, 0x3000, 0)
+                      ];
+        let table = table(&symbols);
+        let mut buf = [0; 3];
+        let index = SymbolIndex::new(table, &mut buf).unwrap();
+
+        assert_eq!(index.symbolize(0x1fff), Some(("qux", 0xfff)));
+        assert_eq!(index.symbolize(0x2000), Some(("foo", 0)));
+        // the last unsized symbol has nothing to end it.
+        assert_eq!(index.symbolize(0x3000), Some(("quux", 0)));
+        assert_eq!(index.symbolize(0x3001), None);
+    }
+
+    #[test]
+    fn test_buffer_too_short() {
+        let symbols = [symbol(1, 0x1000, 0x10), symbol(5, 0x2000, 0x10)];
+        let mut buf = [0; 1];
+        assert!(SymbolIndex::new(table(&symbols), &mut buf).is_err());
+    }
+}
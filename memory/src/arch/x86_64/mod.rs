@@ -7,7 +7,7 @@
 //  directory of this repository for more information.
 //
 //! Architecture-specific memory management.
-use ::{Addr, Page};
+use ::{Addr, Page, VAddr, VirtualPage};
 
 use core::{fmt, ops, mem};
 
@@ -19,6 +19,13 @@ pub const LARGE_PAGE_SIZE: u64 = 1024 * 1024 * 2;
 /// The size of a huge page (2GiB) in bytes
 pub const HUGE_PAGE_SIZE: u64 = 1024 * 1024 * 1024;
 
+/// Base virtual address of the kernel's direct map of physical memory.
+///
+/// This is the start of the higher half of the address space (PML4 entry 256).
+/// Once the physical memory map has been set up, physical address `n` can be
+/// accessed at virtual address `PHYS_MAP_OFFSET + n`.
+pub const PHYS_MAP_OFFSET: u64 = 0xffff_8000_0000_0000;
+
 
 macro_attr! {
     /// A physical (linear) memory address is a 64-bit unsigned integer
@@ -27,6 +34,20 @@ macro_attr! {
     pub struct PAddr(u64);
 }
 
+impl PAddr {
+    /// Returns the virtual address of this physical address in the kernel's
+    /// direct map of physical memory.
+    ///
+    /// Computing the address is always safe, but dereferencing it is only
+    /// valid once the physical memory map has been set up (by
+    /// `paging::kernel_remap`), and only if this address is in a usable
+    /// memory area.
+    #[inline]
+    pub const fn to_virtual(&self) -> VAddr {
+        VAddr::from_usize((self.0 + PHYS_MAP_OFFSET) as usize)
+    }
+}
+
 macro_attr! {
     /// A frame (physical page)
     //  TODO: consider renaming this to `Frame` (less typing)?
@@ -82,6 +103,18 @@ impl PhysicalPage {
         PhysicalPage { number: addr.0 >> PAGE_SHIFT }
     }
 
+    /// Returns the page containing this frame in the kernel's direct map of
+    /// physical memory.
+    ///
+    /// The same caveats as [`PAddr::to_virtual`] apply.
+    ///
+    /// [`PAddr::to_virtual`]: struct.PAddr.html#method.to_virtual
+    #[inline]
+    pub const fn to_virtual(&self) -> VirtualPage {
+        VirtualPage { number: ((self.number << PAGE_SHIFT) + PHYS_MAP_OFFSET)
+                              as usize >> PAGE_SHIFT }
+    }
+
     /// Convert the frame into a raw pointer to the frame's base address
     #[inline]
     pub unsafe fn as_ptr<T>(&self) -> *const T {
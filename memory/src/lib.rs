@@ -31,7 +31,8 @@ pub mod arch;
 use core::{ops, cmp, convert, fmt};
 use util::Align;
 
-pub use arch::{PAddr, PAGE_SHIFT, PAGE_SIZE};
+pub use arch::{PAddr, PAGE_SHIFT, PAGE_SIZE, LARGE_PAGE_SIZE};
+#[cfg(target_arch = "x86_64")] pub use arch::PHYS_MAP_OFFSET;
 
 /// Trait representing an address, whether physical or virtual.
 pub trait Addr: ops::Add<Self> + ops::Sub<Self>
@@ -164,6 +165,7 @@ macro_attr!{
 impl VirtualPage {
     fn containing_addr( addr: VAddr) -> Self {
         use ::PAGE_SHIFT;
+        #[cfg(target_arch = "x86_64")]
         assert!( (addr < 0x0000_8000_0000_0000) || (addr >= 0xffff_8000_0000_0000)
                , "invalid address : 0x{:x}", addr );
         Self { number: addr.0 >> PAGE_SHIFT }
@@ -0,0 +1,192 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Paging
+//!
+//! By default, 32-bit `x86` uses two-level paging: a Page Directory (PD)
+//! whose entries point to Page Tables (PT). This works on every CPU, but
+//! its entries are 32 bits wide and have no execute-disable bit.
+//!
+//! With the `pae` feature, Physical Address Extension (PAE) paging is used
+//! instead. This has the same 64-bit page table entries as `x86_64`
+//! (including the `NO_EXECUTE` bit), but only three table levels: the Page
+//! Directory Pointer Table (PDPT), the PD, and the PT. It requires a CPU
+//! with PAE.
+//!
+//! See the `levels` module for how the tables are reached through the
+//! recursive mapping in each mode. The page tables, the paging
+//! [`Backend`](backend/trait.Backend.html), and the `Mapper` implementation
+//! are shared with `x86_64`.
+//!
+//! N.B. that SOS has no 32-bit boot code yet, so nothing sets up the
+//! recursive mapping, `%cr4.PAE` or `IA32_EFER.NXE` before `kernel_remap`
+//! runs. Booting an `x86` kernel is out of scope for this module; whatever
+//! boot code is eventually written must do those things first.
+use core::ops;
+
+use alloc::FrameAllocator;
+use memory::{ PAddr, Page, PhysicalPage, VAddr, VirtualPage };
+use params::InitParams;
+use ::{Mapper, MapResult, MapErr};
+
+use self::active::{identity_map_checked, identity_map_section};
+use self::table::*;
+use self::temp::TempPage;
+
+pub use self::active::ActiveTable as ActiveTopLevel;
+#[cfg(not(feature = "pae"))]
+pub use self::active::ActiveTable as ActivePD;
+#[cfg(feature = "pae")]
+pub use self::active::ActiveTable as ActivePDPT;
+pub use self::inactive::InactivePageTable;
+
+#[path = "../x86_all/backend.rs"] pub mod backend;
+#[cfg(not(feature = "pae"))]
+#[path = "two_level/levels.rs"] pub mod levels;
+#[cfg(feature = "pae")]
+#[path = "pae/levels.rs"] pub mod levels;
+#[path = "../x86_all/table.rs"] pub mod table;
+#[path = "../x86_all/tlb.rs"] pub mod tlb;
+#[path = "../x86_all/temp.rs"] pub mod temp;
+#[path = "../x86_all/active.rs"] mod active;
+#[cfg(not(feature = "pae"))]
+#[path = "two_level/inactive.rs"] mod inactive;
+#[cfg(feature = "pae")]
+#[path = "pae/inactive.rs"] mod inactive;
+
+#[derive(Debug)]
+pub struct ActivePageTable { top: ActiveTopLevel }
+
+impl ops::Deref for ActivePageTable {
+    type Target = ActiveTopLevel;
+
+    fn deref(&self) -> &ActiveTopLevel {
+        &self.top
+    }
+}
+
+impl ops::DerefMut for ActivePageTable {
+    fn deref_mut(&mut self) -> &mut ActiveTopLevel {
+        &mut self.top
+    }
+}
+
+impl ActivePageTable {
+    pub unsafe fn new() -> ActivePageTable {
+        ActivePageTable { top: ActiveTopLevel::new() }
+    }
+}
+
+pub fn test_paging<A>(alloc: &mut A) -> MapResult<()>
+where A: FrameAllocator {
+    info!("testing paging");
+    let mut table = unsafe { ActiveTopLevel::new() };
+
+    // address 0 is mapped
+    trace!("Some = {:?}", table.translate(VAddr::from(0)));
+     // second PT entry
+    trace!("Some = {:?}", table.translate(VAddr::from(4096)));
+    // second PD entry
+    trace!("Some = {:?}", table.translate(VAddr::from(N_ENTRIES * 4096)));
+
+    let addr = VAddr::from(42 * N_ENTRIES * 4096); // 42th PD entry
+    let page = VirtualPage::containing(addr);
+    let frame = unsafe { alloc.allocate().expect("no more frames") };
+    trace!("None = {:?}, map to {:?}",
+             table.translate(addr),
+             frame);
+    let _ = table.map(page, frame, EntryFlags::empty(), alloc)?;
+    trace!("Some = {:?}", table.translate(addr));
+    trace!( "next free frame: {:?}"
+            , unsafe { alloc.allocate() });
+
+    let _ = table.unmap(Page::containing(addr), alloc)?;
+    trace!("None = {:?}", table.translate(addr));
+    Ok(())
+
+}
+
+/// Remaps the kernel using 4KiB pages.
+///
+/// Unlike on `x86_64`, there is no direct map of physical memory.
+pub fn kernel_remap<A>(params: &InitParams, alloc: &mut A)
+                       -> MapResult<ActivePageTable>
+where A: FrameAllocator {
+    use elf::Section;
+    // create a  temporary page for switching page tables
+    // page number chosen fairly arbitrarily.
+    const TEMP_PAGE_NUMBER: usize = 0xfacad;
+    let mut temp_page = TempPage::new(TEMP_PAGE_NUMBER, alloc);
+    trace!("Created temporary page.");
+
+    // old and new page tables
+    let mut current_table = unsafe { ActivePageTable::new() };
+    trace!("Got current page table.");
+
+    let top_frame = unsafe { alloc.allocate() }
+        .map_err(|err| MapErr::Alloc {
+            message: "create the new page table"
+          , page: *temp_page
+          , cause: err
+        })?;
+    let mut new_table = InactivePageTable::new( top_frame
+                                              , &mut current_table
+                                              , &mut temp_page
+                                              , alloc )?;
+    kinfoln!(dots: " . . ", "Created new {:?}", new_table);
+
+    // actually remap the kernel --------------------------------------------
+    current_table.using(&mut new_table, &mut temp_page, |table| {
+        // extract allocated ELF sections
+        let sections
+            = params.elf_sections()
+                    .filter(|s| s.is_allocated());
+
+        kinfoln!(dots: " . . ", "Remapping kernel ELF sections.");
+
+        for section in sections { // remap ELF sections
+            attempt!( identity_map_section(table, section, alloc) =>
+                      dots: " . . . ",
+                      "Identity mapping {}", section );
+        }
+
+        // remap VGA buffer
+        let vga_buffer_frame = PhysicalPage::containing(PAddr::from(0xb8000));
+        attempt!( identity_map_checked( table, vga_buffer_frame
+                                      , WRITABLE | NO_EXECUTE, alloc) =>
+                  dots: " . . ", "Identity mapping VGA buffer" );
+
+        // remap Multiboot info
+        kinfoln!( dots: " . . ", "Identity mapping multiboot info" );
+        let multiboot_start = PhysicalPage::from(params.multiboot_start());
+        let multiboot_end = PhysicalPage::from(params.multiboot_end());
+
+        for frame in multiboot_start .. multiboot_end {
+            let _ = identity_map_checked( table, frame, PRESENT | NO_EXECUTE
+                                        , alloc)?;
+        }
+        Ok(())
+    })?;
+
+    trace!("replacing old page table with new page table");
+    // switch page tables ---------------------------------------------------
+    let old_table = current_table.replace_with(new_table);
+    kinfoln!(dots: " . . ", "Successfully switched to remapped page table!");
+
+    // make sure the kernel can't write to read-only pages either.
+    unsafe { ::cpu::control_regs::cr0::enable_write_protect(true) };
+    kinfoln!(dots: " . . ", "Enabled kernel write protection.");
+
+    // create guard page at the location of the old top-level table
+    let old_top_vaddr = VAddr::from(*(old_table.frame().base()) as usize);
+    let old_top_page  = VirtualPage::containing(old_top_vaddr);
+    let _ = current_table.unmap(old_top_page, alloc)?;
+    trace!("Unmapped guard page at {:?}", old_top_page.base());
+
+    Ok(current_table)
+}
@@ -0,0 +1,194 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Switching between page tables with PAE.
+use alloc::FrameAllocator;
+use memory::PhysicalPage;
+use ::{MapResult, MapErr};
+
+use super::{ActivePageTable, ActivePDPT};
+use super::backend::{Backend, Current};
+use super::table::*;
+use super::temp::TempPage;
+
+/// The first frame above 4GiB.
+///
+/// In PAE mode, `%cr3` is still only 32 bits wide, so the PDPT must be in a
+/// frame below this.
+const FIRST_HIGH_FRAME: usize = 1 << 20;
+
+impl ActivePageTable {
+    /// Returns the frames of the active page directories.
+    fn pd_frames(&self) -> [PhysicalPage; N_PDPT_ENTRIES] {
+        let pdpt = self.top();
+        let frame = |i: usize| pdpt[i].get_frame()
+            .expect("the page directories are always present");
+        [frame(0), frame(1), frame(2), frame(3)]
+    }
+
+    /// Execute a closure with the recursive mapping temporarily changed to a
+    /// new page table
+    ///
+    /// The recursive mapping is the last four entries of PD 3, so they are
+    /// changed to point to the new table's page directories.
+    pub fn using<F>( &mut self
+                   , table: &mut InactivePageTable
+                   , temp_page: &mut TempPage
+                   , f: F)
+                   -> MapResult
+    where F: FnOnce(&mut ActivePDPT) -> MapResult {
+        let result: MapResult;
+        {
+            // back up the current page directory frames
+            let prev_pd_frames = self.pd_frames();
+
+            // map temporary_page to the current last page directory, which
+            // contains the recursive entries.
+            let pd = temp_page.map_to_table( prev_pd_frames[N_PDPT_ENTRIES - 1]
+                                           , self)?;
+
+            // remap the recursive entries to point to the new table's page
+            // directories.
+            for (i, frame) in table.pd_frames.iter().enumerate() {
+                pd[RECURSIVE_INDEX + i].set(*frame, PRESENT | WRITABLE);
+            }
+            unsafe {
+                // this is safe to execute; we are in kernel mode
+                Current::flush_all();
+            }
+
+            // execute the closure
+            result = f(self);
+
+            // remap the recursive entries to point back to the original
+            // page directories
+            for (i, frame) in prev_pd_frames.iter().enumerate() {
+                pd[RECURSIVE_INDEX + i].set(*frame, PRESENT | WRITABLE);
+            }
+
+            unsafe {
+                // this is safe to execute; we are in kernel mode
+                Current::flush_all();
+            }
+        }
+        let _ = temp_page.unmap(self)?;
+        return result
+
+    }
+
+    /// Replace the current `ActivePageTable` with the given `InactivePageTable`
+    ///
+    /// # Arguments
+    /// + `new_table`: the `InactivePageTable` that will replace the current
+    ///                `ActivePageTable`.
+    ///
+    /// # Returns
+    /// + the old active page table as an `InactivePageTable`.
+    pub fn replace_with(&mut self, new_table: InactivePageTable)
+                       -> InactivePageTable {
+        unsafe {
+            trace!("replacing {:?} with {:?}", self, new_table);
+            let old_pd_frames = self.pd_frames();
+            // this is safe to execute; we are in kernel mode
+            let old_pdpt_frame = Current::current_pagetable_frame();
+            trace!("current PDPT frame is {:?}", old_pdpt_frame);
+
+            // writing `%cr3` also loads the new PDPT's entries.
+            Current::set_pagetable_frame(new_table.pdpt_frame);
+            trace!("set new PDPT frame to {:?}", new_table.pdpt_frame);
+
+            InactivePageTable {
+                pdpt_frame: old_pdpt_frame
+              , pd_frames: old_pd_frames
+            }
+        }
+    }
+
+}
+
+/// An inactive page table that the CPU is not currently using
+#[derive(Debug)]
+pub struct InactivePageTable {
+    pdpt_frame: PhysicalPage
+  , pd_frames: [PhysicalPage; N_PDPT_ENTRIES]
+}
+
+impl InactivePageTable {
+    /// Create a new `InactivePageTable` with its PDPT in the given frame.
+    ///
+    /// The PDPT's entries are loaded into the CPU when it becomes active, so
+    /// all four page directories are allocated from `alloc` now. Each table
+    /// is zeroed by mapping it to `temp`, and the last four entries of PD 3
+    /// are set to point to the page directories.
+    ///
+    /// # Returns
+    /// + `Err(MapErr)` if `frame` is above 4GiB, since `%cr3` can't point
+    ///   to it, or if a page directory could not be allocated.
+    pub fn new<A>( frame: PhysicalPage
+                 , active_table: &mut ActivePageTable
+                 , temp: &mut TempPage
+                 , alloc: &mut A)
+                 -> MapResult<Self>
+    where A: FrameAllocator {
+        if frame.number >= FIRST_HIGH_FRAME {
+            return Err(MapErr::Other {
+                message: "create a PDPT"
+              , page: **temp
+              , cause: "the PDPT must be below 4GiB"
+            })
+        }
+
+        let mut pd_frames = [frame; N_PDPT_ENTRIES];
+        for pd_frame in pd_frames.iter_mut() {
+            *pd_frame = unsafe { alloc.allocate() }
+                .map_err(|err| MapErr::Alloc {
+                    message: "create a page directory"
+                  , page: **temp
+                  , cause: err
+                })?;
+        }
+
+        for (i, pd_frame) in pd_frames.iter().enumerate() {
+            {
+                let pd = temp.map_to_table(*pd_frame, active_table)?;
+                pd.zero();
+                if i == N_PDPT_ENTRIES - 1 {
+                    for (j, frame) in pd_frames.iter().enumerate() {
+                        pd[RECURSIVE_INDEX + j]
+                            .set(*frame, PRESENT | WRITABLE);
+                    }
+                    trace!(" . . . Set recursive entries of new PD 3.")
+                }
+            }
+            let _ = temp.unmap(active_table)?;
+        }
+        trace!( " . . . Zeroed inactive page directories.");
+
+        {
+            trace!("Mapping page {} to frame {}", temp.number, frame.number);
+            let pdpt = temp.map_to_table(frame.clone(), active_table)?;
+            trace!( " . . . Mapped temp page to table frame .");
+            pdpt.zero();
+            // PDPT entries may only have the present and caching flags set.
+            for (i, pd_frame) in pd_frames.iter().enumerate() {
+                pdpt[i].set(*pd_frame, PRESENT);
+            }
+            trace!( " . . . Filled in inactive PDPT.");
+        }
+        let _ = temp.unmap(active_table)?;
+        trace!(" . . Unmapped temp page.");
+
+        Ok(InactivePageTable { pdpt_frame: frame, pd_frames: pd_frames })
+    }
+
+    /// Returns the frame containing this table's PDPT.
+    #[inline]
+    pub fn frame(&self) -> PhysicalPage {
+        self.pdpt_frame
+    }
+}
@@ -0,0 +1,154 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Page table levels with PAE.
+//!
+//! With Physical Address Extension, 32-bit `x86` uses a three-level page
+//! table structure: a Page Directory Pointer Table (PDPT) with four
+//! entries, each of which points to a Page Directory (PD) covering 1GiB, and
+//! the bottom-level Page Tables (PT).
+//!
+//! # The recursive mapping
+//! The PDPT only has four entries, so it can't map itself. Instead, the last
+//! four entries of PD 3 (508 through 511) point to the four page
+//! directories. This means that:
+//!
+//! + PD `i` is at `0xffffc000 + i * 4096`,
+//! + the page table for entry `j` of PD `i` is at
+//!   `0xff800000 + i * 2MiB + j * 4096`,
+//! + and entries 508 through 511 of PD 3, at `0xffffffe0`, are a copy of the
+//!   PDPT.
+//!
+//! The table-walking code uses that copy as the PDPT. Since
+//! `0xffffffe0 << 9` is `0xffffc000` once truncated to 32 bits, the same
+//! recursive address arithmetic as on `x86_64` reaches every table.
+//!
+//! N.B. that the copy is only 32 bytes long, so the `Table<PDPTLevel>` at
+//! `0xffffffe0` may only be indexed, and never zeroed or iterated over.
+use alloc::FrameAllocator;
+use memory::{PhysicalPage, VirtualPage};
+
+use ::MapResult;
+use super::table::{EntryFlags, IndexOf, Sublevel, Table, TableLevel};
+
+/// Base virtual address of the copy of the PDPT in PD 3
+pub const PDPT_VADDR: u32 = 0xffff_ffe0;
+
+/// A pointer to the copy of the PDPT in PD 3
+pub const PDPT_PTR: *mut Table<PDPTLevel> = PDPT_VADDR as *mut _;
+
+/// The number of entries in the PDPT.
+pub const N_PDPT_ENTRIES: usize = 4;
+
+/// Index of the first recursive entry in PD 3.
+pub const RECURSIVE_INDEX: usize = 512 - N_PDPT_ENTRIES;
+
+/// The top-level table is the PDPT.
+pub type TopLevel = PDPTLevel;
+
+/// A pointer to the active top-level table
+pub const TOP_PTR: *mut Table<TopLevel> = PDPT_PTR;
+
+/// The integer type of a physical address.
+///
+/// N.B. that while PAE entries can point to frames above 4GiB, a `PAddr` is
+/// only 32 bits wide, so those frames can't be mapped.
+pub type PAddrWord = u32;
+
+/// The integer type of a page table entry.
+///
+/// PAE entries are 64 bits wide, like on `x86_64`.
+pub type EntryWord = u64;
+
+/// The number of bits in a table index; each PD and PT has 512 entries.
+pub const ENTRY_INDEX_BITS: usize = 9;
+
+/// Mask to apply to a page table entry to isolate the physical address
+///
+/// N.B. that this excludes bit 63, which is the `NO_EXECUTE` flag.
+pub const ENTRY_ADDR_MASK: EntryWord = 0x000fffff_fffff000;
+
+pub enum PDPTLevel {}
+pub enum PDLevel   {}
+pub enum PTLevel   {}
+
+impl TableLevel for PDPTLevel {
+    const ADDR_SHIFT_AMOUNT: usize = 30;
+    const PAGE_SHIFT_AMOUNT: usize = 18;
+    const INDEX_MASK: usize = N_PDPT_ENTRIES - 1;
+}
+impl TableLevel for PDLevel   {
+    const ADDR_SHIFT_AMOUNT: usize = 21;
+    const PAGE_SHIFT_AMOUNT: usize = 9;
+}
+impl TableLevel for PTLevel   {
+    const ADDR_SHIFT_AMOUNT: usize = 12;
+    const PAGE_SHIFT_AMOUNT: usize = 0;
+}
+
+impl Sublevel for PDPTLevel {
+    type Next = PDLevel;
+}
+impl Sublevel for PDLevel {
+    type Next = PTLevel;
+}
+
+impl Table<PDPTLevel> {
+    #[inline]
+    pub fn page_table_for(&self, page: VirtualPage) -> Option<&Table<PTLevel>> {
+        self.next_table(page)
+            .and_then(|pd| pd.next_table(page))
+    }
+
+    #[inline]
+    pub fn page_table_mut_for(&mut self, page: VirtualPage)
+                             -> Option<&mut Table<PTLevel>> {
+        self.next_table_mut(page)
+            .and_then(|pd| pd.next_table_mut(page))
+    }
+
+    /// Returns the page table for `page`, creating it if it doesn't exist
+    /// yet.
+    ///
+    /// The page directories always exist, since they are allocated along
+    /// with the PDPT.
+    pub fn create_page_table<A>(&mut self, page: VirtualPage, alloc: &mut A)
+                               -> MapResult<&mut Table<PTLevel>>
+    where A: FrameAllocator {
+        self.create_next(page, alloc)
+            // get or create the page table at the  page's PD table index
+            .and_then(|pd| pd.create_next(page, alloc))
+    }
+
+    /// Returns the frame which `page` is mapped to, including if it is in a
+    /// 2MiB page.
+    pub fn translate_page(&self, page: VirtualPage) -> Option<PhysicalPage> {
+        self.next_table(page)
+            .and_then(|pd|
+                pd.next_table(page)
+                  .and_then(|pt| pt[page].get_frame())
+                  .or_else(|| pd[page].do_huge(PTLevel::index_of(page))))
+    }
+
+    /// Returns the flags of the entry which maps `page`.
+    ///
+    /// This is the page table entry, or the PD entry if `page` is in a 2MiB
+    /// page.
+    pub fn entry_flags(&self, page: VirtualPage) -> Option<EntryFlags> {
+        let present = |flags: EntryFlags| if flags.is_present() {
+            Some(flags)
+        } else {
+            None
+        };
+        let pd = self.next_table(page)?;
+        if pd[page].is_huge() {
+            return present(pd[page].flags())
+        }
+        pd.next_table(page).and_then(|pt| present(pt[page].flags()))
+    }
+}
@@ -0,0 +1,130 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Switching between page directories without PAE.
+use alloc::FrameAllocator;
+use memory::PhysicalPage;
+use ::MapResult;
+
+use super::{ActivePageTable, ActivePD};
+use super::backend::{Backend, Current};
+use super::table::*;
+use super::temp::TempPage;
+
+impl ActivePageTable {
+    /// Execute a closure with the recursive mapping temporarily changed to a
+    /// new page table
+    pub fn using<F>( &mut self
+                   , table: &mut InactivePageTable
+                   , temp_page: &mut TempPage
+                   , f: F)
+                   -> MapResult
+    where F: FnOnce(&mut ActivePD) -> MapResult {
+        let result: MapResult;
+        {
+            // back up the current page directory frame
+            let prev_pd_frame = unsafe {
+                // this is safe to execute; we are in kernel mode
+                Current::current_pagetable_frame()
+            };
+
+            // map temporary_page to the current page directory
+            let pd = temp_page.map_to_table(prev_pd_frame.clone(), self)?;
+
+            // remap the recursive entry to map to the frame containing the
+            // new page directory.
+            self.top_mut()[RECURSIVE_INDEX]
+                .set(table.pd_frame, PRESENT | WRITABLE);
+            unsafe {
+                // this is safe to execute; we are in kernel mode
+                Current::flush_all();
+            }
+
+            // execute the closure
+            result = f(self);
+
+            // remap the recursive entry to point back to the original frame
+            pd[RECURSIVE_INDEX].set(prev_pd_frame, PRESENT | WRITABLE);
+
+            unsafe {
+                // this is safe to execute; we are in kernel mode
+                Current::flush_all();
+            }
+        }
+        let _ = temp_page.unmap(self)?;
+        return result
+
+    }
+
+    /// Replace the current `ActivePageTable` with the given `InactivePageTable`
+    ///
+    /// # Arguments
+    /// + `new_table`: the `InactivePageTable` that will replace the current
+    ///                `ActivePageTable`.
+    ///
+    /// # Returns
+    /// + the old active page table as an `InactivePageTable`.
+    pub fn replace_with(&mut self, new_table: InactivePageTable)
+                       -> InactivePageTable {
+        unsafe {
+            trace!("replacing {:?} with {:?}", self, new_table);
+            // this is safe to execute; we are in kernel mode
+            let old_pd_frame = Current::current_pagetable_frame();
+            trace!("current page directory frame is {:?}", old_pd_frame);
+
+            Current::set_pagetable_frame(new_table.pd_frame);
+            trace!("set new page directory frame to {:?}", new_table.pd_frame);
+
+            InactivePageTable {
+                pd_frame: old_pd_frame
+            }
+        }
+    }
+
+}
+
+/// An inactive page table that the CPU is not currently using
+#[derive(Debug)]
+pub struct InactivePageTable {
+    pd_frame: PhysicalPage
+}
+
+impl InactivePageTable {
+    /// Create a new `InactivePageTable` with its page directory in the given
+    /// frame.
+    ///
+    /// The page directory is zeroed by mapping it to `temp`, and its last
+    /// entry is set to point to itself. Page tables are only allocated when
+    /// something is mapped, so `alloc` is not used.
+    pub fn new<A>( frame: PhysicalPage
+                 , active_table: &mut ActivePageTable
+                 , temp: &mut TempPage
+                 , _alloc: &mut A)
+                 -> MapResult<Self>
+    where A: FrameAllocator {
+        {
+            trace!("Mapping page {} to frame {}", temp.number, frame.number);
+            let pd = temp.map_to_table(frame.clone(), active_table)?;
+            trace!( " . . . Mapped temp page to table frame .");
+            pd.zero();
+            trace!( " . . . Zeroed inactive table frame.");
+            pd[RECURSIVE_INDEX].set( frame.clone(), PRESENT | WRITABLE);
+            trace!(" . . . Set recursive entry of new page directory.")
+        }
+        let _ = temp.unmap(active_table)?;
+        trace!(" . . Unmapped temp page.");
+
+        Ok(InactivePageTable { pd_frame: frame })
+    }
+
+    /// Returns the frame containing this table's page directory.
+    #[inline]
+    pub fn frame(&self) -> PhysicalPage {
+        self.pd_frame
+    }
+}
@@ -0,0 +1,114 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Page table levels without PAE.
+//!
+//! Without PAE, 32-bit `x86` uses a two-level page table structure: a Page
+//! Directory (PD), whose entries point to Page Tables (PT) or map 4MiB pages
+//! directly. Each table has 1024 four-byte entries.
+//!
+//! The last page directory entry maps the page directory itself, so the page
+//! directory is always at `0xfffff000`, and the page table for PD entry `i`
+//! is at `0xffc00000 + i * 4096`.
+//!
+//! N.B. that two-level page table entries have no execute-disable bit, so
+//! `NO_EXECUTE` is dropped when an entry is set, and every mapped page is
+//! executable.
+use alloc::FrameAllocator;
+use memory::{PhysicalPage, VirtualPage};
+
+use ::MapResult;
+use super::table::{EntryFlags, IndexOf, Sublevel, Table, TableLevel};
+
+/// Base virtual address of the page directory
+pub const PD_VADDR: u32 = 0xffff_f000;
+
+/// A pointer to the page directory
+pub const PD_PTR: *mut Table<PDLevel> = PD_VADDR as *mut _;
+
+/// Index of the recursive entry in the page directory.
+pub const RECURSIVE_INDEX: usize = 1023;
+
+/// The top-level table is the page directory.
+pub type TopLevel = PDLevel;
+
+/// A pointer to the active top-level table
+pub const TOP_PTR: *mut Table<TopLevel> = PD_PTR;
+
+/// The integer type of a physical address.
+pub type PAddrWord = u32;
+
+/// The integer type of a page table entry.
+pub type EntryWord = u32;
+
+/// The number of bits in a table index; each table has 1024 entries.
+pub const ENTRY_INDEX_BITS: usize = 10;
+
+/// Mask to apply to a page table entry to isolate the physical address
+pub const ENTRY_ADDR_MASK: EntryWord = 0xffff_f000;
+
+pub enum PDLevel   {}
+pub enum PTLevel   {}
+
+impl TableLevel for PDLevel   {
+    const ADDR_SHIFT_AMOUNT: usize = 22;
+    const PAGE_SHIFT_AMOUNT: usize = 10;
+}
+impl TableLevel for PTLevel   {
+    const ADDR_SHIFT_AMOUNT: usize = 12;
+    const PAGE_SHIFT_AMOUNT: usize = 0;
+}
+
+impl Sublevel for PDLevel {
+    type Next = PTLevel;
+}
+
+impl Table<PDLevel> {
+    #[inline]
+    pub fn page_table_for(&self, page: VirtualPage) -> Option<&Table<PTLevel>> {
+        self.next_table(page)
+    }
+
+    #[inline]
+    pub fn page_table_mut_for(&mut self, page: VirtualPage)
+                             -> Option<&mut Table<PTLevel>> {
+        self.next_table_mut(page)
+    }
+
+    /// Returns the page table for `page`, creating it if it doesn't exist
+    /// yet.
+    pub fn create_page_table<A>(&mut self, page: VirtualPage, alloc: &mut A)
+                               -> MapResult<&mut Table<PTLevel>>
+    where A: FrameAllocator {
+        self.create_next(page, alloc)
+    }
+
+    /// Returns the frame which `page` is mapped to, including if it is in a
+    /// 4MiB page.
+    pub fn translate_page(&self, page: VirtualPage) -> Option<PhysicalPage> {
+        self.next_table(page)
+            .and_then(|pt| pt[page].get_frame())
+            .or_else(|| self[page].do_huge(PTLevel::index_of(page)))
+    }
+
+    /// Returns the flags of the entry which maps `page`.
+    ///
+    /// This is the page table entry, or the PD entry if `page` is in a 4MiB
+    /// page.
+    pub fn entry_flags(&self, page: VirtualPage) -> Option<EntryFlags> {
+        let present = |flags: EntryFlags| if flags.is_present() {
+            Some(flags)
+        } else {
+            None
+        };
+        if self[page].is_huge() {
+            return present(self[page].flags())
+        }
+        self.next_table(page).and_then(|pt| present(pt[page].flags()))
+    }
+}
@@ -0,0 +1,149 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Page table auditing.
+//!
+//! After the kernel is remapped, we walk the whole page table hierarchy and
+//! check that no page is both writable and executable (W^X), and that no
+//! kernel page is accessible from user mode.
+//!
+//! The effective permissions of a page depend on every level of the
+//! hierarchy: a page is only writable or user-accessible if every entry on
+//! the way to it is, and it is not executable if any entry on the way to it
+//! sets `NO_EXECUTE`.
+use core::fmt;
+
+use super::ActivePML4;
+use super::table::*;
+
+/// The maximum number of violations which are logged individually.
+const MAX_LOGGED: usize = 16;
+
+/// The result of auditing a page table.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Audit { /// the number of mapped pages (of any size)
+                   pub pages: usize
+                 , /// the number of pages which are writable and executable
+                   pub writable_executable: usize
+                 , /// the number of pages which are user-accessible
+                   pub user_accessible: usize
+                 }
+
+impl Audit {
+    /// Returns true if no violations were found.
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.writable_executable == 0 && self.user_accessible == 0
+    }
+
+    #[inline]
+    fn violations(&self) -> usize {
+        self.writable_executable + self.user_accessible
+    }
+
+    /// Check the effective `flags` of the page at `addr`.
+    fn check(&mut self, addr: usize, flags: EntryFlags) {
+        self.pages += 1;
+        if flags.is_writable_executable() {
+            if self.violations() < MAX_LOGGED {
+                error!("W^X violation: page at {:#x} is writable and \
+                        executable", addr);
+            }
+            self.writable_executable += 1;
+        }
+        if flags.contains(USER_ACCESSIBLE) {
+            if self.violations() < MAX_LOGGED {
+                error!("kernel page at {:#x} is user accessible", addr);
+            }
+            self.user_accessible += 1;
+        }
+    }
+}
+
+impl fmt::Display for Audit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!( f, "{} pages mapped, {} writable and executable, \
+                    {} user accessible"
+              , self.pages, self.writable_executable, self.user_accessible)
+    }
+}
+
+/// Combine the flags of a parent entry with the flags of an entry it points
+/// to, giving the effective flags of the child.
+#[inline]
+fn effective(parent: EntryFlags, entry: EntryFlags) -> EntryFlags {
+    let mut flags = parent & entry;
+    if (parent | entry).contains(NO_EXECUTE) {
+        flags.insert(NO_EXECUTE);
+    }
+    flags
+}
+
+/// Returns the canonical virtual address for the given table indices.
+#[inline]
+fn addr(pml4: usize, pdpt: usize, pd: usize, pt: usize) -> usize {
+    let addr = (pml4 << 39) | (pdpt << 30) | (pd << 21) | (pt << 12);
+    // sign-extend bit 47 to make the address canonical
+    if pml4 >= N_ENTRIES / 2 { addr | 0xffff_0000_0000_0000 } else { addr }
+}
+
+/// Walk every mapping in `pml4` and check its effective permissions.
+///
+/// The recursive entry (511) is skipped, since it maps the page tables
+/// themselves rather than kernel memory.
+///
+/// # Returns
+/// + `Ok(Audit)` if no violations were found
+/// + `Err(Audit)` if any page is writable and executable, or is accessible
+///   from user mode.
+pub fn audit(pml4: &ActivePML4) -> Result<Audit, Audit> {
+    let mut audit = Audit::default();
+    let pml4 = pml4.top();
+    let root = PRESENT | WRITABLE | USER_ACCESSIBLE;
+
+    for i in 0 .. N_ENTRIES - 1 {
+        let pdpt = match pml4.next_table(i) {
+            Some(pdpt) => pdpt
+          , None => continue
+        };
+        let pml4_flags = effective(root, pml4[i].flags());
+
+        for j in 0 .. N_ENTRIES {
+            let pdpt_flags = effective(pml4_flags, pdpt[j].flags());
+            if pdpt[j].is_huge() && pdpt[j].flags().is_present() {
+                audit.check(addr(i, j, 0, 0), pdpt_flags);
+                continue
+            }
+            let pd = match pdpt.next_table(j) {
+                Some(pd) => pd
+              , None => continue
+            };
+
+            for k in 0 .. N_ENTRIES {
+                let pd_flags = effective(pdpt_flags, pd[k].flags());
+                if pd[k].is_huge() && pd[k].flags().is_present() {
+                    audit.check(addr(i, j, k, 0), pd_flags);
+                    continue
+                }
+                let pt = match pd.next_table(k) {
+                    Some(pt) => pt
+                  , None => continue
+                };
+
+                for l in 0 .. N_ENTRIES {
+                    if pt[l].flags().is_present() {
+                        audit.check( addr(i, j, k, l)
+                                   , effective(pd_flags, pt[l].flags()));
+                    }
+                }
+            }
+        }
+    }
+
+    if audit.is_ok() { Ok(audit) } else { Err(audit) }
+}
@@ -0,0 +1,152 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Page table levels.
+//!
+//! The `x86_64` architecture uses a four-level page table structure: the
+//! PML4, PDPT, PD, and PT. Entry 511 of the PML4 is the recursive entry, so
+//! the active PML4 is always at `0xffffffff_fffff000`.
+use alloc::FrameAllocator;
+use memory::{PhysicalPage, VirtualPage};
+
+use ::MapResult;
+use super::table::{EntryFlags, IndexOf, Sublevel, Table, TableLevel};
+
+/// Base virtual address of the PML4 table
+pub const PML4_VADDR: u64 =  0xffffffff_fffff000;
+
+/// A pointer to the PML4 table
+pub const PML4_PTR: *mut Table<PML4Level> = PML4_VADDR as *mut _;
+
+/// The top-level table is the PML4.
+pub type TopLevel = PML4Level;
+
+/// A pointer to the active top-level table
+pub const TOP_PTR: *mut Table<TopLevel> = PML4_PTR;
+
+/// The integer type of a physical address.
+pub type PAddrWord = u64;
+
+/// The integer type of a page table entry.
+pub type EntryWord = u64;
+
+/// The number of bits in a table index; each table has 512 entries.
+pub const ENTRY_INDEX_BITS: usize = 9;
+
+/// Mask to apply to a page table entry to isolate the physical address
+///
+/// N.B. that this excludes bit 63, which is the `NO_EXECUTE` flag.
+pub const ENTRY_ADDR_MASK: EntryWord = 0x000fffff_fffff000;
+
+pub enum PML4Level {}
+pub enum PDPTLevel {}
+pub enum PDLevel   {}
+pub enum PTLevel   {}
+
+impl TableLevel for PML4Level {
+    // TODO: make sure these values are correct!
+    //          - eliza, 5/29/2017
+    const ADDR_SHIFT_AMOUNT: usize = 39;
+    const PAGE_SHIFT_AMOUNT: usize = 27;
+}
+impl TableLevel for PDPTLevel {
+    const ADDR_SHIFT_AMOUNT: usize = 30;
+    const PAGE_SHIFT_AMOUNT: usize = 18;
+}
+impl TableLevel for PDLevel   {
+    const ADDR_SHIFT_AMOUNT: usize = 21;
+    const PAGE_SHIFT_AMOUNT: usize = 9;
+}
+impl TableLevel for PTLevel   {
+    const ADDR_SHIFT_AMOUNT: usize = 12;
+    const PAGE_SHIFT_AMOUNT: usize = 0;
+}
+
+impl Sublevel for PML4Level {
+    type Next = PDPTLevel;
+}
+impl Sublevel for PDPTLevel {
+    type Next = PDLevel;
+}
+impl Sublevel for PDLevel {
+    type Next = PTLevel;
+}
+
+impl Table<PML4Level> {
+    #[inline]
+    pub fn page_table_for(&self, page: VirtualPage) -> Option<&Table<PTLevel>> {
+        self.next_table(page)
+            .and_then(|pdpt| pdpt.next_table(page))
+            .and_then(|pd| pd.next_table(page))
+    }
+
+    #[inline]
+    pub fn page_table_mut_for(&mut self, page: VirtualPage)
+                             -> Option<&mut Table<PTLevel>> {
+        self.next_table_mut(page)
+            .and_then(|pdpt| pdpt.next_table_mut(page))
+            .and_then(|pd| pd.next_table_mut(page))
+    }
+
+    /// Returns the page table for `page`, creating any tables which don't
+    /// exist yet.
+    pub fn create_page_table<A>(&mut self, page: VirtualPage, alloc: &mut A)
+                               -> MapResult<&mut Table<PTLevel>>
+    where A: FrameAllocator {
+        // get or create the PDPT table at the page's PML4 index
+        self.create_next(page, alloc)
+            // get or create the PD table at the page's PDPT index
+            .and_then(|pdpt| pdpt.create_next(page, alloc))
+            // get or create the page table at the  page's PD table index
+            .and_then(|pd| pd.create_next(page, alloc))
+    }
+
+    /// Returns the frame which `page` is mapped to, including if it is in a
+    /// huge page.
+    pub fn translate_page(&self, page: VirtualPage) -> Option<PhysicalPage> {
+        let pdpt = self.next_table(page);
+
+        let huge_page = || {
+            pdpt.and_then(|pdpt|
+                pdpt[page]
+                    .do_huge(PDLevel::index_of(page) + PTLevel::index_of(page))
+                    .or_else(|| {
+                        pdpt.next_table(page).and_then(|pd|
+                            pd[page].do_huge(PTLevel::index_of(page))
+                        )
+                    })
+                )
+        };
+
+        pdpt.and_then(|pdpt| pdpt.next_table(page))
+            .and_then(|pd| pd.next_table(page))
+            .and_then(|pt| pt[page].get_frame())
+            .or_else(huge_page)
+    }
+
+    /// Returns the flags of the entry which maps `page`.
+    ///
+    /// This is the bottom-level page table entry, or the PDPT or PD entry if
+    /// `page` is in a huge page.
+    pub fn entry_flags(&self, page: VirtualPage) -> Option<EntryFlags> {
+        let present = |flags: EntryFlags| if flags.is_present() {
+            Some(flags)
+        } else {
+            None
+        };
+        let pdpt = self.next_table(page)?;
+        if pdpt[page].is_huge() {
+            return present(pdpt[page].flags())
+        }
+        let pd = pdpt.next_table(page)?;
+        if pd[page].is_huge() {
+            return present(pd[page].flags())
+        }
+        pd.next_table(page).and_then(|pt| present(pt[page].flags()))
+    }
+}
@@ -0,0 +1,353 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Memory-mapped I/O.
+//!
+//! Device memory (e.g. the local APIC, the HPET, or PCI BARs) must not be
+//! cached like normal memory. [`ioremap`] maps a range of physical device
+//! memory into the kernel's MMIO region with the requested [`CacheType`],
+//! and returns an [`Mmio`] handle which unmaps it again when dropped.
+//!
+//! Write-combining is not expressible with the `NO_CACHE` and
+//! `WRITE_THROUGH` bits alone, so we program the page attribute table
+//! (PAT) with a write-combining entry in [`init_pat`].
+//!
+//! [`ioremap`]: fn.ioremap.html
+//! [`CacheType`]: enum.CacheType.html
+//! [`Mmio`]: struct.Mmio.html
+//! [`init_pat`]: fn.init_pat.html
+use core::{fmt, ptr};
+use spin::Mutex;
+
+use alloc::{AllocResult, AllocErr, FrameAllocator};
+use memory::{ PAGE_SIZE, PAddr, Page, PhysicalPage, VAddr, VirtualPage
+            , FrameRange, MemRange, PageRange };
+
+use super::ActivePageTable;
+use super::table::*;
+use ::{Mapper, MapResult, MapErr};
+
+/// Base address of the kernel's MMIO region.
+///
+/// This is PML4 entry 509, below the kernel stacks.
+pub const MMIO_BASE: usize = 0xfffffe80_00000000;
+
+/// Number of pages in the MMIO region (512GiB worth).
+pub const MMIO_PAGES: usize = 1 << 27;
+
+/// The most released ranges of the MMIO region which are kept for reuse.
+pub const MAX_FREE_RANGES: usize = 32;
+
+/// The pages of the MMIO region which aren't mapped.
+static REGION: Mutex<Region> = Mutex::new(Region::new(MMIO_PAGES));
+
+/// Tracks which pages of the MMIO region are free.
+///
+/// Pages are numbered from the start of the region. Every page from `next`
+/// up is free; below that, ranges released by dropped [`Mmio`]s are kept in
+/// a small free list, so that short-lived mappings (such as the ACPI
+/// tables, which are mapped once to read the header and again to read the
+/// whole table) don't use the region up. Adjacent free ranges are merged,
+/// and a free range which ends at `next` is given back to it. If the free
+/// list is full, a released range is leaked.
+///
+/// [`Mmio`]: struct.Mmio.html
+#[derive(Debug)]
+pub struct Region { /// the number of pages in the region
+                    pages: usize
+                  , /// the first page above every allocated page
+                    next: usize
+                  , /// released ranges of pages, as `(start, end)`
+                    free: [Option<(usize, usize)>; MAX_FREE_RANGES]
+                  }
+
+impl Region {
+    /// Returns a new `Region` of `pages` pages, all of which are free.
+    pub const fn new(pages: usize) -> Self {
+        Region { pages: pages, next: 0, free: [None; MAX_FREE_RANGES] }
+    }
+
+    /// Take `n` contiguous pages from the region.
+    ///
+    /// # Returns
+    /// + `Some(usize)` with the number of the first page
+    /// + `None` if there aren't `n` contiguous free pages.
+    pub fn allocate(&mut self, n: usize) -> Option<usize> {
+        for slot in self.free.iter_mut() {
+            if let Some((start, end)) = *slot {
+                if end - start >= n {
+                    *slot = if end - start == n { None }
+                            else { Some((start + n, end)) };
+                    return Some(start)
+                }
+            }
+        }
+        if self.pages - self.next < n {
+            return None
+        }
+        let start = self.next;
+        self.next += n;
+        Some(start)
+    }
+
+    /// Give the `n` pages starting at `start` back to the region.
+    pub fn release(&mut self, start: usize, n: usize) {
+        let (mut start, mut end) = (start, start + n);
+        // free ranges are never adjacent, so there is at most one on each
+        // side to merge with.
+        for slot in self.free.iter_mut() {
+            match *slot {
+                Some((s, e)) if e == start => { start = s; *slot = None }
+              , Some((s, e)) if s == end => { end = e; *slot = None }
+              , _ => {}
+            }
+        }
+        if end == self.next {
+            self.next = start;
+            return
+        }
+        match self.free.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some((start, end))
+          , None => warn!( "leaking MMIO pages {:#x} .. {:#x}, since the free \
+                            list is full", start, end)
+        }
+    }
+}
+
+/// Memory types for the page attribute table (PAT).
+const PAT_UC: u64 = 0x00;
+const PAT_WC: u64 = 0x01;
+const PAT_WT: u64 = 0x04;
+const PAT_WP: u64 = 0x05;
+const PAT_WB: u64 = 0x06;
+const PAT_UC_MINUS: u64 = 0x07;
+
+/// The PAT we program.
+///
+/// Entries 0 through 3 are the power-on defaults, so that entries with the
+/// `PAT` bit unset keep their usual meaning. Entry 4 (`PAT` set, `NO_CACHE`
+/// and `WRITE_THROUGH` unset) is write-combining.
+const PAT: u64 = PAT_WB
+               | PAT_WT << 8
+               | PAT_UC_MINUS << 16
+               | PAT_UC << 24
+               | PAT_WC << 32
+               | PAT_WP << 40
+               | PAT_UC_MINUS << 48
+               | PAT_UC << 56;
+
+/// Program the page attribute table.
+///
+/// # Safety
+/// + Causes a general protection fault if not executed in kernel mode.
+/// + This must happen before any page is mapped with the `PAT` bit set.
+pub unsafe fn init_pat() {
+    use cpu::msr::{self, IA32_PAT};
+    msr::write(IA32_PAT, PAT);
+    super::tlb::flush_all();
+}
+
+/// Caching behaviour for memory-mapped I/O.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CacheType {
+    /// Reads and writes go straight to the device (UC).
+    Uncached
+  , /// Reads may be cached, but writes go straight to the device (WT).
+    WriteThrough
+  , /// Writes may be combined and delayed, reads are uncached (WC).
+    ///
+    /// This is useful for framebuffers.
+    WriteCombining
+  , /// Reads and writes are cached as for normal memory (WB).
+    ///
+    /// This is for firmware structures in RAM, such as the ACPI tables,
+    /// rather than device registers.
+    WriteBack
+}
+
+impl CacheType {
+    /// Returns the page table entry flags which select this cache type.
+    #[inline]
+    pub fn flags(&self) -> EntryFlags {
+        match *self {
+            CacheType::Uncached => NO_CACHE | WRITE_THROUGH
+          , CacheType::WriteThrough => WRITE_THROUGH
+          , CacheType::WriteCombining => PAT
+          , CacheType::WriteBack => EntryFlags::empty()
+        }
+    }
+}
+
+/// A "frame allocator" for device memory, which was never allocated and so
+/// must never be freed.
+///
+/// This can be passed to `unmap` to unmap any other memory which didn't come
+/// from a frame allocator, too.
+pub struct DeviceFrames;
+
+impl FrameAllocator for DeviceFrames {
+    unsafe fn allocate(&mut self) -> AllocResult<PhysicalPage> {
+        Err(AllocErr::Unsupported {
+            details: "device memory frames cannot be allocated"
+        })
+    }
+
+    unsafe fn deallocate(&mut self, _frame: PhysicalPage) { }
+
+    unsafe fn allocate_range(&mut self, _num: usize)
+                            -> AllocResult<FrameRange> {
+        Err(AllocErr::Unsupported {
+            details: "device memory frames cannot be allocated"
+        })
+    }
+
+    unsafe fn deallocate_range(&mut self, _range: FrameRange) { }
+}
+
+/// A handle on a range of mapped device memory.
+///
+/// The memory is unmapped when the handle is dropped.
+pub struct Mmio { pages: PageRange
+                , /// the virtual address of the start of the device memory
+                  base: VAddr
+                , /// the length of the device memory, in bytes
+                  len: usize
+                , cache: CacheType
+                }
+
+impl Mmio {
+    /// Returns the virtual address of the start of the device memory.
+    #[inline] pub fn base(&self) -> VAddr { self.base }
+
+    /// Returns the length of the device memory, in bytes.
+    #[inline] pub fn len(&self) -> usize { self.len }
+
+    /// Returns the cache type the device memory is mapped with.
+    #[inline] pub fn cache_type(&self) -> CacheType { self.cache }
+
+    /// Returns a pointer to the device memory at `offset`.
+    ///
+    /// # Panics
+    /// + If a `T` at `offset` would not fit inside the device memory.
+    #[inline]
+    pub fn as_ptr<T>(&self, offset: usize) -> *mut T {
+        assert!( offset + ::core::mem::size_of::<T>() <= self.len
+               , "MMIO offset {:#x} is out of bounds", offset);
+        (self.base + offset).as_mut_ptr()
+    }
+
+    /// Perform a volatile read of a `T` at `offset`.
+    ///
+    /// # Safety
+    /// + Reading from device registers may have side effects.
+    #[inline]
+    pub unsafe fn read<T>(&self, offset: usize) -> T {
+        ptr::read_volatile(self.as_ptr(offset))
+    }
+
+    /// Perform a volatile write of `value` at `offset`.
+    ///
+    /// # Safety
+    /// + Writing to device registers may have side effects.
+    #[inline]
+    pub unsafe fn write<T>(&self, offset: usize, value: T) {
+        ptr::write_volatile(self.as_ptr(offset), value)
+    }
+}
+
+impl fmt::Debug for Mmio {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!( f, "MMIO {:#x} bytes at {:?} ({:?})"
+              , self.len, self.base, self.cache)
+    }
+}
+
+impl Drop for Mmio {
+    fn drop(&mut self) {
+        // the frames are device memory, so we mustn't give them to the
+        // frame allocator.
+        let mut table = unsafe { ActivePageTable::new() };
+        match table.unmap_range(self.pages.clone(), &mut DeviceFrames) {
+            // the pages may only be reused once they're no longer mapped.
+            Ok(()) => REGION.lock().release( region_page(self.pages.start)
+                                           , self.pages.length())
+          , Err(why) => warn!("could not unmap {:?}: {:?}", self, why)
+        }
+    }
+}
+
+/// Returns the number of `page` within the MMIO region.
+#[inline]
+fn region_page(page: VirtualPage) -> usize {
+    page.number - (MMIO_BASE >> 12)
+}
+
+/// Map `len` bytes of device memory starting at `paddr` into the kernel's
+/// MMIO region.
+///
+/// The memory is mapped writable and non-executable, with the given
+/// `cache` type.
+///
+/// # Arguments
+/// + `table`: the active page table
+/// + `paddr`: the physical address of the device memory
+/// + `len`: the length of the device memory, in bytes
+/// + `cache`: how the device memory should be cached
+/// + `alloc`: a frame allocator, for creating any new page tables.
+///
+/// # Returns
+/// + `Ok(Mmio)` with a handle on the mapped memory, which unmaps it when
+///   dropped.
+/// + `Err(MapErr)` if the MMIO region is exhausted, or mapping failed. No
+///   device memory is left mapped.
+pub fn ioremap<A>( table: &mut ActivePageTable
+                 , paddr: PAddr
+                 , len: usize
+                 , cache: CacheType
+                 , alloc: &mut A)
+                 -> MapResult<Mmio>
+where A: FrameAllocator {
+    if len == 0 {
+        return Err(MapErr::NoPage {
+            message: "ioremap"
+          , cause: "cannot map zero bytes of device memory"
+        })
+    }
+    let start_frame = PhysicalPage::containing(paddr);
+    let end_frame = PhysicalPage::containing(paddr + (len as u64 - 1)) + 1;
+    let n_pages = (end_frame.number - start_frame.number) as usize;
+
+    let number = REGION.lock().allocate(n_pages)
+        .ok_or(MapErr::NoPage {
+            message: "ioremap"
+          , cause: "the MMIO region is exhausted"
+        })?;
+    let start_page = VirtualPage { number: (MMIO_BASE >> 12) + number };
+    let pages = start_page .. start_page + n_pages;
+
+    let flags = WRITABLE | NO_EXECUTE | cache.flags();
+    for (page, frame) in pages.clone().zip(start_frame .. end_frame) {
+        if let Err(why) = table.map(page, frame, flags, alloc) {
+            // there's no `Mmio` to unmap the pages that were mapped, so we
+            // have to unmap them here.
+            if table.unmap_range(pages.start .. page, &mut DeviceFrames)
+                    .is_ok() {
+                REGION.lock().release(number, n_pages);
+            }
+            return Err(why)
+        }
+    }
+
+    let offset = (*paddr % PAGE_SIZE) as usize;
+    trace!("ioremapped {:?} to {:?} ({:?})", paddr, start_page, cache);
+    Ok(Mmio { pages: pages
+            , base: start_page.base() + offset
+            , len: len
+            , cache: cache
+            })
+}
@@ -0,0 +1,141 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Mock paging backend for running tests on the host.
+//!
+//! Physical memory is simulated by a `Vec` of frames. Each test thread gets
+//! its own simulated memory and its own simulated `%cr3`, so tests can run in
+//! parallel without stepping on each other's page tables.
+use std::cell::{Cell, RefCell};
+use std::vec::Vec;
+
+use alloc::{AllocResult, AllocErr, FrameAllocator, Layout};
+use memory::{PAGE_SIZE, FrameRange, PageRange, PhysicalPage, VAddr, VirtualPage};
+
+use super::backend::Backend;
+use super::table::{Table, PML4Level, N_ENTRIES};
+use super::ActivePageTable;
+
+/// The number of frames of simulated physical memory.
+pub const N_FRAMES: usize = 128;
+
+type Frame = [u64; N_ENTRIES];
+
+thread_local! {
+    /// This thread's simulated physical memory.
+    ///
+    /// The `Vec` is never resized, so pointers into it stay valid for the
+    /// lifetime of the thread.
+    static MEMORY: RefCell<Vec<Frame>>
+        = RefCell::new((0..N_FRAMES).map(|_| [0; N_ENTRIES]).collect());
+
+    /// This thread's simulated `%cr3` register.
+    static CR3: Cell<Option<PhysicalPage>> = Cell::new(None);
+}
+
+/// Returns a pointer to `frame` in this thread's simulated memory.
+fn frame_ptr<T>(frame: PhysicalPage) -> *mut T {
+    assert!( (frame.number as usize) < N_FRAMES
+           , "{:?} is outside of simulated physical memory", frame);
+    MEMORY.with(|memory| unsafe {
+        memory.borrow_mut().as_mut_ptr().offset(frame.number as isize)
+              as *mut T
+    })
+}
+
+/// The backend for running tests on the host.
+pub enum Mock {}
+
+impl Backend for Mock {
+    #[inline]
+    fn next_table_addr(_table: VAddr, _i: usize, frame: PhysicalPage) -> VAddr {
+        VAddr::from(frame_ptr::<u8>(frame) as usize)
+    }
+
+    #[inline]
+    fn active_table() -> *mut Table<PML4Level> {
+        frame_ptr(CR3.with(Cell::get).expect("no mock page table is active"))
+    }
+
+    #[inline]
+    fn inactive_table(frame: PhysicalPage) -> Option<*mut Table<PML4Level>> {
+        Some(frame_ptr(frame))
+    }
+
+    unsafe fn current_pagetable_frame() -> PhysicalPage {
+        CR3.with(Cell::get).expect("no mock page table is active")
+    }
+
+    unsafe fn set_pagetable_frame(frame: PhysicalPage) {
+        CR3.with(|cr3| cr3.set(Some(frame)))
+    }
+
+    // there's no TLB to flush.
+    unsafe fn flush(_page: VirtualPage) { }
+    unsafe fn flush_range(_pages: PageRange) { }
+    unsafe fn flush_all() { }
+}
+
+/// A frame allocator which hands out frames of simulated physical memory.
+///
+/// Frame 0 is never allocated, so that a zero entry can't be mistaken for a
+/// mapping. Deallocated frames are remembered, so tests can check that
+/// unmapping freed them.
+#[derive(Debug)]
+pub struct MockFrames { next: u64
+                      , pub freed: Vec<PhysicalPage>
+                      }
+
+impl MockFrames {
+    pub fn new() -> Self {
+        MockFrames { next: 1, freed: Vec::new() }
+    }
+
+    /// Returns the number of frames allocated so far.
+    pub fn allocated(&self) -> usize {
+        self.next as usize - 1
+    }
+}
+
+impl FrameAllocator for MockFrames {
+    unsafe fn allocate(&mut self) -> AllocResult<PhysicalPage> {
+        self.allocate_range(1).map(|range| range.start)
+    }
+
+    unsafe fn deallocate(&mut self, frame: PhysicalPage) {
+        self.freed.push(frame)
+    }
+
+    unsafe fn allocate_range(&mut self, num: usize)
+                            -> AllocResult<FrameRange> {
+        if self.next as usize + num > N_FRAMES {
+            return Err(AllocErr::Exhausted {
+                request: Layout::from_size_align( PAGE_SIZE as usize * num
+                                                , PAGE_SIZE as usize)
+            })
+        }
+        let start = PhysicalPage { number: self.next };
+        self.next += num as u64;
+        Ok(start .. PhysicalPage { number: self.next })
+    }
+
+    unsafe fn deallocate_range(&mut self, range: FrameRange) {
+        for frame in range {
+            self.deallocate(frame)
+        }
+    }
+}
+
+/// Create a new, empty, simulated address space and make it active.
+pub fn address_space(frames: &mut MockFrames) -> ActivePageTable {
+    unsafe {
+        let pml4 = frames.allocate().expect("allocate mock PML4");
+        Mock::set_pagetable_frame(pml4);
+        ActivePageTable::new()
+    }
+}
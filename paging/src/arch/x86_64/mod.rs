@@ -12,21 +12,34 @@
 //! page table is called the Page Meta-Level 4 (PML4) table, followed by
 //! the Page Directory Pointer Table (PDPT), Page Directory (PD) table, and
 //! finally the bottom-level Page Table (PT).
-use core::{fmt, ops};
-use core::ptr::Unique;
+use core::ops;
 
 use alloc::FrameAllocator;
-use memory::{Addr, PAGE_SIZE, PAddr, Page, PhysicalPage, VAddr, VirtualPage};
+use memory::{ PAddr, Page, PhysicalPage, VAddr, VirtualPage, PHYS_MAP_OFFSET };
 use params::InitParams;
 use ::{Mapper, MapResult, MapErr};
 
+use self::active::{identity_map_checked, identity_map_section};
+use self::backend::{Backend, Current};
 use self::table::*;
 use self::temp::TempPage;
 
-pub mod table;
-pub mod tlb;
-pub mod temp;
+pub use self::active::ActiveTable as ActivePML4;
+
+pub mod audit;
+#[path = "../x86_all/backend.rs"] pub mod backend;
+pub mod levels;
+#[path = "../x86_all/table.rs"] pub mod table;
+#[path = "../x86_all/tlb.rs"] pub mod tlb;
+#[path = "../x86_all/temp.rs"] pub mod temp;
+#[path = "../x86_all/active.rs"] mod active;
 pub mod cr3;
+pub mod mmio;
+pub mod physmap;
+
+#[cfg(test)] pub mod mock;
+#[cfg(test)] mod tests;
+
 #[derive(Debug)]
 pub struct ActivePageTable { pml4: ActivePML4 }
 
@@ -51,29 +64,39 @@ impl ActivePageTable {
 
     /// Execute a closure with the recursive mapping temporarily changed to a
     /// new page table
+    ///
+    /// If the paging backend can access inactive tables directly (e.g. the
+    /// direct map of physical memory is enabled), the recursive mapping is
+    /// left alone and the inactive table is edited directly instead (see
+    /// [`InactivePageTable::edit`]).
+    ///
+    /// [`InactivePageTable::edit`]: struct.InactivePageTable.html#method.edit
     pub fn using<F>( &mut self
                    , table: &mut InactivePageTable
                    , temp_page: &mut temp::TempPage
                    , f: F)
                    -> MapResult
     where F: FnOnce(&mut ActivePML4) -> MapResult {
+        if Current::inactive_table(table.pml4_frame).is_some() {
+            return table.edit(f)
+        }
+
         let result: MapResult;
-        use self::tlb::flush_all;
         {
             // back up the current PML4 frame
             let prev_pml4_frame = unsafe {
                 // this is safe to execute; we are in kernel mode
-                cr3::current_pagetable_frame()
+                Current::current_pagetable_frame()
             };
 
             // map temporary_page to current p4 table
             let pml4 = temp_page.map_to_table(prev_pml4_frame.clone(), self)?;
 
             // remap the 511th PML4 entry (the recursive entry) to map to the // frame containing the new PML4.
-            self.pml4_mut()[511].set(table.pml4_frame, PRESENT | WRITABLE);
+            self.top_mut()[511].set(table.pml4_frame, PRESENT | WRITABLE);
             unsafe {
                 // this is safe to execute; we are in kernel mode
-                flush_all();
+                Current::flush_all();
             }
 
             // execute the closure
@@ -84,7 +107,7 @@ impl ActivePageTable {
 
             unsafe {
                 // this is safe to execute; we are in kernel mode
-                flush_all();
+                Current::flush_all();
             }
         }
         let _ = temp_page.unmap(self)?;
@@ -105,10 +128,10 @@ impl ActivePageTable {
         unsafe {
             trace!("replacing {:?} with {:?}", self, new_table);
             // this is safe to execute; we are in kernel mode
-            let old_pml4_frame = cr3::current_pagetable_frame();
+            let old_pml4_frame = Current::current_pagetable_frame();
             trace!("current pml4 frame is {:?}", old_pml4_frame);
 
-            cr3::set_pagetable_frame(new_table.pml4_frame);
+            Current::set_pagetable_frame(new_table.pml4_frame);
             trace!("set new pml4 frame to {:?}", new_table.pml4_frame);
 
             InactivePageTable {
@@ -119,185 +142,6 @@ impl ActivePageTable {
 
 }
 
-/// Struct representing the currently active PML4 instance.
-///
-/// The `ActivePML4` is a `Unique` reference to a PML4-level page table. It's
-/// unique because, well, there can only be one active PML4 at a given time.
-///
-pub struct ActivePML4(Unique<Table<PML4Level>>);
-impl fmt::Debug for ActivePML4 {
-    #[inline]
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Active {:?}", unsafe { self.0.as_ref() })
-    }
-}
-/// The active PML4 table is the single point of entry for page mapping.
-impl Mapper for ActivePML4 {
-    type Flags = EntryFlags;
-
-    fn translate(&self, vaddr: VAddr) -> Option<PAddr> {
-        let offset = *vaddr % PAGE_SIZE as usize;
-        self.translate_page(Page::containing(vaddr))
-            .map(|frame| PAddr::from(frame.number + offset as u64) )
-    }
-
-    fn translate_page(&self, page: VirtualPage) -> Option<PhysicalPage> {
-        let pdpt = self.pml4().next_table(page);
-
-        let huge_page = || {
-            pdpt.and_then(|pdpt|
-                pdpt[page]
-                    .do_huge(PDLevel::index_of(page) + PTLevel::index_of(page))
-                    .or_else(|| {
-                        pdpt.next_table(page).and_then(|pd|
-                            pd[page].do_huge(PTLevel::index_of(page))
-                        )
-                    })
-                )
-        };
-
-        pdpt.and_then(|pdpt| pdpt.next_table(page))
-            .and_then(|pd| pd.next_table(page))
-            .and_then(|pt| pt[page].get_frame())
-            .or_else(huge_page)
-    }
-
-
-    /// Modifies the page tables so that `page` maps to `frame`.
-    ///
-    /// # Arguments
-    /// + `page`: the virtual `Page` to map
-    /// + `frame`: the physical `Frame` that `Page` should map to.
-    /// + `flags`: the page table entry flags.
-    /// + `alloc`: a memory allocator
-    fn map<A>( &mut self, page: VirtualPage, frame: PhysicalPage
-             , flags: EntryFlags, alloc: &mut A)
-             -> MapResult<()>
-    where A: FrameAllocator {
-        // base virtual address of page being mapped
-        // let addr = page.base();
-
-        // access or create all the lower-level page tables.
-        let mut page_table // get the PML4
-            = self.pml4_mut()
-                  // get or create the PDPT table at the page's PML4 index
-                  .create_next(page, alloc)
-                  // get or create the PD table at the page's PDPT index
-                  .and_then(|pdpt| pdpt.create_next(page, alloc))
-                  // get or create the page table at the  page's PD table index
-                  .and_then(|pd| pd.create_next(page, alloc))?;
-        trace!(" . . Map: Got page table");
-        // check if the page at that index is not currently in use, as we
-        // cannot map a page which is currently in use.
-        if page_table[page].is_unused() {
-            // set the page table entry at that index
-            page_table[page].set(frame, flags | table::PRESENT);
-            Ok(())
-        } else {
-            Err(MapErr::AlreadyInUse {
-                message: "map frame"
-              , page: page
-              , frame: frame
-            })
-        }
-    }
-
-    fn identity_map<A>(&mut self, frame: PhysicalPage, flags: EntryFlags
-                      , alloc: &mut A)
-                      -> MapResult<()>
-    where A: FrameAllocator {
-        self.map( Page::containing(VAddr::from(*frame.base_addr() as usize))
-                , frame
-                , flags
-                , alloc )
-    }
-
-    fn map_to_any<A>( &mut self
-                    , page: VirtualPage
-                    , flags: EntryFlags
-                    , alloc: &mut A)
-                    -> MapResult<()>
-    where A: FrameAllocator {
-        let frame = unsafe { alloc.allocate() }
-            .map_err(|err| MapErr::Alloc {
-                message: "map to any"
-              , page: page
-              , cause: err
-          })?;
-        self.map(page, frame, flags, alloc)
-    }
-
-    /// Unmap the given `VirtualPage`.
-    ///
-    /// All freed frames are returned to the given `FrameAllocator`.
-    fn unmap<A>(&mut self, page: VirtualPage, alloc: &mut A) -> MapResult<()>
-    where A: FrameAllocator {
-        use self::tlb::Flush;
-
-        // get the page table entry corresponding to the page.
-        let page_table = self.pml4_mut()
-                             .next_table_mut(page)
-                             .and_then(|pdpt| pdpt.next_table_mut(page))
-                             .and_then(|pd| pd.next_table_mut(page))
-                             .ok_or(MapErr::Other {
-                                message: "unmap"
-                              , page: page
-                              , cause: "huge pages not supported"
-                            })?;
-        // index the entry from the table
-        let entry = &mut page_table[page];
-        trace!("got page table entry for {:?}", page);
-        // get the pointed frame for the page table entry.
-        let frame = entry.get_frame()
-                         .ok_or(MapErr::Other {
-                           message: "unmap"
-                         , page: page
-                         , cause: "it was not mapped"
-                       })?;
-        trace!("page table entry for {:?} points to {:?}", page, frame);
-        // mark the page table entry as unused
-        entry.set_unused();
-        trace!("set page table entry for {:?} as unused", page);
-        // deallocate the frame and flush the translation lookaside buffer
-        // this is safe because we're in kernel mode
-        unsafe { page.invlpg() };
-        trace!("flushed TLB");
-        unsafe {
-            // this is hopefully safe because nobody else should be using an
-            // allocated page frame
-            alloc.deallocate(frame);
-            trace!("deallocated page {:?}", frame);
-        }
-        // TODO: check if page tables containing the unmapped page are empty
-        //       and deallocate them too?
-        Ok(())
-    }
-
-}
-
-impl ActivePML4 {
-
-    pub unsafe fn new() -> Self {
-        ActivePML4(Unique::new(PML4_PTR))
-    }
-
-    fn pml4(&self) -> &Table<PML4Level> {
-        unsafe { self.0.as_ref() }
-    }
-
-    fn pml4_mut(&mut self) -> &mut Table<PML4Level> {
-        unsafe { self.0.as_mut() }
-    }
-
-    /// Returns true if the given page is mapped.
-    #[inline]
-    pub fn is_mapped(&self, page: &VirtualPage) -> bool {
-         self.translate_page(*page).is_some()
-    }
-
-
-}
-
 /// An inactive page table that the CPU is not currently using
 #[derive(Debug)]
 pub struct InactivePageTable {
@@ -305,10 +149,40 @@ pub struct InactivePageTable {
 }
 
 impl InactivePageTable {
-    pub fn new( frame: PhysicalPage
-              , active_table: &mut ActivePageTable
-              , temp: &mut TempPage)
-              -> MapResult<Self> {
+    /// Create a new `InactivePageTable` in the given frame.
+    ///
+    /// If the paging backend can access inactive tables directly (e.g. the
+    /// direct map of physical memory is enabled), the new table is zeroed
+    /// directly, and it shares the active table's direct map entries; the
+    /// `TempPage` is not used. Otherwise, the table is zeroed by mapping it
+    /// to `temp`.
+    ///
+    /// Lower-level tables are only allocated when something is mapped, so
+    /// `alloc` is not used. It is taken so that this has the same signature
+    /// as on 32-bit `x86`, where PAE page directories must be allocated up
+    /// front.
+    pub fn new<A>( frame: PhysicalPage
+                 , active_table: &mut ActivePageTable
+                 , temp: &mut TempPage
+                 , _alloc: &mut A)
+                 -> MapResult<Self>
+    where A: FrameAllocator {
+        if let Some(table) = Current::inactive_table(frame) {
+            let table = unsafe { &mut *table };
+            table.zero();
+            trace!( " . . . Zeroed inactive table frame.");
+            // share the direct map with the active table.
+            let active = active_table.top();
+            for i in PHYSMAP_PML4_INDEX .. 511 {
+                if let Some(frame) = active[i].get_frame() {
+                    table[i].set(frame, active[i].flags());
+                }
+            }
+            table[511].set( frame.clone(), PRESENT | WRITABLE);
+            trace!(" . . . Set recursive entry of new inactive table.");
+            return Ok(InactivePageTable { pml4_frame: frame })
+        }
+
         {
             trace!("Mapping page {} to frame {}", temp.number, frame.number);
             let table = temp.map_to_table(frame.clone(), active_table)?;
@@ -323,8 +197,29 @@ impl InactivePageTable {
 
         Ok(InactivePageTable { pml4_frame: frame })
     }
+
+    /// Execute a closure which edits this table directly (e.g. through the
+    /// direct map of physical memory).
+    ///
+    /// Unlike [`ActivePageTable::using`], this doesn't touch the recursive
+    /// mapping, so the TLB does not need to be flushed.
+    ///
+    /// # Panics
+    /// + If the paging backend can't access inactive tables directly.
+    ///
+    /// [`ActivePageTable::using`]: struct.ActivePageTable.html#method.using
+    pub fn edit<F>(&mut self, f: F) -> MapResult
+    where F: FnOnce(&mut ActivePML4) -> MapResult {
+        let table_ptr = Current::inactive_table(self.pml4_frame)
+            .expect("cannot edit an inactive table without the direct map!");
+        let mut pml4 = unsafe { ActivePML4::at(table_ptr) };
+        f(&mut pml4)
+    }
 }
 
+/// Index of the first PML4 entry in the direct map of physical memory.
+const PHYSMAP_PML4_INDEX: usize = (PHYS_MAP_OFFSET >> 39) as usize & 0o777;
+
 pub fn test_paging<A>(alloc: &mut A) -> MapResult<()>
 where A: FrameAllocator {
     info!("testing paging");
@@ -379,18 +274,16 @@ where A: FrameAllocator {
     let mut current_table = unsafe { ActivePageTable::new() };
     trace!("Got current page table.");
 
-    let mut new_table = unsafe {
-        InactivePageTable::new(
-             alloc.allocate()
-                  .map_err(|err| MapErr::Alloc {
-                      message: "create the new page table"
-                     , page: *temp_page
-                     , cause: err
-                 })?
-          , &mut current_table
-          , &mut temp_page
-      )?
-    };
+    let pml4_frame = unsafe { alloc.allocate() }
+        .map_err(|err| MapErr::Alloc {
+            message: "create the new page table"
+          , page: *temp_page
+          , cause: err
+        })?;
+    let mut new_table = InactivePageTable::new( pml4_frame
+                                              , &mut current_table
+                                              , &mut temp_page
+                                              , alloc )?;
     kinfoln!(dots: " . . ", "Created new {:?}", new_table);
 
     // actually remap the kernel --------------------------------------------
@@ -403,30 +296,15 @@ where A: FrameAllocator {
         kinfoln!(dots: " . . ", "Remapping kernel ELF sections.");
 
         for section in sections { // remap ELF sections
-            attempt!(
-                if section.address().is_page_aligned() {
-                    let flags = EntryFlags::from(section);
-
-                    let start_frame = PhysicalPage::from(section.address());
-                    let end_frame = PhysicalPage::from(section.end_address());
-
-                    for frame in start_frame .. end_frame {
-                        let _ = pml4.identity_map(frame, flags, alloc)?;
-                    }
-                    Ok(())
-                } else {
-                    Err(MapErr::NoPage::<VirtualPage> {
-                        message: "identity map section"
-                      , cause: "the start address was not page aligned"
-                    })
-                } =>
+            attempt!( identity_map_section(pml4, section, alloc) =>
                       dots: " . . . ",
                       "Identity mapping {}", section );
         }
 
         // remap VGA buffer
         let vga_buffer_frame = PhysicalPage::containing(PAddr::from(0xb8000));
-        attempt!( pml4.identity_map(vga_buffer_frame, WRITABLE, alloc) =>
+        attempt!( identity_map_checked( pml4, vga_buffer_frame
+                                      , WRITABLE | NO_EXECUTE, alloc) =>
                   dots: " . . ", "Identity mapping VGA buffer" );
 
 
@@ -436,9 +314,14 @@ where A: FrameAllocator {
         let multiboot_end = PhysicalPage::from(params.multiboot_end());
 
         for frame in multiboot_start .. multiboot_end {
-            let _ = pml4.identity_map(frame, PRESENT, alloc)?;
+            let _ = identity_map_checked( pml4, frame, PRESENT | NO_EXECUTE
+                                        , alloc)?;
                 // .expect("couldn't identity map Multiboot {:?}", frame);
         }
+
+        // map all of physical memory into the higher half
+        attempt!( physmap::map_physical_memory(pml4, params, alloc) =>
+                  dots: " . . ", "Creating direct map of physical memory" );
         Ok(())
     })?;
 
@@ -447,10 +330,25 @@ where A: FrameAllocator {
     let old_table = current_table.replace_with(new_table);
     kinfoln!(dots: " . . ", "Successfully switched to remapped page table!");
 
+    // the new table contains the direct map, so we can start using it.
+    unsafe { physmap::enable() };
+
+    // set up the page attribute table before anything is mapped with it.
+    unsafe { mmio::init_pat() };
+    kinfoln!(dots: " . . ", "Programmed the page attribute table.");
+
+    // make sure the kernel can't write to read-only pages either.
+    unsafe { ::cpu::control_regs::cr0::enable_write_protect(true) };
+    kinfoln!(dots: " . . ", "Enabled kernel write protection.");
+
     // create guard page at the location of the old PML4 table
     let old_pml4_vaddr = VAddr::from(*(old_table.pml4_frame.base()) as usize);
     let old_pml4_page  = VirtualPage::containing(old_pml4_vaddr);
     let _ = current_table.unmap(old_pml4_page, alloc)?;
     trace!("Unmapped guard page at {:?}", old_pml4_page.base());
+
+    let audit = attempt!( audit::audit(&current_table) =>
+                          dots: " . . ", "Auditing kernel page tables" );
+    kinfoln!(dots: " . . . ", "{}", audit);
     Ok(current_table)
 }
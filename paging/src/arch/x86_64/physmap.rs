@@ -0,0 +1,151 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Direct map of physical memory.
+//!
+//! All usable physical memory is mapped into the higher half of the address
+//! space, starting at [`PHYS_MAP_OFFSET`]. Once the direct map is enabled,
+//! any frame can be accessed through [`PhysicalPage::to_virtual`], and the
+//! page table code walks tables through the direct map rather than through
+//! the recursive PML4 entry. This means that we no longer need to juggle a
+//! `TempPage` to edit page tables which are not currently active.
+//!
+//! Only usable memory is mapped. Reserved and MMIO ranges must not be, since
+//! the direct map is write-back cacheable, and a device mapped uncached by
+//! `ioremap` must not also be mapped with a different memory type. So 2MiB
+//! pages are only used where a whole 2MiB region is usable, and the rest of
+//! each area is mapped with 4KiB pages.
+//!
+//! [`PHYS_MAP_OFFSET`]: ../../../memory/arch/constant.PHYS_MAP_OFFSET.html
+//! [`PhysicalPage::to_virtual`]: ../../../memory/arch/struct.PhysicalPage.html#method.to_virtual
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+
+use alloc::FrameAllocator;
+use memory::{ LARGE_PAGE_SIZE, PAGE_SIZE, PAddr, Page, PhysicalPage };
+use params::InitParams;
+
+use super::ActivePML4;
+use super::active::enforce_wx;
+use super::table::*;
+use ::{MapResult, MapErr};
+
+/// Whether or not the direct map of physical memory is in use.
+static ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Returns true if the direct map of physical memory has been enabled.
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Mark the direct map of physical memory as enabled.
+///
+/// # Safety
+/// + The direct map must have been created by [`map_physical_memory`] in the
+///   page table which is currently active, and every page table created
+///   afterwards must share the direct map's PML4 entries.
+///
+/// [`map_physical_memory`]: fn.map_physical_memory.html
+pub unsafe fn enable() {
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Flags for entries in the direct map.
+///
+/// The direct map is never executable, and is global since it is the same in
+/// every address space. 2MiB pages also set `HUGE_PAGE`.
+#[inline]
+pub fn flags() -> EntryFlags {
+    PRESENT | WRITABLE | NO_EXECUTE | GLOBAL
+}
+
+/// Map every usable memory area into the direct map of physical memory.
+///
+/// Each area is shrunk to whole 4KiB pages. The 2MiB regions that lie
+/// entirely inside it are mapped with 2MiB pages, and its unaligned head and
+/// tail with 4KiB pages, so nothing outside the area is mapped.
+pub fn map_physical_memory<A>( pml4: &mut ActivePML4
+                             , params: &InitParams
+                             , alloc: &mut A)
+                             -> MapResult<()>
+where A: FrameAllocator {
+    for area in params.mem_map() {
+        let start = (*area.start_addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let end = *area.end_addr & !(PAGE_SIZE - 1);
+        if start >= end {
+            continue
+        }
+        trace!( " . . . Direct mapping {:#x} .. {:#x}", start, end);
+        // every page in the direct map has the same flags.
+        enforce_wx(flags(), PAddr::from(start))?;
+
+        let large_start = (start + LARGE_PAGE_SIZE - 1)
+                        & !(LARGE_PAGE_SIZE - 1);
+        let large_end = end & !(LARGE_PAGE_SIZE - 1);
+        if large_start < large_end {
+            map_small(pml4, start, large_start, alloc)?;
+            map_large(pml4, large_start, large_end, alloc)?;
+            map_small(pml4, large_end, end, alloc)?;
+        } else {
+            // the area doesn't contain a whole 2MiB region.
+            map_small(pml4, start, end, alloc)?;
+        }
+    }
+    Ok(())
+}
+
+/// Direct map `start .. end` with 2MiB pages.
+fn map_large<A>(pml4: &mut ActivePML4, start: u64, end: u64, alloc: &mut A)
+               -> MapResult<()>
+where A: FrameAllocator {
+    let mut addr = start;
+    while addr < end {
+        let frame = PhysicalPage::containing(PAddr::from(addr));
+        let page = frame.to_virtual();
+        let pd = pml4.top_mut()
+                     .create_next(page, alloc)
+                     .and_then(|pdpt| pdpt.create_next(page, alloc))?;
+        if pd[page].is_unused() {
+            pd[page].set(frame, flags() | HUGE_PAGE);
+        } else {
+            return Err(MapErr::AlreadyInUse {
+                message: "direct map physical memory"
+              , page: page
+              , frame: frame
+            })
+        }
+        addr += LARGE_PAGE_SIZE;
+    }
+    Ok(())
+}
+
+/// Direct map `start .. end` with 4KiB pages.
+///
+/// Pages which already map the same frame (e.g. because the memory map
+/// lists two adjacent areas which share a page) are skipped.
+fn map_small<A>(pml4: &mut ActivePML4, start: u64, end: u64, alloc: &mut A)
+               -> MapResult<()>
+where A: FrameAllocator {
+    let mut addr = start;
+    while addr < end {
+        let frame = PhysicalPage::containing(PAddr::from(addr));
+        let page = frame.to_virtual();
+        let pt = pml4.top_mut().create_page_table(page, alloc)?;
+        if pt[page].is_unused() {
+            pt[page].set(frame, flags());
+        } else if pt[page].get_frame() != Some(frame) {
+            return Err(MapErr::AlreadyInUse {
+                message: "direct map physical memory"
+              , page: page
+              , frame: frame
+            })
+        }
+        addr += PAGE_SIZE;
+    }
+    Ok(())
+}
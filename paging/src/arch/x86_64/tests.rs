@@ -0,0 +1,305 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+use super::*;
+use super::mock::{self, MockFrames};
+
+fn page(number: usize) -> VirtualPage { VirtualPage { number: number } }
+
+#[test]
+fn test_map_translate() {
+    let mut frames = MockFrames::new();
+    let mut table = mock::address_space(&mut frames);
+    let frame = unsafe { frames.allocate().unwrap() };
+
+    assert!(table.translate_page(page(0xdead)).is_none());
+    assert!(table.map(page(0xdead), frame, WRITABLE, &mut frames).is_ok());
+    assert_eq!(table.translate_page(page(0xdead)), Some(frame));
+    assert_eq!( table.translate(page(0xdead).base() + 42)
+              , Some(frame.base() + 42));
+    // pages sharing a page table with the mapped page are still unmapped.
+    assert!(table.translate_page(page(0xdeae)).is_none());
+}
+
+#[test]
+fn test_map_already_in_use() {
+    let mut frames = MockFrames::new();
+    let mut table = mock::address_space(&mut frames);
+    let frame = unsafe { frames.allocate().unwrap() };
+
+    assert!(table.map(page(1), frame, WRITABLE, &mut frames).is_ok());
+    assert!(table.map(page(1), frame, WRITABLE, &mut frames).is_err());
+}
+
+#[test]
+fn test_unmap_frees_frame() {
+    let mut frames = MockFrames::new();
+    let mut table = mock::address_space(&mut frames);
+
+    assert!(table.map_to_any(page(0xcafe), WRITABLE, &mut frames).is_ok());
+    let frame = table.translate_page(page(0xcafe)).unwrap();
+    assert!(table.unmap(page(0xcafe), &mut frames).is_ok());
+    assert!(table.translate_page(page(0xcafe)).is_none());
+    assert_eq!(frames.freed, [frame]);
+    // unmapping it again is an error.
+    assert!(table.unmap(page(0xcafe), &mut frames).is_err());
+}
+
+#[test]
+fn test_map_unmap_range() {
+    let mut frames = MockFrames::new();
+    let mut table = mock::address_space(&mut frames);
+    let range = unsafe { frames.allocate_range(4).unwrap() };
+
+    assert!( table.map_range(page(510) .. page(512), range.clone(), WRITABLE
+                            , &mut frames)
+                  .is_err());
+    // this range crosses a page table boundary.
+    assert!( table.map_range(page(510) .. page(514), range.clone(), WRITABLE
+                            , &mut frames)
+                  .is_ok());
+    for (page, frame) in (page(510) .. page(514)).zip(range.clone()) {
+        assert_eq!(table.translate_page(page), Some(frame));
+    }
+
+    assert!(table.unmap_range(page(510) .. page(514), &mut frames).is_ok());
+    for page in page(510) .. page(514) {
+        assert!(table.translate_page(page).is_none());
+    }
+    assert_eq!(frames.freed.len(), 4);
+}
+
+#[test]
+fn test_protect_and_remap() {
+    let mut frames = MockFrames::new();
+    let mut table = mock::address_space(&mut frames);
+    let frame = unsafe { frames.allocate().unwrap() };
+    let new_frame = unsafe { frames.allocate().unwrap() };
+
+    assert!(table.map(page(7), frame, WRITABLE, &mut frames).is_ok());
+
+    // making the page non-present keeps its frame around
+    assert!(table.protect(page(7) .. page(8), EntryFlags::empty()).is_ok());
+    assert!(table.translate_page(page(7)).is_none());
+    assert!(table.protect(page(7) .. page(8), PRESENT).is_ok());
+    assert_eq!(table.translate_page(page(7)), Some(frame));
+
+    assert_eq!(table.remap(page(7), new_frame).ok(), Some(frame));
+    assert_eq!(table.translate_page(page(7)), Some(new_frame));
+
+    assert!(table.protect(page(8) .. page(9), PRESENT).is_err());
+    assert!(table.remap(page(8), frame).is_err());
+}
+
+#[test]
+fn test_unmap_non_present() {
+    let mut frames = MockFrames::new();
+    let mut table = mock::address_space(&mut frames);
+
+    assert!(table.map_to_any(page(7), WRITABLE, &mut frames).is_ok());
+    assert!(table.map_to_any(page(8), WRITABLE, &mut frames).is_ok());
+    let frame = table.translate_page(page(7)).unwrap();
+    let frame_2 = table.translate_page(page(8)).unwrap();
+    assert!(table.protect(page(7) .. page(9), EntryFlags::empty()).is_ok());
+
+    // the frames of non-present pages are still freed when they're unmapped.
+    assert!(table.unmap(page(7), &mut frames).is_ok());
+    assert!(table.unmap_range(page(8) .. page(9), &mut frames).is_ok());
+    assert_eq!(frames.freed, [frame, frame_2]);
+    assert!(table.protect(page(7) .. page(8), PRESENT).is_err());
+}
+
+#[test]
+fn test_inactive_table_using() {
+    let mut frames = MockFrames::new();
+    let mut table = mock::address_space(&mut frames);
+    let mut temp = TempPage::new(0xfacade, &mut frames);
+    let frame = unsafe { frames.allocate().unwrap() };
+
+    let pml4_frame = unsafe { frames.allocate().unwrap() };
+    let mut inactive
+        = InactivePageTable::new(pml4_frame, &mut table, &mut temp, &mut frames)
+            .unwrap();
+
+    assert!(table.using(&mut inactive, &mut temp, |pml4| {
+        pml4.map(page(0xb8), frame, WRITABLE, &mut frames)
+    }).is_ok());
+    // the mapping was made in the inactive table, not the active one.
+    assert!(table.translate_page(page(0xb8)).is_none());
+
+    let old = table.replace_with(inactive);
+    let table = unsafe { ActivePageTable::new() };
+    assert_eq!(table.translate_page(page(0xb8)), Some(frame));
+    assert!(old.pml4_frame != pml4_frame);
+}
+
+#[test]
+fn test_stack_pool() {
+    use stack::StackPool;
+
+    let mut frames = MockFrames::new();
+    let mut table = mock::address_space(&mut frames);
+    let mut pool = StackPool::new(page(0x1000), 2);
+
+    let stack = pool.allocate(&mut table, &mut frames).unwrap();
+    assert_eq!(stack, page(0x1001).base() .. page(0x1003).base());
+    // the guard page below the stack is not mapped.
+    assert!(table.translate_page(page(0x1000)).is_none());
+    assert!(table.translate_page(page(0x1001)).is_some());
+    assert!(table.translate_page(page(0x1002)).is_some());
+
+    let stack_2 = pool.allocate(&mut table, &mut frames).unwrap();
+    assert_eq!(stack_2.start, page(0x1004).base());
+    assert!(table.translate_page(page(0x1003)).is_none());
+
+    assert!(pool.deallocate(stack.clone(), &mut table, &mut frames).is_ok());
+    assert!(table.translate_page(page(0x1001)).is_none());
+    assert_eq!(frames.freed.len(), 2);
+    // freeing it twice is an error.
+    assert!(pool.deallocate(stack.clone(), &mut table, &mut frames).is_err());
+
+    // the freed slot is reused.
+    assert_eq!(pool.allocate(&mut table, &mut frames).unwrap(), stack);
+    assert_eq!(pool.allocated(), 2);
+}
+
+#[test]
+fn test_stack_pool_reserve() {
+    use stack::{StackPool, DEFAULT_STACKS, MAX_STACKS};
+
+    let mut frames = MockFrames::new();
+    let mut table = mock::address_space(&mut frames);
+    let mut pool = StackPool::new(page(0x1000), 1);
+    assert_eq!(pool.capacity(), DEFAULT_STACKS);
+
+    for _ in 0 .. DEFAULT_STACKS {
+        assert!(pool.allocate(&mut table, &mut frames).is_ok());
+    }
+    assert!(pool.allocate(&mut table, &mut frames).is_err());
+
+    assert!(pool.reserve(MAX_STACKS).is_err());
+    assert!(pool.reserve(1).is_ok());
+    assert_eq!(pool.capacity(), DEFAULT_STACKS + 1);
+    assert!(pool.allocate(&mut table, &mut frames).is_ok());
+    assert!(pool.allocate(&mut table, &mut frames).is_err());
+}
+
+#[test]
+fn test_audit_wx() {
+    let mut frames = MockFrames::new();
+    let mut table = mock::address_space(&mut frames);
+
+    assert!( table.map_to_any(page(1), WRITABLE | NO_EXECUTE, &mut frames)
+                  .is_ok());
+    assert!(table.map_to_any(page(2), PRESENT, &mut frames).is_ok());
+    assert_eq!(audit::audit(&table).map(|audit| audit.pages).ok(), Some(2));
+
+    assert!(table.map_to_any(page(3), WRITABLE, &mut frames).is_ok());
+    let audit = audit::audit(&table).unwrap_err();
+    assert_eq!(audit.writable_executable, 1);
+    assert_eq!(audit.user_accessible, 0);
+}
+
+#[test]
+fn test_ioremap_unmaps_on_drop() {
+    use memory::PAddr;
+    use self::mmio::{ioremap, CacheType};
+
+    let mut frames = MockFrames::new();
+    let mut table = mock::address_space(&mut frames);
+
+    // two bytes on either side of a page boundary
+    let mmio = ioremap( &mut table, PAddr::from(0xfee00fff), 2
+                      , CacheType::Uncached, &mut frames).unwrap();
+    let page = VirtualPage::containing(mmio.base());
+    assert_eq!( table.translate(mmio.base()), Some(PAddr::from(0xfee00fff)));
+    assert_eq!( table.translate_page(page + 1)
+              , Some(PhysicalPage::containing(PAddr::from(0xfee01000))));
+
+    drop(mmio);
+    assert!(table.translate_page(page).is_none());
+    assert!(table.translate_page(page + 1).is_none());
+    // device memory is never given to the frame allocator.
+    assert!(frames.freed.is_empty());
+}
+
+#[test]
+fn test_mmio_region_reuses_released_pages() {
+    use self::mmio::Region;
+
+    let mut region = Region::new(8);
+    assert_eq!(region.allocate(2), Some(0));
+    assert_eq!(region.allocate(2), Some(2));
+    assert_eq!(region.allocate(2), Some(4));
+
+    // a released range is reused, and split if it's too big.
+    region.release(0, 2);
+    assert_eq!(region.allocate(1), Some(0));
+    assert_eq!(region.allocate(1), Some(1));
+
+    // adjacent released ranges are merged.
+    region.release(0, 2);
+    region.release(2, 2);
+    assert_eq!(region.allocate(4), Some(0));
+
+    // releasing the last range gives it back to the end of the region.
+    region.release(4, 2);
+    assert_eq!(region.allocate(4), Some(4));
+    assert_eq!(region.allocate(1), None);
+}
+
+#[test]
+fn test_physmap_unaligned_area() {
+    use memory::PAddr;
+    use params::{InitParams, mem};
+
+    let mut frames = MockFrames::new();
+    let mut table = mock::address_space(&mut frames);
+    let mut params = InitParams::default();
+    params.mem_map.push(mem::Area { start_addr: PAddr::from(0x1800)
+                                  , end_addr: PAddr::from(0x401234)
+                                  , is_usable: true
+                                  });
+    assert!(physmap::map_physical_memory(&mut table, &params, &mut frames)
+                .is_ok());
+
+    let direct = |addr: u64| PhysicalPage::containing(PAddr::from(addr));
+    let mapped = |addr: u64| table.translate_page(direct(addr).to_virtual());
+    // only the whole pages inside the area are mapped
+    assert!(mapped(0x1000).is_none());
+    assert_eq!(mapped(0x2000), Some(direct(0x2000)));
+    assert_eq!(mapped(0x1ff000), Some(direct(0x1ff000)));
+    // with a 2MiB page for the aligned region in the middle
+    assert_eq!(mapped(0x200000), Some(direct(0x200000)));
+    assert_eq!(mapped(0x3ff000), Some(direct(0x3ff000)));
+    assert_eq!( table.entry_flags(direct(0x300000).to_virtual())
+                     .map(|flags| flags.is_huge())
+              , Some(true));
+    assert_eq!(mapped(0x400000), Some(direct(0x400000)));
+    assert!(mapped(0x401000).is_none());
+    assert!(mapped(0x600000).is_none());
+}
+
+#[test]
+fn test_ioremap_failure_unmaps() {
+    use memory::PAddr;
+    use self::mmio::{ioremap, CacheType};
+
+    let mut frames = MockFrames::new();
+    let mut table = mock::address_space(&mut frames);
+    // leave enough frames for one PDPT, PD and page table, but not for the
+    // second page table that a 513 page mapping needs.
+    let _ = unsafe { frames.allocate_range(mock::N_FRAMES - 5).unwrap() };
+
+    assert!( ioremap( &mut table, PAddr::from(0xfd000000), 513 * 4096
+                    , CacheType::Uncached, &mut frames)
+                .is_err());
+    // none of the device memory was left mapped.
+    assert_eq!(audit::audit(&table).map(|audit| audit.pages).ok(), Some(0));
+    assert!(frames.freed.is_empty());
+}
@@ -0,0 +1,358 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The active top-level page table.
+//!
+//! This is the PML4 on `x86_64`, and the PD (or the PDPT, with PAE) on
+//! 32-bit `x86`. The architecture's `levels` module tells us how to walk
+//! from it to the page table for a given page; everything else is the same.
+use core::fmt;
+use core::ptr::Unique;
+
+use alloc::FrameAllocator;
+use memory::{ Addr, PAGE_SIZE, PAddr, Page, PhysicalPage, VAddr, VirtualPage
+            , PageRange, FrameRange, MemRange };
+use ::{Mapper, MapResult, MapErr};
+
+use super::backend::{Backend, Current};
+use super::table::*;
+
+/// Struct representing the currently active top-level page table.
+///
+/// The `ActiveTable` is a `Unique` reference to the top-level page table.
+/// It's unique because, well, there can only be one active top-level table at
+/// a given time.
+///
+pub struct ActiveTable(Unique<Table<TopLevel>>);
+impl fmt::Debug for ActiveTable {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Active {:?}", unsafe { self.0.as_ref() })
+    }
+}
+/// The active top-level table is the single point of entry for page mapping.
+impl Mapper for ActiveTable {
+    type Flags = EntryFlags;
+
+    fn translate(&self, vaddr: VAddr) -> Option<PAddr> {
+        let offset = *vaddr % PAGE_SIZE as usize;
+        self.translate_page(Page::containing(vaddr))
+            .map(|frame| frame.base() + offset as PAddrWord)
+    }
+
+    fn translate_page(&self, page: VirtualPage) -> Option<PhysicalPage> {
+        self.top().translate_page(page)
+    }
+
+
+    /// Modifies the page tables so that `page` maps to `frame`.
+    ///
+    /// # Arguments
+    /// + `page`: the virtual `Page` to map
+    /// + `frame`: the physical `Frame` that `Page` should map to.
+    /// + `flags`: the page table entry flags.
+    /// + `alloc`: a memory allocator
+    fn map<A>( &mut self, page: VirtualPage, frame: PhysicalPage
+             , flags: EntryFlags, alloc: &mut A)
+             -> MapResult<()>
+    where A: FrameAllocator {
+        // access or create all the lower-level page tables.
+        let page_table = self.top_mut().create_page_table(page, alloc)?;
+        trace!(" . . Map: Got page table");
+        // check if the page at that index is not currently in use, as we
+        // cannot map a page which is currently in use.
+        if page_table[page].is_unused() {
+            // set the page table entry at that index
+            page_table[page].set(frame, flags | PRESENT);
+            Ok(())
+        } else {
+            Err(MapErr::AlreadyInUse {
+                message: "map frame"
+              , page: page
+              , frame: frame
+            })
+        }
+    }
+
+    fn identity_map<A>(&mut self, frame: PhysicalPage, flags: EntryFlags
+                      , alloc: &mut A)
+                      -> MapResult<()>
+    where A: FrameAllocator {
+        self.map( Page::containing(VAddr::from(*frame.base_addr() as usize))
+                , frame
+                , flags
+                , alloc )
+    }
+
+    fn map_to_any<A>( &mut self
+                    , page: VirtualPage
+                    , flags: EntryFlags
+                    , alloc: &mut A)
+                    -> MapResult<()>
+    where A: FrameAllocator {
+        let frame = unsafe { alloc.allocate() }
+            .map_err(|err| MapErr::Alloc {
+                message: "map to any"
+              , page: page
+              , cause: err
+          })?;
+        self.map(page, frame, flags, alloc)
+    }
+
+    /// Unmap the given `VirtualPage`.
+    ///
+    /// All freed frames are returned to the given `FrameAllocator`.
+    fn unmap<A>(&mut self, page: VirtualPage, alloc: &mut A) -> MapResult<()>
+    where A: FrameAllocator {
+        let frame = {
+            // get the page table entry corresponding to the page.
+            let entry = self.entry_mut(page, "unmap")?;
+            // get the pointed frame for the page table entry. a page made
+            // non-present by `protect` still points to its frame.
+            let frame = entry.mapped_frame()
+                             .ok_or(MapErr::Other {
+                               message: "unmap"
+                             , page: page
+                             , cause: "it was not mapped"
+                           })?;
+            // mark the page table entry as unused
+            entry.set_unused();
+            frame
+        };
+        trace!("unmapped {:?} from {:?}", page, frame);
+        // deallocate the frame and flush the translation lookaside buffer
+        // this is safe because we're in kernel mode
+        unsafe { Current::flush(page) };
+        unsafe {
+            // this is hopefully safe because nobody else should be using an
+            // allocated page frame
+            alloc.deallocate(frame);
+        }
+        // TODO: check if page tables containing the unmapped page are empty
+        //       and deallocate them too?
+        Ok(())
+    }
+
+    fn map_range<A>( &mut self, pages: PageRange, frames: FrameRange
+                   , flags: EntryFlags, alloc: &mut A)
+                   -> MapResult<()>
+    where A: FrameAllocator {
+        if pages.length() != frames.length() {
+            return Err(MapErr::Other {
+                message: "map range"
+              , page: pages.start
+              , cause: "page and frame ranges have different lengths"
+            })
+        }
+        // since none of these pages were present before, there's nothing to
+        // invalidate in the TLB.
+        for (page, frame) in pages.zip(frames) {
+            self.map(page, frame, flags, alloc)?;
+        }
+        Ok(())
+    }
+
+    fn unmap_range<A>(&mut self, pages: PageRange, alloc: &mut A)
+                     -> MapResult<()>
+    where A: FrameAllocator {
+        // clear all the entries first, and only flush the TLB once we're done.
+        let mut result = Ok(());
+        for page in pages.clone() {
+            result = self.entry_mut(page, "unmap range")
+                .and_then(|entry| {
+                    let frame = entry.mapped_frame()
+                        .ok_or(MapErr::Other {
+                            message: "unmap range"
+                          , page: page
+                          , cause: "it was not mapped"
+                        })?;
+                    entry.set_unused();
+                    unsafe {
+                        // this is hopefully safe because nobody else should be
+                        // using an allocated page frame
+                        alloc.deallocate(frame);
+                    }
+                    Ok(())
+                });
+            if result.is_err() { break; }
+        }
+        // even if we bailed out early, some entries may have been cleared, so
+        // we always flush the whole range.
+        // this is safe because we're in kernel mode
+        unsafe { Current::flush_range(pages) };
+        result
+    }
+
+    fn protect(&mut self, pages: PageRange, flags: EntryFlags)
+              -> MapResult<()> {
+        let mut result = Ok(());
+        for page in pages.clone() {
+            result = self.entry_mut(page, "protect")
+                .and_then(|entry|
+                    // an entry that is unused was never mapped, so we
+                    // have no frame to point it at.
+                    if entry.is_unused() {
+                        Err(MapErr::Other {
+                            message: "protect"
+                          , page: page
+                          , cause: "it was not mapped"
+                        })
+                    } else {
+                        entry.set_flags(flags);
+                        Ok(())
+                    });
+            if result.is_err() { break; }
+        }
+        // this is safe because we're in kernel mode
+        unsafe { Current::flush_range(pages) };
+        result
+    }
+
+    fn remap(&mut self, page: VirtualPage, new_frame: PhysicalPage)
+            -> MapResult<PhysicalPage> {
+        let old_frame = {
+            let entry = self.entry_mut(page, "remap")?;
+            let old_frame = entry.mapped_frame()
+                .ok_or(MapErr::Other {
+                    message: "remap"
+                  , page: page
+                  , cause: "it was not mapped"
+                })?;
+            let flags = entry.flags();
+            entry.set(new_frame, flags);
+            old_frame
+        };
+        trace!("remapped {:?} from {:?} to {:?}", page, old_frame, new_frame);
+        // this is safe because we're in kernel mode
+        unsafe { Current::flush(page) };
+        Ok(old_frame)
+    }
+
+}
+
+impl ActiveTable {
+
+    pub unsafe fn new() -> Self {
+        ActiveTable::at(Current::active_table())
+    }
+
+    /// Returns an `ActiveTable` for the top-level table at `table`.
+    ///
+    /// # Safety
+    /// + `table` must point to a top-level table which is reachable through
+    ///   the current paging backend, and nothing else may be using it.
+    pub unsafe fn at(table: *mut Table<TopLevel>) -> Self {
+        ActiveTable(Unique::new(table))
+    }
+
+    pub fn top(&self) -> &Table<TopLevel> {
+        unsafe { self.0.as_ref() }
+    }
+
+    pub fn top_mut(&mut self) -> &mut Table<TopLevel> {
+        unsafe { self.0.as_mut() }
+    }
+
+    /// Returns true if the given page is mapped.
+    #[inline]
+    pub fn is_mapped(&self, page: &VirtualPage) -> bool {
+         self.translate_page(*page).is_some()
+    }
+
+    /// Returns the flags of the entry which maps `page`.
+    ///
+    /// This is the bottom-level page table entry, or the entry which maps the
+    /// huge page containing `page`.
+    ///
+    /// # Returns
+    /// + `Some(EntryFlags)` if `page` is mapped
+    /// + `None` if it is not.
+    #[inline]
+    pub fn entry_flags(&self, page: VirtualPage) -> Option<EntryFlags> {
+        self.top().entry_flags(page)
+    }
+
+    /// Returns the bottom-level page table entry for `page`.
+    ///
+    /// # Returns
+    /// + `Ok(&mut Entry)` if the page table containing `page` exists
+    /// + `Err(MapErr)` if it does not, or if `page` is in a huge page.
+    fn entry_mut(&mut self, page: VirtualPage, message: &'static str)
+                -> MapResult<&mut Entry> {
+        self.top_mut()
+            .page_table_mut_for(page)
+            .map(|page_table| &mut page_table[page])
+            .ok_or(MapErr::TableNotFound {
+                message: message
+              , page: page
+              , what: "page table (or the page is in a huge page)"
+            })
+    }
+
+}
+
+/// Identity map a kernel ELF section, with flags matching its permissions.
+///
+/// # Returns
+/// + `Ok(())` if the section was mapped
+/// + `Err(MapErr)` if the section is not page aligned, would be mapped both
+///   writable and executable, or could not be mapped.
+pub fn identity_map_section<A, W>( table: &mut ActiveTable
+                                 , section: &::elf::Section<W>
+                                 , alloc: &mut A)
+                                 -> MapResult<()>
+where A: FrameAllocator
+    , W: ::elf::ElfWord {
+    if !section.address().is_page_aligned() {
+        return Err(MapErr::NoPage {
+            message: "identity map section"
+          , cause: "the start address was not page aligned"
+        })
+    }
+    let flags = EntryFlags::from(section);
+    enforce_wx(flags, section.address())?;
+
+    let start_frame = PhysicalPage::from(section.address());
+    let end_frame = PhysicalPage::from(section.end_address());
+    for frame in start_frame .. end_frame {
+        table.identity_map(frame, flags, alloc)?;
+    }
+    Ok(())
+}
+
+/// Identity map `frame`, refusing to if `flags` are both writable and
+/// executable.
+pub fn identity_map_checked<A>( table: &mut ActiveTable
+                              , frame: PhysicalPage
+                              , flags: EntryFlags
+                              , alloc: &mut A)
+                              -> MapResult<()>
+where A: FrameAllocator {
+    enforce_wx(flags, frame.base_addr())?;
+    table.identity_map(frame, flags, alloc)
+}
+
+/// Refuse to create a mapping which is both writable and executable.
+///
+/// Every mapping created by `kernel_remap` goes through this check, by way
+/// of [`identity_map_section`], [`identity_map_checked`], or (on `x86_64`)
+/// the direct map of physical memory, so that the remapped kernel is W^X.
+///
+/// [`identity_map_section`]: fn.identity_map_section.html
+/// [`identity_map_checked`]: fn.identity_map_checked.html
+pub fn enforce_wx(flags: EntryFlags, addr: PAddr) -> MapResult<()> {
+    if flags.is_writable_executable() {
+        Err(MapErr::Other {
+            message: "enforce W^X"
+          , page: VirtualPage::containing(VAddr::from(*addr as usize))
+          , cause: "the mapping would be both writable and executable"
+        })
+    } else {
+        Ok(())
+    }
+}
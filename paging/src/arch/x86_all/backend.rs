@@ -0,0 +1,154 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Paging backends.
+//!
+//! The table-walking code in this module doesn't know how page tables are
+//! actually reached in memory, or how to talk to the MMU. Instead, it asks
+//! a [`Backend`]. When running on real hardware, the [`Hardware`] backend
+//! reaches tables through the recursive mapping (or through the direct map
+//! of physical memory on `x86_64`, once it is enabled), and uses `%cr3` and
+//! `invlpg`.
+//!
+//! When running `cargo test` on the host, the `Mock` backend is used instead,
+//! which simulates physical memory in a `Vec`.
+//!
+//! [`Backend`]: trait.Backend.html
+//! [`Hardware`]: enum.Hardware.html
+use cpu::control_regs::cr3;
+use memory::{PageRange, PhysicalPage, VAddr, VirtualPage};
+
+use super::table::{Table, TopLevel, ENTRY_INDEX_BITS, TOP_PTR};
+use super::tlb;
+#[cfg(target_arch = "x86_64")] use super::physmap;
+
+/// The paging backend currently in use.
+#[cfg(not(all(test, target_arch = "x86_64")))]
+pub type Current = Hardware;
+
+/// The paging backend currently in use.
+#[cfg(all(test, target_arch = "x86_64"))]
+pub type Current = super::mock::Mock;
+
+/// A `Backend` determines how page tables are reached, and how the MMU is
+/// told about changes to them.
+pub trait Backend {
+    /// Returns the address of the table which entry `i` of the table at
+    /// `table` points to.
+    ///
+    /// # Arguments
+    /// + `table`: the virtual address of the parent table
+    /// + `i`: the index of the entry in the parent table
+    /// + `frame`: the frame which entry `i` points to
+    fn next_table_addr(table: VAddr, i: usize, frame: PhysicalPage) -> VAddr;
+
+    /// Returns a pointer to the active top-level table.
+    fn active_table() -> *mut Table<TopLevel>;
+
+    /// Returns a pointer to the top-level table in `frame`, if tables which
+    /// are not active can be accessed directly.
+    ///
+    /// # Returns
+    /// + `Some(ptr)` if the table can be accessed directly
+    /// + `None` if the table can only be accessed by temporarily making it
+    ///   the target of the recursive mapping.
+    fn inactive_table(frame: PhysicalPage) -> Option<*mut Table<TopLevel>>;
+
+    /// Returns the frame containing the active top-level table.
+    ///
+    /// # Safety
+    /// + Causes a general protection fault if not executed in kernel mode.
+    unsafe fn current_pagetable_frame() -> PhysicalPage;
+
+    /// Makes the top-level table in `frame` the active top-level table.
+    ///
+    /// # Safety
+    /// + Causes a general protection fault if not executed in kernel mode.
+    /// + Everything currently executing must be mapped in the new table.
+    unsafe fn set_pagetable_frame(frame: PhysicalPage);
+
+    /// Invalidate `page` in the TLB.
+    ///
+    /// # Safety
+    /// + Causes a general protection fault if not executed in kernel mode.
+    unsafe fn flush(page: VirtualPage);
+
+    /// Invalidate every page in `pages` in the TLB.
+    ///
+    /// # Safety
+    /// + Causes a general protection fault if not executed in kernel mode.
+    unsafe fn flush_range(pages: PageRange);
+
+    /// Invalidate the entire TLB.
+    ///
+    /// # Safety
+    /// + Causes a general protection fault if not executed in kernel mode.
+    unsafe fn flush_all();
+}
+
+/// Returns the address of `frame` in the direct map of physical memory, if
+/// the direct map is enabled.
+#[cfg(target_arch = "x86_64")]
+#[inline]
+fn direct_mapped(frame: PhysicalPage) -> Option<VAddr> {
+    use memory::Page;
+    if physmap::is_enabled() {
+        Some(frame.base().to_virtual())
+    } else {
+        None
+    }
+}
+
+/// There is no direct map of physical memory on 32-bit `x86`.
+#[cfg(target_arch = "x86")]
+#[inline]
+fn direct_mapped(_frame: PhysicalPage) -> Option<VAddr> { None }
+
+/// The backend for running on real hardware.
+pub enum Hardware {}
+
+impl Backend for Hardware {
+    #[inline]
+    fn next_table_addr(table: VAddr, i: usize, frame: PhysicalPage) -> VAddr {
+        direct_mapped(frame).unwrap_or_else(||
+            // the recursive mapping means that shifting the parent table's
+            // address left by the number of bits in a table index gives us
+            // the base address of all the tables it points to.
+            VAddr::from(*table << ENTRY_INDEX_BITS) | (i << 12))
+    }
+
+    #[inline]
+    fn active_table() -> *mut Table<TopLevel> { TOP_PTR }
+
+    #[inline]
+    fn inactive_table(frame: PhysicalPage) -> Option<*mut Table<TopLevel>> {
+        direct_mapped(frame).map(|addr| addr.as_mut_ptr())
+    }
+
+    #[inline]
+    unsafe fn current_pagetable_frame() -> PhysicalPage {
+        cr3::current_pagetable_frame()
+    }
+
+    #[inline]
+    unsafe fn set_pagetable_frame(frame: PhysicalPage) {
+        cr3::set_pagetable_frame(frame)
+    }
+
+    #[inline]
+    unsafe fn flush(page: VirtualPage) {
+        use self::tlb::Flush;
+        page.invlpg()
+    }
+
+    #[inline]
+    unsafe fn flush_range(pages: PageRange) { tlb::flush_range(pages) }
+
+    #[inline]
+    unsafe fn flush_all() { tlb::flush_all() }
+}
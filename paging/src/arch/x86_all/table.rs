@@ -6,6 +6,14 @@
 //  Released under the terms of the MIT license. See `LICENSE` in the root
 //  directory of this repository for more information.
 //
+//! Page tables.
+//!
+//! Page tables on `x86_64` and 32-bit `x86` work the same way, so everything
+//! here is shared. What differs is the number of table levels, and the width
+//! and number of entries in each table: `x86_64` and 32-bit `x86` with PAE
+//! use 512 64-bit entries, while two-level 32-bit paging uses 1024 32-bit
+//! entries. Those are defined in each architecture's `levels` module, and
+//! re-exported here.
 use alloc::FrameAllocator;
 use ::elf;
 use memory::{Addr, PAGE_SIZE, PAddr, Page, PhysicalPage, VAddr, VirtualPage};
@@ -15,20 +23,17 @@ use core::ops::{Index, IndexMut};
 use core::{convert, fmt, intrinsics};
 
 use ::{ MapResult, MapErr};
+use super::backend::{Backend, Current};
+
+pub use super::levels::*;
 
 /// The number of entries in a page table.
-pub const N_ENTRIES: usize = 512;
+pub const N_ENTRIES: usize = 1 << ENTRY_INDEX_BITS;
 /// Size of a page table (in bytes)
 pub const PAGE_TABLE_SIZE: usize = N_ENTRIES * PAGE_SIZE as usize;
 
-/// Base virtual address of the PML4 table
-pub const PML4_VADDR: u64 =  0xffffffff_fffff000;
-
-/// A pointer to the PML4 table
-pub const PML4_PTR: *mut Table<PML4Level> = PML4_VADDR as *mut _;
-
 /// Mask to apply to a page table entry to isolate the flags
-pub const ENTRY_FLAGS_MASK: u64 = (PAGE_SIZE as u64 - 1) as u64;
+pub const ENTRY_FLAGS_MASK: EntryWord = PAGE_SIZE as EntryWord - 1;
 
 /// A page table
 #[repr(C)]
@@ -46,16 +51,13 @@ impl<L:TableLevel> fmt::Debug for Table<L> {
               , self)
     }
 }
-// TODO: this can be moved to x86_all; it's the number of level traits that is
-//       x86-64 specific.
-//          - eliza, 5/29/2017
 pub trait TableLevel {
     /// How much to shift an address by to find its index in this table.
     const ADDR_SHIFT_AMOUNT: usize;
     /// How much to shift a page number by to find its index in this level table
     const PAGE_SHIFT_AMOUNT: usize;
     /// Mask for indices
-    const INDEX_MASK: usize = 0o777;
+    const INDEX_MASK: usize = N_ENTRIES - 1;
 
 }
 
@@ -93,60 +95,9 @@ where T: TableLevel {
 
 }
 
-pub enum PML4Level {}
-pub enum PDPTLevel {}
-pub enum PDLevel   {}
-pub enum PTLevel   {}
-
-impl TableLevel for PML4Level {
-    // TODO: make sure these values are correct!
-    //          - eliza, 5/29/2017
-    const ADDR_SHIFT_AMOUNT: usize = 39;
-    const PAGE_SHIFT_AMOUNT: usize = 27;
-}
-impl TableLevel for PDPTLevel {
-    const ADDR_SHIFT_AMOUNT: usize = 30;
-    const PAGE_SHIFT_AMOUNT: usize = 18;
-}
-impl TableLevel for PDLevel   {
-    const ADDR_SHIFT_AMOUNT: usize = 21;
-    const PAGE_SHIFT_AMOUNT: usize = 9;
-}
-impl TableLevel for PTLevel   {
-    const ADDR_SHIFT_AMOUNT: usize = 12;
-    const PAGE_SHIFT_AMOUNT: usize = 0;
-}
-
 pub trait Sublevel: TableLevel {
     type Next: TableLevel;
 }
-impl Sublevel for PML4Level {
-    type Next = PDPTLevel;
-}
-impl Sublevel for PDPTLevel {
-    type Next = PDLevel;
-}
-impl Sublevel for PDLevel {
-    type Next = PTLevel;
-}
-
-impl Table<PML4Level> {
-    #[inline]
-    pub fn page_table_for(&self, page: VirtualPage) -> Option<&Table<PTLevel>> {
-        self.next_table(page)
-            .and_then(|pdpt| pdpt.next_table(page))
-            .and_then(|pd| pd.next_table(page))
-    }
-
-    #[inline]
-    pub fn page_table_mut_for(&mut self, page: VirtualPage)
-                             -> Option<&mut Table<PTLevel>> {
-        self.next_table_mut(page)
-            .and_then(|pdpt| pdpt.next_table_mut(page))
-            .and_then(|pd| pd.next_table_mut(page))
-    }
-}
-
 
 impl<L, I> Index<I> for Table<L>
 where L: TableLevel
@@ -197,12 +148,16 @@ impl<L: Sublevel> Table<L> {
 
 
     /// Returns the address of the next table, or None if none exists.
+    ///
+    /// How the next table is reached depends on the current paging
+    /// [`Backend`](../backend/trait.Backend.html).
     #[inline]
     fn next_table_addr(&self, i: usize) -> Option<VAddr> {
         let flags = self[i].flags();
         if flags.contains(PRESENT) && !flags.contains(HUGE_PAGE) {
-            let table_addr = self as *const _ as usize;
-            Some(VAddr::from(table_addr << 9) | (i << 12))
+            let table_addr = VAddr::from(self as *const _ as usize);
+            self[i].get_frame()
+                   .map(|frame| Current::next_table_addr(table_addr, i, frame))
         } else {
             None
         }
@@ -280,6 +235,10 @@ bitflags! {
       , const ACCESSED =        1 << 5
       , const DIRTY =           1 << 6
       , const HUGE_PAGE =       1 << 7
+      , /// Page attribute table index.
+        /// In bottom-level page table entries, bit 7 is the high bit of the
+        /// PAT index rather than the huge page flag.
+        const PAT =             1 << 7
       , const GLOBAL =          1 << 8
       , const NO_EXECUTE =      1 << 63
     }
@@ -298,6 +257,13 @@ impl EntryFlags {
         self.contains(PRESENT)
     }
 
+    /// Returns true if these flags would allow a page to be both written to
+    /// and executed, violating W^X.
+    #[inline]
+    pub fn is_writable_executable(&self) -> bool {
+        self.contains(WRITABLE) && !self.contains(NO_EXECUTE)
+    }
+
     #[inline]
     pub fn set_present(&mut self, present: bool) -> &mut Self {
         if present { self.insert(PRESENT) }
@@ -321,13 +287,13 @@ impl EntryFlags {
 }
 
 #[derive(Debug)]
-pub struct Entry(u64);
+pub struct Entry(EntryWord);
 
 impl Entry {
 
     pub fn new(addr: PAddr) -> Self {
         assert!(addr.is_page_aligned());
-        Entry(*addr)
+        Entry(*addr as EntryWord)
     }
 
     // TODO: this is one of the worst names I have ever given a thing
@@ -366,13 +332,13 @@ impl Entry {
     /// Access the entry's bitflags.
     #[inline]
     pub fn flags(&self) -> EntryFlags {
-        EntryFlags::from_bits_truncate(self.0)
+        EntryFlags::from_bits_truncate(self.0 as u64)
     }
 
     /// Returns the physical address pointed to by this page table entry
     #[inline]
     pub fn get_addr(&self) -> PAddr {
-        PAddr::from(self.0 & PML4_VADDR)
+        PAddr::from((self.0 & ENTRY_ADDR_MASK) as PAddrWord)
     }
 
     /// Returns the frame in memory pointed to by this page table entry.
@@ -385,16 +351,47 @@ impl Entry {
         }
     }
 
+    /// Returns the frame this entry points to, even if it is not present.
+    ///
+    /// A page made non-present with `Mapper::protect` still owns its frame,
+    /// so this is what unmapping uses to find the frame to free.
+    ///
+    /// # Returns
+    /// + `Some(PhysicalPage)` if the entry is in use
+    /// + `None` if the entry is unused.
+    #[inline]
+    pub fn mapped_frame(&self) -> Option<PhysicalPage> {
+        if self.is_unused() {
+            None
+        } else {
+            Some(PhysicalPage::containing(self.get_addr()))
+        }
+    }
+
+    /// Point this entry at `frame`, with the given flags.
+    ///
+    /// N.B. that flags which don't fit in an entry are dropped, so
+    /// `NO_EXECUTE` has no effect with two-level 32-bit paging.
     pub fn set(&mut self, frame: PhysicalPage, flags: EntryFlags) {
-        let addr: u64 = frame.base_addr().into();
-        assert!(addr & !0x000fffff_fffff000 == 0);
-        self.0 = addr | flags.bits();
+        let addr = *frame.base_addr() as EntryWord;
+        assert!(addr & !ENTRY_ADDR_MASK == 0);
+        self.0 = addr | flags.bits() as EntryWord;
+    }
+
+    /// Replace this entry's flags, keeping the address it points to.
+    ///
+    /// N.B. that this does not require the entry to be present, so it can
+    /// be used to make a non-present entry present again.
+    #[inline]
+    pub fn set_flags(&mut self, flags: EntryFlags) {
+        self.0 = (self.0 & ENTRY_ADDR_MASK) | flags.bits() as EntryWord;
     }
 
 }
 
-impl<'a> convert::From<&'a elf::Section<u64>> for EntryFlags {
-    fn from(section: &'a elf::Section<u64>) -> Self {
+impl<'a, W> convert::From<&'a elf::Section<W>> for EntryFlags
+where W: elf::ElfWord {
+    fn from(section: &'a elf::Section<W>) -> Self {
         *EntryFlags::empty()
             .set_present(section.is_allocated())
             .set_writable(section.is_writable())
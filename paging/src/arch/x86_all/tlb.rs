@@ -1,6 +1,10 @@
-use memory::VAddr;
+use memory::{MemRange, PageRange, VAddr};
 use super::{Page, VirtualPage};
 
+/// Ranges longer than this many pages are flushed by reloading `%cr3`,
+/// rather than with one `invlpg` per page.
+pub const FLUSH_ALL_THRESHOLD: usize = 32;
+
 /// Invalidate the TLB completely by reloading the CR3 register.
 ///
 /// # Safety
@@ -10,6 +14,23 @@ pub unsafe fn flush_all() {
     cr3::write(cr3::read());
 }
 
+/// Invalidate every page in `pages` in the TLB.
+///
+/// If the range is short, each page is flushed with `invlpg`. Otherwise, the
+/// whole TLB is flushed at once, since that is cheaper than many `invlpg`s.
+///
+/// # Safety
+/// + Causes a general protection fault if not executed in kernel mode.
+pub unsafe fn flush_range(pages: PageRange) {
+    if pages.length() > FLUSH_ALL_THRESHOLD {
+        flush_all()
+    } else {
+        for page in pages {
+            page.invlpg()
+        }
+    }
+}
+
 /// Something which may be flushed from the TLB
 pub trait Flush {
     /// Invalidate this object in the TLB using the `invlpg` instruction.
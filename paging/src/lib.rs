@@ -16,6 +16,10 @@
 #![feature(core_intrinsics)]
 #![no_std]
 
+// std's macros are imported first, so that `vga`'s `print!` and `println!`
+// shadow them.
+#[cfg(test)] #[macro_use] extern crate std;
+
 #[macro_use] extern crate bitflags;
 #[macro_use] extern crate log;
 #[macro_use] extern crate vga;
@@ -32,7 +36,8 @@ pub mod arch;
 pub mod stack;
 pub use self::arch::{kernel_remap, test_paging};
 
-use memory::{Page, PAddr, PhysicalPage, VAddr, VirtualPage};
+use memory::{ Page, PAddr, PhysicalPage, VAddr, VirtualPage
+            , PageRange, FrameRange };
 use alloc::{FrameAllocator, AllocErr};
 use core::fmt;
 
@@ -50,7 +55,21 @@ pub enum MapErr<P: Page + fmt::Debug = VirtualPage> {
 impl<P> fmt::Debug for MapErr<P> where P: Page + fmt::Debug {
     #[inline]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        unimplemented!()
+        match *self {
+            MapErr::Alloc { message, ref page, ref cause } =>
+                write!( f, "Could not {} {:?}: allocation failed: {:?}"
+                      , message, page, cause)
+          , MapErr::Other { message, ref page, cause } =>
+                write!(f, "Could not {} {:?}: {}", message, page, cause)
+          , MapErr::TableNotFound { message, ref page, what } =>
+                write!( f, "Could not {} {:?}: no {} found"
+                      , message, page, what)
+          , MapErr::AlreadyInUse { message, ref page, ref frame } =>
+                write!( f, "Could not {} {:?}: already mapped to {:?}"
+                      , message, page, frame)
+          , MapErr::NoPage { message, cause } =>
+                write!(f, "Could not {}: {}", message, cause)
+        }
     }
 }
 
@@ -108,8 +127,54 @@ pub trait Mapper {
 
     /// Unmap the given `VirtualPage`.
     ///
-    /// All freed frames are returned to the given `FrameAllocator`.
+    /// All freed frames are returned to the given `FrameAllocator`. This
+    /// includes the frames of pages made non-present with `protect`.
     fn unmap<A>(&mut self, page: VirtualPage, alloc: &mut A) -> MapResult<()>
     where A: FrameAllocator;
 
+    /// Map each page in `pages` to the corresponding frame in `frames`.
+    ///
+    /// The two ranges must be the same length. If mapping any page fails,
+    /// the pages before it are left mapped.
+    ///
+    /// # Arguments
+    /// + `pages`: the range of `VirtualPage`s to map
+    /// + `frames`: the range of `PhysicalPage`s those pages should map to
+    /// + `flags`: the page table entry flags.
+    /// + `alloc`: a memory allocator
+    fn map_range<A>( &mut self, pages: PageRange, frames: FrameRange
+                   , flags: Self::Flags, alloc: &mut A )
+                   -> MapResult<()>
+    where A: FrameAllocator;
+
+    /// Unmap every page in `pages`.
+    ///
+    /// All freed frames are returned to the given `FrameAllocator`. The TLB
+    /// is invalidated once for the whole range, rather than once per page.
+    fn unmap_range<A>(&mut self, pages: PageRange, alloc: &mut A)
+                     -> MapResult<()>
+    where A: FrameAllocator;
+
+    /// Change the flags of every page in `pages` to `flags`.
+    ///
+    /// Unlike `map`, `flags` is used as-is: passing flags without the
+    /// present bit marks the pages as non-present while keeping the frames
+    /// they point to, so that a later `protect` can make them present again.
+    ///
+    /// # Returns
+    /// + `Ok(())` if every page in the range had a mapping
+    /// + `Err(MapErr)` if any page in the range was never mapped. Pages
+    ///   before it will already have had their flags changed.
+    fn protect(&mut self, pages: PageRange, flags: Self::Flags)
+              -> MapResult<()>;
+
+    /// Change the frame that `page` maps to, keeping its flags.
+    ///
+    /// # Returns
+    /// + `Ok(PhysicalPage)` containing the frame `page` previously mapped to.
+    ///   It is the caller's responsibility to free that frame if needed.
+    /// + `Err(MapErr)` if `page` was not mapped.
+    fn remap(&mut self, page: VirtualPage, new_frame: PhysicalPage)
+            -> MapResult<PhysicalPage>;
+
 }
@@ -7,67 +7,276 @@
 //  directory of this repository for more information.
 //
 //! Stack allocator
-use alloc::{AllocResult, AllocErr, FrameAllocator, Layout};
-use memory::{PageRange, VAddr};
-use ::Mapper;
+//!
+//! Kernel stacks (for threads, and for the TSS's interrupt stack table) are
+//! allocated from a [`StackPool`]. Each stack has an unmapped guard page
+//! directly below it, so that a stack overflow causes a page fault rather
+//! than silently corrupting whatever is below the stack.
+//!
+//! The pool starts out with room for [`DEFAULT_STACKS`] stacks. Code which
+//! knows it will need more (e.g. to give every CPU its own stacks) raises
+//! the pool's capacity with [`StackPool::reserve`], up to [`MAX_STACKS`].
+//!
+//! [`StackPool`]: struct.StackPool.html
+//! [`DEFAULT_STACKS`]: constant.DEFAULT_STACKS.html
+//! [`StackPool::reserve`]: struct.StackPool.html#method.reserve
+//! [`MAX_STACKS`]: constant.MAX_STACKS.html
+use alloc::{AllocErr, FrameAllocator, Layout};
+use memory::{PAGE_SIZE, Page, PageRange, VAddr, VirtualPage};
+use util::sync::IrqMutex;
+use ::{Mapper, MapResult, MapErr};
 use arch::ActivePageTable;
-
+use arch::table::{WRITABLE, NO_EXECUTE};
 
 use core::ops::Range;
 
+/// A stack, from its lowest address (`start`) to its top (`end`).
+///
+/// Stacks grow down, so the initial stack pointer for a new stack is `end`.
 pub type Stack = Range<VAddr>;
 
+/// Base address of the region of the address space used for kernel stacks.
+///
+/// This is PML4 entry 510, just below the recursive mapping.
+#[cfg(target_arch = "x86_64")]
+pub const KERNEL_STACKS_BASE: usize = 0xffffff00_00000000;
+
+/// Base address of the region of the address space used for kernel stacks.
+///
+/// This is entries 504 through 507 of PD 3, just below the recursive mapping.
+#[cfg(target_arch = "x86")]
+pub const KERNEL_STACKS_BASE: usize = 0xff00_0000;
+
+/// Size of each kernel stack, in pages (not including the guard page).
+pub const KERNEL_STACK_PAGES: usize = 4;
+
+/// The number of stacks a `StackPool` has room for when it is created.
+pub const DEFAULT_STACKS: usize = 64;
+
+/// The most stacks a `StackPool` can have room for.
+///
+/// The kernel stack region is a whole PML4 entry, so this is only limited
+/// by the size of the pool's bitmap.
+#[cfg(target_arch = "x86_64")]
+pub const MAX_STACKS: usize = 1024;
+
+/// The most stacks a `StackPool` can have room for.
+///
+/// The kernel stack region is four 2MiB page directory entries, which fit
+/// this many stacks of `KERNEL_STACK_PAGES` pages plus a guard page.
+#[cfg(target_arch = "x86")]
+pub const MAX_STACKS: usize = 384;
+
+/// The number of words in a `StackPool`'s bitmap.
+const BITMAP_WORDS: usize = (MAX_STACKS + 63) / 64;
+
+/// The pool that kernel stacks are allocated from.
+///
+/// This is locked by the thread code, which runs with interrupts enabled
+/// and may be preempted, so it disables interrupts while it is held.
+pub static KERNEL_STACKS: IrqMutex<StackPool>
+    = IrqMutex::new(StackPool::new( VirtualPage {
+                                       number: KERNEL_STACKS_BASE >> 12
+                                    }
+                                  , KERNEL_STACK_PAGES));
+
 pub trait StackAllocator {
     fn allocate<A>( &mut self
                       , page_table: &mut ActivePageTable
                       , frames: &mut A
-                      , num_pages: usize) -> AllocResult<Stack>
+                      , num_pages: usize) -> MapResult<Stack>
     where A: FrameAllocator;
 }
 
+/// Returns the error for a stack allocation that couldn't be satisfied.
+#[inline]
+fn exhausted(page: VirtualPage, num_pages: usize) -> MapErr {
+    MapErr::Alloc {
+        message: "allocate stack"
+      , page: page
+      , cause: AllocErr::Exhausted {
+            request: Layout::from_size_align( PAGE_SIZE as usize * num_pages
+                                            , PAGE_SIZE as usize)
+        }
+    }
+}
+
+/// Map each page in `pages` to any frame, for use as a stack.
+///
+/// If mapping any page fails, the pages that were already mapped are
+/// unmapped again.
+fn map_stack<A>( pages: PageRange
+               , page_table: &mut ActivePageTable
+               , frames: &mut A)
+               -> MapResult<Stack>
+where A: FrameAllocator {
+    for page in pages.clone() {
+        if let Err(why) = page_table.map_to_any( page, WRITABLE | NO_EXECUTE
+                                               , frames) {
+            let _ = page_table.unmap_range(pages.start .. page, frames);
+            return Err(why)
+        }
+    }
+    Ok(pages.start.base() .. pages.end.base())
+}
+
 impl StackAllocator for PageRange {
 
     fn allocate<A>( &mut self
                       , page_table: &mut ActivePageTable
                       , frames: &mut A
-                      , num_pages: usize) -> AllocResult<Stack>
+                      , num_pages: usize) -> MapResult<Stack>
     where A: FrameAllocator {
-        use memory::{PAGE_SIZE, Page};
-        use arch::table::WRITABLE;
-        let exhausted = || {
-            AllocErr::Exhausted {
-                request: Layout::from_size_align( PAGE_SIZE as usize * num_pages
-                                                , PAGE_SIZE as usize)
-            }
-        };
         if num_pages == 0 {
-            Err(AllocErr::Unsupported {
-                details: "Why would you try to allocate a zero-page stack?"
+            Err(MapErr::Other {
+                message: "allocate stack"
+              , page: self.start
+              , cause: "Why would you try to allocate a zero-page stack?"
             })
+        } else if self.end.number - self.start.number < num_pages + 1 {
+            Err(exhausted(self.start, num_pages))
         } else {
-            // clone a working copy of the stack allocator's page range
-            // we will only write it back if we successfully allocate a new
-            // stack
-            let mut working_pages = self.clone();
+            // skip a guard page, and take `num_pages` pages for the stack.
+            let start_page = self.start + 1;
+            let end_page = start_page + num_pages;
+            let stack = map_stack(start_page .. end_page, page_table, frames)?;
 
-            // try to get a guard page
-            working_pages.next().ok_or_else(&exhausted)?;
+            // successfully allocated! remove the stack from the page range
+            self.start = end_page;
+            Ok(stack)
+        }
+    }
+}
 
-            let start_page = working_pages.next().ok_or_else(&exhausted)?;
-            let end_page   = if num_pages == 1 { start_page }
-                             else { working_pages.nth(num_pages - 2)
-                                                 .ok_or_else(&exhausted)?
-                             };
+/// A pool of fixed-size stacks, each with a guard page below it.
+///
+/// The pool manages a region of the address space starting at `base`, which
+/// is divided into `capacity` slots of `stack_pages + 1` pages. The first
+/// page of each slot is the guard page, which is never mapped.
+#[derive(Debug)]
+pub struct StackPool { base: VirtualPage
+                     , stack_pages: usize
+                     , /// the number of slots which may be used.
+                       capacity: usize
+                     , /// bitmap of the slots which are currently in use.
+                       in_use: [u64; BITMAP_WORDS]
+                     }
 
-            // successfully allocated! write back the working page range
-            *self = working_pages;
+impl StackPool {
+    /// Create a new `StackPool` of stacks of `stack_pages` pages, starting at
+    /// the page `base`, with room for `DEFAULT_STACKS` stacks.
+    pub const fn new(base: VirtualPage, stack_pages: usize) -> Self {
+        StackPool { base: base
+                  , stack_pages: stack_pages
+                  , capacity: DEFAULT_STACKS
+                  , in_use: [0; BITMAP_WORDS]
+                  }
+    }
 
-            for page in start_page .. end_page {
-                page_table.map_to_any(page, WRITABLE, frames);
-            }
+    /// Returns the number of stacks this pool has room for.
+    #[inline]
+    pub fn capacity(&self) -> usize { self.capacity }
 
-            let stack_top = end_page.end_address();
-            Ok(stack_top .. start_page.base())
+    /// Make room for `additional` more stacks than are currently free.
+    ///
+    /// # Returns
+    /// + `Ok(())` if the pool now has at least `additional` free slots
+    /// + `Err(&str)` if that would take more than `MAX_STACKS` slots.
+    pub fn reserve(&mut self, additional: usize) -> Result<(), &'static str> {
+        let needed = self.allocated() + additional;
+        if needed > MAX_STACKS {
+            return Err("the kernel stack pool can't hold that many stacks")
+        }
+        if needed > self.capacity {
+            trace!("stack pool grew from {} to {}", self.capacity, needed);
+            self.capacity = needed;
         }
+        Ok(())
+    }
+
+    /// Returns true if `slot` is in use.
+    #[inline]
+    fn is_used(&self, slot: usize) -> bool {
+        self.in_use[slot / 64] & (1 << (slot % 64)) != 0
+    }
+
+    #[inline]
+    fn set_used(&mut self, slot: usize, used: bool) {
+        if used { self.in_use[slot / 64] |= 1 << (slot % 64) }
+        else { self.in_use[slot / 64] &= !(1 << (slot % 64)) }
+    }
+
+    /// Returns the number of pages in each slot (including the guard page).
+    #[inline]
+    fn slot_pages(&self) -> usize { self.stack_pages + 1 }
+
+    /// Returns the range of pages for the stack in `slot`, not including the
+    /// guard page.
+    #[inline]
+    fn slot(&self, slot: usize) -> PageRange {
+        let start = VirtualPage {
+            number: self.base.number + slot * self.slot_pages() + 1
+        };
+        start .. VirtualPage { number: start.number + self.stack_pages }
+    }
+
+    /// Returns the number of stacks currently allocated from this pool.
+    #[inline]
+    pub fn allocated(&self) -> usize {
+        self.in_use.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    /// Allocate a new stack from this pool, and map it to free frames.
+    ///
+    /// # Returns
+    /// + `Ok(Stack)` if a stack was allocated
+    /// + `Err(MapErr)` if all `capacity` stacks are in use, or mapping the
+    ///   stack failed.
+    pub fn allocate<A>( &mut self
+                      , page_table: &mut ActivePageTable
+                      , frames: &mut A)
+                      -> MapResult<Stack>
+    where A: FrameAllocator {
+        let slot = match (0 .. self.capacity).find(|&i| !self.is_used(i)) {
+            Some(slot) => slot
+          , None => return Err(exhausted(self.base, self.stack_pages))
+        };
+        let stack = map_stack(self.slot(slot), page_table, frames)?;
+        self.set_used(slot, true);
+        trace!("allocated stack {} at {:?}", slot, stack);
+        Ok(stack)
+    }
+
+    /// Return a stack to this pool, unmapping it and freeing its frames.
+    ///
+    /// # Returns
+    /// + `Ok(())` if the stack was freed
+    /// + `Err(MapErr)` if `stack` was not allocated from this pool, or it
+    ///   could not be unmapped.
+    pub fn deallocate<A>( &mut self
+                        , stack: Stack
+                        , page_table: &mut ActivePageTable
+                        , frames: &mut A)
+                        -> MapResult<()>
+    where A: FrameAllocator {
+        let page = VirtualPage::containing(stack.start);
+        let not_ours = MapErr::Other {
+            message: "deallocate stack"
+          , page: page
+          , cause: "the stack was not allocated from this pool"
+        };
+        if page.number <= self.base.number { return Err(not_ours) }
+
+        let slot = (page.number - self.base.number - 1) / self.slot_pages();
+        let pages = self.slot(slot);
+        if slot >= MAX_STACKS || !self.is_used(slot)
+            || stack != (pages.start.base() .. pages.end.base()) {
+            return Err(not_ours)
+        }
+
+        page_table.unmap_range(pages, frames)?;
+        self.set_used(slot, false);
+        trace!("freed stack {} at {:?}", slot, stack);
+        Ok(())
     }
 }
@@ -65,11 +65,17 @@ pub struct InitParams {
     /// N.B. that this is currently never `None`, as we only support multiboot.
     /// However, this may change at a later date.
     pub multiboot_end: Option<PAddr>
+  , /// The physical address of the ACPI root system description pointer, if
+    /// one was found.
+    pub acpi_rsdp: Option<PAddr>
   , /// Map of memory areas
     pub mem_map: ArrayVec<[mem::Area; MAX_MEM_AREAS]>
     , /// Map of elf sections
     // todo: construct using convert::From<multiboot>
      pub elf_sections: Option<ElfSections>
+  , /// The index of the ELF section containing the sections' names, if
+    /// the ELF sections are known.
+    pub elf_strtab_index: Option<u32>
 }
 
 impl Default for InitParams {
@@ -88,8 +94,10 @@ impl Default for InitParams {
                    , stack_top: PAddr::from(0x0)
                    , multiboot_start: None
                    , multiboot_end: None
+                   , acpi_rsdp: None
                    , mem_map: ArrayVec::<[mem::Area; MAX_MEM_AREAS]>::new()
                    , elf_sections: None
+                   , elf_strtab_index: None
                    }
     }
 }
@@ -1,5 +1,5 @@
 //! This module integrates the buddy heap allocator into the Rust runtime.
-use spin::Mutex;
+use util::sync::IrqMutex;
 use core::ptr;
 
 use ::{Allocator, Layout};
@@ -8,8 +8,12 @@ use super::{Heap, FreeList};
 /// The number of free lists for the kernel heap
 pub const NUM_FREE_LISTS: usize = 19;
 
-static ALLOC: Mutex<Option<Heap<'static>>>
-    = Mutex::new(None);
+/// The kernel heap.
+///
+/// Interrupt handlers may allocate, so this disables interrupts while it is
+/// locked.
+static ALLOC: IrqMutex<Option<Heap<'static>>>
+    = IrqMutex::new(None);
 
 static mut KERNEL_FREE_LISTS: [FreeList; NUM_FREE_LISTS]
     // TODO: I really wish there was a less awful way to do this...
@@ -10,6 +10,11 @@
 //!
 //! This is basically just a bump pointer allocator for frames; since
 //! it doesn't support deallocating frames.
+//!
+//! Frames below 1 MiB are never allocated. Real mode code can only address
+//! that memory, so it's left for code which needs a frame there at a known
+//! address (such as the trampoline which starts the other CPUs), along with
+//! the BIOS's own data.
 use super::{Frame, FrameRange, Allocator};
 use ::{AllocResult, AllocErr, Layout};
 use params::{InitParams, mem};
@@ -31,6 +36,9 @@ pub struct MemMapAllocator<'a> { next_free: Frame
                                , areas: mem::Map<'a>
                                , kernel_frames: FrameRange
                                , mb_frames: FrameRange
+                               , /// the frames below 1 MiB, which are
+                                 /// never allocated
+                                 low_frames: FrameRange
                                }
 impl<'a> MemMapAllocator<'a> {
     fn next_area(&mut self) {
@@ -59,6 +67,8 @@ impl<'a> From<&'a InitParams> for MemMapAllocator<'a> {
             // TODO: handle non-multiboot case
             , mb_frames: Frame::containing(params.multiboot_start()) ..
                          Frame::containing(params.multiboot_end()).add_one()
+            , low_frames: Frame::containing(PAddr::new(0)) ..
+                          Frame::containing(PAddr::new(0x10_0000))
             };
         trace!("creating mem map allocator");
         trace!("kernel frames: {:?}", new_allocator.kernel_frames);
@@ -76,7 +86,12 @@ impl<'a> Allocator for MemMapAllocator<'a> {
         if let Some(area) = self.current_area {
             let frame = Frame { number: self.next_free.number };
             match frame {
-                // all frames in the current memory area are in use
+                // this frame is below 1 MiB, which is reserved.
+                f if f < self.low_frames.end => {
+                    // skip ahead to the first frame above 1 MiB.
+                    self.next_free = self.low_frames.end;
+                }
+              , // all frames in the current memory area are in use
                 f if f > Frame::containing(area.end_addr) => {
                     // so we advance to the next free area
 
@@ -73,6 +73,8 @@ extern crate spin;
 
 #[cfg(feature = "buddy_as_system")]
 #[macro_use] extern crate once;
+#[cfg(feature = "buddy_as_system")]
+extern crate util;
 
 #[macro_use] extern crate log;
 
@@ -27,7 +27,8 @@
 #[cfg(target_arch="x86_64")] pub use self::x86_64::*;
 
 // 32-bit x86 (protected mode)
-// TODO: NYI
+// TODO: NYI. the `paging` crate supports 32-bit x86, but there is no boot
+//       code or `arch::x86` module yet, so the kernel can't be built for it.
 #[cfg(target_arch = "x86")] mod x86;
 #[cfg(target_arch = "x86")] pub use self::x86::*;
 
@@ -0,0 +1,79 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Selecting the kernel's clock source.
+use core::mem;
+
+use cpu::timer::clock::{self, Counter, Ticks};
+use cpu::timer::hpet::Hpet;
+use memory::PAGE_SIZE;
+use paging::MapResult;
+use paging::arch::ActivePageTable;
+use paging::arch::mmio::{ioremap, CacheType};
+use sos_alloc::FrameAllocator;
+
+/// How long to measure the TSC for, in milliseconds.
+const CALIBRATION_MS: u64 = 50;
+
+/// Tell the time with the TSC, if it is reliable.
+///
+/// The TSC is calibrated against the HPET, if the ACPI tables describe one,
+/// and otherwise against the system tick. If the TSC is unreliable, the
+/// system tick remains the clock source.
+///
+/// This must be called after interrupts have been initialized, since the
+/// system tick must be running.
+pub fn initialize<A>(table: &mut ActivePageTable, alloc: &mut A)
+                    -> MapResult<()>
+where A: FrameAllocator {
+    if let Err(why) = clock::is_tsc_reliable() {
+        kinfoln!(dots: " . . ", "Not using the TSC: {}", why);
+        return Ok(())
+    }
+
+    let hpet_addr = super::acpi::tables()
+                        .and_then(|acpi| acpi.hpet)
+                        .map(|hpet| hpet.address);
+    let hz = match hpet_addr {
+        Some(addr) => {
+            let regs = ioremap( table, addr, PAGE_SIZE as usize
+                              , CacheType::Uncached, alloc)?;
+            match unsafe { Hpet::new(regs.base()) } {
+                Ok(hpet) => {
+                    unsafe { hpet.enable() };
+                    kinfoln!( dots: " . . ", "{}-bit HPET counter runs at {} Hz"
+                            , if hpet.is_64_bit() { 64 } else { 32 }
+                            , hpet.frequency());
+                    // the HPET registers stay mapped for as long as the
+                    // kernel runs.
+                    mem::forget(regs);
+                    calibrate(&hpet)
+                }
+              , Err(why) => {
+                    kinfoln!(dots: " . . ", "Not using the HPET: {}", why);
+                    calibrate(&Ticks)
+                }
+            }
+        }
+      , None => calibrate(&Ticks)
+    };
+
+    match clock::use_tsc(hz) {
+        Ok(()) => kinfoln!( dots: " . . ", "Clock source is the {}"
+                          , clock::source())
+      , Err(why) => kinfoln!(dots: " . . ", "Not using the TSC: {}", why)
+    }
+    Ok(())
+}
+
+fn calibrate<C: Counter>(reference: &C) -> u64 {
+    let hz = clock::calibrate_tsc(reference, CALIBRATION_MS);
+    kinfoln!( dots: " . . ", "TSC runs at {}.{:03} MHz (measured against {})"
+            , hz / 1_000_000, hz / 1_000 % 1_000, reference.name());
+    hz
+}
@@ -16,12 +16,11 @@
 //! See [the OS Dev wiki](http://wiki.osdev.org/Serial_Ports) for more
 //! information.
 
-use spin::Mutex;
-
 use core::fmt;
 
 use ::arch::bda;
 use cpu::Port;
+use cpu::interrupts::IrqMutex;
 // use ::io;
 use util::{io, Void};
 //
@@ -50,17 +49,17 @@ lazy_static! {
     //       locked instead? I think multiple threads should be able to read
     //       from a serial port at the same time without causing trouble?
     //          - eliza, 10/9/2016
-    pub static ref COM1: Mutex<Serial>
-        = Mutex::new(Serial(bda::ports::com1().map(SerialPort::new)));
+    pub static ref COM1: IrqMutex<Serial>
+        = IrqMutex::new(Serial(bda::ports::com1().map(SerialPort::new)));
 
-    pub static ref COM2: Mutex<Serial>
-        = Mutex::new(Serial(bda::ports::com2().map(SerialPort::new)));
+    pub static ref COM2: IrqMutex<Serial>
+        = IrqMutex::new(Serial(bda::ports::com2().map(SerialPort::new)));
 
-    pub static ref COM3: Mutex<Serial>
-        = Mutex::new(Serial(bda::ports::com3().map(SerialPort::new)));
+    pub static ref COM3: IrqMutex<Serial>
+        = IrqMutex::new(Serial(bda::ports::com3().map(SerialPort::new)));
 
-    pub static ref COM4: Mutex<Serial>
-        = Mutex::new(Serial(bda::ports::com4().map(SerialPort::new)));
+    pub static ref COM4: IrqMutex<Serial>
+        = IrqMutex::new(Serial(bda::ports::com4().map(SerialPort::new)));
 }
 
 
@@ -9,7 +9,7 @@
 //! Arch-specific VGA port port driver
 
 use vga::{Palette, Color, Terminal};
-use spin::Mutex;
+use cpu::interrupts::IrqMutex;
 
 // extern {
 //     #[link_section = ".__vga_buffer"]
@@ -17,8 +17,8 @@ use spin::Mutex;
 // }
 
 /// The system's global VGA terminal
-pub static CONSOLE: Mutex<Terminal>
-    = Mutex::new(unsafe { Terminal::new(
+pub static CONSOLE: IrqMutex<Terminal>
+    = IrqMutex::new(unsafe { Terminal::new(
          Palette::new(Color::LightGrey, Color::Black )
        , 0x8000
     )});
@@ -0,0 +1,69 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The kernel's Global Descriptor Table.
+//!
+//! The boot code loads a minimal, read-only GDT with just the kernel code
+//! and data segments. This replaces it with one that also has the user
+//! segments, and room for each CPU's TSS descriptor. The selectors for the
+//! segments are the constants in `cpu::segment`.
+use cpu::dtable::DTable;
+use cpu::segment::{Gdt, Selector};
+use cpu::task::StateSegment;
+use spin::Mutex;
+
+/// The GDT.
+///
+/// This is never moved, so the CPU can keep using it while it's unlocked.
+static GDT: Mutex<Gdt> = Mutex::new(Gdt::new());
+
+/// Returns the GDT in `GDT`, with the `'static` lifetime that loading it
+/// requires.
+///
+/// The lock guard only borrows the GDT for as long as it's held, but the GDT
+/// itself lives in a `static`, so it outlives the guard. The lock should
+/// still be held while the GDT is loaded, so that it isn't changed
+/// underneath `lgdt`.
+///
+/// # Safety
+/// + `gdt` must have been borrowed from a lock guard on `GDT`.
+unsafe fn static_gdt(gdt: &Gdt) -> &'static Gdt {
+    &*(gdt as *const Gdt)
+}
+
+/// Load the kernel's GDT, replacing the boot GDT.
+///
+/// # Safety
+/// + This should only be called by the kernel init process.
+pub unsafe fn initialize() {
+    let gdt = GDT.lock();
+    static_gdt(&gdt).activate();
+    kinfoln!(dots: " . ", target: "Loading the kernel GDT", "[ OKAY ]");
+}
+
+/// Load the kernel's GDT on an application processor.
+///
+/// # Safety
+/// + This should only be called by an application processor as it starts,
+///   after the boot CPU has called `initialize`.
+pub unsafe fn initialize_ap() {
+    let gdt = GDT.lock();
+    static_gdt(&gdt).activate();
+}
+
+/// Add a descriptor for the TSS `tss` to the GDT, and reload it.
+///
+/// # Returns
+/// + `Ok(Selector)` with the selector for the TSS, to pass to `ltr`
+/// + `Err(&str)` if the GDT is full.
+pub fn add_tss(tss: &'static StateSegment) -> Result<Selector, &'static str> {
+    let mut gdt = GDT.lock();
+    let selector = gdt.add_tss(tss)?;
+    unsafe { static_gdt(&gdt) }.load();
+    Ok(selector)
+}
@@ -7,11 +7,20 @@
 //  directory of this repository for more information.
 //
 
-use cpu::interrupts::pics;
+use cpu::interrupts::{apic, end_of_interrupt, ioapic, irq, pics};
+use cpu::timer;
 use cpu::interrupts::idt::{Gate, Idt};
 
 use cpu::context::InterruptFrame;
 use cpu::dtable::DTable;
+use cpu::fpu;
+use cpu::task;
+use util::backtrace::Symbolized;
+use vga::panic::print_backtrace;
+
+use sos_alloc::FrameAllocator;
+use paging::MapResult;
+use paging::arch::ActivePageTable;
 
 
 //==--------------------------------------------------------------------------==
@@ -19,14 +28,23 @@ use cpu::dtable::DTable;
 
 /// Initialize interrupt handling.
 ///
-/// This function initializes the PICs, populates the IDT with interrupt
-/// handlers, loads the IDT pointer, and enables interrupts.
+/// This function loads the TSS, initializes the PICs, populates the IDT with
+/// interrupt handlers, loads the IDT pointer, starts the system tick, registers the
+/// keyboard IRQ handler, and enables interrupts.
+///
+/// If the CPU has a local APIC, IRQs are delivered through the local APIC
+/// and I/O APIC rather than the PICs (see [`initialize_apic`]). Mapping their
+/// registers requires the active page table and a frame allocator.
 ///
 /// This is called from the kernel during the init process.
-// TODO: make the result returned by this meaningful?
-#[inline]
-pub unsafe fn initialize() -> Result<(), ()>{
+///
+/// [`initialize_apic`]: fn.initialize_apic.html
+pub unsafe fn initialize<A>(table: &mut ActivePageTable, alloc: &mut A)
+                           -> MapResult<()>
+where A: FrameAllocator {
 
+    // the IDT refers to the TSS's interrupt stacks, so the TSS goes first.
+    super::tss::initialize(table, alloc)?;
     pics::initialize();
    // TODO: consider loading double-fault handler before anything else in case
    //       a double fault occurs during init?
@@ -35,22 +53,110 @@ pub unsafe fn initialize() -> Result<(), ()>{
     // debug!("Testing interrupt handling");
     // asm!("int $0" :: "N" (0xff));
 
+    if apic::is_supported() {
+        initialize_apic(table, alloc)?;
+    } else {
+        kinfoln!(dots: " . . ", "No local APIC found, using the PICs.");
+    }
+
+    let tick_ns = attempt!( timer::init(timer::DEFAULT_HZ) =>
+                            dots: " . . ", "Starting the system tick" );
+    kinfoln!( dots: " . . ", "System tick runs at {} Hz ({} ns/tick)"
+            , timer::DEFAULT_HZ, tick_ns);
+    attempt!( irq::register(1, "keyboard", keyboard) =>
+              dots: " . . ", "Registering keyboard IRQ handler" );
+
     Idt::enable_interrupts(); // enable interrupts
     Ok(())
 
 }
 
+/// Load the IDT on an application processor, and enable its local APIC.
+///
+/// IRQs are still only delivered to the boot CPU; application processors
+/// receive exceptions and inter-processor interrupts. Interrupts are left
+/// disabled.
+///
+/// # Safety
+/// + This should only be called by an application processor as it starts,
+///   after the boot CPU has called `initialize` and loaded the AP's TSS.
+pub unsafe fn initialize_ap() {
+    IDT.load();
+    if let Some(lapic) = apic::local() {
+        lapic.enable();
+    }
+}
+
+/// Switch IRQ delivery from the PICs to the local APIC and I/O APIC.
+///
+/// The I/O APICs and ISA IRQ overrides are taken from the ACPI MADT, if there
+/// is one. ISA IRQs are routed through the I/O APIC to the same vectors the
+/// PICs deliver them on, as their first handlers are registered (see
+/// `irq::register`).
+unsafe fn initialize_apic<A>(table: &mut ActivePageTable, alloc: &mut A)
+                            -> MapResult<()>
+where A: FrameAllocator {
+    use core::mem;
+    use memory::PAGE_SIZE;
+    use paging::arch::mmio::{ioremap, CacheType};
+
+    let lapic_regs = ioremap( table, apic::base_paddr(), PAGE_SIZE as usize
+                            , CacheType::Uncached, alloc)?;
+    let lapic = apic::init(lapic_regs.base());
+    kinfoln!( dots: " . . ", "Enabled local APIC {} (version {:#x}) at {:?}"
+            , lapic.id(), lapic.version(), lapic_regs);
+
+    match super::acpi::tables().and_then(|acpi| acpi.madt.as_ref()) {
+        Some(madt) => {
+            for io_apic in madt.io_apics.iter() {
+                let regs = ioremap( table, io_apic.address, PAGE_SIZE as usize
+                                  , CacheType::Uncached, alloc)?;
+                let controller = ioapic::IoApic::new( regs.base()
+                                                    , io_apic.gsi_base);
+                attempt!( ioapic::register(controller) =>
+                          dots: " . . ", "Registering I/O APIC {} at {:?}"
+                          , io_apic.id, regs );
+                // the I/O APIC registers stay mapped for as long as the
+                // kernel runs.
+                mem::forget(regs);
+            }
+            for over in madt.overrides.iter() {
+                ioapic::override_isa_irq( over.irq, over.gsi
+                                        , over.redirection_flags());
+            }
+        }
+      , None => {
+            // without a MADT, assume the usual configuration.
+            let regs = ioremap( table, ioapic::DEFAULT_PADDR
+                              , PAGE_SIZE as usize, CacheType::Uncached
+                              , alloc)?;
+            attempt!( ioapic::register(ioapic::IoApic::new(regs.base(), 0)) =>
+                      dots: " . . ", "Registering I/O APIC at {:?}", regs );
+            mem::forget(regs);
+            // the PIT is connected to GSI 2 on nearly every PC (including
+            // QEMU).
+            ioapic::override_isa_irq(0, 2, ioapic::RedirectionFlags::empty());
+        }
+    }
+
+    // the local APIC registers stay mapped for as long as the kernel runs.
+    mem::forget(lapic_regs);
+
+    pics::disable();
+    Ok(())
+}
+
 macro_rules! exception_inner {
     ($title:expr, $kind:expr, $source:expr, $f:expr) => {
         use vga::{CONSOLE, Color};
         use core::fmt::Write;
         let _ = write!( CONSOLE.lock()
                                .set_colors(Color::White, Color::Blue)
-                      , "EVERYTHING IS FINE: {}{} at {:p}\n\
+                      , "EVERYTHING IS FINE: {}{} at {}\n\
                          Source: {}.\nThis is fine.\n\n\
                          {:?}"
                          , $title, $kind
-                         , (*$f).rip
+                         , Symbolized((*$f).rip as usize)
                          , $source
                          , *$f);
     };
@@ -59,12 +165,12 @@ macro_rules! exception_inner {
         use core::fmt::Write;
         let _ = write!( CONSOLE.lock()
                                .set_colors(Color::White, Color::Blue)
-                      , "EVERYTHING IS FINE: {}{} at {:p}\n\
+                      , "EVERYTHING IS FINE: {}{} at {}\n\
                          Source: {}.\n
                          Error code: {:x}\nThis is fine.\n\n\
                          {:?}"
                          , $title, $kind
-                         , (*$f).rip
+                         , Symbolized((*$f).rip as usize)
                          , $source
                          , $e
                          , *$f);
@@ -76,6 +182,7 @@ macro_rules! exceptions {
         #[doc=$title]
         extern "x86-interrupt" fn $name(frame: &InterruptFrame) {
             exception_inner! ($title, "Fault", $source, frame);
+            print_backtrace(unsafe { frame.backtrace() });
             loop {}
         }
 
@@ -86,6 +193,7 @@ macro_rules! exceptions {
         extern "x86-interrupt" fn $name( frame: &InterruptFrame
                                        , error_code: usize) {
            exception_inner! ($title, "Fault", $source, frame, error_code);
+           print_backtrace(unsafe { frame.backtrace() });
            loop {}
        }
        exceptions! { $($tail)* }
@@ -111,9 +219,6 @@ exceptions! {
           "BOUND instruction",
     fault: undefined_opcode, "Undefined Opcode",
            "UD2 instruction or reserved opcode",
-    fault: device_not_available, "Device Not Available"
-         , "Floating-point or WAIT/FWAIT instruction \
-            (no math coprocessor)",
     fault (code): double_fault, "Double Fault"
          , "Any instruction that can generate an exception, a NMI, or \
             an INTR",
@@ -133,8 +238,41 @@ exceptions! {
          , "Model-dependent (probably hardware!)",
     fault (code): alignment_check, "Alignment Check"
          , "Any data reference in memory",
-    fault: simd_fp_exception, "SIMD Floating-Point Exception"
-         , "SSE/SSE2/SSE3 floating-point instructions",
+}
+
+/// Handles device not available exceptions (`#NM`).
+///
+/// These are expected after a context switch, when extended state is
+/// switched lazily; anything else is fatal.
+extern "x86-interrupt" fn device_not_available(frame: &InterruptFrame) {
+    if unsafe { fpu::handle_device_not_available() } {
+        return
+    }
+    exception_inner!( "Device Not Available", "Fault"
+                    , "Floating-point or WAIT/FWAIT instruction \
+                       (no math coprocessor)"
+                    , frame);
+    print_backtrace(unsafe { frame.backtrace() });
+    loop {}
+}
+
+/// Handles SIMD floating-point exceptions (`#XF`).
+///
+/// Since the kernel doesn't use SSE, and there are no signals to deliver to
+/// user code yet, these are always fatal. The exception flags in `MXCSR`
+/// say which exception it was.
+extern "x86-interrupt" fn simd_fp_exception(frame: &InterruptFrame) {
+    exception_inner!( "SIMD Floating-Point Exception", "Fault"
+                    , "SSE/SSE2/SSE3 floating-point instructions"
+                    , frame);
+    {
+        use vga::CONSOLE;
+        use core::fmt::Write;
+        let _ = write!( CONSOLE.lock(), "\nMXCSR: {:#06x}\n"
+                      , unsafe { fpu::read_mxcsr() });
+    }
+    print_backtrace(unsafe { frame.backtrace() });
+    loop {}
 }
 
 lazy_static! {
@@ -147,28 +285,36 @@ lazy_static! {
         //          - eliza, 5/22/2017
         idt.divide_by_zero = Gate::from(divide_by_zero as InterruptHandler);
         idt.nmi = Gate::from(nmi as InterruptHandler);
+        idt.nmi.set_stack_index(task::NMI_IST);
         idt.overflow = Gate::from(overflow as InterruptHandler);
         idt.overflow.set_trap();
         idt.bound_exceeded = Gate::from(bound_exceeded as InterruptHandler);
         idt.undefined_opcode = Gate::from(undefined_opcode as InterruptHandler);
         idt.device_not_available = Gate::from(device_not_available as InterruptHandler);
         idt.double_fault = Gate::from(double_fault as ErrorCodeHandler);
+        idt.double_fault.set_stack_index(task::DOUBLE_FAULT_IST);
         idt.invalid_tss = Gate::from(invalid_tss as ErrorCodeHandler);
         idt.segment_not_present = Gate::from(segment_not_present as ErrorCodeHandler);
         idt.stack_segment_fault = Gate::from(stack_segment_fault as ErrorCodeHandler);
         idt.general_protection_fault = Gate::from(general_protection_fault as ErrorCodeHandler);
-        idt.page_fault = Gate::from(page_fault as ErrorCodeHandler);
 
         idt.floating_point_error = Gate::from(floating_point_error as InterruptHandler);
         idt.alignment_check = Gate::from(alignment_check as ErrorCodeHandler);
         idt.machine_check = Gate::from(machine_check as InterruptHandler);
+        idt.machine_check.set_stack_index(task::MACHINE_CHECK_IST);
         idt.simd_fp_exception = Gate::from(simd_fp_exception as InterruptHandler);
 
         idt.breakpoint = Gate::from(breakpoint as InterruptHandler);
-        idt.page_fault = Gate::from(page_fault as ErrorCodeHandler);
+        idt.page_fault = Gate::from(self::page_fault as ErrorCodeHandler);
 
-        idt.interrupts[0x20 - 32] = Gate::from(timer as InterruptHandler);
-        idt.interrupts[0x21 - 32] = Gate::from(keyboard as InterruptHandler);
+        for (i, &stub) in irq::STUBS.iter().enumerate() {
+            idt.interrupts[irq::VECTOR_BASE as usize - 32 + i]
+                = Gate::from(stub);
+        }
+        idt.interrupts[apic::ERROR_VECTOR as usize - 32]
+            = Gate::from(apic_error as InterruptHandler);
+        idt.interrupts[apic::SPURIOUS_VECTOR as usize - 32]
+            = Gate::from(spurious as InterruptHandler);
         idt.interrupts[0xff - 32] = Gate::from(test as InterruptHandler);
 
         kinfoln!( dots: " . . ", target: "Adding interrupt handlers to IDT"
@@ -178,8 +324,8 @@ lazy_static! {
 }
 
 
-#[no_mangle] #[inline(never)]
-pub extern "x86-interrupt" fn keyboard(_frame: &InterruptFrame) {
+/// Handler for the keyboard IRQ.
+fn keyboard(_irq: u8) -> bool {
     use io::keyboard;
 
     // println!("keyboard happened");
@@ -190,26 +336,46 @@ pub extern "x86-interrupt" fn keyboard(_frame: &InterruptFrame) {
             print!("{}", input);
         }
     }
-   // send the PICs the end interrupt signal
-   unsafe {
-       pics::end_pic_interrupt(0x21);
-   }
+    true
 }
 
 #[no_mangle] #[inline(never)]
 pub extern "x86-interrupt" fn breakpoint(frame: &InterruptFrame) {
     println!("Breakpoint! Frame: {:#?}", frame);
-   // send the PICs the end interrupt signal
+   // signal the end of the interrupt
    unsafe {
-       pics::end_pic_interrupt(0x03);
+       end_of_interrupt(0x03);
    }
 }
 
-/// Empty dummy handler for undefined interrupts.
-#[no_mangle] #[inline(never)]
-pub extern "x86-interrupt" fn empty_handler(_frame: &InterruptFrame) {
-    // TODO: it would be nice to know *which vector* the dummy interrupt
-    //      fired on, for debugging purposes...
-    //          - eliza, 05/25/2017
-    debug!("an empty interrupt fired!")
+/// Handles page fault exceptions.
+///
+/// This reports the same information as `cpu::interrupts::page_fault`, but
+/// also checks the page tables to explain faults caused by SMEP or SMAP.
+#[inline(never)]
+pub extern "x86-interrupt" fn page_fault( frame: &InterruptFrame
+                                        , error_code: usize) {
+    use cpu::control_regs::cr2;
+    use cpu::interrupts::{describe_page_fault, PageFaultErrorCode};
+    use cpu::smap;
+    use memory::{Page, VAddr, VirtualPage};
+    use paging::arch::ActivePageTable;
+    use paging::arch::table::USER_ACCESSIBLE;
+
+    let addr = VAddr::from(unsafe { cr2::read() });
+    let user_page = unsafe { ActivePageTable::new() }
+        .entry_flags(VirtualPage::containing(addr))
+        .map_or(false, |flags| flags.contains(USER_ACCESSIBLE));
+    let code = PageFaultErrorCode::from_bits_truncate(error_code as u32);
+
+    if let Some(why) = smap::diagnose(code, frame.rflags, user_page) {
+        use vga::{CONSOLE, Color};
+        use core::fmt::Write;
+        let _ = write!( CONSOLE.lock()
+                               .set_colors(Color::White, Color::Red)
+                      , "{} (at {:?})\n", why, addr);
+    }
+    describe_page_fault(frame, error_code);
+    print_backtrace(unsafe { frame.backtrace() });
+    loop { }
 }
@@ -8,9 +8,17 @@
 //
 //! `x86_64` architecture-specific implementation.
 // pub mod cpu;
+pub mod clock;
 pub mod drivers;
+pub mod gdt;
 pub mod interrupts;
+pub mod percpu;
+pub mod smp;
+pub mod symbols;
+pub mod syscall;
+pub mod tss;
 
+#[path = "../x86_all/acpi/mod.rs"] pub mod acpi;
 #[path = "../x86_all/bda.rs"] pub mod bda;
 #[path = "../x86_all/multiboot2.rs"] pub mod multiboot2;
 
@@ -66,7 +74,7 @@ pub unsafe extern "C" fn long_mode_init() {
 /// bad problem and not go to space today.
 #[no_mangle]
 pub extern "C" fn arch_init(multiboot_addr: PAddr) {
-    use cpu::{control_regs, msr};
+    use cpu::{control_regs, cpuid, fpu, msr};
     use params::{InitParams, mem};
 
     kinfoln!(dots: " . ", "Beginning `arch_init()` for x86_64");
@@ -75,6 +83,10 @@ pub extern "C" fn arch_init(multiboot_addr: PAddr) {
     ::logger::initialize()
         .expect("Could not initialize logger!");
 
+    unsafe { gdt::initialize() };
+    unsafe { percpu::install(percpu::boot_cpu()) };
+    kinfoln!(dots: " . ", "Installed the boot CPU's per-CPU area");
+
 
     // -- Unpack multiboot tag ------------------------------------------------
     kinfoln!( dots: " . "
@@ -126,18 +138,33 @@ pub extern "C" fn arch_init(multiboot_addr: PAddr) {
     kinfoln!( dots: " . . ", "Multiboot info begins at {:#x} and ends at {:#x}."
             , multiboot_addr, multiboot_end);
 
+    // Find the ACPI RSDP now, while the BIOS areas are still identity mapped
+    let acpi_rsdp = unsafe { acpi::find_rsdp(boot_info) };
+    match acpi_rsdp {
+        Some(rsdp) => kinfoln!(dots: " . ", "Found ACPI RSDP at {:?}", rsdp)
+      , None => kinfoln!(dots: " . ", "No ACPI RSDP found.")
+    }
+
     let mut params = InitParams { kernel_base: kernel_begin
                             , kernel_top: kernel_end
                             , multiboot_start: Some(multiboot_addr)
                             , multiboot_end: Some(multiboot_end)
+                            , acpi_rsdp: acpi_rsdp
                             , heap_base: unsafe { PAddr::from(HEAP_BASE) }
                             , heap_top: unsafe { PAddr::from(HEAP_TOP) }
                             , stack_base: unsafe { PAddr::from(STACK_BASE) }
                             , stack_top: unsafe { PAddr::from(STACK_TOP) }
                             , elf_sections: Some(elf_sections_tag.sections())
+                            , elf_strtab_index:
+                                Some(elf_sections_tag.string_table_index())
                             , ..Default::default()
                         };
 
+    // backtraces only follow frame pointers inside the kernel stack.
+    ::util::backtrace::set_stack_bounds(
+        params.stack_base.as_ptr::<u8>() as usize
+      , params.stack_top.as_ptr::<u8>() as usize );
+
     // Extract the memory map tag from the multiboot info
     let mem_map = boot_info.mem_map()
                            .expect("Memory map tag required!");
@@ -149,19 +176,35 @@ pub extern "C" fn arch_init(multiboot_addr: PAddr) {
         if a.is_usable == true { params.mem_map.push(a); }
     }
 
-     //-- enable flags needed for paging ------------------------------------
-     unsafe {
-        //  control_regs::cr0::enable_write_protect(true);
-        //  kinfoln!(dots: " . ", "Page write protect ENABED" );
-
-        let efer = msr::read(msr::IA32_EFER);
-        trace!("EFER = {:#x}", efer);
-        msr::write(msr::IA32_EFER, efer | (1 << 11));
-        let efer = msr::read(msr::IA32_EFER);
-        trace!("EFER = {:#x}", efer);
-        kinfoln!(dots: " . ", "Page no execute bit ENABLED");
+     //-- detect CPU features ----------------------------------------------
+     kinfoln!(dots: " . ", "Detected CPU:");
+     cpuid::info().print_summary();
+
+     //-- initialize the FPU and SSE unit -----------------------------------
+     match unsafe { fpu::initialize() } {
+         Ok(mode) =>
+            kinfoln!(dots: " . ", "Saving FPU and SSE state with {}", mode)
+       , Err(why) => kinfoln!(dots: " . ", "FPU not initialized: {}", why)
      }
 
+     //-- enable flags needed for paging ------------------------------------
+     // (page write protection is enabled by `paging::kernel_remap`, once the
+     // kernel's page tables are set up.)
+     // the kernel's page tables always use the no execute bit, so there's no
+     // going on without it.
+     assert!( cpuid::has_nx()
+            , "The CPU doesn't support the page no execute bit!");
+     unsafe { msr::enable_nxe() };
+     trace!("EFER = {:#x}", unsafe { msr::read(msr::IA32_EFER) });
+     kinfoln!(dots: " . ", "Page no execute bit ENABLED");
+
+     //-- enable supervisor mode execution & access prevention -------------
+     let prevention = unsafe { cpu::smap::enable() };
+     kinfoln!( dots: " . ", "Supervisor mode execution prevention {}"
+             , if prevention.smep { "ENABLED" } else { "not supported" });
+     kinfoln!( dots: " . ", "Supervisor mode access prevention {}"
+             , if prevention.smap { "ENABLED" } else { "not supported" });
+
     kinfoln!(dots: " . ", "Transferring to `kernel_init()`.");
     ::kernel_init(&params);
 }
@@ -0,0 +1,120 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Per-CPU data.
+//!
+//! Each CPU has a [`Cpu`] area holding its TSS, system call stack and FPU
+//! bookkeeping. The boot CPU's is a static; each application processor's
+//! lives at the top of its kernel stack (see `smp`). The `%gs` base points
+//! at the running CPU's area, so that [`current`] can find it.
+//!
+//! While the kernel runs, `IA32_KERNEL_GS_BASE` holds the user's `%gs`
+//! base, which is 0. The system call entry stub swaps the two bases with
+//! `swapgs` on the way in and out, so that the area is only reachable
+//! through `%gs` in kernel mode.
+//!
+//! N.B. that nothing else swaps them yet. The IRQ stubs and exception
+//! handlers are `x86-interrupt` functions, which don't check the CPL of the
+//! interrupted code or run `swapgs`, so an interrupt taken in user mode
+//! would run with the user's `%gs` base, and [`current`] (which the
+//! scheduler calls after every IRQ) and the `#NM` handler would read through
+//! it. The kernel doesn't start any user code yet; before it does,
+//! interrupt and exception entry must `swapgs` when they interrupt user
+//! code, and again before returning to it.
+//!
+//! [`Cpu`]: struct.Cpu.html
+//! [`current`]: fn.current.html
+use cpu::{cpuid, fpu, msr};
+use cpu::syscall::SyscallStack;
+use cpu::task::StateSegment;
+
+/// Offset of `Cpu::this`, which `current` reads through `%gs`.
+const THIS_OFFSET: usize = 24;
+
+/// A CPU's per-CPU area.
+///
+/// The system call entry stub refers to `syscall` at offset 0, `current` to
+/// `this` at `THIS_OFFSET`, and the `fpu` module to `fpu` at
+/// `fpu::GS_OFFSET`, so the order of the first three fields must not
+/// change.
+#[repr(C)]
+pub struct Cpu { /// The CPU's system call stack
+                 pub syscall: SyscallStack
+               , /// The address of this area, once it's installed
+                 this: *const Cpu
+               , /// The extended state the CPU is switching between
+                 fpu: fpu::PerCpu
+               , /// The CPU's index, counting the boot CPU as 0
+                 index: usize
+               , /// The ID of the CPU's local APIC
+                 apic_id: u8
+               , /// The CPU's TSS
+                 pub tss: StateSegment
+               }
+
+impl Cpu {
+    /// Returns a new per-CPU area for the CPU with the given index.
+    pub const fn new(index: usize) -> Self {
+        Cpu { syscall: SyscallStack::new()
+            , this: 0 as *const Cpu
+            , fpu: fpu::PerCpu::new()
+            , index: index
+            , apic_id: 0
+            , tss: StateSegment::new()
+            }
+    }
+
+    /// Returns the CPU's index, counting the boot CPU as 0.
+    #[inline] pub fn index(&self) -> usize { self.index }
+
+    /// Returns the ID of the CPU's local APIC.
+    #[inline] pub fn apic_id(&self) -> u8 { self.apic_id }
+}
+
+/// The boot CPU's per-CPU area.
+static mut BOOT_CPU: Cpu = Cpu::new(0);
+
+/// Returns the boot CPU's per-CPU area.
+///
+/// # Safety
+/// + This should only be used by the kernel init process, to set the area
+///   up before it is installed.
+#[inline]
+pub unsafe fn boot_cpu() -> &'static mut Cpu { &mut BOOT_CPU }
+
+/// Make `cpu` the running CPU's per-CPU area.
+///
+/// This points the `%gs` base at `cpu`, and sets the user's `%gs` base (in
+/// `IA32_KERNEL_GS_BASE`) to 0.
+///
+/// # Safety
+/// + This should be called once on each CPU, with that CPU's area, after
+///   the GDT has been loaded (since loading `%gs` clears its base).
+pub unsafe fn install(cpu: &'static mut Cpu) {
+    let addr = &mut *cpu as *mut Cpu;
+    cpu.apic_id = (cpuid::cpuid(1, 0).ebx >> 24) as u8;
+    cpu.this = addr;
+    msr::write(msr::IA32_GS_BASE, addr as u64);
+    msr::write(msr::IA32_KERNEL_GS_BASE, 0);
+}
+
+/// Returns the running CPU's per-CPU area.
+///
+/// This must not be called before the area is installed, which `arch_init`
+/// does for the boot CPU as soon as the GDT is loaded, or with the user's
+/// `%gs` base loaded (see the [module docs](index.html)).
+pub fn current() -> &'static Cpu {
+    let this: *const Cpu;
+    unsafe {
+        asm!( "movq %gs:($1), $0"
+            : "=r" (this)
+            : "r" (THIS_OFFSET)
+            :: "volatile" );
+        &*this
+    }
+}
@@ -0,0 +1,425 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Starting the application processors.
+//!
+//! Only the boot CPU is running when the kernel starts. The others, the
+//! application processors (APs), wait until they're sent an INIT
+//! inter-processor interrupt and then a startup IPI, which starts them in
+//! real mode at the page the startup IPI names.
+//!
+//! That page holds a copy of the trampoline below, which switches to
+//! protected mode, turns on paging with the kernel's page tables and long
+//! mode, and calls [`ap_main`] on the stack the boot CPU left in
+//! `AP_BOOT`. The trampoline reads `AP_BOOT` at its link address, which
+//! works before paging is on since the kernel is identity mapped. APs are
+//! started one at a time, so `AP_BOOT` only ever holds one AP's arguments.
+//!
+//! An AP which is slow to start may still be on its way after the boot CPU
+//! has given up on it. So before it touches anything else, the trampoline
+//! claims `AP_BOOT` by atomically changing its state from `AP_STARTING` to
+//! `AP_CLAIMED`, and the boot CPU gives up by changing it to
+//! `AP_ABANDONED`. An AP which loses halts, and one which the boot CPU gave
+//! up on has its stacks freed. Either way, no more APs are started after
+//! one fails, so that `AP_BOOT` is never rewritten under a late AP.
+//!
+//! Each AP's per-CPU area (see `percpu`) goes at the top of its kernel
+//! stack, like a thread's control block. `ap_main` loads the GDT, the AP's
+//! TSS and the IDT, sets up the AP's control registers like `arch_init`
+//! does for the boot CPU, and then halts. Threads still only run on the
+//! boot CPU, and IRQs are only delivered to it.
+//!
+//! [`ap_main`]: fn.ap_main.html
+use core::{iter, mem, ptr};
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+use cpu::{control_regs, fpu, syscall};
+use cpu::interrupts::apic::{self, Destination, Ipi, LocalApic};
+use cpu::interrupts::idt::Idt;
+use cpu::timer::Deadline;
+use memory::{Page, PAddr, PhysicalPage, VAddr, VirtualPage, PAGE_SIZE};
+use paging::Mapper;
+use paging::arch::ActivePageTable;
+use paging::arch::mmio::DeviceFrames;
+use paging::arch::table::{PRESENT, WRITABLE, NO_EXECUTE};
+use paging::stack::{KERNEL_STACKS, KERNEL_STACK_PAGES, Stack};
+use sos_alloc::FrameAllocator;
+
+use super::percpu::{self, Cpu};
+use super::tss;
+
+/// The physical address the trampoline is copied to.
+///
+/// This must be a page below 1 MiB, which the frame allocator never hands
+/// out, and must match `AP_BASE` in the trampoline.
+const TRAMPOLINE: u64 = 0x8000;
+
+/// How long to wait for an AP to start, in milliseconds.
+const START_TIMEOUT_MS: u64 = 100;
+
+/// The number of kernel stacks each AP needs: the stack it boots on (which
+/// holds its per-CPU area), its interrupt stacks, and its system call stack.
+const STACKS_PER_AP: usize = 2 + tss::N_IST_STACKS;
+
+/// `ApBoot::state` while no AP is starting.
+const AP_IDLE: usize = 0;
+/// `ApBoot::state` while an AP is starting, until it claims `AP_BOOT`.
+///
+/// This must match `AP_STARTING` in the trampoline.
+const AP_STARTING: usize = 1;
+/// `ApBoot::state` once the starting AP has claimed `AP_BOOT`.
+///
+/// This must match `AP_CLAIMED` in the trampoline.
+const AP_CLAIMED: usize = 2;
+/// `ApBoot::state` once the boot CPU has given up on the starting AP.
+const AP_ABANDONED: usize = 3;
+
+/// What the trampoline needs to start an AP.
+///
+/// The trampoline refers to these fields by offset, so their order must
+/// not change.
+#[repr(C)]
+struct ApBoot { /// The physical address of the kernel's PML4 table
+                cr3: u64
+              , /// The AP's initial stack pointer
+                stack_top: u64
+              , /// The AP's per-CPU area, which is passed to `ap_main`
+                cpu: u64
+              , /// Whether an AP is starting, and if it has claimed this
+                state: AtomicUsize
+              }
+
+#[no_mangle]
+static mut AP_BOOT: ApBoot = ApBoot { cr3: 0, stack_top: 0, cpu: 0
+                                    , state: ATOMIC_USIZE_INIT };
+
+/// The number of APs which have reached `ap_main`.
+static APS_ONLINE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+extern {
+    static ap_trampoline: u8;
+    static ap_trampoline_end: u8;
+}
+
+// The trampoline starts in real mode, with `%cs` set so that its first
+// instruction is at offset 0. It must be position-independent, except for
+// the addresses it computes from `AP_BASE`.
+global_asm!(r#"
+    .pushsection .text.ap_trampoline, "ax"
+    .set AP_BASE, 0x8000
+    .set AP_STARTING, 1
+    .set AP_CLAIMED, 2
+    .global ap_trampoline
+    .global ap_trampoline_end
+
+    .code16
+ap_trampoline:
+    cli
+    cld
+    movw %cs, %ax
+    movw %ax, %ds
+    lgdtl ap_gdt_ptr - ap_trampoline
+    movl %cr0, %eax
+    orl $1, %eax
+    movl %eax, %cr0
+    ljmpl $0x08, $(AP_BASE + ap_protected - ap_trampoline)
+
+    .code32
+ap_protected:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    # claim `AP_BOOT`. if the boot CPU has given up on us, the stacks in it
+    # may be gone, so halt without touching anything else.
+    movl $AP_STARTING, %eax
+    movl $AP_CLAIMED, %ecx
+    lock cmpxchgl %ecx, AP_BOOT + 24
+    jne ap_abandoned
+    # enable PAE, and load the kernel's page tables.
+    movl %cr4, %eax
+    orl $(1 << 5), %eax
+    movl %eax, %cr4
+    movl AP_BOOT, %eax
+    movl %eax, %cr3
+    # enable long mode, and the no execute bit that the page tables use.
+    movl $0xc0000080, %ecx
+    rdmsr
+    orl $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+    # enable paging, which switches to long mode.
+    movl %cr0, %eax
+    orl $(1 << 31), %eax
+    movl %eax, %cr0
+    ljmpl $0x18, $(AP_BASE + ap_long - ap_trampoline)
+
+ap_abandoned:
+    cli
+    hlt
+    jmp ap_abandoned
+
+    .code64
+ap_long:
+    xorl %eax, %eax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    movw %ax, %fs
+    movw %ax, %gs
+    movabsq $AP_BOOT, %rax
+    movq 8(%rax), %rsp
+    movq 16(%rax), %rdi
+    movabsq $ap_main, %rax
+    callq *%rax
+    ud2
+
+    .p2align 3
+ap_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff    # 32-bit code
+    .quad 0x00cf92000000ffff    # 32-bit data
+    .quad 0x00af9a000000ffff    # 64-bit code
+ap_gdt_ptr:
+    .word ap_gdt_ptr - ap_gdt - 1
+    .long AP_BASE + ap_gdt - ap_trampoline
+ap_trampoline_end:
+    .popsection
+"#);
+
+/// Returns the number of CPUs which are running, including the boot CPU.
+#[inline]
+pub fn cpus_online() -> usize {
+    1 + APS_ONLINE.load(Ordering::Acquire)
+}
+
+/// Start every AP listed in the ACPI MADT.
+///
+/// APs are started in order until one doesn't start, with a warning.
+///
+/// # Returns
+/// + `Ok(usize)` with the number of CPUs which are now running
+/// + `Err(&str)` if the trampoline could not be set up.
+///
+/// # Safety
+/// + This should only be called once, by the kernel init process, after
+///   interrupts, system calls and the scheduler have been initialized.
+pub unsafe fn initialize<A>(table: &mut ActivePageTable, frames: &mut A)
+                           -> Result<usize, &'static str>
+where A: FrameAllocator {
+    let lapic = match apic::local() {
+        Some(lapic) => lapic
+      , None => {
+            kinfoln!(dots: " . . ", "No local APIC, so only one CPU is used.");
+            return Ok(1)
+        }
+    };
+    let madt = match super::acpi::tables().and_then(|acpi| acpi.madt.as_ref()) {
+        Some(madt) => madt
+      , None => {
+            kinfoln!(dots: " . . ", "No ACPI MADT, so only one CPU is used.");
+            return Ok(1)
+        }
+    };
+
+    // the trampoline loads `%cr3` in protected mode, which can only load a
+    // 32-bit address.
+    let cr3 = *control_regs::cr3::read();
+    if cr3 > u32::max_value() as u64 {
+        return Err("the kernel's PML4 table is above 4 GiB")
+    }
+    AP_BOOT.cr3 = cr3;
+
+    let page = install_trampoline(table, frames)?;
+    kinfoln!( dots: " . . ", "Copied the AP trampoline to {:#x}"
+            , TRAMPOLINE);
+
+    let aps = madt.enabled_cpus().saturating_sub(1);
+    if let Err(why) = KERNEL_STACKS.lock().reserve(aps * STACKS_PER_AP) {
+        warn!("{}, so some CPUs may not start", why);
+    }
+
+    let bsp_id = lapic.id() as u32;
+    let mut index = 1;
+    for processor in madt.processors.iter()
+                         .filter(|p| p.enabled && p.apic_id != bsp_id) {
+        if processor.apic_id > u8::max_value() as u32 {
+            warn!( "can't start the CPU with x2APIC ID {} in xAPIC mode"
+                 , processor.apic_id);
+            continue
+        }
+        match start_ap(index, processor.apic_id as u8, lapic, table, frames) {
+            Ok(()) => index += 1
+          , Err(why) => {
+                warn!( "the CPU with local APIC {} didn't start: {}, so no \
+                        more CPUs will be started"
+                     , processor.apic_id, why);
+                break
+            }
+        }
+    }
+
+    // the trampoline is only needed while APs are starting, unless one
+    // which claimed `AP_BOOT` is still on its way. the trampoline's frame
+    // is in reserved low memory, so it mustn't be freed.
+    if AP_BOOT.state.load(Ordering::Acquire) == AP_CLAIMED {
+        warn!("a late CPU may still be starting, so the trampoline stays");
+    } else {
+        table.unmap(page, &mut DeviceFrames)
+             .map_err(|_| "could not unmap the trampoline")?;
+    }
+
+    kinfoln!( dots: " . . ", "{} of {} CPUs are online"
+            , cpus_online(), madt.enabled_cpus());
+    Ok(cpus_online())
+}
+
+/// Copy the trampoline to `TRAMPOLINE`, and identity map it.
+///
+/// # Returns
+/// + `Ok(VirtualPage)` with the page the trampoline is mapped at
+/// + `Err(&str)` if it could not be mapped.
+unsafe fn install_trampoline<A>(table: &mut ActivePageTable, frames: &mut A)
+                               -> Result<VirtualPage, &'static str>
+where A: FrameAllocator {
+    let start = &ap_trampoline as *const u8;
+    let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+    assert!( len <= PAGE_SIZE as usize
+           , "the AP trampoline doesn't fit in a page!");
+
+    let frame = PhysicalPage::containing(PAddr::from(TRAMPOLINE));
+    let page = VirtualPage::containing(VAddr::from(TRAMPOLINE as usize));
+    table.identity_map(frame, WRITABLE | NO_EXECUTE, frames)
+         .map_err(|_| "could not map the trampoline")?;
+    ptr::copy_nonoverlapping(start, TRAMPOLINE as *mut u8, len);
+
+    // the APs run the trampoline through this mapping once paging is on,
+    // so it must be executable (and so, not writable).
+    table.protect(page.range_of(1), PRESENT)
+         .map_err(|_| "could not make the trampoline executable")?;
+    Ok(page)
+}
+
+/// Start the AP with local APIC `apic_id`, as CPU `index`.
+///
+/// # Returns
+/// + `Ok(())` once the AP has reached `ap_main`
+/// + `Err(&str)` if its stacks could not be allocated, or it didn't start
+///   in time. Its stacks are freed, unless it has claimed `AP_BOOT` and so
+///   may still be using them.
+unsafe fn start_ap<A>( index: usize, apic_id: u8, lapic: LocalApic
+                     , table: &mut ActivePageTable, frames: &mut A)
+                     -> Result<(), &'static str>
+where A: FrameAllocator {
+    let stack = KERNEL_STACKS.lock()
+                             .allocate(table, frames)
+                             .map_err(|_| "could not allocate a stack")?;
+    let cpu_addr = (stack.end.as_usize() - mem::size_of::<Cpu>())
+                 & !(mem::align_of::<Cpu>() - 1);
+    let cpu = cpu_addr as *mut Cpu;
+    ptr::write(cpu, Cpu::new(index));
+    let allocated =
+        tss::allocate_stacks(&mut (*cpu).tss, table, frames)
+            .map_err(|_| "could not allocate interrupt stacks")
+            .and_then(|_|
+                super::syscall::allocate_stack( &mut (*cpu).syscall
+                                              , table, frames)
+                    .map_err(|_| "could not allocate a system call stack"));
+    if let Err(why) = allocated {
+        free_stacks(stack, cpu, table, frames);
+        return Err(why)
+    }
+
+    AP_BOOT.stack_top = (cpu_addr & !0xf) as u64;
+    AP_BOOT.cpu = cpu_addr as u64;
+    AP_BOOT.state.store(AP_STARTING, Ordering::Release);
+    let started = APS_ONLINE.load(Ordering::Acquire);
+
+    // INIT, then two startup IPIs, as in the MultiProcessor Specification.
+    // if the first startup IPI works, the second is ignored.
+    let vector = (TRAMPOLINE >> 12) as u8;
+    lapic.send_ipi(Destination::Apic(apic_id), Ipi::Init);
+    ::thread::sleep_ms(10);
+    lapic.send_ipi(Destination::Apic(apic_id), Ipi::Startup(vector));
+    ::thread::sleep_ms(1);
+    lapic.send_ipi(Destination::Apic(apic_id), Ipi::Startup(vector));
+
+    let deadline = Deadline::after_ms(START_TIMEOUT_MS);
+    while APS_ONLINE.load(Ordering::Acquire) == started {
+        if deadline.has_passed() {
+            // give up on the AP, unless it has already claimed `AP_BOOT`.
+            let state = AP_BOOT.state.compare_and_swap( AP_STARTING
+                                                      , AP_ABANDONED
+                                                      , Ordering::AcqRel);
+            if state == AP_STARTING {
+                free_stacks(stack, cpu, table, frames);
+                return Err("it didn't start in time")
+            }
+            return Err("it started, but didn't reach `ap_main` in time")
+        }
+        ::thread::sleep_ms(1);
+    }
+    AP_BOOT.state.store(AP_IDLE, Ordering::Release);
+    Ok(())
+}
+
+/// Return the stacks allocated for an AP which didn't start to
+/// `KERNEL_STACKS`.
+///
+/// `boot` is the stack the AP was to boot on, which holds its per-CPU area
+/// `cpu`. The interrupt and system call stacks are found through `cpu`, and
+/// any which weren't allocated (whose tops are still 0) are skipped.
+unsafe fn free_stacks<A>( boot: Stack, cpu: *const Cpu
+                        , table: &mut ActivePageTable, frames: &mut A)
+where A: FrameAllocator {
+    let size = KERNEL_STACK_PAGES * PAGE_SIZE as usize;
+    let tops = (1 .. 8).map(|i| (*cpu).tss.ist_stack(i))
+                       .chain(iter::once((*cpu).syscall.kernel_stack()))
+                       .filter(|top| top.as_usize() != 0);
+    let mut stacks = KERNEL_STACKS.lock();
+    // the boot stack holds `cpu`, so it has to go last.
+    for stack in tops.map(|top| VAddr::from(top.as_usize() - size) .. top)
+                     .chain(iter::once(boot)) {
+        if let Err(why) = stacks.deallocate(stack, table, frames) {
+            warn!("could not free an AP's stack: {:?}", why);
+        }
+    }
+}
+
+/// Where each AP goes once the trampoline has put it in long mode.
+///
+/// `area` is the AP's per-CPU area, at the top of the stack it's running
+/// on.
+#[no_mangle]
+pub extern "C" fn ap_main(area: *mut Cpu) -> ! {
+    unsafe {
+        super::gdt::initialize_ap();
+        percpu::install(&mut *area);
+        tss::load(&(*area).tss)
+            .expect("the GDT should have room for every CPU's TSS");
+        super::interrupts::initialize_ap();
+
+        // the same as `arch_init` and `kernel_remap` do on the boot CPU.
+        control_regs::cr0::enable_write_protect(true);
+        ::paging::arch::mmio::init_pat();
+        let _ = ::cpu::smap::enable();
+        if let Err(why) = fpu::initialize() {
+            warn!("FPU not initialized: {}", why);
+        }
+        syscall::enable();
+    }
+
+    let cpu = percpu::current();
+    kinfoln!( dots: " . . ", "CPU {} (local APIC {}) is online"
+            , cpu.index(), cpu.apic_id());
+    APS_ONLINE.fetch_add(1, Ordering::AcqRel);
+
+    unsafe { Idt::enable_interrupts() };
+    loop {
+        unsafe { asm!("hlt" :::: "volatile") }
+    }
+}
@@ -0,0 +1,118 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The kernel's symbol table.
+//!
+//! GRUB loads every section of the kernel binary, including `.symtab` and
+//! `.strtab`, and describes them in the multiboot ELF sections tag. They
+//! aren't allocated sections, so `kernel_remap` doesn't map them, but they
+//! can be read through the direct map of physical memory.
+//!
+//! Once the symbols are indexed, backtraces and exception screens print
+//! addresses as `function+offset`. Since the kernel is built with debug
+//! info, GRUB also loads its `.debug_line` section, which is used to print
+//! the source file and line of each address as well.
+use core::slice;
+
+use elf::dwarf::DebugLine;
+use elf::section::{Header, StrTable, Type};
+use elf::symbol::{SymbolIndex, SymbolRepr64, SymbolTable};
+use params::InitParams;
+use spin::Once;
+use util::backtrace::SourceLocation;
+
+/// The maximum number of function symbols which can be indexed.
+const MAX_SYMBOLS: usize = 16 * 1024;
+
+/// Storage for the symbol index.
+///
+/// This is static, since the symbols are loaded before there is a heap.
+static mut INDEX_BUF: [u32; MAX_SYMBOLS] = [0; MAX_SYMBOLS];
+
+/// The index of the kernel's symbols, once they have been loaded.
+static SYMBOLS: Once<SymbolIndex<'static, SymbolRepr64>> = Once::new();
+
+/// The kernel's DWARF line tables, once they have been loaded.
+static LINES: Once<DebugLine<'static>> = Once::new();
+
+/// Returns the contents of a loaded ELF section, through the direct map.
+unsafe fn section_data(section: &Header<Word = u64>) -> &'static [u8] {
+    slice::from_raw_parts( section.address().to_virtual().as_ptr()
+                         , section.length())
+}
+
+/// Find the symbol containing `addr`, for `util::backtrace`.
+fn symbolize(addr: usize) -> Option<(&'static str, usize)> {
+    SYMBOLS.try()
+           .and_then(|index| index.symbolize(addr as u64))
+           .map(|(name, offset)| (name, offset as usize))
+}
+
+/// Find the source location of `addr`, for `util::backtrace`.
+fn locate(addr: usize) -> Option<SourceLocation> {
+    LINES.try()
+         .and_then(|lines| lines.find(addr as u64))
+         .map(|location| SourceLocation { directory: location.directory
+                                        , file: location.file
+                                        , line: location.line
+                                        })
+}
+
+/// Index the kernel's symbols, and use them to symbolize addresses.
+///
+/// This must be called after `kernel_remap`, since the symbol table is read
+/// through the direct map of physical memory.
+///
+/// # Returns
+/// + `Ok(usize)` with the number of function symbols indexed
+/// + `Err(&str)` if the kernel has no symbol table, or it could not be
+///   indexed.
+pub fn initialize(params: &InitParams) -> Result<usize, &'static str> {
+    let sections = params.elf_sections();
+    let symtab = sections.clone()
+                         .find(|s| s.get_type() == Ok(Type::SymbolTable))
+                         .ok_or("the kernel has no symbol table")?;
+    let strtab = sections.get(symtab.link())
+                         .ok_or("the symbol table's string table is missing")?;
+
+    let index = unsafe {
+        let table = SymbolTable::<SymbolRepr64>::new( section_data(symtab)
+                                                    , section_data(strtab))?;
+        SymbolIndex::new(table, &mut INDEX_BUF)?
+    };
+    let len = index.len();
+    SYMBOLS.call_once(|| index);
+    ::util::backtrace::set_symbolizer(symbolize);
+    Ok(len)
+}
+
+/// Load the kernel's DWARF line tables, and use them to find the source
+/// locations of addresses.
+///
+/// Like [`initialize`], this must be called after `kernel_remap`.
+///
+/// # Returns
+/// + `Ok(usize)` with the size of the line tables, in bytes
+/// + `Err(&str)` if the kernel has no `.debug_line` section.
+///
+/// [`initialize`]: fn.initialize.html
+pub fn initialize_lines(params: &InitParams) -> Result<usize, &'static str> {
+    let sections = params.elf_sections();
+    let names = params.elf_strtab_index
+                      .and_then(|index| sections.get(index))
+                      .ok_or("the kernel's section names are missing")?;
+    let names = StrTable::from(unsafe { section_data(names) });
+    let debug_line
+        = sections.clone()
+                  .find(|s| s.get_name(names.clone()) == ".debug_line")
+                  .ok_or("the kernel has no .debug_line section")?;
+
+    LINES.call_once(|| DebugLine::from(unsafe { section_data(debug_line) }));
+    ::util::backtrace::set_locator(locate);
+    Ok(debug_line.length())
+}
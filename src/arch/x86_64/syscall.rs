@@ -0,0 +1,111 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The kernel's system calls.
+//!
+//! Every operation is a system call of its own, numbered by its index in
+//! [`TABLE`]. New calls go at the end, so that existing numbers never
+//! change.
+//!
+//! [`TABLE`]: static.TABLE.html
+use core::{cmp, str};
+
+use cpu::smap::UserAccess;
+use cpu::syscall::{self, Args, Error, Handler, SyscallResult, SyscallStack
+                  , UserPtr};
+use cpu::timer::clock;
+use paging::MapResult;
+use paging::arch::ActivePageTable;
+use paging::stack::KERNEL_STACKS;
+use sos_alloc::FrameAllocator;
+
+/// `write_console(buf: *const u8, len: usize) -> usize`
+///
+/// Write `len` bytes of UTF-8 text to the console.
+pub const WRITE_CONSOLE: u64 = 0;
+/// `uptime() -> u64`
+///
+/// Returns the time since boot, in nanoseconds.
+pub const UPTIME: u64 = 1;
+
+/// The system call table, indexed by call number.
+pub static TABLE: [Handler; 2] = [ write_console // WRITE_CONSOLE
+                                 , uptime        // UPTIME
+                                 ];
+
+/// How many bytes `write_console` copies out of user memory at a time.
+const WRITE_CHUNK: usize = 256;
+
+fn write_console(args: &Args) -> SyscallResult {
+    let buf: UserPtr<u8> = args.get(0)?;
+    let len: usize = args.get(1)?;
+    let text = unsafe { buf.as_slice(len)? };
+
+    // copy the text out of user memory a chunk at a time, so that the
+    // console isn't written to with user access enabled.
+    let mut chunk = [0u8; WRITE_CHUNK];
+    let mut written = 0;
+    while written < len {
+        let n = cmp::min(WRITE_CHUNK, len - written);
+        {
+            let _access = UserAccess::new();
+            chunk[..n].copy_from_slice(&text[written..written + n]);
+        }
+        // a chunk boundary may split a character, so print as much as is
+        // valid, and copy the rest again with the next chunk.
+        let valid = match str::from_utf8(&chunk[..n]) {
+            Ok(s) => s.len()
+          , Err(e) if e.valid_up_to() > 0 && n == WRITE_CHUNK =>
+                e.valid_up_to()
+          , Err(_) => return Err(Error::InvalidArgument)
+        };
+        print!("{}", unsafe { str::from_utf8_unchecked(&chunk[..valid]) });
+        written += valid;
+    }
+    Ok(written as u64)
+}
+
+fn uptime(_: &Args) -> SyscallResult {
+    Ok(clock::now())
+}
+
+/// Enable system calls on the boot CPU.
+///
+/// This allocates the kernel stack system calls run on.
+///
+/// # Safety
+/// + This should only be called once, by the kernel init process, after the
+///   GDT has been loaded and the boot CPU's per-CPU area installed.
+pub unsafe fn initialize<A>(table: &mut ActivePageTable, alloc: &mut A)
+                           -> MapResult<()>
+where A: FrameAllocator {
+    let stack = &mut super::percpu::boot_cpu().syscall;
+    allocate_stack(stack, table, alloc)?;
+
+    syscall::set_table(&TABLE);
+    syscall::set_kill(::thread::exit);
+    syscall::enable();
+    kinfoln!(dots: " . . ", "Registered {} system calls", TABLE.len());
+    Ok(())
+}
+
+/// Allocate the kernel stack that system calls on a CPU run on.
+///
+/// System calls are enabled on other CPUs with `cpu::syscall::enable`, once
+/// `initialize` has set the table up and `stack`'s per-CPU area has been
+/// installed.
+pub fn allocate_stack<A>( stack: &mut SyscallStack
+                        , table: &mut ActivePageTable
+                        , alloc: &mut A)
+                        -> MapResult<()>
+where A: FrameAllocator {
+    let pages = KERNEL_STACKS.lock().allocate(table, alloc)?;
+    kinfoln!(dots: " . . ", "Allocated system call stack at {:?}", pages);
+    stack.set_kernel_stack(pages.end);
+    Ok(())
+}
@@ -0,0 +1,82 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The kernel's task state segment.
+//!
+//! The TSS gives the double fault, NMI and machine check handlers stacks of
+//! their own, so that they still have somewhere to run if the kernel stack
+//! has overflowed (or is otherwise broken). Without them, a kernel stack
+//! overflow turns a page fault into a triple fault, and the machine resets
+//! without telling us anything.
+//!
+//! Each CPU has a TSS of its own, in its per-CPU area (see `percpu`).
+use cpu::segment::Selector;
+use cpu::task::{self, StateSegment};
+use paging::MapResult;
+use paging::arch::ActivePageTable;
+use paging::stack::KERNEL_STACKS;
+use sos_alloc::FrameAllocator;
+
+/// The number of interrupt stacks each CPU has.
+pub const N_IST_STACKS: usize = 3;
+
+/// The handlers which get their own stack, and their IST entries.
+const IST_STACKS: [(u8, &'static str); N_IST_STACKS]
+    = [ (task::DOUBLE_FAULT_IST, "double fault")
+      , (task::NMI_IST, "NMI")
+      , (task::MACHINE_CHECK_IST, "machine check")
+      ];
+
+/// Allocate the boot CPU's interrupt stacks, and load its TSS.
+///
+/// This must be called before the IDT is loaded, since the double fault, NMI
+/// and machine check gates refer to the interrupt stacks.
+///
+/// # Safety
+/// + This should only be called once, by the kernel init process.
+pub unsafe fn initialize<A>(table: &mut ActivePageTable, alloc: &mut A)
+                           -> MapResult<()>
+where A: FrameAllocator {
+    let tss = &mut super::percpu::boot_cpu().tss;
+    allocate_stacks(tss, table, alloc)?;
+    let selector = load(tss)
+                       .expect("the GDT should have room for the first TSS");
+    kinfoln!(dots: " . . ", "Loaded the TSS at {}", selector);
+    Ok(())
+}
+
+/// Allocate the interrupt stacks for `tss`.
+pub fn allocate_stacks<A>( tss: &mut StateSegment
+                         , table: &mut ActivePageTable
+                         , alloc: &mut A)
+                         -> MapResult<()>
+where A: FrameAllocator {
+    let mut stacks = KERNEL_STACKS.lock();
+    for &(index, name) in IST_STACKS.iter() {
+        let stack = stacks.allocate(table, alloc)?;
+        kinfoln!( dots: " . . ", "Allocated {} stack (IST {}) at {:?}"
+                , name, index, stack);
+        tss.set_ist_stack(index, stack.end);
+    }
+    Ok(())
+}
+
+/// Add a descriptor for `tss` to the GDT, and load it on this CPU.
+///
+/// # Returns
+/// + `Ok(Selector)` with the TSS's selector
+/// + `Err(&str)` if the GDT has no room for another TSS.
+///
+/// # Safety
+/// + This should be called once on each CPU, with that CPU's TSS.
+pub unsafe fn load(tss: &'static StateSegment)
+                  -> Result<Selector, &'static str> {
+    let selector = super::gdt::add_tss(tss)?;
+    task::load_task_register(selector);
+    Ok(selector)
+}
@@ -0,0 +1,137 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The Fixed ACPI Description Table (FADT).
+//!
+//! The FADT describes the fixed ACPI hardware: the SCI interrupt, the power
+//! management timer, the reset register, and which legacy devices are
+//! present. Its signature is `FACP`, for historical reasons.
+//!
+//! The FADT has grown with each revision of the spec, so any field past the
+//! ACPI 1.0 table may be missing.
+use memory::PAddr;
+
+use super::sdt::{GenericAddress, Sdt, Signature};
+
+/// The FADT's signature.
+pub const SIGNATURE: Signature = Signature(*b"FACP");
+
+// Offsets of fields in the FADT.
+const DSDT: usize = 40;
+const SCI_INTERRUPT: usize = 46;
+const SMI_COMMAND: usize = 48;
+const ACPI_ENABLE: usize = 52;
+const ACPI_DISABLE: usize = 53;
+const PM_TIMER_BLOCK: usize = 76;
+const CENTURY: usize = 108;
+const BOOT_ARCH: usize = 109;
+const FLAGS: usize = 112;
+const RESET_REG: usize = 116;
+const RESET_VALUE: usize = 128;
+const X_DSDT: usize = 140;
+
+bitflags! {
+    /// Flags describing the legacy devices on an IA-PC system.
+    pub flags BootArchFlags: u16 {
+        /// There are ISA devices which the OS can't enumerate itself.
+        const LEGACY_DEVICES = 1 << 0
+      , /// There is an 8042 (or equivalent) PS/2 controller.
+        const HAS_8042 = 1 << 1
+      , /// VGA hardware must not be probed.
+        const NO_VGA = 1 << 2
+      , /// Message signalled interrupts must not be enabled.
+        const NO_MSI = 1 << 3
+      , /// PCIe active state power management must not be enabled.
+        const NO_ASPM = 1 << 4
+      , /// There is no CMOS real-time clock.
+        const NO_CMOS_RTC = 1 << 5
+    }
+}
+
+bitflags! {
+    /// Fixed feature flags.
+    pub flags FixedFlags: u32 {
+        /// The power management timer counts with 32 bits, rather than 24.
+        const TIMER_32_BIT = 1 << 8
+      , /// The reset register is supported.
+        const RESET_SUPPORTED = 1 << 10
+      , /// The system is hardware-reduced, and has none of the fixed ACPI
+        /// hardware.
+        const HW_REDUCED = 1 << 20
+    }
+}
+
+/// The parsed FADT.
+#[derive(Copy, Clone, Debug)]
+pub struct Fadt { /// The physical address of the DSDT.
+                  pub dsdt: PAddr
+                , /// The ISA IRQ that the system control interrupt (SCI) is
+                  /// wired to.
+                  pub sci_interrupt: u16
+                , /// The I/O port used to enable or disable ACPI mode, or 0
+                  /// if the system is always in ACPI mode.
+                  pub smi_command: u32
+                , /// The value to write to `smi_command` to enable ACPI mode.
+                  pub acpi_enable: u8
+                , /// The value to write to `smi_command` to disable ACPI
+                  /// mode.
+                  pub acpi_disable: u8
+                , /// The I/O port of the power management timer, if there is
+                  /// one.
+                  pub pm_timer: Option<u16>
+                , /// The index of the century in the CMOS RTC, or 0 if there
+                  /// is none.
+                  pub century: u8
+                , pub boot_arch: BootArchFlags
+                , pub flags: FixedFlags
+                , /// The register to write to reset the system, and the value
+                  /// to write to it.
+                  pub reset: Option<(GenericAddress, u8)>
+                }
+
+impl Fadt {
+    /// Parse the FADT from a mapped table.
+    ///
+    /// # Returns
+    /// + `Some(Fadt)` if the table could be parsed
+    /// + `None` if it was shorter than the ACPI 1.0 FADT.
+    pub fn parse(table: &Sdt) -> Option<Self> {
+        let flags = FixedFlags::from_bits_truncate(table.read(FLAGS)?);
+        // the 64-bit DSDT address takes precedence, if there is one.
+        let dsdt = match table.read::<u64>(X_DSDT) {
+            Some(addr) if addr != 0 => addr
+          , _ => table.read::<u32>(DSDT)? as u64
+        };
+        let reset = if flags.contains(RESET_SUPPORTED) {
+            match ( table.read::<GenericAddress>(RESET_REG)
+                  , table.read::<u8>(RESET_VALUE) ) {
+                (Some(reg), Some(value)) => Some((reg, value))
+              , _ => None
+            }
+        } else {
+            None
+        };
+
+        Some(Fadt { dsdt: PAddr::from(dsdt)
+                  , sci_interrupt: table.read(SCI_INTERRUPT)?
+                  , smi_command: table.read(SMI_COMMAND)?
+                  , acpi_enable: table.read(ACPI_ENABLE)?
+                  , acpi_disable: table.read(ACPI_DISABLE)?
+                  , pm_timer: match table.read::<u32>(PM_TIMER_BLOCK)? {
+                        0 => None
+                      , port => Some(port as u16)
+                    }
+                  , century: table.read(CENTURY)?
+                  , boot_arch: BootArchFlags::from_bits_truncate(
+                        // the boot architecture flags were added in ACPI 2.0
+                        table.read(BOOT_ARCH).unwrap_or(0))
+                  , flags: flags
+                  , reset: reset
+                  })
+    }
+}
@@ -0,0 +1,67 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The High Precision Event Timer (HPET) description table.
+use memory::PAddr;
+
+use super::sdt::{GenericAddress, Sdt, Signature};
+
+/// The HPET table's signature.
+pub const SIGNATURE: Signature = Signature(*b"HPET");
+
+// Offsets of fields in the HPET table.
+const EVENT_TIMER_BLOCK_ID: usize = 36;
+const BASE_ADDRESS: usize = 40;
+const HPET_NUMBER: usize = 52;
+const MIN_TICK: usize = 53;
+
+/// Bit in the event timer block ID which is set if the main counter is 64
+/// bits wide.
+const COUNTER_64_BIT: u32 = 1 << 13;
+/// Bit in the event timer block ID which is set if the HPET can replace the
+/// PIT and RTC interrupts.
+const LEGACY_REPLACEMENT: u32 = 1 << 15;
+
+/// The parsed HPET table.
+#[derive(Copy, Clone, Debug)]
+pub struct Hpet { /// The physical address of the HPET's registers.
+                  pub address: PAddr
+                , /// Which HPET this is, if there are several.
+                  pub number: u8
+                , /// The PCI vendor ID of the HPET's manufacturer.
+                  pub vendor_id: u16
+                , /// The number of comparators (timers) the HPET has.
+                  pub comparators: u8
+                , /// True if the main counter is 64 bits wide.
+                  pub counter_64_bit: bool
+                , /// True if the HPET can replace the PIT and RTC interrupts.
+                  pub legacy_replacement: bool
+                , /// The minimum period, in main counter ticks, that can be
+                  /// programmed in periodic mode without losing interrupts.
+                  pub min_tick: u16
+                }
+
+impl Hpet {
+    /// Parse the HPET table from a mapped table.
+    ///
+    /// # Returns
+    /// + `Some(Hpet)` if the table could be parsed
+    /// + `None` if it was too short.
+    pub fn parse(table: &Sdt) -> Option<Self> {
+        let id = table.read::<u32>(EVENT_TIMER_BLOCK_ID)?;
+        let address = table.read::<GenericAddress>(BASE_ADDRESS)?;
+        Some(Hpet { address: PAddr::from(address.address)
+                  , number: table.read::<u8>(HPET_NUMBER)?
+                  , vendor_id: (id >> 16) as u16
+                  , comparators: ((id >> 8) & 0x1f) as u8 + 1
+                  , counter_64_bit: id & COUNTER_64_BIT != 0
+                  , legacy_replacement: id & LEGACY_REPLACEMENT != 0
+                  , min_tick: table.read::<u16>(MIN_TICK)?
+                  })
+    }
+}
@@ -0,0 +1,227 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The Multiple APIC Description Table (MADT).
+//!
+//! The MADT lists every CPU's local APIC, every I/O APIC and the GSIs it
+//! handles, and any ISA IRQs which aren't connected to the GSI with the same
+//! number.
+use arrayvec::ArrayVec;
+use cpu::interrupts::ioapic::{self, RedirectionFlags, ISA_IRQS, MAX_IO_APICS};
+use memory::PAddr;
+
+use super::sdt::{self, Sdt, Signature};
+
+/// The MADT's signature.
+pub const SIGNATURE: Signature = Signature(*b"APIC");
+
+/// The maximum number of CPUs the MADT may describe.
+pub const MAX_CPUS: usize = 64;
+
+// Offsets of fields in the MADT.
+const LOCAL_APIC_ADDR: usize = 36;
+const FLAGS: usize = 40;
+const ENTRIES: usize = 44;
+
+// Types of MADT entries.
+const LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const INTERRUPT_OVERRIDE: u8 = 2;
+const LOCAL_APIC_ADDR_OVERRIDE: u8 = 5;
+const LOCAL_X2APIC: u8 = 9;
+
+/// Bit in the MADT flags which is set if the system also has 8259 PICs.
+const PCAT_COMPAT: u32 = 1 << 0;
+/// Bit in a processor's flags which is set if it can be used.
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+
+/// A CPU, and its local APIC.
+#[derive(Copy, Clone, Debug)]
+pub struct Processor { /// The CPU's ACPI processor UID.
+                       pub acpi_id: u32
+                     , /// The ID of the CPU's local APIC.
+                       pub apic_id: u32
+                     , /// If false, the CPU is present but must not be
+                       /// used.
+                       pub enabled: bool
+                     }
+
+/// An I/O APIC.
+#[derive(Copy, Clone, Debug)]
+pub struct IoApic { pub id: u8
+                  , /// The physical address of the I/O APIC's registers.
+                    pub address: PAddr
+                  , /// The first GSI handled by the I/O APIC.
+                    pub gsi_base: u32
+                  }
+
+/// The polarity of an interrupt.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Polarity { /// Whatever is usual for the bus (active high, for ISA).
+                    Conforming
+                  , ActiveHigh
+                  , ActiveLow
+                  }
+
+/// How an interrupt is triggered.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Trigger { /// Whatever is usual for the bus (edge, for ISA).
+                   Conforming
+                 , Edge
+                 , Level
+                 }
+
+/// An ISA IRQ which isn't connected to the GSI with the same number, or
+/// isn't edge triggered and active high.
+#[derive(Copy, Clone, Debug)]
+pub struct InterruptOverride { /// The ISA IRQ.
+                               pub irq: u8
+                             , /// The GSI the IRQ is connected to.
+                               pub gsi: u32
+                             , pub polarity: Polarity
+                             , pub trigger: Trigger
+                             }
+
+impl InterruptOverride {
+    /// Returns the I/O APIC redirection flags to route this IRQ with.
+    pub fn redirection_flags(&self) -> RedirectionFlags {
+        let mut flags = RedirectionFlags::empty();
+        if self.polarity == Polarity::ActiveLow {
+            flags.insert(ioapic::ACTIVE_LOW);
+        }
+        if self.trigger == Trigger::Level {
+            flags.insert(ioapic::LEVEL_TRIGGERED);
+        }
+        flags
+    }
+}
+
+/// The parsed MADT.
+#[derive(Debug)]
+pub struct Madt { /// The physical address of every CPU's local APIC.
+                  pub local_apic_addr: PAddr
+                , /// True if the system also has 8259 PICs, which must be
+                  /// disabled to use the APICs.
+                  pub has_pics: bool
+                , pub processors: ArrayVec<[Processor; MAX_CPUS]>
+                , pub io_apics: ArrayVec<[IoApic; MAX_IO_APICS]>
+                , pub overrides: ArrayVec<[InterruptOverride; ISA_IRQS]>
+                }
+
+impl Madt {
+    /// Parse the MADT from a mapped table.
+    pub fn parse(table: &Sdt) -> Self {
+        let mut madt = Madt {
+            local_apic_addr:
+                PAddr::from(table.read::<u32>(LOCAL_APIC_ADDR)
+                                 .unwrap_or(0) as u64)
+          , has_pics: table.read::<u32>(FLAGS).unwrap_or(0) & PCAT_COMPAT != 0
+          , processors: ArrayVec::new()
+          , io_apics: ArrayVec::new()
+          , overrides: ArrayVec::new()
+        };
+
+        let bytes = table.bytes();
+        let mut offset = ENTRIES;
+        while let (Some(ty), Some(len)) = ( sdt::read::<u8>(bytes, offset)
+                                          , sdt::read::<u8>(bytes, offset + 1)) {
+            let len = len as usize;
+            if len < 2 || offset + len > bytes.len() {
+                warn!("malformed MADT entry at offset {}", offset);
+                break
+            }
+            madt.parse_entry(ty, &bytes[offset .. offset + len]);
+            offset += len;
+        }
+        madt
+    }
+
+    /// Parse one MADT entry, of type `ty`.
+    fn parse_entry(&mut self, ty: u8, entry: &[u8]) {
+        match ty {
+            LOCAL_APIC => {
+                if let ( Some(acpi_id), Some(apic_id), Some(flags) )
+                    = ( sdt::read::<u8>(entry, 2), sdt::read::<u8>(entry, 3)
+                      , sdt::read::<u32>(entry, 4) ) {
+                    self.add_processor(Processor {
+                        acpi_id: acpi_id as u32
+                      , apic_id: apic_id as u32
+                      , enabled: flags & PROCESSOR_ENABLED != 0
+                    })
+                }
+            }
+          , LOCAL_X2APIC => {
+                if let ( Some(apic_id), Some(flags), Some(acpi_id) )
+                    = ( sdt::read::<u32>(entry, 4), sdt::read::<u32>(entry, 8)
+                      , sdt::read::<u32>(entry, 12) ) {
+                    self.add_processor(Processor {
+                        acpi_id: acpi_id
+                      , apic_id: apic_id
+                      , enabled: flags & PROCESSOR_ENABLED != 0
+                    })
+                }
+            }
+          , IO_APIC => {
+                if let ( Some(id), Some(address), Some(gsi_base) )
+                    = ( sdt::read::<u8>(entry, 2), sdt::read::<u32>(entry, 4)
+                      , sdt::read::<u32>(entry, 8) ) {
+                    let io_apic = IoApic { id: id
+                                         , address: PAddr::from(address as u64)
+                                         , gsi_base: gsi_base
+                                         };
+                    if self.io_apics.push(io_apic).is_some() {
+                        warn!("too many I/O APICs, ignoring {:?}", io_apic);
+                    }
+                }
+            }
+          , INTERRUPT_OVERRIDE => {
+                if let ( Some(irq), Some(gsi), Some(flags) )
+                    = ( sdt::read::<u8>(entry, 3), sdt::read::<u32>(entry, 4)
+                      , sdt::read::<u16>(entry, 8) ) {
+                    let polarity = match flags & 0b11 {
+                        0b01 => Polarity::ActiveHigh
+                      , 0b11 => Polarity::ActiveLow
+                      , _ => Polarity::Conforming
+                    };
+                    let trigger = match (flags >> 2) & 0b11 {
+                        0b01 => Trigger::Edge
+                      , 0b11 => Trigger::Level
+                      , _ => Trigger::Conforming
+                    };
+                    let over = InterruptOverride { irq: irq
+                                                 , gsi: gsi
+                                                 , polarity: polarity
+                                                 , trigger: trigger
+                                                 };
+                    if (irq as usize) >= ISA_IRQS
+                        || self.overrides.push(over).is_some() {
+                        warn!("ignoring interrupt override {:?}", over);
+                    }
+                }
+            }
+          , LOCAL_APIC_ADDR_OVERRIDE => {
+                if let Some(address) = sdt::read::<u64>(entry, 4) {
+                    self.local_apic_addr = PAddr::from(address);
+                }
+            }
+          , _ => trace!("skipping MADT entry of type {}", ty)
+        }
+    }
+
+    fn add_processor(&mut self, processor: Processor) {
+        if self.processors.push(processor).is_some() {
+            warn!("too many CPUs, ignoring {:?}", processor);
+        }
+    }
+
+    /// Returns the number of CPUs which can be used.
+    #[inline]
+    pub fn enabled_cpus(&self) -> usize {
+        self.processors.iter().filter(|p| p.enabled).count()
+    }
+}
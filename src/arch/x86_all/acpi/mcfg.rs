@@ -0,0 +1,72 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The PCI Express memory-mapped configuration space table (MCFG).
+//!
+//! The MCFG lists the regions of memory through which the configuration
+//! space of each PCI segment can be accessed (the "enhanced configuration
+//! access mechanism", or ECAM).
+use arrayvec::ArrayVec;
+use memory::PAddr;
+
+use super::sdt::{self, Sdt, Signature};
+
+/// The MCFG's signature.
+pub const SIGNATURE: Signature = Signature(*b"MCFG");
+
+/// The maximum number of configuration space regions the MCFG may describe.
+pub const MAX_REGIONS: usize = 8;
+
+/// Offset of the first entry in the MCFG.
+const ENTRIES: usize = 44;
+/// Size of each entry in the MCFG.
+const ENTRY_LEN: usize = 16;
+
+/// A region of memory-mapped PCI configuration space.
+#[derive(Copy, Clone, Debug)]
+pub struct ConfigRegion { /// The physical address of the configuration
+                          /// space of bus 0 (even if `start_bus` is not 0).
+                          pub base: PAddr
+                        , /// The PCI segment group.
+                          pub segment: u16
+                        , /// The first bus in this region.
+                          pub start_bus: u8
+                        , /// The last bus in this region.
+                          pub end_bus: u8
+                        }
+
+/// The parsed MCFG.
+#[derive(Debug)]
+pub struct Mcfg { pub regions: ArrayVec<[ConfigRegion; MAX_REGIONS]> }
+
+impl Mcfg {
+    /// Parse the MCFG from a mapped table.
+    pub fn parse(table: &Sdt) -> Self {
+        let mut mcfg = Mcfg { regions: ArrayVec::new() };
+        let bytes = table.bytes();
+        let mut offset = ENTRIES;
+        while offset + ENTRY_LEN <= bytes.len() {
+            let entry = &bytes[offset .. offset + ENTRY_LEN];
+            if let ( Some(base), Some(segment), Some(start_bus), Some(end_bus) )
+                = ( sdt::read::<u64>(entry, 0), sdt::read::<u16>(entry, 8)
+                  , sdt::read::<u8>(entry, 10), sdt::read::<u8>(entry, 11) ) {
+                let region = ConfigRegion { base: PAddr::from(base)
+                                          , segment: segment
+                                          , start_bus: start_bus
+                                          , end_bus: end_bus
+                                          };
+                if mcfg.regions.push(region).is_some() {
+                    warn!("too many PCI config regions, ignoring {:?}"
+                         , region);
+                }
+            }
+            offset += ENTRY_LEN;
+        }
+        mcfg
+    }
+}
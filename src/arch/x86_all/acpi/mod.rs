@@ -0,0 +1,318 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Advanced Configuration and Power Interface (ACPI) tables.
+//!
+//! The firmware describes the machine's hardware in a set of ACPI tables.
+//! The root system description pointer (RSDP) points to the RSDT (or, since
+//! ACPI 2.0, the XSDT), which lists the physical address of every other
+//! table.
+//!
+//! Finding the RSDP happens in two steps:
+//!
+//! 1. [`find_rsdp`] is called from `arch_init`, while low memory is still
+//!    identity mapped. It uses the copy of the RSDP in the Multiboot 2 info,
+//!    if the bootloader passed one, and otherwise scans the first KiB of the
+//!    EBDA and the BIOS ROM area for it.
+//! 2. [`initialize`] is called from `kernel_init`, once the kernel has been
+//!    remapped. It maps the tables with `ioremap`, parses the ones we care
+//!    about (see [`Acpi`]), and stores them for [`tables`] to return.
+//!
+//! See chapter 5 of the [ACPI specification] for the layout of each table.
+//!
+//! [`find_rsdp`]: fn.find_rsdp.html
+//! [`initialize`]: fn.initialize.html
+//! [`Acpi`]: struct.Acpi.html
+//! [`tables`]: fn.tables.html
+//! [ACPI specification]: http://uefi.org/specifications
+use core::{fmt, mem, ptr, slice};
+
+use arrayvec::ArrayVec;
+use memory::PAddr;
+use paging::MapErr;
+use paging::arch::ActivePageTable;
+use paging::arch::mmio::{ioremap, CacheType};
+use sos_alloc::FrameAllocator;
+use spin::Once;
+
+use super::{bda, multiboot2};
+
+mod sdt;
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+pub use self::sdt::{GenericAddress, OemId, SdtHeader, Signature};
+use self::sdt::Sdt;
+
+/// The signature at the start of the RSDP.
+const RSDP_SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
+
+/// The RSDP is always 16-byte aligned.
+const RSDP_ALIGN: usize = 16;
+
+/// The RSDP may be in the first KiB of the EBDA...
+const EBDA_SCAN_LEN: usize = 1024;
+/// ...or in the BIOS ROM area, between these addresses.
+const BIOS_AREA_START: usize = 0x000e_0000;
+const BIOS_AREA_END: usize = 0x0010_0000;
+
+/// The maximum number of tables the RSDT or XSDT may list.
+pub const MAX_TABLES: usize = 32;
+
+/// Errors that may occur while reading the ACPI tables.
+pub enum AcpiErr {
+    /// A table could not be mapped.
+    Map(MapErr)
+  , /// A table was invalid.
+    Invalid { table: Signature, cause: &'static str }
+}
+
+impl fmt::Debug for AcpiErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AcpiErr::Map(ref err) =>
+                write!(f, "Could not map ACPI table: {:?}", err)
+          , AcpiErr::Invalid { table, cause } =>
+                write!(f, "Invalid ACPI table {}: {}", table, cause)
+        }
+    }
+}
+
+impl From<MapErr> for AcpiErr {
+    #[inline] fn from(err: MapErr) -> Self { AcpiErr::Map(err) }
+}
+
+pub type AcpiResult<T> = Result<T, AcpiErr>;
+
+/// The ACPI 1.0 root system description pointer.
+///
+/// The checksums are never read directly, since an RSDP is valid if all of
+/// its bytes (including the checksum) sum to zero.
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+struct Rsdp { signature: [u8; 8]
+            , _checksum: u8
+            , oem_id: OemId
+            , revision: u8
+            , rsdt_addr: u32
+            }
+
+/// The ACPI 2.0+ root system description pointer.
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+struct Rsdp2 { v1: Rsdp
+             , /// The length of the whole RSDP.
+               length: u32
+             , xsdt_addr: u64
+             , _extended_checksum: u8
+             , _reserved: [u8; 3]
+             }
+
+/// Returns true if there is a valid RSDP at `ptr`.
+///
+/// # Safety
+/// + `ptr` must be mapped, and so must the `length` bytes following it, if
+///   it is a valid ACPI 2.0 RSDP.
+unsafe fn is_rsdp(ptr: *const Rsdp) -> bool {
+    let rsdp = &*ptr;
+    if &rsdp.signature != RSDP_SIGNATURE
+        || !sdt::checksum(slice::from_raw_parts( ptr as *const u8
+                                               , mem::size_of::<Rsdp>())) {
+        return false
+    }
+    if rsdp.revision < 2 { return true }
+
+    let length = (*(ptr as *const Rsdp2)).length as usize;
+    length >= mem::size_of::<Rsdp2>()
+        && sdt::checksum(slice::from_raw_parts(ptr as *const u8, length))
+}
+
+/// Returns the address of the first valid RSDP between `start` and `end`.
+unsafe fn scan_for_rsdp(start: usize, end: usize) -> Option<PAddr> {
+    (start / RSDP_ALIGN .. end / RSDP_ALIGN)
+        .map(|i| i * RSDP_ALIGN)
+        .find(|&addr| is_rsdp(addr as *const Rsdp))
+        .map(|addr| PAddr::from(addr as u64))
+}
+
+/// Find the RSDP.
+///
+/// # Returns
+/// + `Some(PAddr)` with the physical address of a valid RSDP
+/// + `None` if no RSDP could be found.
+///
+/// # Safety
+/// + Low memory, and the Multiboot 2 info, must be identity mapped, as they
+///   are during `arch_init`.
+pub unsafe fn find_rsdp(boot_info: &'static multiboot2::Info)
+                       -> Option<PAddr> {
+    if let Some(rsdp) = boot_info.acpi_rsdp() {
+        if is_rsdp(rsdp.as_ptr()) { return Some(rsdp) }
+        warn!("bootloader passed an invalid RSDP at {:?}", rsdp);
+    }
+    bda::ebda_addr()
+        .and_then(|ebda| {
+            let start: u64 = ebda.into();
+            scan_for_rsdp(start as usize, start as usize + EBDA_SCAN_LEN)
+        })
+        .or_else(|| scan_for_rsdp(BIOS_AREA_START, BIOS_AREA_END))
+}
+
+/// The ACPI tables we know how to parse.
+#[derive(Debug)]
+pub struct Acpi { /// The ACPI revision of the RSDP (0 for ACPI 1.0).
+                  pub revision: u8
+                , pub oem_id: OemId
+                , /// The signature and address of every table in the RSDT
+                  /// or XSDT.
+                  pub tables: ArrayVec<[(Signature, PAddr); MAX_TABLES]>
+                , pub madt: Option<madt::Madt>
+                , pub fadt: Option<fadt::Fadt>
+                , pub hpet: Option<hpet::Hpet>
+                , pub mcfg: Option<mcfg::Mcfg>
+                }
+
+static ACPI: Once<Acpi> = Once::new();
+
+/// Returns the parsed ACPI tables, if [`initialize`] has found them.
+///
+/// [`initialize`]: fn.initialize.html
+#[inline]
+pub fn tables() -> Option<&'static Acpi> { ACPI.try() }
+
+/// Parse the ACPI tables pointed to by the RSDP at `rsdp_addr`.
+///
+/// Tables which fail to map or have a bad checksum are skipped, with a
+/// warning; only a bad RSDP, RSDT, or XSDT is an error.
+///
+/// # Returns
+/// + `Ok(&Acpi)` with the parsed tables
+/// + `Err(AcpiErr)` if the root tables could not be read.
+pub fn initialize<A>( rsdp_addr: PAddr
+                    , table: &mut ActivePageTable
+                    , alloc: &mut A)
+                    -> AcpiResult<&'static Acpi>
+where A: FrameAllocator {
+    let rsdp = {
+        let mmio = ioremap( table, rsdp_addr, mem::size_of::<Rsdp2>()
+                          , CacheType::WriteBack, alloc)?;
+        unsafe { ptr::read_unaligned(mmio.as_ptr::<Rsdp2>(0)) }
+    };
+    let (revision, oem_id) = (rsdp.v1.revision, rsdp.v1.oem_id);
+
+    let mut acpi = Acpi { revision: revision
+                        , oem_id: oem_id
+                        , tables: ArrayVec::new()
+                        , madt: None
+                        , fadt: None
+                        , hpet: None
+                        , mcfg: None
+                        };
+
+    // the XSDT lists 64-bit addresses, and the RSDT 32-bit ones.
+    {
+        let xsdt_addr = rsdp.xsdt_addr;
+        let (root, entry_len) = if revision >= 2 && xsdt_addr != 0 {
+            ( Sdt::map_expecting( PAddr::from(xsdt_addr)
+                                , Signature(*b"XSDT"), table, alloc)?
+            , 8 )
+        } else {
+            ( Sdt::map_expecting( PAddr::from(rsdp.v1.rsdt_addr as u64)
+                                , Signature(*b"RSDT"), table, alloc)?
+            , 4 )
+        };
+
+        let n_entries = (root.bytes().len() - mem::size_of::<SdtHeader>())
+                      / entry_len;
+        for i in 0 .. n_entries {
+            let offset = mem::size_of::<SdtHeader>() + i * entry_len;
+            let addr = if entry_len == 8 { root.read::<u64>(offset) }
+                       else { root.read::<u32>(offset).map(|a| a as u64) };
+            if let Some(addr) = addr {
+                acpi.parse_table(PAddr::from(addr), table, alloc);
+            }
+        }
+    }
+
+    Ok(ACPI.call_once(|| acpi))
+}
+
+impl Acpi {
+    /// Map the table at `addr`, and parse it if it's one we know about.
+    fn parse_table<A>( &mut self
+                     , addr: PAddr
+                     , table: &mut ActivePageTable
+                     , alloc: &mut A)
+    where A: FrameAllocator {
+        let sdt = match Sdt::map(addr, table, alloc) {
+            Ok(sdt) => sdt
+          , Err(why) => {
+                warn!("skipping ACPI table at {:?}: {:?}", addr, why);
+                return
+            }
+        };
+        let signature = sdt.signature();
+        if self.tables.push((signature, addr)).is_some() {
+            warn!("too many ACPI tables, ignoring {} at {:?}", signature, addr);
+        }
+
+        if signature == madt::SIGNATURE {
+            self.madt = Some(madt::Madt::parse(&sdt));
+        } else if signature == fadt::SIGNATURE {
+            self.fadt = fadt::Fadt::parse(&sdt);
+        } else if signature == hpet::SIGNATURE {
+            self.hpet = hpet::Hpet::parse(&sdt);
+        } else if signature == mcfg::SIGNATURE {
+            self.mcfg = Some(mcfg::Mcfg::parse(&sdt));
+        }
+    }
+
+    /// Print a summary of the ACPI tables to the console.
+    pub fn print_summary(&self) {
+        kinfoln!( dots: " . . ", "ACPI {} tables from {}"
+                , if self.revision >= 2 { "2.0+" } else { "1.0" }
+                , self.oem_id);
+        for &(signature, addr) in self.tables.iter() {
+            kinfoln!(dots: " . . . ", "{} at {:?}", signature, addr);
+        }
+
+        if let Some(ref madt) = self.madt {
+            kinfoln!( dots: " . . ", "MADT: {} CPUs ({} enabled), {} I/O APICs"
+                    , madt.processors.len(), madt.enabled_cpus()
+                    , madt.io_apics.len());
+            for io_apic in madt.io_apics.iter() {
+                kinfoln!( dots: " . . . ", "I/O APIC {} at {:?}, GSIs from {}"
+                        , io_apic.id, io_apic.address, io_apic.gsi_base);
+            }
+            for over in madt.overrides.iter() {
+                kinfoln!( dots: " . . . ", "IRQ {} -> GSI {} ({:?}, {:?})"
+                        , over.irq, over.gsi, over.polarity, over.trigger);
+            }
+        }
+        if let Some(ref fadt) = self.fadt {
+            kinfoln!( dots: " . . ", "FADT: SCI on IRQ {}, PM timer {}"
+                    , fadt.sci_interrupt
+                    , if fadt.pm_timer.is_some() { "present" }
+                      else { "absent" });
+        }
+        if let Some(ref hpet) = self.hpet {
+            kinfoln!( dots: " . . ", "HPET: {} comparators at {:?}"
+                    , hpet.comparators, hpet.address);
+        }
+        if let Some(ref mcfg) = self.mcfg {
+            for region in mcfg.regions.iter() {
+                kinfoln!( dots: " . . "
+                        , "MCFG: PCI segment {} buses {}-{} at {:?}"
+                        , region.segment, region.start_bus, region.end_bus
+                        , region.base);
+            }
+        }
+    }
+}
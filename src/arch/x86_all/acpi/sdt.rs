@@ -0,0 +1,214 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! System description table headers, and mapping tables into memory.
+use core::{fmt, mem, ptr, slice, str};
+
+use memory::PAddr;
+use paging::arch::ActivePageTable;
+use paging::arch::mmio::{ioremap, CacheType, Mmio};
+use sos_alloc::FrameAllocator;
+
+use super::{AcpiErr, AcpiResult};
+
+/// Returns true if the bytes in `bytes` sum to zero.
+///
+/// Every ACPI structure with a checksum is valid only if this is true.
+#[inline]
+pub fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Write `bytes` as ASCII, or as `?` if they aren't.
+fn fmt_ascii(bytes: &[u8], f: &mut fmt::Formatter) -> fmt::Result {
+    match str::from_utf8(bytes) {
+        Ok(s) => f.write_str(s.trim_right())
+      , Err(_) => f.write_str("?")
+    }
+}
+
+/// The four-character signature that identifies an ACPI table.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct Signature(pub [u8; 4]);
+
+impl fmt::Display for Signature {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_ascii(&self.0, f)
+    }
+}
+
+impl fmt::Debug for Signature {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+/// The six-character ID of the OEM that supplied an ACPI table.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct OemId(pub [u8; 6]);
+
+impl fmt::Display for OemId {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_ascii(&self.0, f)
+    }
+}
+
+impl fmt::Debug for OemId {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+/// The header shared by every system description table.
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct SdtHeader { pub signature: Signature
+                     , /// The length of the table, including this header.
+                       pub length: u32
+                     , pub revision: u8
+                     , pub checksum: u8
+                     , pub oem_id: OemId
+                     , pub oem_table_id: [u8; 8]
+                     , pub oem_revision: u32
+                     , pub creator_id: u32
+                     , pub creator_revision: u32
+                     }
+
+/// Generic address space ID for system memory.
+pub const SYSTEM_MEMORY: u8 = 0;
+/// Generic address space ID for system I/O ports.
+pub const SYSTEM_IO: u8 = 1;
+
+/// An ACPI generic address structure.
+///
+/// This describes the location of a register, which may be in memory, in
+/// I/O port space, or elsewhere.
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct GenericAddress { /// The address space the register is in.
+                            pub space: u8
+                          , pub bit_width: u8
+                          , pub bit_offset: u8
+                          , pub access_size: u8
+                          , pub address: u64
+                          }
+
+impl fmt::Debug for GenericAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (space, address) = (self.space, self.address);
+        match space {
+            SYSTEM_MEMORY => write!(f, "memory {:#x}", address)
+          , SYSTEM_IO => write!(f, "port {:#x}", address)
+          , _ => write!(f, "space {} address {:#x}", space, address)
+        }
+    }
+}
+
+/// A system description table, mapped into memory.
+///
+/// The table is unmapped again when this is dropped.
+pub struct Sdt { mmio: Mmio }
+
+impl Sdt {
+    /// Map the table at `paddr`, and check its checksum.
+    ///
+    /// # Returns
+    /// + `Ok(Sdt)` if the table was mapped, and its checksum is valid
+    /// + `Err(AcpiErr)` if the table could not be mapped, or is invalid.
+    pub fn map<A>( paddr: PAddr
+                 , table: &mut ActivePageTable
+                 , alloc: &mut A)
+                 -> AcpiResult<Self>
+    where A: FrameAllocator {
+        // map just the header first, to find out how long the table is.
+        let header: SdtHeader = {
+            let mmio = ioremap( table, paddr, mem::size_of::<SdtHeader>()
+                              , CacheType::WriteBack, alloc)?;
+            unsafe { ptr::read_unaligned(mmio.as_ptr(0)) }
+        };
+        let signature = header.signature;
+        let length = header.length as usize;
+        if length < mem::size_of::<SdtHeader>() {
+            return Err(AcpiErr::Invalid { table: signature
+                                        , cause: "table is too short" })
+        }
+
+        let sdt = Sdt {
+            mmio: ioremap(table, paddr, length, CacheType::WriteBack, alloc)?
+        };
+        if !checksum(sdt.bytes()) {
+            return Err(AcpiErr::Invalid { table: signature
+                                        , cause: "bad checksum" })
+        }
+        trace!("mapped ACPI table {} at {:?}", signature, paddr);
+        Ok(sdt)
+    }
+
+    /// Map the table at `paddr`, and check that it has the signature
+    /// `expected`, as well as a valid checksum.
+    pub fn map_expecting<A>( paddr: PAddr
+                           , expected: Signature
+                           , table: &mut ActivePageTable
+                           , alloc: &mut A)
+                           -> AcpiResult<Self>
+    where A: FrameAllocator {
+        let sdt = Sdt::map(paddr, table, alloc)?;
+        if sdt.signature() == expected {
+            Ok(sdt)
+        } else {
+            Err(AcpiErr::Invalid { table: expected
+                                 , cause: "unexpected signature" })
+        }
+    }
+
+    /// Returns this table's header.
+    #[inline]
+    pub fn header(&self) -> SdtHeader {
+        unsafe { ptr::read_unaligned(self.mmio.as_ptr(0)) }
+    }
+
+    /// Returns this table's signature.
+    #[inline]
+    pub fn signature(&self) -> Signature { self.header().signature }
+
+    /// Returns every byte in this table, including the header.
+    #[inline]
+    pub fn bytes(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts( self.mmio.as_ptr::<u8>(0)
+                                  , self.mmio.len())
+        }
+    }
+
+    /// Read a `T` at `offset` bytes from the start of this table.
+    ///
+    /// # Returns
+    /// + `Some(T)` if the table is long enough to contain it
+    /// + `None` if it is not (e.g. if the field was added in a later
+    ///   revision than this table's).
+    #[inline]
+    pub fn read<T: Copy>(&self, offset: usize) -> Option<T> {
+        read(self.bytes(), offset)
+    }
+}
+
+/// Read a `T` at `offset` bytes into `bytes`, if it fits.
+#[inline]
+pub fn read<T: Copy>(bytes: &[u8], offset: usize) -> Option<T> {
+    if offset + mem::size_of::<T>() <= bytes.len() {
+        Some(unsafe {
+            ptr::read_unaligned(bytes[offset ..].as_ptr() as *const T)
+        })
+    } else {
+        None
+    }
+}
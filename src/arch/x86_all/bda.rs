@@ -12,8 +12,25 @@
 //! (http://wiki.osdev.org/Memory_Map_(x86)#BIOS_Data_Area_.28BDA.29)
 //! for more information.
 
+use memory::PAddr;
+
 type Word = u16;
 
+/// Address in the BDA of the real-mode segment of the Extended BIOS Data Area
+const EBDA_SEGMENT_ADDR: usize = 0x040e;
+
+/// Returns the physical address of the Extended BIOS Data Area (EBDA), if
+/// the BIOS reported one.
+///
+/// # Safety
+/// + The BDA must be identity mapped, as it is during early boot.
+pub unsafe fn ebda_addr() -> Option<PAddr> {
+    match *(EBDA_SEGMENT_ADDR as *const Word) {
+        0 => None
+      , segment => Some(PAddr::from((segment as u64) << 4))
+    }
+}
+
 pub mod ports {
     use super::Word;
     const PORTS_ADDR: usize = 0x0400;
@@ -123,6 +123,21 @@ impl Info {
             })
     }
 
+    /// Finds the copy of the ACPI RSDP passed by the bootloader.
+    ///
+    /// The ACPI 2.0+ RSDP is preferred, since it may point to the XSDT.
+    ///
+    ///  # Returns
+    ///  - `Some(PAddr)` with the address of the copied RSDP, if the
+    ///    bootloader passed one.
+    ///  - `None` if neither ACPI tag could be found.
+    pub fn acpi_rsdp(&'static self) -> Option<PAddr> {
+        self.get_tag(TagType::AcpiNew)
+            .or_else(|| self.get_tag(TagType::AcpiOld))
+            // the RSDP is copied directly after the tag header.
+            .map(|tag| PAddr::from(tag as *const Tag as u64 + 8))
+    }
+
     /// Returns an iterator over all Multiboot tags.
     #[inline]
     fn tags(&'static self) -> Tags { Tags(&self.tag_start as *const Tag) }
@@ -221,6 +236,19 @@ pub enum TagType { /// Tag that indicates the end of multiboot tags
                  , FramebufferInfo  = 8
                  , ELFSections      = 9
                  , APMTable         = 10
+                 , EFI32Table       = 11
+                 , EFI64Table       = 12
+                 , SMBIOSTables     = 13
+                 , /// A copy of the ACPI 1.0 RSDP
+                   AcpiOld          = 14
+                 , /// A copy of the ACPI 2.0+ RSDP
+                   AcpiNew          = 15
+                 , NetworkInfo      = 16
+                 , EFIMemoryMap     = 17
+                 , EFIBootServices  = 18
+                 , EFI32ImageHandle = 19
+                 , EFI64ImageHandle = 20
+                 , LoadBaseAddr     = 21
                  }
 
 /// An iterator over Multiboot 2 tags.
@@ -400,11 +428,16 @@ impl ElfSectionsTag {
     //  TODO: can the &'static bound be reduced to &'a? is there any reason to?
     //          - eliza, 03/04/2017
     #[inline] pub fn sections(&'static self) -> Sections<'static, Word> {
+        // `first_section` is the null section at index 0, so this counts
+        // every section header.
         Sections::new( &self.first_section
-                     , self.n_sections - 1
+                     , self.n_sections
                      , self.section_size
                      )
     }
+
+    /// Returns the index of the section containing the sections' names.
+    #[inline] pub fn string_table_index(&self) -> u32 { self.stringtable_idx }
 }
 
 impl IntoIterator for &'static ElfSectionsTag {
@@ -23,7 +23,7 @@
 
 #![doc(html_root_url = "https://hawkw.github.io/sos-kernel/")]
 
-#![feature( lang_items, asm, naked_functions )]
+#![feature( lang_items, asm, global_asm, naked_functions )]
 #![feature( linkage )]
 #![feature( const_fn
           , slice_patterns
@@ -46,6 +46,7 @@
 #[macro_use] extern crate log;
 
 extern crate alloc;
+extern crate arrayvec;
 extern crate rlibc;
 extern crate spin;
 
@@ -63,6 +64,7 @@ extern crate util;
 #[macro_use] pub mod io;
 
 pub mod heap;
+pub mod thread;
 pub mod arch;
 pub mod logger;
 
@@ -85,7 +87,7 @@ pub fn kernel_main() -> ! {
     // let mut frame_allocator = frame_alloc::FrameAllocator::new();
     // paging::test_paging(&mut frame_allocator);
 
-    loop { }
+    loop { thread::park() }
 }
 
 /// Kernel initialization function called into by architecture-specific init
@@ -121,7 +123,7 @@ pub fn kernel_init(params: &InitParams) {
     // -- remap the kernel ----------------------------------------------------
     let mut frame_allocator = MemMapAllocator::from(params);
     kinfoln!(dots: " . ", "Remapping the kernel...");
-    let page_table = match kernel_remap(&params, &mut frame_allocator) {
+    let mut page_table = match kernel_remap(&params, &mut frame_allocator) {
         Ok(p) => {
             kinfoln!(dots: " . ", target: "Remapping the kernel", "[ OKAY ]");
             p
@@ -135,6 +137,16 @@ pub fn kernel_init(params: &InitParams) {
     attempt!(paging::test_paging(&mut frame_allocator) =>
              dots: " . . ", "Testing paging...");
 
+    // -- load the kernel's symbols -------------------------------------------
+    match arch::symbols::initialize(params) {
+        Ok(n) => kinfoln!(dots: " . ", "Indexed {} kernel symbols.", n)
+      , Err(why) => kinfoln!(dots: " . ", "Not using kernel symbols: {}", why)
+    }
+    match arch::symbols::initialize_lines(params) {
+        Ok(n) => kinfoln!(dots: " . ", "Loaded {} bytes of line tables.", n)
+      , Err(why) => kinfoln!(dots: " . ", "Not using line tables: {}", why)
+    }
+
     // -- initialize the heap ------------------------------------------------
     attempt!( unsafe { heap::initialize(params) } =>
              dots: " . ", "Intializing heap...");
@@ -143,9 +155,44 @@ pub fn kernel_init(params: &InitParams) {
             , params.heap_base, params.heap_top);
 
 
+    // -- parse ACPI tables ---------------------------------------------------
+    if let Some(rsdp) = params.acpi_rsdp {
+        let acpi = attempt!( arch::acpi::initialize( rsdp, &mut page_table
+                                                   , &mut frame_allocator) =>
+                             dots: " . ", "Parsing ACPI tables..." );
+        acpi.print_summary();
+    }
+
     // -- initialize interrupts ----------------------------------------------
-    // attempt!( unsafe { arch::interrupts::initialize() } =>
-    //           "Initializing interrupts...", dots: " . " );
+    attempt!( unsafe { arch::interrupts::initialize( &mut page_table
+                                                   , &mut frame_allocator) } =>
+              dots: " . ", "Initializing interrupts..." );
+
+    // -- enable system calls ------------------------------------------------
+    attempt!( unsafe { arch::syscall::initialize( &mut page_table
+                                                , &mut frame_allocator) } =>
+              dots: " . ", "Enabling system calls..." );
+
+    // -- select a clock source ----------------------------------------------
+    attempt!( arch::clock::initialize(&mut page_table, &mut frame_allocator) =>
+              dots: " . ", "Selecting a clock source..." );
+
+    // -- start threading ----------------------------------------------------
+    attempt!( unsafe { thread::initialize( &mut page_table
+                                         , &mut frame_allocator) } =>
+              dots: " . ", "Starting the scheduler..." );
+    attempt!( thread::test_threads(&mut page_table, &mut frame_allocator) =>
+              dots: " . ", "Testing kernel threads..." );
+    attempt!( thread::test_preemption(&mut page_table, &mut frame_allocator) =>
+              dots: " . ", "Testing preemption..." );
+    let stats = thread::stats();
+    kinfoln!( dots: " . . ", "{} context switches, {} preemptions"
+            , stats.context_switches, stats.preemptions );
+
+    // -- start the application processors ----------------------------------
+    attempt!( unsafe { arch::smp::initialize( &mut page_table
+                                            , &mut frame_allocator) } =>
+              dots: " . ", "Starting application processors..." );
 
     println!("\n{} {}-bit\n", VERSION_STRING, arch::ARCH_BITS);
 
@@ -0,0 +1,602 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Kernel threads.
+//!
+//! Threads are scheduled preemptively, by [`Priority`]: the highest
+//! priority thread that's ready always runs, and threads of the same
+//! priority take turns, each running for a time slice of
+//! [`SLICE_TICKS`] timer ticks unless it blocks or yields first.
+//! A thread blocks when it [`sleep`]s, [`park`]s, or [`join`]s a thread
+//! which is still running. When no thread is ready, the idle thread halts
+//! the CPU until the next interrupt.
+//!
+//! Since there's no heap yet, everything a thread needs lives on its own
+//! stack: [`spawn`] puts the thread's control block at the very top of the
+//! stack, and the closure it runs just below that. The scheduler itself
+//! only keeps a fixed-size table of pointers to the control blocks. The
+//! stack is handed back by [`JoinHandle::join`], so that it can be returned
+//! to the stack allocator it came from.
+//!
+//! [`stats`] and [`for_each_thread`] report how much each thread has run,
+//! and how often the scheduler has switched threads.
+//!
+//! [`Priority`]: enum.Priority.html
+//! [`SLICE_TICKS`]: constant.SLICE_TICKS.html
+//! [`sleep`]: fn.sleep_until.html
+//! [`park`]: fn.park.html
+//! [`join`]: struct.JoinHandle.html#method.join
+//! [`spawn`]: fn.spawn.html
+//! [`JoinHandle::join`]: struct.JoinHandle.html#method.join
+//! [`stats`]: fn.stats.html
+//! [`for_each_thread`]: fn.for_each_thread.html
+use core::{fmt, mem, ptr};
+use core::ops::Range;
+
+use cpu::context::Context;
+use cpu::fpu;
+use cpu::interrupts::{self, irq, IrqMutexGuard};
+use cpu::interrupts::idt::Idt;
+use cpu::timer::{self, Deadline};
+use paging::arch::ActivePageTable;
+use paging::stack::{KERNEL_STACKS, Stack};
+use sos_alloc::FrameAllocator;
+
+use self::scheduler::{Scheduler, SCHEDULER};
+
+mod scheduler;
+
+pub use self::scheduler::{Stats, SLICE_TICKS};
+
+/// The maximum number of threads, including the boot and idle threads.
+pub const MAX_THREADS: usize = 32;
+
+/// The number of thread priorities.
+const NUM_PRIORITIES: usize = 3;
+
+/// The least stack space a new thread is left with, below its control block
+/// and closure.
+const MIN_STACK: usize = 4096;
+
+/// Identifies a thread.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct ThreadId(usize);
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "thread {}", self.0)
+    }
+}
+
+/// How urgently a thread should run.
+///
+/// A thread only runs when no thread of a higher priority is ready.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Priority { Low = 0, Normal = 1, High = 2 }
+
+impl Default for Priority {
+    #[inline] fn default() -> Self { Priority::Normal }
+}
+
+/// What a thread is doing.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum State { /// Waiting to run
+                 Ready
+               , /// Running right now
+                 Running
+               , /// Waiting for another thread to exit
+                 Joining(ThreadId)
+               , /// Sleeping until a deadline passes
+                 Sleeping(Deadline)
+               , /// Waiting to be woken with `wake`
+                 Parked
+               , /// Finished, but not yet joined
+                 Exited
+               }
+
+/// A thread's control block.
+///
+/// This is only public so that the scheduler's thread table can be; none of
+/// it is accessible outside this module.
+pub struct Thread { id: ThreadId
+                  , name: &'static str
+                  , priority: Priority
+                  , state: State
+                  , context: Context
+                  , /// The stack the thread runs on, if it was spawned
+                    stack: Option<Stack>
+                  , /// The range of the stack which backtraces may walk
+                    bounds: Range<usize>
+                  , /// Ticks left in the thread's time slice
+                    slice: u32
+                  , /// Set if the thread was woken while it wasn't parked, so
+                    /// that its next `park` returns straight away
+                    unparked: bool
+                  , /// The number of ticks the thread has run for
+                    runtime_ticks: u64
+                  , /// The number of times the thread has been switched to
+                    switches: u64
+                  }
+
+impl Thread {
+    /// Returns a control block for a thread which isn't running yet.
+    const fn new(id: ThreadId, name: &'static str, priority: Priority
+                , context: Context, stack: Option<Stack>
+                , bounds: Range<usize>) -> Self {
+        Thread { id: id
+               , name: name
+               , priority: priority
+               , state: State::Ready
+               , context: context
+               , stack: stack
+               , bounds: bounds
+               , slice: 0
+               , unparked: false
+               , runtime_ticks: 0
+               , switches: 0
+               }
+    }
+
+    /// Returns what can be reported about this thread.
+    fn info(&self) -> ThreadInfo {
+        ThreadInfo { id: self.id
+                   , name: self.name
+                   , priority: self.priority
+                   , state: self.state
+                   , runtime_ticks: self.runtime_ticks
+                   , switches: self.switches
+                   }
+    }
+}
+
+/// A snapshot of a thread, as reported by [`for_each_thread`].
+///
+/// [`for_each_thread`]: fn.for_each_thread.html
+#[derive(Copy, Clone, Debug)]
+pub struct ThreadInfo { pub id: ThreadId
+                      , pub name: &'static str
+                      , pub priority: Priority
+                      , pub state: State
+                      , /// The number of timer ticks the thread has run for
+                        pub runtime_ticks: u64
+                      , /// The number of times the thread has been switched
+                        /// to
+                        pub switches: u64
+                      }
+
+/// The control block of the thread that called `initialize`.
+static mut BOOT_THREAD: Thread
+    = Thread::new( ThreadId(0), "boot", Priority::Normal
+                 , Context::empty(), None, 0..0 );
+
+/// Lock the scheduler, and pass it to `f` with interrupts disabled.
+///
+/// Anything that might reschedule must lock the scheduler this way, since
+/// the thread that's switched to mustn't be interrupted until the switch is
+/// finished.
+#[inline]
+fn with_scheduler<F, T>(f: F) -> T
+where F: FnOnce(IrqMutexGuard<Scheduler>) -> T {
+    interrupts::without_interrupts(|| f(SCHEDULER.lock()))
+}
+
+/// Make the running code the boot thread, spawn the idle thread, and start
+/// preempting threads on the timer tick.
+///
+/// The idle thread's stack is allocated from `KERNEL_STACKS`. Backtraces
+/// are limited to the current stack bounds while the boot thread runs, so
+/// those should be set to the boot stack first.
+///
+/// # Safety
+/// + This must be called once, by the kernel init process, after the
+///   system tick has been started and before any threads are spawned.
+pub unsafe fn initialize<A>(table: &mut ActivePageTable, frames: &mut A)
+                           -> Result<(), &'static str>
+where A: FrameAllocator {
+    BOOT_THREAD.bounds = ::util::backtrace::stack_bounds();
+    BOOT_THREAD.state = State::Running;
+    {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.threads[0] = &mut BOOT_THREAD;
+        scheduler.current = 0;
+    }
+
+    let stack = KERNEL_STACKS.lock()
+                             .allocate(table, frames)
+                             .map_err(|_| "could not allocate a stack")?;
+    let id = Builder::new().name("idle")
+                           .priority(Priority::Low)
+                           .spawn_idle(idle, stack)?;
+    kinfoln!(dots: " . . ", "Spawned the idle thread ({})", id);
+
+    timer::periodic(1, scheduler::tick)?;
+    irq::set_exit_hook(scheduler::preempt);
+    kinfoln!(dots: " . . ", "Time slices are {} ticks", SLICE_TICKS);
+    Ok(())
+}
+
+/// The idle thread, which halts until the next interrupt, forever.
+///
+/// Any interrupt that makes a thread ready preempts the idle thread on its
+/// way out.
+fn idle() {
+    loop {
+        unsafe { asm!("hlt" :::: "volatile") }
+    }
+}
+
+/// Returns the running thread's ID.
+///
+/// # Panics
+/// + If called before [`initialize`](fn.initialize.html).
+pub fn current() -> ThreadId {
+    SCHEDULER.lock().current().id
+}
+
+/// Returns the scheduler's statistics.
+pub fn stats() -> Stats {
+    SCHEDULER.lock().stats
+}
+
+/// Call `f` with a snapshot of every thread that hasn't been joined.
+///
+/// The snapshots are taken all at once, and `f` is called after the
+/// scheduler has been unlocked, so it may do anything, including print.
+pub fn for_each_thread<F>(mut f: F)
+where F: FnMut(ThreadInfo) {
+    let mut infos: [Option<ThreadInfo>; MAX_THREADS] = [None; MAX_THREADS];
+    {
+        let scheduler = SCHEDULER.lock();
+        for (index, info) in infos.iter_mut().enumerate() {
+            *info = scheduler.get(index).map(|thread| thread.info());
+        }
+    }
+    for info in infos.iter().filter_map(|info| *info) {
+        f(info)
+    }
+}
+
+/// A handle to a spawned thread, which can be used to wait for it to exit.
+#[must_use]
+#[derive(Debug)]
+pub struct JoinHandle { id: ThreadId }
+
+impl JoinHandle {
+    /// Returns the thread's ID.
+    #[inline] pub fn id(&self) -> ThreadId { self.id }
+
+    /// Wait for the thread to exit.
+    ///
+    /// # Returns
+    /// + The stack the thread ran on, which is no longer in use and can be
+    ///   deallocated.
+    pub fn join(self) -> Stack {
+        loop {
+            let stack = with_scheduler(|mut scheduler| {
+                let index =
+                    scheduler.find(self.id)
+                             .expect("a thread can only be joined once");
+                let thread = scheduler.get(index)
+                                      .expect("found threads are in the table");
+                if thread.state == State::Exited {
+                    // the exited thread's control block is on the stack we
+                    // hand back, and `switch` drops the scheduler lock just
+                    // before it switches away from the thread, while it's
+                    // still on that stack. this is only safe because threads
+                    // are all scheduled on the boot CPU with interrupts
+                    // disabled, so once we're running here, that switch has
+                    // finished. scheduling threads on more than one CPU
+                    // will need a reaper that frees the stack later.
+                    scheduler.threads[index] = ptr::null_mut();
+                    // the control block is about to go away with the stack.
+                    fpu::release(&mut thread.context.extended);
+                    return thread.stack.take()
+                }
+                scheduler.current().state = State::Joining(self.id);
+                scheduler::reschedule(scheduler);
+                None
+            });
+            if let Some(stack) = stack {
+                return stack
+            }
+        }
+    }
+}
+
+/// Configures a new thread.
+#[derive(Copy, Clone, Debug)]
+pub struct Builder { name: &'static str
+                   , priority: Priority
+                   }
+
+impl Builder {
+    /// Returns a builder for an unnamed thread of normal priority.
+    pub const fn new() -> Self {
+        Builder { name: "unnamed", priority: Priority::Normal }
+    }
+
+    /// Name the thread, for diagnostics.
+    pub fn name(self, name: &'static str) -> Self {
+        Builder { name: name, ..self }
+    }
+
+    /// Set the thread's priority.
+    pub fn priority(self, priority: Priority) -> Self {
+        Builder { priority: priority, ..self }
+    }
+
+    /// Spawn the thread, which runs `f` on `stack`.
+    ///
+    /// The new thread is ready to run straight away; if it outranks the
+    /// running thread, it runs as soon as the next interrupt ends.
+    ///
+    /// # Returns
+    /// + `Ok(JoinHandle)` for the new thread
+    /// + `Err(&str)` if there are too many threads, or `stack` is too small.
+    pub fn spawn<F>(self, f: F, stack: Stack)
+                   -> Result<JoinHandle, &'static str>
+    where F: FnOnce() + Send + 'static {
+        with_scheduler(|mut scheduler| {
+            let index = self.create(&mut scheduler, f, stack)?;
+            let id = scheduler.get(index)
+                              .expect("created threads are in the table")
+                              .id;
+            scheduler.enqueue(index);
+            Ok(JoinHandle { id: id })
+        })
+    }
+
+    /// Spawn the idle thread, which is never queued, and runs only when no
+    /// other thread is ready.
+    fn spawn_idle(self, f: fn(), stack: Stack)
+                 -> Result<ThreadId, &'static str> {
+        let mut scheduler = SCHEDULER.lock();
+        let index = self.create(&mut scheduler, f, stack)?;
+        scheduler.idle = Some(index);
+        Ok(scheduler.get(index)
+                    .expect("created threads are in the table")
+                    .id)
+    }
+
+    /// Set up a control block for a thread which runs `f` on `stack`, and
+    /// add it to the thread table.
+    ///
+    /// # Returns
+    /// + `Ok(usize)` with the new thread's index in the thread table
+    /// + `Err(&str)` if there are too many threads, or `stack` is too small.
+    fn create<F>(self, scheduler: &mut Scheduler, f: F, stack: Stack)
+                -> Result<usize, &'static str>
+    where F: FnOnce() + Send + 'static {
+        let base = stack.start.as_usize();
+        let thread_addr = (stack.end.as_usize() - mem::size_of::<Thread>())
+                        & !(mem::align_of::<Thread>() - 1);
+        let f_addr = thread_addr.checked_sub(mem::size_of::<F>())
+                                .map(|addr| addr & !(mem::align_of::<F>() - 1))
+                                .ok_or("the stack is too small")?;
+        if f_addr < base + MIN_STACK {
+            return Err("the stack is too small")
+        }
+
+        let index = scheduler.threads.iter()
+                             .position(|thread| thread.is_null())
+                             .ok_or("too many threads")?;
+        let id = ThreadId(scheduler.next_id);
+        scheduler.next_id += 1;
+
+        unsafe {
+            ptr::write(f_addr as *mut F, f);
+            let thread = thread_addr as *mut Thread;
+            let context = Context::new(f_addr as *mut u8, start::<F>, f_addr);
+            ptr::write(thread, Thread::new( id, self.name, self.priority
+                                          , context, Some(stack)
+                                          , base .. thread_addr ));
+            scheduler.threads[index] = thread;
+        }
+        trace!("spawned {} ({}) at {:#x}", id, self.name, thread_addr);
+        Ok(index)
+    }
+}
+
+impl Default for Builder {
+    #[inline] fn default() -> Self { Builder::new() }
+}
+
+/// Spawn a new thread of normal priority, which runs `f` on `stack`.
+///
+/// See [`Builder::spawn`].
+///
+/// [`Builder::spawn`]: struct.Builder.html#method.spawn
+#[inline]
+pub fn spawn<F>(f: F, stack: Stack) -> Result<JoinHandle, &'static str>
+where F: FnOnce() + Send + 'static {
+    Builder::new().spawn(f, stack)
+}
+
+/// The entry point of a new thread, which runs the closure at `f`.
+extern "C" fn start<F>(f: usize) -> !
+where F: FnOnce() + Send + 'static {
+    // a thread is first switched to with interrupts disabled, and (unlike a
+    // thread that's switched back to) has nothing to restore them.
+    unsafe { Idt::enable_interrupts() }
+    let f = unsafe { ptr::read(f as *const F) };
+    f();
+    exit()
+}
+
+/// Let another thread of the same or higher priority run, if any are
+/// ready.
+pub fn yield_now() {
+    with_scheduler(scheduler::reschedule)
+}
+
+/// Sleep until `deadline` has passed.
+///
+/// The thread may be woken early with [`wake`].
+///
+/// [`wake`]: fn.wake.html
+pub fn sleep_until(deadline: Deadline) {
+    with_scheduler(|mut scheduler| {
+        if !deadline.has_passed() {
+            scheduler.current().state = State::Sleeping(deadline);
+            scheduler::reschedule(scheduler);
+        }
+    })
+}
+
+/// Sleep for at least `ms` milliseconds.
+///
+/// The thread may be woken early with [`wake`].
+///
+/// [`wake`]: fn.wake.html
+#[inline]
+pub fn sleep_ms(ms: u64) { sleep_until(Deadline::after_ms(ms)) }
+
+/// Block the running thread until it's woken with [`wake`].
+///
+/// If the thread was woken since it last parked, this returns straight
+/// away. It may also return spuriously, so callers should check whatever
+/// they're waiting for, and park again if need be.
+///
+/// [`wake`]: fn.wake.html
+pub fn park() {
+    with_scheduler(|mut scheduler| {
+        let thread = scheduler.current();
+        if mem::replace(&mut thread.unparked, false) {
+            return
+        }
+        thread.state = State::Parked;
+        scheduler::reschedule(scheduler);
+    })
+}
+
+/// Wake the thread `id`, if it's parked or sleeping.
+///
+/// If it isn't, its next call to [`park`] returns straight away. This may
+/// be called from interrupt handlers.
+///
+/// # Returns
+/// + `true` if there is a thread `id`
+/// + `false` if it has exited.
+///
+/// [`park`]: fn.park.html
+pub fn wake(id: ThreadId) -> bool {
+    let mut scheduler = SCHEDULER.lock();
+    let index = match scheduler.find(id) {
+        Some(index) => index
+      , None => return false
+    };
+    let thread = scheduler.get(index)
+                          .expect("found threads are in the table");
+    match thread.state {
+        State::Parked | State::Sleeping(_) => scheduler.enqueue(index)
+      , State::Exited => return false
+      , _ => thread.unparked = true
+    }
+    true
+}
+
+/// End the running thread.
+///
+/// # Panics
+/// + If called by the boot thread, which has nothing to return to.
+pub fn exit() -> ! {
+    with_scheduler(|mut scheduler| {
+        let id = {
+            let thread = scheduler.current();
+            assert!(thread.stack.is_some(), "the boot thread can't exit!");
+            thread.state = State::Exited;
+            thread.id
+        };
+        // wake up anything that's waiting for us.
+        for index in 0 .. MAX_THREADS {
+            let joining = scheduler.get(index)
+                                   .map(|t| t.state == State::Joining(id))
+                                   .unwrap_or(false);
+            if joining {
+                scheduler.enqueue(index);
+            }
+        }
+        trace!("{} exited", id);
+        scheduler::reschedule(scheduler);
+    });
+    unreachable!("exited threads are never switched back to")
+}
+
+/// Spawn a couple of threads which take turns, and join them.
+///
+/// Their stacks are allocated from (and returned to) `KERNEL_STACKS`.
+pub fn test_threads<A>(table: &mut ActivePageTable, frames: &mut A)
+                      -> Result<(), &'static str>
+where A: FrameAllocator {
+    use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+    static COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+    const ROUNDS: usize = 8;
+
+    fn take_turns() {
+        for _ in 0..ROUNDS {
+            COUNT.fetch_add(1, Ordering::Relaxed);
+            yield_now();
+        }
+    }
+
+    let mut handles = [None, None];
+    for handle in handles.iter_mut() {
+        let stack = KERNEL_STACKS.lock()
+                                 .allocate(table, frames)
+                                 .map_err(|_| "could not allocate a stack")?;
+        *handle = Some(spawn(take_turns, stack)?);
+    }
+    for handle in handles.iter_mut().filter_map(Option::take) {
+        let stack = handle.join();
+        KERNEL_STACKS.lock()
+                     .deallocate(stack, table, frames)
+                     .map_err(|_| "could not free a stack")?;
+    }
+
+    if COUNT.load(Ordering::Relaxed) == 2 * ROUNDS {
+        Ok(())
+    } else {
+        Err("the threads didn't both run to completion")
+    }
+}
+
+/// Spawn a thread which never yields, and check that it's preempted.
+///
+/// The running thread sleeps while the spinning thread runs, and must be
+/// switched back to when its sleep is over, since the spinning thread has
+/// used up its time slice by then. Its stack is allocated from (and
+/// returned to) `KERNEL_STACKS`.
+pub fn test_preemption<A>(table: &mut ActivePageTable, frames: &mut A)
+                         -> Result<(), &'static str>
+where A: FrameAllocator {
+    use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+    static STOP: AtomicBool = ATOMIC_BOOL_INIT;
+    const SLEEP_MS: u64 = 50;
+
+    fn spin() {
+        while !STOP.load(Ordering::Acquire) { }
+    }
+
+    let stack = KERNEL_STACKS.lock()
+                             .allocate(table, frames)
+                             .map_err(|_| "could not allocate a stack")?;
+    let before = stats().preemptions;
+    let handle = Builder::new().name("spin").spawn(spin, stack)?;
+    sleep_ms(SLEEP_MS);
+    STOP.store(true, Ordering::Release);
+    let stack = handle.join();
+    KERNEL_STACKS.lock()
+                 .deallocate(stack, table, frames)
+                 .map_err(|_| "could not free a stack")?;
+
+    if stats().preemptions > before {
+        Ok(())
+    } else {
+        Err("the spinning thread was never preempted")
+    }
+}
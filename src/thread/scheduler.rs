@@ -0,0 +1,302 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! The scheduler's run queues, and preemption.
+//!
+//! Each priority has its own FIFO run queue of threads which are ready to
+//! run, and the next thread to run is always taken from the highest
+//! priority queue that isn't empty. If every queue is empty, the idle
+//! thread runs.
+//!
+//! Preemption happens in two halves. [`tick`] runs as a timer callback in
+//! the timer interrupt: it wakes sleeping threads whose deadlines have
+//! passed, charges the tick to the running thread, and, once that thread's
+//! time slice runs out, sets [`NEED_RESCHED`] if something else should run.
+//! [`preempt`] runs at the end of the IRQ, once the interrupt has been
+//! acknowledged, and switches threads if `NEED_RESCHED` is set. The
+//! preempted thread's interrupt frame stays on its own stack, so when it's
+//! switched back to, `preempt` returns and the IRQ stub returns through the
+//! frame to wherever the thread was interrupted.
+//!
+//! Threads are only scheduled on the boot CPU. Application processors just
+//! halt once they're online, and never switch threads, so `preempt` does
+//! nothing on them. That's why the scheduler's own state, `NEED_RESCHED`,
+//! and the backtrace stack bounds (which follow the running thread) can
+//! all be global rather than per-CPU. Scheduling on other CPUs will need
+//! them to be per-CPU.
+//!
+//! [`tick`]: fn.tick.html
+//! [`NEED_RESCHED`]: static.NEED_RESCHED.html
+//! [`preempt`]: fn.preempt.html
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+
+use cpu::interrupts::{IrqMutex, IrqMutexGuard};
+use cpu::syscall;
+use cpu::timer;
+
+use super::{Priority, State, Thread, MAX_THREADS, NUM_PRIORITIES};
+
+/// How many ticks a thread may run for before it's preempted, if another
+/// thread of the same or higher priority is ready.
+pub const SLICE_TICKS: u32 = 10;
+
+/// A FIFO queue of threads, by their index in the thread table.
+///
+/// A thread is in at most one queue at a time, so a queue never holds more
+/// than `MAX_THREADS` threads.
+pub struct RunQueue { slots: [usize; MAX_THREADS]
+                    , head: usize
+                    , len: usize
+                    }
+
+impl RunQueue {
+    pub const fn new() -> Self {
+        RunQueue { slots: [0; MAX_THREADS], head: 0, len: 0 }
+    }
+
+    #[inline] pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    /// Add the thread at `index` to the back of the queue.
+    pub fn push(&mut self, index: usize) {
+        assert!(self.len < MAX_THREADS, "run queue overflowed!");
+        self.slots[(self.head + self.len) % MAX_THREADS] = index;
+        self.len += 1;
+    }
+
+    /// Take the thread at the front of the queue.
+    pub fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None
+        }
+        let index = self.slots[self.head];
+        self.head = (self.head + 1) % MAX_THREADS;
+        self.len -= 1;
+        Some(index)
+    }
+}
+
+/// Statistics about the scheduler.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Stats { /// The number of times a different thread was switched to
+                   pub context_switches: u64
+                 , /// The number of those switches which preempted the
+                   /// running thread
+                   pub preemptions: u64
+                 , /// The number of ticks spent in the idle thread
+                   pub idle_ticks: u64
+                 }
+
+/// The thread table and run queues.
+pub struct Scheduler { pub threads: [*mut Thread; MAX_THREADS]
+                     , /// Index of the running thread in `threads`
+                       pub current: usize
+                     , /// Index of the idle thread, once it's spawned
+                       pub idle: Option<usize>
+                     , pub next_id: usize
+                     , queues: [RunQueue; NUM_PRIORITIES]
+                     , pub stats: Stats
+                     }
+
+// the control blocks are only touched with the scheduler locked, or by the
+// thread they belong to.
+unsafe impl Send for Scheduler { }
+
+/// The scheduler.
+///
+/// This is locked by the timer interrupt, so it disables interrupts while
+/// it is held.
+pub static SCHEDULER: IrqMutex<Scheduler>
+    = IrqMutex::new(Scheduler { threads: [0 as *mut Thread; MAX_THREADS]
+                              , current: 0
+                              , idle: None
+                              , next_id: 1
+                              , queues: [ RunQueue::new()
+                                        , RunQueue::new()
+                                        , RunQueue::new() ]
+                              , stats: Stats { context_switches: 0
+                                             , preemptions: 0
+                                             , idle_ticks: 0
+                                             }
+                              });
+
+/// Set when the running thread should be preempted at the end of the
+/// current IRQ on the boot CPU.
+static NEED_RESCHED: AtomicBool = ATOMIC_BOOL_INIT;
+
+impl Scheduler {
+    /// Returns the running thread.
+    ///
+    /// # Panics
+    /// + If threads haven't been initialized yet, since there is no thread
+    ///   table entry for the running thread until then.
+    #[inline]
+    pub fn current(&self) -> &'static mut Thread {
+        self.get(self.current)
+            .expect("threads have not been initialized yet")
+    }
+
+    /// Returns the thread at `index`, if there is one.
+    #[inline]
+    pub fn get(&self, index: usize) -> Option<&'static mut Thread> {
+        let thread = self.threads[index];
+        if thread.is_null() { None } else { Some(unsafe { &mut *thread }) }
+    }
+
+    /// Returns the index of the thread `id`, if it hasn't been joined.
+    pub fn find(&self, id: super::ThreadId) -> Option<usize> {
+        (0 .. MAX_THREADS).find(|&i|
+            self.get(i).map(|thread| thread.id == id).unwrap_or(false))
+    }
+
+    /// Returns true if the running thread is the idle thread.
+    #[inline]
+    fn is_idle(&self) -> bool { self.idle == Some(self.current) }
+
+    /// Returns true if a thread of at least `priority` is ready to run.
+    fn is_ready(&self, priority: Priority) -> bool {
+        self.queues[priority as usize ..].iter().any(|q| !q.is_empty())
+    }
+
+    /// Make the thread at `index` ready to run, and queue it.
+    ///
+    /// If it outranks the running thread, the running thread is preempted
+    /// at the end of the next IRQ.
+    pub fn enqueue(&mut self, index: usize) {
+        let priority = {
+            let thread = self.get(index).expect("no thread to enqueue");
+            thread.state = State::Ready;
+            thread.priority
+        };
+        self.queues[priority as usize].push(index);
+        if self.is_idle() || priority > self.current().priority {
+            NEED_RESCHED.store(true, Ordering::Release);
+        }
+    }
+
+    /// Take the highest priority thread that's ready to run, or the idle
+    /// thread if none are.
+    ///
+    /// # Panics
+    /// + If nothing is ready to run and there's no idle thread yet.
+    fn dequeue(&mut self) -> usize {
+        let next = self.queues.iter_mut().rev()
+                       .filter_map(RunQueue::pop)
+                       .next();
+        match next.or(self.idle) {
+            Some(index) => index
+          , None => panic!( "{} is blocked, and no threads can run!"
+                          , self.current().id)
+        }
+    }
+}
+
+/// Switch to the next thread that should run.
+///
+/// If the current thread is still running, it goes to the back of its run
+/// queue; otherwise, it stays off the run queues until it's woken.
+///
+/// This must be called with interrupts disabled, and they must stay
+/// disabled until it returns: the thread that's switched to may re-enable
+/// them, and then switch back to this one at any point. So `scheduler`
+/// must have been locked with interrupts already disabled, so that dropping
+/// it doesn't re-enable them.
+#[inline]
+pub fn reschedule(scheduler: IrqMutexGuard<Scheduler>) {
+    switch(scheduler, false)
+}
+
+/// Switch to the next thread that should run, counting the switch as a
+/// preemption if `preempting` is true.
+///
+/// # Panics
+/// + If the running thread is in the middle of a system call, since the
+///   next thread's system calls would overwrite its saved registers on the
+///   CPU's system call stack.
+fn switch(mut scheduler: IrqMutexGuard<Scheduler>, preempting: bool) {
+    assert!( !syscall::in_progress()
+           , "cannot switch threads in the middle of a system call");
+    NEED_RESCHED.store(false, Ordering::Release);
+    let prev_index = scheduler.current;
+    let prev = scheduler.current();
+    if scheduler.is_idle() {
+        // the idle thread never waits in a run queue.
+        prev.state = State::Ready;
+    } else if prev.state == State::Running {
+        scheduler.enqueue(prev_index);
+    }
+    let next_index = scheduler.dequeue();
+    let next = scheduler.get(next_index)
+                        .expect("queued threads are in the thread table");
+    next.state = State::Running;
+    next.slice = SLICE_TICKS;
+    if next_index == prev_index {
+        return
+    }
+
+    scheduler.current = next_index;
+    scheduler.stats.context_switches += 1;
+    if preempting {
+        scheduler.stats.preemptions += 1;
+    }
+    next.switches += 1;
+    ::util::backtrace::set_stack_bounds(next.bounds.start, next.bounds.end);
+
+    // the lock must be released before switching, since the next thread
+    // will lock it again when it next reschedules.
+    drop(scheduler);
+    unsafe { prev.context.switch_to(&mut next.context) }
+}
+
+/// Account for a timer tick.
+///
+/// This runs as a timer callback every tick, with interrupts disabled.
+pub fn tick() {
+    let now = timer::ticks();
+    let mut scheduler = SCHEDULER.lock();
+
+    // wake up any threads whose sleep is over.
+    for index in 0 .. MAX_THREADS {
+        let wake = match scheduler.get(index) {
+            Some(&mut Thread { state: State::Sleeping(until), .. }) =>
+                until.tick() <= now
+          , _ => false
+        };
+        if wake {
+            scheduler.enqueue(index);
+        }
+    }
+
+    let thread = scheduler.current();
+    thread.runtime_ticks += 1;
+    if scheduler.is_idle() {
+        scheduler.stats.idle_ticks += 1;
+        if scheduler.is_ready(Priority::Low) {
+            NEED_RESCHED.store(true, Ordering::Release);
+        }
+        return
+    }
+
+    thread.slice = thread.slice.saturating_sub(1);
+    if thread.slice == 0 && scheduler.is_ready(thread.priority) {
+        NEED_RESCHED.store(true, Ordering::Release);
+    }
+}
+
+/// Switch threads if the running thread should be preempted.
+///
+/// This is the IRQ exit hook, so it runs after every IRQ has been
+/// acknowledged, with interrupts disabled. IRQs on application processors
+/// are ignored, since threads only run on the boot CPU.
+pub fn preempt() {
+    if ::arch::percpu::current().index() != 0 {
+        return
+    }
+    if NEED_RESCHED.load(Ordering::Acquire) {
+        switch(SCHEDULER.lock(), true);
+    }
+}
@@ -0,0 +1,351 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Stack backtraces, by walking frame pointers.
+//!
+//! The kernel is built with frame pointers (`"eliminate-frame-pointer":
+//! false` in the target spec), so every function's prologue pushes the
+//! caller's `%rbp` and points `%rbp` at it. Each frame is therefore a
+//! saved `%rbp`, followed by the return address into the caller:
+//!
+//! ```text
+//!  rbp + 8 -> return address
+//!  rbp     -> caller's rbp
+//! ```
+//!
+//! [`Backtrace`] follows that chain, yielding each return address. Since a
+//! broken stack could send it anywhere, it only reads frames inside the
+//! stack bounds set with [`set_stack_bounds`], and stops as soon as a frame
+//! isn't strictly above the last one.
+//!
+//! Addresses can be printed as `function+offset` with [`Symbolized`] (or
+//! [`ReturnAddress`], for return addresses), once the kernel has loaded its
+//! symbol table and installed a [`Symbolizer`].
+//! If it has also installed a [`Locator`], the source file and line are
+//! printed too.
+//!
+//! This lives here rather than in `cpu` so that the panic handler in `vga`
+//! can use it.
+//!
+//! [`Backtrace`]: struct.Backtrace.html
+//! [`set_stack_bounds`]: fn.set_stack_bounds.html
+//! [`Symbolized`]: struct.Symbolized.html
+//! [`ReturnAddress`]: struct.ReturnAddress.html
+//! [`Symbolizer`]: type.Symbolizer.html
+//! [`Locator`]: type.Locator.html
+use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+use spin::Once;
+
+/// The maximum number of frames a `Backtrace` will yield.
+pub const MAX_DEPTH: usize = 64;
+
+/// The lowest address of the kernel stack.
+static STACK_BASE: AtomicUsize = ATOMIC_USIZE_INIT;
+/// The highest address of the kernel stack.
+static STACK_TOP: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Set the bounds of the kernel stack, which backtraces stay inside.
+///
+/// Until this is called, backtraces are empty (apart from the instruction
+/// pointer of an interrupted frame).
+pub fn set_stack_bounds(base: usize, top: usize) {
+    STACK_BASE.store(base, Ordering::Relaxed);
+    STACK_TOP.store(top, Ordering::Relaxed);
+}
+
+/// Returns the bounds of the kernel stack.
+#[inline]
+pub fn stack_bounds() -> Range<usize> {
+    STACK_BASE.load(Ordering::Relaxed) .. STACK_TOP.load(Ordering::Relaxed)
+}
+
+/// A function which finds the symbol containing an address.
+///
+/// It returns the symbol's name, and the address's offset from the start of
+/// the symbol.
+pub type Symbolizer = fn(addr: usize) -> Option<(&'static str, usize)>;
+
+/// The symbolizer, once the kernel's symbols have been loaded.
+static SYMBOLIZER: Once<Symbolizer> = Once::new();
+
+/// Use `symbolizer` to find the symbols containing addresses.
+///
+/// This can only be done once; later calls do nothing.
+pub fn set_symbolizer(symbolizer: Symbolizer) {
+    SYMBOLIZER.call_once(|| symbolizer);
+}
+
+/// Find the symbol containing `addr`, if a symbolizer has been set.
+///
+/// # Returns
+/// + `Some((name, offset))` if `addr` is `offset` bytes into the symbol
+///   `name`
+/// + `None` if no symbol contains `addr`, or there is no symbolizer yet.
+pub fn symbolize(addr: usize) -> Option<(&'static str, usize)> {
+    SYMBOLIZER.try().and_then(|symbolizer| symbolizer(addr))
+}
+
+/// A location in the kernel's source code.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SourceLocation { /// The directory containing `file`, if it is
+                            /// not the directory the kernel was built in
+                            pub directory: Option<&'static str>
+                          , /// The source file's name
+                            pub file: &'static str
+                          , /// The line number, starting at 1
+                            pub line: u64
+                          }
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(directory) = self.directory {
+            write!(f, "{}/", directory)?;
+        }
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// A function which finds the source location of the instruction at an
+/// address.
+pub type Locator = fn(addr: usize) -> Option<SourceLocation>;
+
+/// The locator, once the kernel's line tables have been loaded.
+static LOCATOR: Once<Locator> = Once::new();
+
+/// Use `locator` to find the source locations of addresses.
+///
+/// This can only be done once; later calls do nothing.
+pub fn set_locator(locator: Locator) {
+    LOCATOR.call_once(|| locator);
+}
+
+/// Find the source location of the instruction at `addr`, if a locator has
+/// been set.
+pub fn locate(addr: usize) -> Option<SourceLocation> {
+    LOCATOR.try().and_then(|locator| locator(addr))
+}
+
+/// An address, which is formatted along with the symbol containing it
+/// and its source location (as `0x... <function+0x12> at src/foo.rs:42`),
+/// if those are known.
+#[derive(Copy, Clone, Debug)]
+pub struct Symbolized(pub usize);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        describe(f, self.0, self.0)
+    }
+}
+
+/// A return address, which is formatted like a [`Symbolized`] address.
+///
+/// A return address points just past a call instruction. If the call was
+/// the last instruction in a function (e.g. a call to a function that
+/// never returns), that's the start of the next function, and in any case
+/// the line after the call. So the symbol and source location are looked up
+/// for the address before it, which is inside the call.
+///
+/// [`Symbolized`]: struct.Symbolized.html
+#[derive(Copy, Clone, Debug)]
+pub struct ReturnAddress(pub usize);
+
+impl fmt::Display for ReturnAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        describe(f, self.0, self.0.wrapping_sub(1))
+    }
+}
+
+/// Format `addr`, along with the symbol and source location of `lookup`.
+///
+/// The offset printed is from the start of the symbol to `addr`.
+fn describe(f: &mut fmt::Formatter, addr: usize, lookup: usize)
+           -> fmt::Result {
+    write!(f, "{:#018x}", addr)?;
+    if let Some((name, offset)) = symbolize(lookup) {
+        write!(f, " <{}+{:#x}>", name, offset + (addr - lookup))?;
+    }
+    match locate(lookup) {
+        Some(location) => write!(f, " at {}", location)
+      , None => Ok(())
+    }
+}
+
+/// An address in a [`Backtrace`].
+///
+/// [`Backtrace`]: struct.Backtrace.html
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Frame { /// The instruction pointer of the interrupted code
+                 Interrupted(usize)
+               , /// The return address of a stack frame
+                 Return(usize)
+               }
+
+impl Frame {
+    /// Returns the address of this frame.
+    #[inline]
+    pub fn addr(&self) -> usize {
+        match *self {
+            Frame::Interrupted(addr) | Frame::Return(addr) => addr
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Frame::Interrupted(addr) => Symbolized(addr).fmt(f)
+          , Frame::Return(addr) => ReturnAddress(addr).fmt(f)
+        }
+    }
+}
+
+/// Returns the current value of `%rbp`.
+///
+/// Since this is always inlined, this is the frame pointer of the function
+/// which calls it.
+#[inline(always)]
+pub fn current_rbp() -> usize {
+    let rbp: usize;
+    unsafe { asm!("mov %rbp, $0" : "=r" (rbp) ::: "volatile") }
+    rbp
+}
+
+/// An iterator over the return addresses on the stack.
+///
+/// If the backtrace is of interrupted code, the interrupted instruction
+/// pointer comes first.
+#[derive(Clone, Debug)]
+pub struct Backtrace { /// An address to yield before walking the stack.
+                       first: Option<usize>
+                     , /// The frame pointer of the next frame.
+                       rbp: usize
+                     , bounds: Range<usize>
+                     , depth: usize
+                     }
+
+impl Backtrace {
+    /// Returns a backtrace starting at the frame `rbp` points to, inside
+    /// the kernel stack.
+    ///
+    /// # Safety
+    /// + The kernel stack's bounds must be mapped, if they have been set.
+    pub unsafe fn from_rbp(rbp: usize) -> Self {
+        Backtrace::within(rbp, stack_bounds())
+    }
+
+    /// Returns a backtrace starting at the frame `rbp` points to, which only
+    /// reads frames inside `bounds`.
+    ///
+    /// # Safety
+    /// + Every address in `bounds` must be mapped.
+    pub unsafe fn within(rbp: usize, bounds: Range<usize>) -> Self {
+        Backtrace { first: None, rbp: rbp, bounds: bounds, depth: 0 }
+    }
+
+    /// Returns a backtrace of the code an interrupt handler interrupted.
+    ///
+    /// `rip` is the instruction pointer from the handler's interrupt frame,
+    /// and `handler_rbp` is the handler's own frame pointer, which points at
+    /// the interrupted code's `%rbp`.
+    ///
+    /// # Safety
+    /// + `handler_rbp` must be the frame pointer of an interrupt handler.
+    pub unsafe fn from_interrupt(rip: usize, handler_rbp: usize) -> Self {
+        Backtrace { first: Some(rip)
+                  , ..Backtrace::from_rbp(*(handler_rbp as *const usize))
+                  }
+    }
+
+    /// Returns a backtrace of the function which calls this.
+    ///
+    /// # Safety
+    /// + The kernel stack's bounds must be mapped, if they have been set.
+    #[inline(always)]
+    pub unsafe fn current() -> Self {
+        Backtrace::from_rbp(current_rbp())
+    }
+
+    /// Returns true if a frame at `rbp` lies entirely inside the bounds.
+    fn in_bounds(&self, rbp: usize) -> bool {
+        rbp % 8 == 0
+            && rbp >= self.bounds.start
+            && rbp.saturating_add(16) <= self.bounds.end
+    }
+}
+
+impl Iterator for Backtrace {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        if let Some(rip) = self.first.take() {
+            self.depth += 1;
+            return Some(Frame::Interrupted(rip))
+        }
+        if self.depth >= MAX_DEPTH || !self.in_bounds(self.rbp) {
+            return None
+        }
+        let (next_rbp, ret) = unsafe {
+            let frame = self.rbp as *const usize;
+            (*frame, *frame.offset(1))
+        };
+        if ret == 0 {
+            return None
+        }
+        // the stack grows down, so callers' frames are always higher up.
+        // anything else means the chain is broken, or loops.
+        self.rbp = if next_rbp > self.rbp { next_rbp } else { 0 };
+        self.depth += 1;
+        Some(Frame::Return(ret))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Backtrace;
+
+    /// Build a fake stack of `frames` (return address, next frame index)
+    /// pairs, and walk it.
+    fn walk(stack: &mut [usize; 16], frames: &[(usize, usize)]) -> [usize; 8] {
+        let base = stack.as_ptr() as usize;
+        for (i, &(ret, next)) in frames.iter().enumerate() {
+            stack[i * 2] = base + next * 16;
+            stack[i * 2 + 1] = ret;
+        }
+        let mut out = [0; 8];
+        let bt = unsafe { Backtrace::within(base, base .. base + 16 * 8) };
+        for (slot, frame) in out.iter_mut().zip(bt) {
+            *slot = frame.addr();
+        }
+        out
+    }
+
+    #[test]
+    fn test_walks_chain() {
+        let mut stack = [0; 16];
+        let out = walk(&mut stack, &[(0x10, 1), (0x20, 2), (0x30, 3)]);
+        // the fourth frame's return address is zero, ending the walk.
+        assert_eq!(&out[..4], &[0x10, 0x20, 0x30, 0]);
+    }
+
+    #[test]
+    fn test_stops_on_loop() {
+        let mut stack = [0; 16];
+        let out = walk(&mut stack, &[(0x10, 1), (0x20, 0)]);
+        assert_eq!(&out[..3], &[0x10, 0x20, 0]);
+    }
+
+    #[test]
+    fn test_stops_out_of_bounds() {
+        let mut stack = [0; 16];
+        let out = walk(&mut stack, &[(0x10, 1), (0x20, 100)]);
+        assert_eq!(&out[..3], &[0x10, 0x20, 0]);
+    }
+}
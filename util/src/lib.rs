@@ -11,14 +11,21 @@
 #![no_std]
 
 #![feature(step_trait)]
+#![feature(asm, const_fn)]
 // #[cfg(not(test))] extern crate vga;
 
+extern crate spin;
+
 use core::{fmt, ops};
 use ops::*;
 use core::iter::Step;
 // use core::num::One;
 
 pub mod io;
+#[cfg(target_arch = "x86_64")]
+pub mod backtrace;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod sync;
 
 #[macro_use] pub mod macros;
 
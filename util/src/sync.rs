@@ -0,0 +1,203 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Locks which are safe to share with interrupt handlers.
+//!
+//! If an interrupt handler locks a `spin::Mutex` which the code it
+//! interrupted was holding, the handler spins forever, since the lock holder
+//! can't run again until the handler returns. [`IrqMutex`] and
+//! [`IrqRwLock`] disable interrupts for as long as they are held, and
+//! restore the interrupt flag to what it was when the guard is dropped.
+//!
+//! These live here rather than in `cpu` (next to `cpu::flags` and
+//! `Idt::disable_interrupts`) because the `vga` and `sos_alloc` crates, which
+//! `cpu` depends on, need them too. `cpu::interrupts` re-exports them.
+//!
+//! [`IrqMutex`]: struct.IrqMutex.html
+//! [`IrqRwLock`]: struct.IrqRwLock.html
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+
+use spin::{ Mutex, MutexGuard
+          , RwLock, RwLockReadGuard, RwLockWriteGuard };
+
+/// The interrupt enable bit in `%eflags`/`%rflags`.
+const IF: usize = 1 << 9;
+
+/// Whether interrupts were enabled before a lock was taken.
+///
+/// When this is dropped, interrupts are re-enabled if they were.
+struct SavedIf(bool);
+
+impl SavedIf {
+    /// Disable interrupts, saving whether or not they were enabled.
+    #[inline(always)]
+    fn disable() -> Self {
+        let flags: usize;
+        unsafe {
+            asm!( "pushf
+                   pop $0
+                   cli"
+                : "=r" (flags)
+                :
+                : "memory"
+                : "volatile" );
+        }
+        SavedIf(flags & IF != 0)
+    }
+}
+
+impl Drop for SavedIf {
+    #[inline(always)]
+    fn drop(&mut self) {
+        if self.0 {
+            unsafe { asm!("sti" :::: "volatile") }
+        }
+    }
+}
+
+/// A mutual exclusion lock which disables interrupts while it is held.
+pub struct IrqMutex<T: ?Sized> { inner: Mutex<T> }
+
+/// A guard holding an [`IrqMutex`].
+///
+/// Interrupts are restored after the lock is released, when this is
+/// dropped.
+///
+/// [`IrqMutex`]: struct.IrqMutex.html
+pub struct IrqMutexGuard<'a, T: ?Sized + 'a> {
+    // N.B. that fields are dropped in order, so the lock is released before
+    // interrupts are re-enabled.
+    guard: MutexGuard<'a, T>
+  , _saved: SavedIf
+}
+
+impl<T> IrqMutex<T> {
+    /// Returns a new `IrqMutex` holding `data`.
+    pub const fn new(data: T) -> Self {
+        IrqMutex { inner: Mutex::new(data) }
+    }
+}
+
+impl<T: ?Sized> IrqMutex<T> {
+    /// Disable interrupts and lock this mutex, spinning until it is
+    /// available.
+    #[inline]
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        let saved = SavedIf::disable();
+        IrqMutexGuard { guard: self.inner.lock(), _saved: saved }
+    }
+
+    /// Try to lock this mutex, with interrupts disabled, without spinning.
+    ///
+    /// # Returns
+    /// + `Some(IrqMutexGuard)` if the lock was acquired
+    /// + `None` if it is held elsewhere, in which case interrupts are
+    ///   left as they were.
+    #[inline]
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<T>> {
+        let saved = SavedIf::disable();
+        self.inner.try_lock()
+            .map(|guard| IrqMutexGuard { guard: guard, _saved: saved })
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "IrqMutex({:?})", self.inner)
+    }
+}
+
+impl<'a, T: ?Sized> Deref for IrqMutexGuard<'a, T> {
+    type Target = T;
+    #[inline] fn deref(&self) -> &T { &*self.guard }
+}
+
+impl<'a, T: ?Sized> DerefMut for IrqMutexGuard<'a, T> {
+    #[inline] fn deref_mut(&mut self) -> &mut T { &mut *self.guard }
+}
+
+/// A reader-writer lock which disables interrupts while it is held.
+pub struct IrqRwLock<T: ?Sized> { inner: RwLock<T> }
+
+/// A guard holding an [`IrqRwLock`] for reading.
+///
+/// [`IrqRwLock`]: struct.IrqRwLock.html
+pub struct IrqReadGuard<'a, T: ?Sized + 'a> {
+    guard: RwLockReadGuard<'a, T>
+  , _saved: SavedIf
+}
+
+/// A guard holding an [`IrqRwLock`] for writing.
+///
+/// [`IrqRwLock`]: struct.IrqRwLock.html
+pub struct IrqWriteGuard<'a, T: ?Sized + 'a> {
+    guard: RwLockWriteGuard<'a, T>
+  , _saved: SavedIf
+}
+
+impl<T> IrqRwLock<T> {
+    /// Returns a new `IrqRwLock` holding `data`.
+    pub const fn new(data: T) -> Self {
+        IrqRwLock { inner: RwLock::new(data) }
+    }
+}
+
+impl<T: ?Sized> IrqRwLock<T> {
+    /// Disable interrupts and lock this `IrqRwLock` for reading, spinning
+    /// until there are no writers.
+    #[inline]
+    pub fn read(&self) -> IrqReadGuard<T> {
+        let saved = SavedIf::disable();
+        IrqReadGuard { guard: self.inner.read(), _saved: saved }
+    }
+
+    /// Disable interrupts and lock this `IrqRwLock` for writing, spinning
+    /// until there are no readers or writers.
+    #[inline]
+    pub fn write(&self) -> IrqWriteGuard<T> {
+        let saved = SavedIf::disable();
+        IrqWriteGuard { guard: self.inner.write(), _saved: saved }
+    }
+
+    /// Try to lock this `IrqRwLock` for reading, without spinning.
+    #[inline]
+    pub fn try_read(&self) -> Option<IrqReadGuard<T>> {
+        let saved = SavedIf::disable();
+        self.inner.try_read()
+            .map(|guard| IrqReadGuard { guard: guard, _saved: saved })
+    }
+
+    /// Try to lock this `IrqRwLock` for writing, without spinning.
+    #[inline]
+    pub fn try_write(&self) -> Option<IrqWriteGuard<T>> {
+        let saved = SavedIf::disable();
+        self.inner.try_write()
+            .map(|guard| IrqWriteGuard { guard: guard, _saved: saved })
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for IrqRwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "IrqRwLock({:?})", self.inner)
+    }
+}
+
+impl<'a, T: ?Sized> Deref for IrqReadGuard<'a, T> {
+    type Target = T;
+    #[inline] fn deref(&self) -> &T { &*self.guard }
+}
+
+impl<'a, T: ?Sized> Deref for IrqWriteGuard<'a, T> {
+    type Target = T;
+    #[inline] fn deref(&self) -> &T { &*self.guard }
+}
+
+impl<'a, T: ?Sized> DerefMut for IrqWriteGuard<'a, T> {
+    #[inline] fn deref_mut(&mut self) -> &mut T { &mut *self.guard }
+}
@@ -27,7 +27,7 @@
 #![no_std]
 
 #[cfg(feature = "system_term")]
-extern crate spin;
+extern crate util;
 
 
 
@@ -36,7 +36,7 @@ use core::fmt::{Write, Result};
 // use core::ptr::Unique;
 
 #[cfg(feature = "system_term")]
-use spin::Mutex;
+use util::sync::IrqMutex;
 #[cfg(feature = "kinfo")]
 #[macro_use] extern crate log;
 
@@ -86,10 +86,13 @@ pub mod status;
 
 
 /// The system's global VGA terminal
+///
+/// Interrupt handlers print to this, so it disables interrupts while it is
+/// locked.
 /// TODO: should this live in the kernel instead?
 #[cfg(feature = "system_term")]
-pub static CONSOLE: Mutex<Terminal>
-    = Mutex::new(unsafe { Terminal::new(
+pub static CONSOLE: IrqMutex<Terminal>
+    = IrqMutex::new(unsafe { Terminal::new(
          Palette::new(Color::LightGrey, Color::Black )
        , 0xB8000
     )});
@@ -9,11 +9,26 @@
 //! Panic handling.
 //!
 //! This module contains the `panic_fmt` language item. This function handles
-//! panics at runtime.
+//! panics at runtime. It also contains [`print_backtrace`], which the
+//! kernel's fatal exception handlers share with the panic handler.
+//!
+//! [`print_backtrace`]: fn.print_backtrace.html
 
 use core::fmt::{Arguments, Write};
+#[cfg(target_arch = "x86_64")]
+use util::backtrace::Backtrace;
 use super::{Color, CONSOLE};
 
+#[cfg(target_arch = "x86_64")]
+/// Print each return address in `backtrace` to the console and the log.
+pub fn print_backtrace(backtrace: Backtrace) {
+    let _ = write!(CONSOLE.lock(), "\nBacktrace:\n");
+    for (i, frame) in backtrace.enumerate() {
+        let _ = write!(CONSOLE.lock(), "  {:>2}: {}\n", i, frame);
+        error!(target: "backtrace", "{:>2}: {}", i, frame);
+    }
+}
+
 /// Called to handle a panic.
 ///
 /// Since kernel panics are non-recoverable, this function prints out
@@ -35,5 +50,7 @@ pub extern "C" fn rust_begin_unwind( args: Arguments
                   , file, line, args
                   );
     error!(target: file, "{}", args);
+    #[cfg(target_arch = "x86_64")]
+    print_backtrace(unsafe { Backtrace::current() });
     loop { }
 }